@@ -6,9 +6,13 @@ struct PerCornData {
   rotation: vec2<f32>,
   //currently empty: could be used to flag certain per corn field data later
   uuid: u32,
+  // 1 if rendered, CORN_CUT if an edit disabled it, 0 otherwise
   enabled: u32
 }
 
+// Value of `PerCornData.enabled` for stalks disabled by an edit. Mirrors `CornData::CUT` in `src/ecs/corn/mod.rs`
+const CORN_CUT: u32 = 2u;
+
 // Per corn data converted to this in the scan prepass.
 struct VertexPerCornData {
   to_world: mat4x4<f32>
@@ -18,5 +22,7 @@ struct CornSettings {
   origin_res_width: vec4<f32>,
   height_width_min: vec2<f32>,
   step: vec2<f32>,
-  random_settings: vec4<f32>
+  random_settings: vec4<f32>,
+  // Seed for the random offsets, scales and rotations of the stalks
+  seed: u32
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+#import corn_game::corn::{PerCornData, CORN_CUT}
+
+// Mirrors `CornCircleEdit` in `src/ecs/corn/edit.rs`
+struct CircleEdit {
+  // field space xz center of the edit
+  center: vec2<f32>,
+  radius: f32,
+  enabled: u32
+}
+
+@group(0) @binding(0)
+var<storage, read_write> instance_data: array<PerCornData>;
+@group(0) @binding(1)
+var<storage, read> circles: array<CircleEdit>;
+// <stalk index, enabled>
+@group(0) @binding(2)
+var<storage, read> sets: array<vec2<u32>>;
+
+// Cuts (enabled 0) or restores (enabled 1) a stalk. Cuts only disable rendered stalks, and restores only enable cut ones,
+// so stalks the layout disabled stay disabled. Mirrors `CornData::edit`
+fn edit(index: u32, enabled: u32) {
+  let state = instance_data[index].enabled;
+  if enabled == 0u && state == 1u {
+    instance_data[index].enabled = CORN_CUT;
+  } else if enabled == 1u && state == CORN_CUT {
+    instance_data[index].enabled = 1u;
+  }
+}
+
+// Cuts or restores every stalk inside a circle. Later circles win, as if the edits were applied one at a time
+@compute @workgroup_size(256, 1, 1)
+fn edit_circles(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if gid.x >= arrayLength(&instance_data) {return;}
+  let position = instance_data[gid.x].offset.xz;
+  for (var i: u32 = arrayLength(&circles); i > 0u; i--) {
+    let circle = circles[i - 1u];
+    let offset = position - circle.center;
+    if dot(offset, offset) <= circle.radius*circle.radius {
+      edit(gid.x, circle.enabled);
+      return;
+    }
+  }
+}
+
+// Enables or cuts stalks by index. Mirrors `CornData::set_edited`
+@compute @workgroup_size(64, 1, 1)
+fn edit_sets(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if gid.x >= arrayLength(&sets) {return;}
+  let entry = sets[gid.x];
+  if entry.x >= arrayLength(&instance_data) {return;}
+  if entry.y == 1u {
+    instance_data[entry.x].enabled = 1u;
+  } else {
+    edit(entry.x, 0u);
+  }
+}
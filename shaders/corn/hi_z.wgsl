@@ -0,0 +1,63 @@
+// Builds a depth pyramid, where each texel holds the farthest depth of the texels below it.
+// Depth is reversed, so the farthest depth is the smallest.
+
+#ifdef DOWNSAMPLE
+@group(0) @binding(0)
+var input: texture_2d<f32>;
+#else
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var depth: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0)
+var depth: texture_depth_2d;
+#endif
+#endif
+@group(0) @binding(1)
+var output: texture_storage_2d<r32float, write>;
+
+#ifndef DOWNSAMPLE
+// Farthest depth of a texel of the depth texture, over all its samples
+fn load_depth(position: vec2<u32>) -> f32{
+  let clamped = min(position, textureDimensions(depth) - 1u);
+#ifdef MULTISAMPLED
+  var farthest: f32 = 1.0;
+  for (var i = 0u; i < textureNumSamples(depth); i++){
+    farthest = min(farthest, textureLoad(depth, clamped, i32(i)));
+  }
+  return farthest;
+#else
+  return textureLoad(depth, clamped, 0);
+#endif
+}
+
+// Reduces each 2x2 block of the depth texture into mip 0. Texels past the edge repeat the edge
+@compute @workgroup_size(8, 8, 1)
+fn reduce_depth(@builtin(global_invocation_id) id: vec3<u32>){
+  if any(id.xy >= textureDimensions(output)) {return;}
+  let base = id.xy*2u;
+  let farthest = min(
+    min(load_depth(base), load_depth(base + vec2<u32>(1u, 0u))),
+    min(load_depth(base + vec2<u32>(0u, 1u)), load_depth(base + vec2<u32>(1u, 1u)))
+  );
+  textureStore(output, id.xy, vec4<f32>(farthest, 0.0, 0.0, 0.0));
+}
+#endif
+
+#ifdef DOWNSAMPLE
+fn load_input(position: vec2<u32>) -> f32{
+  return textureLoad(input, min(position, textureDimensions(input) - 1u), 0).r;
+}
+
+// Reduces each 2x2 block of the previous mip into the next
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) id: vec3<u32>){
+  if any(id.xy >= textureDimensions(output)) {return;}
+  let base = id.xy*2u;
+  let farthest = min(
+    min(load_input(base), load_input(base + vec2<u32>(1u, 0u))),
+    min(load_input(base + vec2<u32>(0u, 1u)), load_input(base + vec2<u32>(1u, 1u)))
+  );
+  textureStore(output, id.xy, vec4<f32>(farthest, 0.0, 0.0, 0.0));
+}
+#endif
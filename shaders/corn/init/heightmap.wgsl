@@ -0,0 +1,39 @@
+#import corn_game::corn::PerCornData
+
+struct HeightmapSettings {
+  // field space xz of the heightmap's first texel corner
+  origin: vec2<f32>,
+  // 1 / field space size of the heightmap
+  inv_size: vec2<f32>,
+  // <minimum height, height range>
+  height_min_range: vec2<f32>,
+  padding: vec2<f32>
+}
+
+@group(0) @binding(0)
+var<storage, read_write> instance_data: array<PerCornData>;
+@group(0) @binding(1)
+var<uniform> settings: HeightmapSettings;
+@group(0) @binding(2) var heightmap: texture_2d<f32>;
+
+fn load_height(texel: vec2<i32>) -> f32 {
+  let dimensions = vec2<i32>(textureDimensions(heightmap));
+  return textureLoad(heightmap, clamp(texel, vec2<i32>(0), dimensions - 1), 0).r;
+}
+
+// Moves every stalk onto the ground. Heights are bilinearly filtered by hand so non filterable formats work
+@compute @workgroup_size(256, 1, 1)
+fn apply_heightmap(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if gid.x >= arrayLength(&instance_data) {return;}
+  let uv = clamp((instance_data[gid.x].offset.xz - settings.origin)*settings.inv_size, vec2<f32>(0.0), vec2<f32>(1.0));
+  let position = uv*vec2<f32>(textureDimensions(heightmap)) - 0.5;
+  let base = floor(position);
+  let fraction = position - base;
+  let texel = vec2<i32>(base);
+  let height = mix(
+    mix(load_height(texel), load_height(texel + vec2<i32>(1, 0)), fraction.x),
+    mix(load_height(texel + vec2<i32>(0, 1)), load_height(texel + vec2<i32>(1, 1)), fraction.x),
+    fraction.y
+  );
+  instance_data[gid.x].offset.y = settings.height_min_range.x + height*settings.height_min_range.y;
+}
@@ -1,6 +1,6 @@
 #import corn_game::{
   corn::{PerCornData, CornSettings},
-  utils::{randValue, randNext}
+  utils::{initRand, randNext, rand_xorshift}
 }
 
 @group(0) @binding(0)
@@ -19,7 +19,8 @@ fn simple_init(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workg
   // Add the field's origin position to the corn stalk position
   out.offset = settings.origin_res_width.xyz + vec3<f32>(xz_offset.x, 0.0, xz_offset.y);
   // Add random offsets to the x and z position of the corn stalk
-  out.offset += vec3<f32>(randValue(instance_index), 0.5, randNext())*settings.random_settings.x*2.0 - 1.0;
+  initRand(settings.seed, instance_index);
+  out.offset += vec3<f32>(randNext(), 0.5, randNext())*settings.random_settings.x*2.0 - 1.0;
   // set the random scale of the corn stalk
   out.scale = randNext() * settings.height_width_min.x + settings.height_width_min.y;
   // set the random rotation of the corn stalk
@@ -27,7 +28,8 @@ fn simple_init(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workg
   out.rotation = vec2<f32>(sin(theta), cos(theta));
   // enable the corn stalk
   out.enabled = 1u;
-  out.uuid = 1u;
+  // random id, picks the model variant of the stalk
+  out.uuid = rand_xorshift();
   instance_data[gid.x] = out;
 }
 
@@ -42,7 +44,8 @@ fn simple_rect_init(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_
   // Add the field's origin position to the corn stalk position
   out.offset = settings.origin_res_width.xyz + vec3<f32>(xz_offset.x, 0.0, xz_offset.y);
   // Add random offsets to the x and z position of the corn stalk
-  out.offset += vec3<f32>(randValue(instance_index), 0.5, randNext())*settings.random_settings.x*2.0 - 1.0;
+  initRand(settings.seed, instance_index);
+  out.offset += vec3<f32>(randNext(), 0.5, randNext())*settings.random_settings.x*2.0 - 1.0;
   // set the random scale of the corn stalk
   out.scale = randNext() * settings.height_width_min.x + settings.height_width_min.y;
   // set the random rotation of the corn stalk
@@ -50,6 +53,7 @@ fn simple_rect_init(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_
   out.rotation = vec2<f32>(sin(theta), cos(theta));
   // enable the corn stalk
   out.enabled = 1u;
-  out.uuid = 1u;
+  // random id, picks the model variant of the stalk
+  out.uuid = rand_xorshift();
   instance_data[gid.x] = out;
 }
\ No newline at end of file
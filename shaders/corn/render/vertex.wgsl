@@ -14,7 +14,11 @@ struct InstancedVertex{
     @location(11) corn_col4: vec4<f32>,
 }
 // index of our mesh. used instead of instance index
+#ifdef CORN_NO_PUSH_CONSTANTS
+@group(3) @binding(0) var<uniform> mesh_index: u32;
+#else
 var<push_constant> mesh_index: u32;
+#endif
 
 #ifdef MORPH_TARGETS
 fn morph_vertex(vertex_in: Vertex) -> Vertex {
@@ -58,6 +62,12 @@ fn vertex(vertex_no_morph: Vertex, instance_data: InstancedVertex) -> VertexOutp
         instance_data.corn_col3, 
         instance_data.corn_col4
     );
+#ifdef CORN_BILLBOARD
+    // Corn: billboards store their atlas cell and the atlas' cell count in the w of the first two columns
+    let atlas_cell = vec2<f32>(world_from_local[0].w, world_from_local[1].w);
+    world_from_local[0].w = 0.0;
+    world_from_local[1].w = 0.0;
+#endif // CORN_BILLBOARD
 #else
 #ifdef SKINNED
     var world_from_local = skinning::skin_model(vertex.joint_indices, vertex.joint_weights);
@@ -89,7 +99,12 @@ fn vertex(vertex_no_morph: Vertex, instance_data: InstancedVertex) -> VertexOutp
 #endif
 
 #ifdef VERTEX_UVS_A
+#ifdef CORN_BILLBOARD
+    // Corn: sample the stalk's variant from the atlas
+    out.uv = vec2<f32>((vertex.uv.x + atlas_cell.x)/atlas_cell.y, vertex.uv.y);
+#else
     out.uv = vertex.uv;
+#endif // CORN_BILLBOARD
 #endif
 #ifdef VERTEX_UVS_B
     out.uv_b = vertex.uv_b;
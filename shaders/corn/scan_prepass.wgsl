@@ -7,65 +7,178 @@ const LOD_COUNT = #{OVERRIDE_LOD_COUNT}u;
 const LOD_COUNT = 1u;
 #endif
 
-const INDIRECT_COUNT = LOD_COUNT*5u;
+// Total number of corn model variants. Stalks use variant uuid % VARIANT_COUNT
+#ifdef OVERRIDE_VARIANT_COUNT
+const VARIANT_COUNT = #{OVERRIDE_VARIANT_COUNT}u;
+#else
+const VARIANT_COUNT = 1u;
+#endif
 
+// Stalks are sorted into a bucket per lod of each variant, bucket = variant*LOD_COUNT + lod
+// Stalks past the last lod, but within the billboard cutoff, go in the billboard bucket after them
+const BILLBOARD_BUCKET = LOD_COUNT*VARIANT_COUNT;
+const BUCKET_COUNT = BILLBOARD_BUCKET + 1u;
+// Every loaded corn field's stalks, one field after another
 @group(0) @binding(0)
 var<storage> instance_data: array<PerCornData>;
-// x holds lod level, y holds corresponding lod counter
+// x holds the bucket, y holds corresponding bucket counter. Each scan's votes start on a multiple of 256
 @group(0) @binding(1)
 var<storage,read_write> vote_buffer: array<vec2<u32>>;
-// Buffers to hold higher order prefix scans.
+// Buffers to hold higher order prefix scans. Each scan's first level counts start on a multiple of 256
 @group(0) @binding(2)
-var<storage,read_write> count_buffer_1: array<array<u32, LOD_COUNT>>;
+var<storage,read_write> count_buffer_1: array<array<u32, BUCKET_COUNT>>;
 @group(0) @binding(3)
-var<storage,read_write> count_buffer_2: array<array<u32, LOD_COUNT>>;
-// Holds indirect values for drawing the mesh lods
+var<storage,read_write> count_buffer_2: array<array<u32, BUCKET_COUNT>>;
+// Holds indirect values for drawing the mesh lods, BUCKET_COUNT draws for each field
 @group(0) @binding(4)
-var<storage, read_write> indirect_buffer: array<u32, INDIRECT_COUNT>;
+var<storage, read_write> indirect_buffer: array<u32>;
 // Hold per corn data sent to the Vertex Shader
 @group(0) @binding(5)
 var<storage,read_write> instance_index_buffer: array<VertexPerCornData>;
 // Local memory to store scan prepass. 512 since we need temporary space to store values during the scan
-var<workgroup> scan_buffer: array<array<u32, LOD_COUNT>, 256>;
+var<workgroup> scan_buffer: array<array<u32, BUCKET_COUNT>, 256>;
 
 
-struct ConfigValues {
+// A field scanned in this view. Every field with the same lod and variant count is scanned by the same dispatches
+struct Scan {
   /// The mesh matrix for each lod level
   field_to_world: mat4x4<f32>,
   /// Field object space to camera clip space matrix
   field_to_clip: mat4x4<f32>,
   /// Camera position in field object space
-  camera_pos_field_space: vec4<f32>
+  camera_pos_field_space: vec4<f32>,
+  /// Field object space to the clip space of the depth in the Hi-Z pyramid, which lags a frame behind
+  field_to_occlusion_clip: mat4x4<f32>,
+  /// Depth texture size, pyramid mip count, and whether occlusion culling is enabled
+  occlusion: vec4<f32>,
+  /// Width and height of the corn model, then whether the view is a shadow view
+  stalk_bounds: vec4<f32>,
+  /// First stalk of the field in the instance data, and its stalk count
+  instance_offset: u32,
+  instance_count: u32,
+  /// First vote and first level count of the scan. Both are multiples of 256
+  vote_offset: u32,
+  count_offset: u32,
+  /// First second level count of the scan
+  count_offset_2: u32,
+  /// First indirect draw and vertex instance of the field
+  draw_offset: u32,
+  vertex_instance_offset: u32,
+  /// Vertex offset of the field's mesh
+  vertex_offset: u32,
+  /// Vertex offset of the billboard quad mesh
+  billboard_vertex_offset: u32,
+  /// Distance up to which stalks past the last lod are billboarded
+  billboard_cutoff: f32,
+  /// Distance past which only `density` of the stalks are kept. Used to thin out shadow views
+  thinning_distance: f32,
+  density: f32,
+  lod_cutoffs: array<f32, LOD_COUNT>
 }
 @group(0) @binding(6)
-var<uniform> config: ConfigValues;
+var<storage> scans: array<Scan>;
+
+// Depth pyramid of the previous frame, each texel holds the farthest depth below it
+@group(1) @binding(0)
+var hi_z: texture_2d<f32>;
 
-var<push_constant> vertex_offset: u32;
-var<push_constant> lod_cutoffs: array<f32, LOD_COUNT>;
+// First workgroup of a scan, over the votes for level 0, or the first level counts for level 1
+fn first_workgroup(scan: u32, level: u32) -> u32{
+  return select(scans[scan].vote_offset, scans[scan].count_offset, level == 1u) / 256u;
+}
+
+// Scan a workgroup belongs to. Scans are packed in order, so it's the last one starting at or before the workgroup
+fn find_scan(workgroup: u32, level: u32) -> u32{
+  var lo = 0u;
+  var hi = arrayLength(&scans);
+  while hi - lo > 1u {
+    let mid = (lo + hi)/2u;
+    if first_workgroup(mid, level) <= workgroup {lo = mid;} else {hi = mid;}
+  }
+  return lo;
+}
 
-// Calculates LOD from a index into the instance data. 
-// 0 is highest, LOD_COUNT-1 is lowest, LOD_COUNT is not rendered
-fn calc_lod(position: u32) -> u32{
+// Calculates the bucket of a stalk of a scan's field, from its variant and lod.
+// lod 0 is highest, LOD_COUNT-1 is lowest, then billboards. BUCKET_COUNT is not rendered
+fn calc_bucket(scan: u32, stalk: u32) -> u32{
+  // Votes past the field's last stalk pad out its last workgroup
+  if stalk >= scans[scan].instance_count {return BUCKET_COUNT;}
+  let data = instance_data[scans[scan].instance_offset + stalk];
   var lod: u32 = 0;
-  let pos: vec4<f32> = vec4<f32>(instance_data[position].offset.xyz, 1.0);
-  let offset: vec2<f32> = pos.xz - config.camera_pos_field_space.xz;
+  let pos: vec4<f32> = vec4<f32>(data.offset.xyz, 1.0);
+  let offset: vec2<f32> = pos.xz - scans[scan].camera_pos_field_space.xz;
   let distance: f32 = dot(offset, offset);
   for (var i = 0u; i < LOD_COUNT; i++){
-    if distance >= lod_cutoffs[i]*lod_cutoffs[i]{
+    let cutoff = scans[scan].lod_cutoffs[i];
+    if distance >= cutoff*cutoff{
       lod += 1u;
     }
   }
-  let projected: vec4<f32> = config.field_to_clip*pos;
-  let bounds: vec3<f32> = projected.xyz / projected.w;
-  var enabled: u32 = u32(
-    step(projected.x, projected.w*1.1)*step(-projected.w*1.1, projected.x)*step(projected.z, projected.w)*step(0.0, projected.z) > 0.0 
-    || distance < lod_cutoffs[0] // always render closest corn b/c shadows
-  ) * instance_data[position].enabled * u32(position < arrayLength(&instance_data));
-  //return select(LOD_COUNT, 3u, position < arrayLength(&instance_data) && distance < 200.0);
-  return select(LOD_COUNT, lod, bool(enabled));
+  let projected: vec4<f32> = scans[scan].field_to_clip*pos;
+  // Shadow casters in front of a shadow view's near plane are clamped onto it, so they aren't culled by it
+  let shadow_view: bool = scans[scan].stalk_bounds.z != 0.0;
+  let in_frustum: bool = step(projected.x, projected.w*1.1)*step(-projected.w*1.1, projected.x)*step(0.0, projected.z) > 0.0
+    && (shadow_view || projected.z <= projected.w);
+  let thinning_distance = scans[scan].thinning_distance;
+  let thinned: bool = distance >= thinning_distance*thinning_distance
+    && f32(hash(data.uuid) >> 8u) >= scans[scan].density*16777216.0;
+  var enabled: u32 = u32(in_frustum && !thinned && !occluded(scan, data) && data.enabled == 1u);
+  let variant: u32 = data.uuid % VARIANT_COUNT;
+  let billboard_cutoff = scans[scan].billboard_cutoff;
+  let billboard: bool = distance < billboard_cutoff*billboard_cutoff;
+  let bucket: u32 = select(select(BUCKET_COUNT, BILLBOARD_BUCKET, billboard), variant*LOD_COUNT + lod, lod < LOD_COUNT);
+  return select(BUCKET_COUNT, bucket, bool(enabled));
+}
+
+// Scrambles a stalk's uuid, so thinning doesn't follow the order stalks were placed in
+fn hash(value: u32) -> u32{
+  var x = value*747796405u + 2891336453u;
+  x = ((x >> ((x >> 28u) + 4u)) ^ x)*277803737u;
+  return (x >> 22u) ^ x;
 }
 
-fn calculate_vertex_data(data: PerCornData) -> VertexPerCornData{
+// Whether a stalk's bounding box is behind the farthest depth it covers in the Hi-Z pyramid. Depth is reversed, nearer is larger
+fn occluded(scan: u32, data: PerCornData) -> bool{
+  if scans[scan].occlusion.w == 0.0 {return false;}
+  // The model's width is measured along x and z, so it's widened to cover any rotation
+  let radius = 0.7072*scans[scan].stalk_bounds.x*data.scale;
+  let height = scans[scan].stalk_bounds.y*data.scale;
+  var min_uv = vec2<f32>(1.0);
+  var max_uv = vec2<f32>(0.0);
+  var nearest = 0.0;
+  for (var i = 0u; i < 8u; i++){
+    let corner = data.offset + vec3<f32>(
+      select(-radius, radius, (i & 1u) != 0u), select(0.0, height, (i & 2u) != 0u), select(-radius, radius, (i & 4u) != 0u)
+    );
+    let clip = scans[scan].field_to_occlusion_clip*vec4<f32>(corner, 1.0);
+    // Bounds crossing the camera plane can't be tested
+    if clip.w <= 0.0 {return false;}
+    let ndc = clip.xyz/clip.w;
+    let uv = ndc.xy*vec2<f32>(0.5, -0.5) + 0.5;
+    min_uv = min(min_uv, uv);
+    max_uv = max(max_uv, uv);
+    nearest = max(nearest, ndc.z);
+  }
+  // Stalks which were off screen last frame have no depth to test against
+  if any(min_uv < vec2<f32>(0.0)) || any(max_uv > vec2<f32>(1.0)) {return false;}
+  let min_px = min_uv*scans[scan].occlusion.xy;
+  let max_px = max_uv*scans[scan].occlusion.xy;
+  // Pick the mip where the bounds cover at most 2x2 texels. Texels of mip n cover 2^(n+1) pixels
+  let extent = max(max_px.x - min_px.x, max_px.y - min_px.y);
+  let level = min(u32(max(ceil(log2(max(extent, 1.0))), 1.0)) - 1u, u32(scans[scan].occlusion.z) - 1u);
+  let texel = f32(1u << (level + 1u));
+  let mip = i32(level);
+  let last = textureDimensions(hi_z, mip) - 1u;
+  let lo = min(vec2<u32>(min_px/texel), last);
+  let hi = min(vec2<u32>(max_px/texel), last);
+  let farthest = min(
+    min(textureLoad(hi_z, lo, mip).r, textureLoad(hi_z, vec2<u32>(hi.x, lo.y), mip).r),
+    min(textureLoad(hi_z, vec2<u32>(lo.x, hi.y), mip).r, textureLoad(hi_z, hi, mip).r)
+  );
+  return nearest < farthest;
+}
+
+fn calculate_vertex_data(scan: u32, data: PerCornData) -> VertexPerCornData{
   // multiply mesh matrix by instance matrix
   // Rotate+Scale -> Transform -> Mesh
   let instance_matrix = mat4x4<f32>(
@@ -74,7 +187,53 @@ fn calculate_vertex_data(data: PerCornData) -> VertexPerCornData{
     vec4<f32>(data.scale*data.rotation.x, 0.0, data.scale*data.rotation.y, 0.0), 
     vec4<f32>(data.offset, 1.0)
   );
-  return VertexPerCornData(config.field_to_world*instance_matrix);
+  return VertexPerCornData(scans[scan].field_to_world*instance_matrix);
+}
+
+// Billboards are rotated to face the camera, and store their atlas cell and the atlas' cell count in the unused w of the first two columns
+fn calculate_billboard_data(scan: u32, data: PerCornData) -> VertexPerCornData{
+  let offset: vec2<f32> = scans[scan].camera_pos_field_space.xz - data.offset.xz;
+  let facing: vec2<f32> = select(vec2<f32>(0.0, 1.0), normalize(offset), dot(offset, offset) > 0.0);
+  let instance_matrix = mat4x4<f32>(
+    vec4<f32>(data.scale*facing.y, 0.0, -data.scale*facing.x, 0.0), 
+    vec4<f32>(0.0, data.scale, 0.0, 0.0), 
+    vec4<f32>(data.scale*facing.x, 0.0, data.scale*facing.y, 0.0), 
+    vec4<f32>(data.offset, 1.0)
+  );
+  var to_world: mat4x4<f32> = scans[scan].field_to_world*instance_matrix;
+  to_world[0].w = f32(data.uuid % VARIANT_COUNT);
+  to_world[1].w = f32(VARIANT_COUNT);
+  return VertexPerCornData(to_world);
+}
+
+// First vertex instance of a bucket within its field. Without indirect first instances, each bucket has room for all of the
+// field's stalks, and is drawn with the vertex instance buffer bound at its start
+fn bucket_start(scan: u32, bucket: u32) -> u32{
+#ifdef CORN_NO_INDIRECT_FIRST_INSTANCE
+  return bucket*scans[scan].instance_count;
+#else
+  return indirect_buffer[(scans[scan].draw_offset + bucket)*5u+4u];
+#endif
+}
+
+fn compact_instance(scan: u32, gid: u32){
+  let stalk = gid - scans[scan].vote_offset;
+  if stalk >= scans[scan].instance_count {return;}
+  let bucket = vote_buffer[gid].x;
+  if bucket < BUCKET_COUNT{
+    let group = stalk >> 8u;
+    let offset = vote_buffer[gid].y + 
+      count_buffer_1[scans[scan].count_offset + group][bucket] + 
+      count_buffer_2[scans[scan].count_offset_2 + (group >> 8u)][bucket] + 
+      bucket_start(scan, bucket);
+    let data = instance_data[scans[scan].instance_offset + stalk];
+    let index = scans[scan].vertex_instance_offset + offset;
+    if bucket == BILLBOARD_BUCKET{
+      instance_index_buffer[index] = calculate_billboard_data(scan, data);
+    } else {
+      instance_index_buffer[index] = calculate_vertex_data(scan, data);
+    }
+  }
 }
 
 fn upswing(id: u32){
@@ -85,7 +244,7 @@ fn upswing(id: u32){
     if (id < i){
       let ai: u32 = offset*(id+1u)-(1u);
       let bi: u32 = offset*(id+2u)-(1u);
-      for(var j: u32 = 0u; j < LOD_COUNT; j++){
+      for(var j: u32 = 0u; j < BUCKET_COUNT; j++){
         scan_buffer[bi][j] += scan_buffer[ai][j];
       }
     }
@@ -101,7 +260,7 @@ fn downswing(id: u32) {
     if (id < i){
       let ai: u32 = offset*(id+1u)-(1u);
       let bi: u32 = offset*(id+2u)-(1u);
-      for(var j: u32 = 0u; j < LOD_COUNT; j++){
+      for(var j: u32 = 0u; j < BUCKET_COUNT; j++){
         let temp: u32 = scan_buffer[ai][j];
         scan_buffer[ai][j] = scan_buffer[bi][j];
         scan_buffer[bi][j] += temp;
@@ -110,6 +269,8 @@ fn downswing(id: u32) {
   }
 }
 
+// Each stage runs as a single dispatch over every scan. Vote and compact workgroups cover 256 votes of a scan,
+// group scan workgroups cover 256 first level counts of a scan, and group scan 2 has a workgroup per scan
 @compute @workgroup_size(128, 1, 1)
 fn vote_scan(
   // workgroup_id*workgroup_size+local_invocation_id=global_invocation_id
@@ -119,16 +280,18 @@ fn vote_scan(
 ) {
   let lid: u32 = 2u*simple_lid.x;
   let gid: u32 = 2u*simple_gid.x;
+  let scan = find_scan(wid.x, 0u);
+  let stalk = gid - scans[scan].vote_offset;
   // Populate vote_buffer and scan_buffer with vote data
-  let loda = calc_lod(gid);
-  let lodb = calc_lod(gid+1u);
+  let loda = calc_bucket(scan, stalk);
+  let lodb = calc_bucket(scan, stalk+1u);
   vote_buffer[gid].x = loda; vote_buffer[gid+1u].x = lodb;
-  scan_buffer[lid][loda] += u32(loda<LOD_COUNT); scan_buffer[lid+1u][lodb] += u32(lodb<LOD_COUNT);
+  scan_buffer[lid][loda] += u32(loda<BUCKET_COUNT); scan_buffer[lid+1u][lodb] += u32(lodb<BUCKET_COUNT);
 
   upswing(lid);
   // Record maximum in count
-  if (simple_lid.x < LOD_COUNT) {
-    count_buffer_1[wid.x][simple_lid.x] = scan_buffer[255][simple_lid.x];
+  if (simple_lid.x < BUCKET_COUNT) {
+    count_buffer_1[scans[scan].count_offset + wid.x - first_workgroup(scan, 0u)][simple_lid.x] = scan_buffer[255][simple_lid.x];
     scan_buffer[255][simple_lid.x] = 0u;
   }
   downswing(lid);
@@ -148,22 +311,26 @@ fn group_scan(
 ) {
   let lid: u32 = 2u*simple_lid.x;
   let gid: u32 = 2u*simple_gid.x;
+  let scan = find_scan(wid.x, 1u);
+  // Counts past the scan's last vote workgroup are left over from other frames
+  let groups = (scans[scan].instance_count + 255u)/256u;
+  let group = gid - scans[scan].count_offset;
   // Populate scan_buffer with data from count_buffer_1
-  for(var j: u32 = 0; j < LOD_COUNT; j++){
-    scan_buffer[lid][j] = count_buffer_1[gid][j]; 
-    scan_buffer[lid+1u][j] = count_buffer_1[gid+1u][j]; 
+  for(var j: u32 = 0; j < BUCKET_COUNT; j++){
+    scan_buffer[lid][j] = select(0u, count_buffer_1[gid][j], group < groups); 
+    scan_buffer[lid+1u][j] = select(0u, count_buffer_1[gid+1u][j], group+1u < groups); 
   }
 
   upswing(lid);
   // Record maximum in count 2
-  if (simple_lid.x < LOD_COUNT) {
-    count_buffer_2[wid.x][simple_lid.x] = scan_buffer[255][simple_lid.x];
+  if (simple_lid.x < BUCKET_COUNT) {
+    count_buffer_2[scans[scan].count_offset_2 + wid.x - first_workgroup(scan, 1u)][simple_lid.x] = scan_buffer[255][simple_lid.x];
     scan_buffer[255][simple_lid.x] = 0u;
   }
   downswing(lid);
 
   // place scan info into the count_buffer_1
-  for(var j: u32 = 0; j < LOD_COUNT; j++){
+  for(var j: u32 = 0; j < BUCKET_COUNT; j++){
     count_buffer_1[gid][j] = scan_buffer[lid][j]; 
     count_buffer_1[gid+1u][j] = scan_buffer[lid+1u][j]; 
   }
@@ -172,65 +339,54 @@ fn group_scan(
 
 @compute @workgroup_size(128, 1, 1)
 fn group_scan2(
-  // workgroup_id*workgroup_size+local_invocation_id=global_invocation_id
-  @builtin(global_invocation_id) simple_gid: vec3<u32>, 
-  @builtin(local_invocation_id) simple_lid: vec3<u32>
+  @builtin(local_invocation_id) simple_lid: vec3<u32>, 
+  @builtin(workgroup_id) wid: vec3<u32>
 ) {
   let lid: u32 = 2u*simple_lid.x;
-  let gid: u32 = 2u*simple_gid.x;
-  // Populate scan_buffer with data from count_buffer_1
-  for(var j: u32 = 0; j < LOD_COUNT; j++){
-    scan_buffer[lid][j] = count_buffer_2[gid][j]; 
-    scan_buffer[lid+1u][j] = count_buffer_2[gid+1u][j]; 
+  let scan = wid.x;
+  let groups = ((scans[scan].instance_count + 255u)/256u + 255u)/256u;
+  let first = scans[scan].count_offset_2;
+  // Populate scan_buffer with data from count_buffer_2
+  for(var j: u32 = 0; j < BUCKET_COUNT; j++){
+    scan_buffer[lid][j] = select(0u, count_buffer_2[first+lid][j], lid < groups); 
+    scan_buffer[lid+1u][j] = select(0u, count_buffer_2[first+lid+1u][j], lid+1u < groups); 
   }
  
   upswing(lid);
-  // Record maximum in count 2
+  // Record maximum in the field's indirect draws
   if (lid == 0u) {
+    let draw = scans[scan].draw_offset;
     var sum: u32 = 0u;
-    for(var j: u32 = 0u; j < LOD_COUNT; j++){
-      indirect_buffer[j*5u+1u] = scan_buffer[255][j];
-      indirect_buffer[j*5u+4u] = sum;
-      // setup vertex offset here
-      indirect_buffer[j*5u+3u] = vertex_offset;
+    for(var j: u32 = 0u; j < BUCKET_COUNT; j++){
+      indirect_buffer[(draw+j)*5u+1u] = scan_buffer[255][j];
+#ifdef CORN_NO_INDIRECT_FIRST_INSTANCE
+      indirect_buffer[(draw+j)*5u+4u] = 0u;
+#else
+      indirect_buffer[(draw+j)*5u+4u] = sum;
+#endif
+      // setup vertex offset here, billboards are drawn with their own mesh
+      indirect_buffer[(draw+j)*5u+3u] = select(scans[scan].vertex_offset, scans[scan].billboard_vertex_offset, j == BILLBOARD_BUCKET);
       sum += scan_buffer[255][j];
       scan_buffer[255][j] = 0u;
     }
   }
   downswing(lid);
 
-  // place scan info into the count_buffer_1
-  for(var j: u32 = 0; j < LOD_COUNT; j++){
-    count_buffer_2[gid][j] = scan_buffer[lid][j]; 
-    count_buffer_2[gid+1u][j] = scan_buffer[lid+1u][j]; 
+  // place scan info into the count_buffer_2, without touching the next scan's counts
+  for(var j: u32 = 0; j < BUCKET_COUNT; j++){
+    if lid < groups {count_buffer_2[first+lid][j] = scan_buffer[lid][j];}
+    if lid+1u < groups {count_buffer_2[first+lid+1u][j] = scan_buffer[lid+1u][j];}
   }
 }
 
 @compute @workgroup_size(128, 1, 1)
 fn compact(
   // workgroup_id*workgroup_size+local_invocation_id=global_invocation_id
-  @builtin(global_invocation_id) simple_gid: vec3<u32>
+  @builtin(global_invocation_id) simple_gid: vec3<u32>, 
+  @builtin(workgroup_id) wid: vec3<u32>
 ) {
-  var gid: u32 = 2u*simple_gid.x;
-  if gid < arrayLength(&instance_data){
-    let lod = vote_buffer[gid].x;
-    if lod < LOD_COUNT{
-      let offset = vote_buffer[gid].y + 
-        count_buffer_1[gid>>8u][lod] + 
-        count_buffer_2[gid>>16u][lod] + 
-        indirect_buffer[lod*5u+4u];
-      instance_index_buffer[offset] = calculate_vertex_data(instance_data[gid]);
-    }
-  }
-  gid += 1u;
-  if gid < arrayLength(&instance_data){
-    let lod = vote_buffer[gid].x;
-    if lod < LOD_COUNT{
-      let offset = vote_buffer[gid].y + 
-        count_buffer_1[gid>>8u][lod] + 
-        count_buffer_2[gid>>16u][lod] + 
-        indirect_buffer[lod*5u+4u];
-      instance_index_buffer[offset] = calculate_vertex_data(instance_data[gid]);
-    }
-  }
-}
\ No newline at end of file
+  let gid: u32 = 2u*simple_gid.x;
+  let scan = find_scan(wid.x, 0u);
+  compact_instance(scan, gid);
+  compact_instance(scan, gid+1u);
+}
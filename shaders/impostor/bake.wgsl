@@ -0,0 +1,42 @@
+#import bevy_pbr::{
+    forward_io::VertexOutput,
+    mesh_view_bindings::view,
+}
+
+struct ImpostorBakeUniform{
+    base_color: vec4<f32>,
+    center: vec3<f32>,
+    radius: f32,
+    alpha_cutoff: f32,
+    // 0: albedo, 1: normal and depth
+    output: u32,
+}
+@group(2) @binding(0) var<uniform> settings: ImpostorBakeUniform;
+@group(2) @binding(1) var base_color_texture: texture_2d<f32>;
+@group(2) @binding(2) var base_color_sampler: sampler;
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> @location(0) vec4<f32> {
+    var color = settings.base_color;
+#ifdef VERTEX_UVS_A
+    color *= textureSample(base_color_texture, base_color_sampler, in.uv);
+#endif
+    if color.a < settings.alpha_cutoff {
+        discard;
+    }
+    if settings.output == 0u {
+        return vec4(color.rgb, 1.0);
+    }
+    // Leaves are double sided, so back faces get flipped normals
+    var normal = normalize(in.world_normal);
+    if !is_front {
+        normal = -normal;
+    }
+    // Depth toward the camera, 0 at the far side of the bounding sphere and 1 at the near side
+    let to_camera = normalize(view.world_position - settings.center);
+    let depth = 0.5 + dot(in.world_position.xyz - settings.center, to_camera) / (2.0 * settings.radius);
+    return vec4(normal * 0.5 + 0.5, clamp(depth, 0.0, 1.0));
+}
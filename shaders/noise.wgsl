@@ -27,11 +27,13 @@ fn randNext() -> f32 {
     return f32(rand_xorshift())* (1.0 / 4294967296.0);
 }
 
-fn initRand(seed : u32){
+// Seeds the generator for element `index` of the sequence picked by `seed`, so values only depend on (seed, index)
+fn initRand(seed : u32, index : u32){
     wang_hash(seed);
+    wang_hash(rng_state ^ index);
 }
 
 fn randValue(seed : u32) -> f32 {
-    initRand(seed);
+    wang_hash(seed);
     return randNext();
 }
\ No newline at end of file
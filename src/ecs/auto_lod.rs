@@ -0,0 +1,112 @@
+//! Generated lods for regular meshes, like blueprint props. Add `AutoLod` to an entity, or as a custom property in blender,
+//! and every mesh below it gets simplified copies which are switched between with `VisibilityRange`.
+//!
+//! Bevy has no gltf saver, so unlike corn models these lods can't be baked by the asset processor. They are generated
+//! when the blueprint spawns instead, once per mesh, and shared between every instance.
+use bevy::{prelude::*, render::view::VisibilityRange, utils::HashMap};
+use crate::util::simplify::simplify_mesh;
+
+/// Marks an entity whose meshes, and the meshes of its descendants, get generated lods
+#[derive(Debug, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct AutoLod{
+    /// Number of generated lods, not counting the original mesh
+    pub levels: u32,
+    /// Fraction of triangles kept by each lod, relative to the lod before it
+    pub ratio: f32,
+    /// Distance at which the first generated lod takes over. Each further lod takes over at twice the distance of the one before
+    pub distance: f32
+}
+impl Default for AutoLod{
+    fn default() -> Self {
+        Self{levels: 3, ratio: 0.5, distance: 20.0}
+    }
+}
+impl AutoLod{
+    /// Distance at which lod `level` stops being drawn, 0 being the original mesh
+    pub fn cutoff(&self, level: u32) -> f32{
+        if level >= self.levels {f32::MAX} else {self.distance*2f32.powi(level as i32)}
+    }
+
+    /// Generates lods for the meshes below each new `AutoLod`, once they have loaded
+    fn generate_lods(
+        roots: Query<(Entity, &AutoLod), Without<AutoLodGenerated>>,
+        children: Query<&Children>,
+        targets: Query<(&Mesh3d, Option<&MeshMaterial3d<StandardMaterial>>), Without<AutoLodLevel>>,
+        mut meshes: ResMut<Assets<Mesh>>,
+        mut cache: ResMut<AutoLodMeshes>,
+        mut commands: Commands
+    ){
+        for (root, settings) in roots.iter(){
+            let targets: Vec<(Entity, &Mesh3d, Option<&MeshMaterial3d<StandardMaterial>>)> = std::iter::once(root)
+                .chain(children.iter_descendants(root))
+                .filter_map(|entity| targets.get(entity).ok().map(|(mesh, material)| (entity, mesh, material)))
+                .collect();
+            // Blueprints spawn their meshes a few frames after the root, and meshes load after that
+            if targets.is_empty() || targets.iter().any(|(_, mesh, _)| !meshes.contains(&mesh.0)) {continue;}
+            for (entity, mesh, material) in targets{
+                let key = (mesh.0.id(), settings.levels, settings.ratio.to_bits());
+                let lods = cache.0.entry(key).or_insert_with(|| {
+                    let mut lods = vec![];
+                    let mut current = meshes.get(&mesh.0).cloned();
+                    for _ in 0..settings.levels{
+                        let lod = match current.as_ref().map(|mesh| simplify_mesh(mesh, settings.ratio)){
+                            Some(Ok(lod)) => lod,
+                            Some(Err(err)) => {warn!("Could not generate lods for {entity}: {err}"); break;},
+                            None => break
+                        };
+                        current = Some(lod.clone());
+                        lods.push(meshes.add(lod));
+                    }
+                    lods
+                }).clone();
+                if lods.is_empty() {continue;}
+                commands.entity(entity).insert(VisibilityRange{
+                    start_margin: 0.0..0.0,
+                    end_margin: settings.cutoff(0)..settings.cutoff(0),
+                    use_aabb: false
+                });
+                // Simplification can stop early, so the last lod generated is the one drawn out to infinity
+                let last = lods.len() as u32;
+                for (level, lod) in lods.into_iter().enumerate(){
+                    let level = level as u32 + 1;
+                    let (start, end) = (settings.cutoff(level - 1), if level == last {f32::MAX} else {settings.cutoff(level)});
+                    let mut child = commands.spawn((
+                        Name::from(format!("LOD{level}")),
+                        Mesh3d(lod),
+                        VisibilityRange{start_margin: start..start, end_margin: end..end, use_aabb: false},
+                        AutoLodLevel(level)
+                    ));
+                    if let Some(material) = material {child.insert(material.clone());}
+                    let child = child.id();
+                    commands.entity(entity).add_child(child);
+                }
+            }
+            commands.entity(root).insert(AutoLodGenerated);
+        }
+    }
+}
+
+/// Marker for `AutoLod` entities whose lods have been generated
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Component)]
+#[component(storage = "SparseSet")]
+pub struct AutoLodGenerated;
+
+/// A generated lod mesh, and its level
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Component)]
+pub struct AutoLodLevel(pub u32);
+
+/// Generated lods of each mesh, keyed by mesh, number of lods, and ratio
+#[derive(Default, Debug, Clone, Resource)]
+pub struct AutoLodMeshes(pub HashMap<(AssetId<Mesh>, u32, u32), Vec<Handle<Mesh>>>);
+
+/// Adds generated lods for meshes marked with `AutoLod`
+pub struct AutoLodPlugin;
+impl Plugin for AutoLodPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<AutoLod>()
+            .init_resource::<AutoLodMeshes>()
+            .add_systems(Update, AutoLod::generate_lods);
+    }
+}
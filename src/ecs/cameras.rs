@@ -1,5 +1,6 @@
 use bevy::{prelude::*, render::extract_component::{ExtractComponent, ExtractComponentPlugin}};
 use serde::{Deserialize, Serialize};
+use super::corn::scan_prepass::hi_z::CornOcclusionCulling;
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Reflect, Component, ExtractComponent, Serialize, Deserialize)]
 #[reflect(Component)]
@@ -10,6 +11,7 @@ impl MainCamera{
             Self, 
             Camera3d::default(), 
             Camera{order: 0, hdr: true, ..Default::default()},
+            CornOcclusionCulling,
             Name::from("Main Camera")
         )).id()
     }
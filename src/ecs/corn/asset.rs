@@ -1,145 +1,486 @@
-use async_channel::Sender;
-use bevy::{prelude::*, render::extract_resource::{ExtractResource, ExtractResourcePlugin}, utils::hashbrown::HashMap};
+//! Corn models. A `.cornmodel` file is a small RON sidecar pointing at a gltf, and naming the nodes which make up each lod of each model variant.
+//! The loader merges every lod of every variant into a single mesh, so a field can draw all of its stalks with one multi draw.
+//!
+//! ```ron
+//! (
+//!     gltf: "CornTest.glb",
+//!     variants: [
+//!         (name: "Young", lods: ["Young_LOD0", "Young_LOD1", "Young_LOD2"]),
+//!         (name: "Dried", lods: ["Dried_LOD0", "Dried_LOD1"]),
+//!     ],
+//!     lod_cutoffs: Some([20.0, 40.0, 160.0]),
+//!     material: Some("Corn"),
+//!     billboard: Some((atlas: "CornBillboards.png", distance: 2000.0)),
+//! )
+//! ```
+//!
+//! Each lod is a gltf node, its meshes and the meshes of its children are merged into the lod. Lods are listed from most to least detailed.
+//! A model can have any number of lods, variants with fewer lods than the others keep drawing their least detailed one.
+//! Past the last lod, stalks are drawn as billboards up to the billboard distance, see `billboard`.
+//! With the asset processor, missing lods can be generated by setting `lod_count` in the model's `.cornmodel.meta`, see `baked_model`.
+use bevy::{
+    asset::{io::Reader, AssetLoadFailedEvent, AssetLoader, LoadContext, LoadDirectError, LoadedAsset, ParseAssetPathError},
+    gltf::{Gltf, GltfMesh, GltfNode},
+    prelude::*,
+    render::{extract_component::{ExtractComponent, ExtractComponentPlugin}, render_resource::VertexFormat, renderer::RenderDevice},
+    utils::{HashMap, HashSet}
+};
+use serde::{Deserialize, Serialize};
 use crate::util::observer_ext::ObserveAsAppExt;
+use super::{billboard::{CornBillboardDescriptor, CornFieldBillboard, CornModelBillboard}, baked_model::{BakedCornModelLoader, CornModelProcessor, CornModelSaver, GenerateCornLods}, lod::{lod_errors, AutoLodCutoffsApplied}, render::CornMaterial, scan_prepass::vote::PerFieldLodCutoffs, CornField, CornFieldObserver};
 
-use super::{CornField, CornFieldObserver};
-
-#[derive(Default, Debug)]
-pub struct ConvertCornMeshError;
-impl std::fmt::Display for ConvertCornMeshError {
+#[derive(Debug)]
+pub enum CornModelError{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    Path(ParseAssetPathError),
+    Gltf(LoadDirectError),
+    MissingNode(String),
+    MissingMaterial(String),
+    NoMeshes,
+    MismatchedAttributes(String),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    Truncated
+}
+impl std::fmt::Display for CornModelError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self}")
+        match self{
+            Self::Io(err) => write!(f, "Could not read corn model: {err}"),
+            Self::Ron(err) => write!(f, "Could not parse corn model: {err}"),
+            Self::Path(err) => write!(f, "Invalid corn model gltf path: {err}"),
+            Self::Gltf(err) => write!(f, "Could not load corn model gltf: {err}"),
+            Self::MissingNode(name) => write!(f, "Corn model gltf has no node named '{name}'"),
+            Self::MissingMaterial(name) => write!(f, "Corn model gltf has no material named '{name}'"),
+            Self::NoMeshes => write!(f, "Corn model has no meshes"),
+            Self::MismatchedAttributes(name) => write!(f, "Corn model meshes don't all have vertex attribute '{name}' in the same format"),
+            Self::InvalidMagic => write!(f, "Baked corn model is corrupt, or not a corn model"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported baked corn model version: {version}"),
+            Self::Truncated => write!(f, "Baked corn model ended early")
+        }
     }
 }
-impl core::error::Error for ConvertCornMeshError{}
+impl core::error::Error for CornModelError{}
+impl From<std::io::Error> for CornModelError{
+    fn from(value: std::io::Error) -> Self {Self::Io(value)}
+}
+impl From<ron::error::SpannedError> for CornModelError{
+    fn from(value: ron::error::SpannedError) -> Self {Self::Ron(value)}
+}
+impl From<ParseAssetPathError> for CornModelError{
+    fn from(value: ParseAssetPathError) -> Self {Self::Path(value)}
+}
+impl From<LoadDirectError> for CornModelError{
+    fn from(value: LoadDirectError) -> Self {Self::Gltf(value)}
+}
+
+/// A single variant of a corn model (young, broken, dried, ...)
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CornModelVariant{
+    pub name: String,
+    /// Names of the gltf nodes making up each lod, from most to least detailed
+    pub lods: Vec<String>
+}
+
+/// Contents of a `.cornmodel` file
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CornModelDescriptor{
+    /// Path to the gltf holding the model, relative to the `.cornmodel` file
+    pub gltf: String,
+    /// Variants of the model. A stalk uses variant `uuid % variant_count`
+    pub variants: Vec<CornModelVariant>,
+    /// Lod cutoffs used by fields with this model, instead of the global cutoffs. One per lod
+    #[serde(default)]
+    pub lod_cutoffs: Option<Vec<f32>>,
+    /// Name of the gltf material used for fields with this model. Defaults to the material of the first mesh
+    #[serde(default)]
+    pub material: Option<String>,
+    /// Billboards drawn past the last lod. Without them, stalks past the last lod cutoff are culled
+    #[serde(default)]
+    pub billboard: Option<CornBillboardDescriptor>
+}
 
-// Observer which attaches corn meshes to any corn field
-pub fn attach_mesh(trigger: Trigger<OnAdd, CornField>, mut commands: Commands, model: Res<CornModel>){
-    commands.entity(trigger.entity()).insert_if_new(Mesh3d(model.mesh_handle.clone()));
+/// A corn model. Holds one or more variants merged into a single mesh, each with the same number of lods.
+#[derive(Debug, Clone, PartialEq, Asset, Reflect)]
+pub struct CornModelAsset{
+    /// Merged mesh of every lod of every variant
+    #[dependency]
+    pub mesh: Handle<Mesh>,
+    #[dependency]
+    pub material: Option<Handle<StandardMaterial>>,
+    /// List of (# of vtcs, start vtx) for each lod of each variant.
+    pub lod_info: Vec<Vec<(usize, usize)>>,
+    pub lod_cutoffs: Option<Vec<f32>>,
+    /// Geometric error of switching from each lod to the next, used for automatic lod cutoffs
+    pub lod_errors: Vec<f32>,
+    pub billboard: Option<CornModelBillboard>
 }
+impl CornModelAsset{
+    /// Merges the meshes of every lod of every variant into a single mesh, returning it along with the lod info.
+    /// Variants missing lods keep drawing their lowest lod
+    pub fn merge_lods(variants: Vec<Vec<Vec<Mesh>>>) -> Result<(Mesh, Vec<Vec<(usize, usize)>>), CornModelError>{
+        let mut lod_info = vec![];
+        let mut merged: Option<Mesh> = None;
+        let mut sum = 0;
+        let lod_count = variants.iter().map(Vec::len).max().unwrap_or(0);
+        // Merging panics on mismatched formats, and leaves attributes missing from some meshes shorter than the rest
+        let layouts: Vec<Vec<(&str, VertexFormat)>> = variants.iter().flatten().flatten().map(|mesh| {
+            mesh.attributes().map(|(attribute, values)| (attribute.name, VertexFormat::from(values))).collect()
+        }).collect();
+        if let Some(first) = layouts.first(){
+            for layout in layouts.iter(){
+                let mismatched = first.iter().find(|entry| !layout.contains(entry))
+                    .or_else(|| layout.iter().find(|entry| !first.contains(entry)));
+                if let Some((name, _)) = mismatched {return Err(CornModelError::MismatchedAttributes(name.to_string()));}
+            }
+        }
+        for variant in variants{
+            let mut variant_info: Vec<(usize, usize)> = vec![];
+            for meshes in variant{
+                // Get Index counts
+                let count: usize = meshes.iter().map(|m| m.indices().map_or(0, |i| i.len())).sum();
+                sum += count;
+                variant_info.push((count, sum - count));
+                // Merge lods in order, matching `lod_info`
+                for mesh in meshes{
+                    match merged.as_mut(){
+                        Some(merged) => merged.merge(&mesh),
+                        None => merged = Some(mesh)
+                    }
+                }
+            }
+            let Some(last) = variant_info.last().copied() else {continue;};
+            variant_info.resize(lod_count, last);
+            lod_info.push(variant_info);
+        }
+        Ok((merged.ok_or(CornModelError::NoMeshes)?, lod_info))
+    }
+
+    /// Builds a single variant model from a gltf scene laid out the old way, where each lod is a node whose children hold its meshes
+    pub fn from_scene(world: &World, gltf: &Gltf, meshes: &mut Assets<Mesh>) -> Result<Self, CornModelError>{
+        let mut lods: HashMap<Entity, Vec<Mesh>> = HashMap::default();
+        for entity in world.iter_entities(){
+            let (Some(Mesh3d(handle)), Some(parent)) = (entity.get::<Mesh3d>(), entity.get::<Parent>()) else {continue;};
+            let (Some(lod), Some(mesh)) = (world.get::<Parent>(parent.get()), meshes.get(handle)) else {continue;};
+            lods.entry(lod.get()).or_default().push(mesh.clone());
+        }
+        let mut lods: Vec<Vec<Mesh>> = lods.into_values().collect();
+        // Most detailed lod first
+        lods.sort_by_key(|lod| std::cmp::Reverse(lod.iter().map(Mesh::count_vertices).sum::<usize>()));
+        let (merged, lod_info) = Self::merge_lods(vec![lods])?;
+        let lod_errors = lod_errors(&merged, lod_info.as_slice());
+        Ok(Self{
+            mesh: meshes.add(merged),
+            material: gltf.materials.first().cloned(),
+            lod_info,
+            lod_cutoffs: None,
+            lod_errors,
+            billboard: None
+        })
+    }
+
+    /// Number of lods of each variant
+    pub fn lod_count(&self) -> u32{
+        self.lod_info.first().map_or(0, |lods| lods.len() as u32)
+    }
+    /// Most lods the vote scan can sort stalks into. Each lod, plus the billboard bucket, takes up 1kb of workgroup memory in the scan
+    pub fn max_lod_count(render_device: &RenderDevice) -> u32{
+        (render_device.limits().max_compute_workgroup_storage_size/(256*4)).saturating_sub(1).clamp(1, 127)
+    }
+    /// Most variants the vote scan can sort stalks into, for models with `lod_count` lods.
+    /// Each lod of each variant, plus the billboard bucket, takes up 1kb of workgroup memory in the scan
+    pub fn max_variant_count(render_device: &RenderDevice, lod_count: u32) -> u32{
+        let lod_count = lod_count.max(1);
+        let buckets = (render_device.limits().max_compute_workgroup_storage_size/(256*4)).min(128);
+        (buckets.saturating_sub(1)/lod_count).max(1)
+    }
+}
+
+/// Loads `.cornmodel` files, along with the gltf they point to
+#[derive(Default, Debug, Clone)]
+pub struct CornModelLoader;
+impl CornModelLoader{
+    /// Collects the meshes of a gltf node and its children
+    fn node_meshes(gltf: &LoadedAsset<Gltf>, node: &Handle<GltfNode>, meshes: &mut Vec<Mesh>) -> Option<()>{
+        let node = gltf.get_labeled(node.path()?.label()?.to_owned())?.get::<GltfNode>()?;
+        if let Some(mesh) = node.mesh.as_ref(){
+            let mesh = gltf.get_labeled(mesh.path()?.label()?.to_owned())?.get::<GltfMesh>()?;
+            for primitive in mesh.primitives.iter(){
+                meshes.push(gltf.get_labeled(primitive.mesh.path()?.label()?.to_owned())?.get::<Mesh>()?.clone());
+            }
+        }
+        for child in node.children.iter(){
+            Self::node_meshes(gltf, child, meshes)?;
+        }
+        Some(())
+    }
+}
+impl AssetLoader for CornModelLoader{
+    type Asset = CornModelAsset;
+    type Settings = ();
+    type Error = CornModelError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let descriptor: CornModelDescriptor = ron::de::from_bytes(bytes.as_slice())?;
+        let gltf_path = load_context.asset_path().resolve_embed(descriptor.gltf.as_str())?;
+        let gltf = load_context.loader().immediate().load::<Gltf>(gltf_path.clone()).await?;
+
+        let mut variants = vec![];
+        for variant in descriptor.variants.iter(){
+            let mut lods = vec![];
+            for lod in variant.lods.iter(){
+                let node = gltf.get().named_nodes.get(lod.as_str()).ok_or_else(|| CornModelError::MissingNode(lod.clone()))?;
+                let mut meshes = vec![];
+                Self::node_meshes(&gltf, node, &mut meshes).ok_or_else(|| CornModelError::MissingNode(lod.clone()))?;
+                lods.push(meshes);
+            }
+            variants.push(lods);
+        }
+        let (merged, lod_info) = CornModelAsset::merge_lods(variants)?;
+        let lod_errors = lod_errors(&merged, lod_info.as_slice());
+        let billboard = match descriptor.billboard.as_ref(){
+            Some(billboard) => {
+                let atlas = load_context.asset_path().resolve_embed(billboard.atlas.as_str())?;
+                let size = CornModelBillboard::fit_size(&merged, lod_info.as_slice());
+                Some(CornModelBillboard::load(load_context, atlas, size, billboard.distance))
+            },
+            None => None
+        };
+        let mesh = load_context.add_labeled_asset("Mesh".to_string(), merged);
+
+        // The materials of an immediately loaded gltf aren't available outside this loader, so load the material by its label instead
+        let material = match descriptor.material.as_ref(){
+            Some(name) => Some(gltf.get().named_materials.get(name.as_str()).ok_or_else(|| CornModelError::MissingMaterial(name.clone()))?),
+            None => gltf.get().materials.first()
+        };
+        let material = material.and_then(|m| m.path()).and_then(|p| p.label())
+            .map(|label| load_context.load::<StandardMaterial>(gltf_path.clone().with_label(label.to_owned())));
+
+        Ok(CornModelAsset{mesh, material, lod_info, lod_cutoffs: descriptor.lod_cutoffs, lod_errors, billboard})
+    }
 
-/// Component which is used to send corn meshes to an async load function for the corn mesh asset. This way the handle for the mesh can be created before the gltf has loaded
-#[derive(Debug, Clone, Component)]
-pub struct CornMeshSender(pub Handle<Gltf>, pub Sender<Vec<Vec<Mesh>>>);
+    fn extensions(&self) -> &[&str] {
+        &["cornmodel"]
+    }
+}
 
-#[derive(Debug, Clone, PartialEq, Eq, Reflect, Resource, ExtractResource)]
+/// The default corn model, used by corn fields without their own `CornFieldModel`.
+/// Falls back to the gltf the model was loaded from before `.cornmodel` files, if the `.cornmodel` is missing
+#[derive(Debug, Clone, PartialEq, Eq, Reflect, Resource)]
 #[reflect(Resource)]
-pub struct CornModel{
-    gltf_handle: Handle<Gltf>,
-    pub mesh_handle: Handle<Mesh>,
-    /// List of (# of vtcs, start vtx) for each lod.
-    pub lod_info: Vec<(usize, usize)>
+pub struct CornModel(pub Handle<CornModelAsset>);
+impl FromWorld for CornModel{
+    fn from_world(world: &mut World) -> Self {
+        Self(world.resource::<AssetServer>().load(Self::PATH))
+    }
 }
 impl CornModel{
-    // Whenever GLTF loads, this runs, and checks to see if it was the corn model. If so we recompute the mesh
-    fn on_load_gltf(
-        mut event_reader: EventReader<AssetEvent<Gltf>>,
-        mut resource: ResMut<Self>,
-        gltf_assets: Res<Assets<Gltf>>,
-        mut scene_assets: ResMut<Assets<Scene>>,
-        mesh_assets: Res<Assets<Mesh>>,
+    pub const PATH: &'static str = "models/CornTest.cornmodel";
+    /// Loaded when the default `.cornmodel` is missing, see `CornModelAsset::from_scene`
+    pub const FALLBACK_PATH: &'static str = "models/CornTest.glb";
+
+    /// Loads the fallback gltf if the default model fails to load, and turns it into the default model once it loads
+    #[allow(clippy::too_many_arguments)]
+    fn fall_back_to_gltf(
+        model: Res<Self>,
+        fallback: Option<Res<CornModelFallback>>,
+        mut failures: EventReader<AssetLoadFailedEvent<CornModelAsset>>,
+        mut gltf_events: EventReader<AssetEvent<Gltf>>,
+        gltfs: Res<Assets<Gltf>>,
+        scenes: Res<Assets<Scene>>,
+        mut meshes: ResMut<Assets<Mesh>>,
+        mut models: ResMut<Assets<CornModelAsset>>,
+        mut model_events: EventWriter<AssetEvent<CornModelAsset>>,
         asset_server: Res<AssetServer>,
-        mut senders: Query<(Entity, &mut CornMeshSender)>,
         mut commands: Commands
     ){
-        for event in event_reader.read(){
-            if !event.is_loaded_with_dependencies(&resource.gltf_handle) {continue;}
-            // Grab GLTF Scene
-            let Some(scene) = gltf_assets.get(resource.gltf_handle.id())
-                .and_then(|gltf| gltf.scenes.get(0))
-                .and_then(|scene| scene_assets.get_mut(scene)) 
-            else {continue;};
-            let world = &mut scene.world;
-            // Find the meshes
-            let mut mesh_query = world.query::<(&Parent, &Mesh3d)>();
-            let meshes: Vec<(Handle<Mesh>, Entity)> = mesh_query.iter(world)
-                .map(|(parent, mesh3d)| (mesh3d.0.clone(), parent.get())).collect();
-            // Group meshes by their parents parent. 
-            let mut parent_query = world.query::<&Parent>();
-            let mut lods: HashMap<Entity, Vec<Handle<Mesh>>> = HashMap::default();
-            for (mesh, parent) in meshes.into_iter(){
-                let Ok(middle) = parent_query.get(world, parent) else {continue;};
-                if let Some(list) = lods.get_mut(&middle.get()) {
-                    list.push(mesh);
-                } else {
-                    lods.insert(middle.get(), vec![mesh]);
-                }
-            }
-            // Get Mesh Pointers
-            let mut lods: Vec<(usize, Vec<&Mesh>)> = lods.into_values().map(|lod| {
-                let mesh_pointers: Vec<&Mesh> = lod.into_iter().map(|handle| mesh_assets.get(&handle).unwrap()).collect();
-                let size = mesh_pointers.iter().map(|mesh| mesh.count_vertices()).sum();
-                (size, mesh_pointers)
-            }).collect();
-            // Sort lods
-            lods.sort_by(|(a, _), (b, _)| b.cmp(a));
-            // Get Index counts
-            let vertex_counts: Vec<usize> = lods.iter().map(|(_, mesh)| {
-                mesh.iter().map(|m| m.indices().unwrap().len()).sum()
-            }).collect();
-            let lod_data = vertex_counts.iter().fold((vec![], 0), | (mut lod_data, sum), val| {
-                lod_data.push((*val, sum));
-                (lod_data, sum+val)
-            }).0;
-            resource.lod_info = lod_data;
-            // Clone meshes
-            let lods = lods.into_iter().map(|(_, lod)| lod.into_iter().cloned().collect()).collect();
-            // Send Mesh, or Queue its creation
-            if let Some((entity, sender)) = senders.iter_mut().find(|(_, s)| s.0 == resource.gltf_handle) {
-                let _ = sender.1.force_send(lods);
-                sender.1.close();
-                commands.entity(entity).despawn();
-            } else {
-                resource.mesh_handle = asset_server.add_async(Self::convert_gltf(lods));
-            }
+        if failures.read().any(|failure| failure.id == model.0.id()) {
+            warn!("Could not load the default corn model {}, falling back to {}", Self::PATH, Self::FALLBACK_PATH);
+            commands.insert_resource(CornModelFallback(asset_server.load(Self::FALLBACK_PATH)));
+            return;
+        }
+        let Some(CornModelFallback(handle)) = fallback.as_deref() else {return;};
+        if !gltf_events.read().any(|event| event.is_loaded_with_dependencies(handle)) {return;}
+        let Some(gltf) = gltfs.get(handle) else {return;};
+        let Some(scene) = gltf.scenes.first().and_then(|scene| scenes.get(scene)) else {return;};
+        match CornModelAsset::from_scene(&scene.world, gltf, meshes.as_mut()){
+            Ok(asset) => {
+                models.insert(model.0.id(), asset);
+                // Nothing loaded the fallback, so announce it like a loaded model
+                model_events.send(AssetEvent::LoadedWithDependencies{id: model.0.id()});
+            },
+            Err(err) => error!("Could not use {} as the default corn model: {}", Self::FALLBACK_PATH, err)
         }
     }
-    // Given a vec of vec of meshes, create the merged final mesh
-    async fn convert_gltf(meshes: Vec<Vec<Mesh>>) -> Result<Mesh, ConvertCornMeshError>{
-        let meshes = meshes;
-        let mut lods: Vec<(usize, Vec<&Mesh>)> = meshes.iter().map(|lod| {
-            let count = lod.iter().map(|mesh| mesh.count_vertices()).sum();
-            (count, lod.iter().collect::<Vec<&Mesh>>())
+}
+
+/// Gltf loaded in place of the default corn model when its `.cornmodel` is missing
+#[derive(Debug, Clone, PartialEq, Eq, Resource)]
+pub struct CornModelFallback(pub Handle<Gltf>);
+
+/// Component for corn fields holding the handle of the corn model they are drawn with
+#[derive(Debug, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CornFieldModel(pub Handle<CornModelAsset>);
+impl CornFieldModel{
+    /// Once a field's model loads, attaches its mesh, material, lod cutoffs, lods, and billboards.
+    /// Re-runs when the model is reloaded, replacing everything attached from the old one
+    fn attach_model(
+        query: Query<(
+            Entity, Ref<Self>, Has<CornModelLods>, Has<MeshMaterial3d<CornMaterial>>, Has<ModelMaterialApplied>, Option<&CornFieldBillboard>
+        ), With<CornField>>,
+        mut events: EventReader<AssetEvent<CornModelAsset>>,
+        models: Res<Assets<CornModelAsset>>,
+        meshes: Res<Assets<Mesh>>,
+        render_device: Res<RenderDevice>,
+        mut commands: Commands
+    ){
+        let loaded: HashSet<AssetId<CornModelAsset>> = events.read().filter_map(|event| match event{
+            AssetEvent::LoadedWithDependencies{id} | AssetEvent::Modified{id} => Some(*id),
+            _ => None
         }).collect();
-        lods.sort_by(|(a, _), (b, _)| {b.cmp(a)});
-        let mut iter = lods.into_iter().map(|(_, lod)| lod.into_iter()).flatten();
-        let mut merged = iter.next().unwrap().clone();
-        for mesh in iter {merged.merge(mesh);}
-        Ok(merged)
+        let max_lods = CornModelAsset::max_lod_count(render_device.as_ref());
+        for (entity, field_model, attached, has_material, material_applied, billboard) in query.iter(){
+            if attached && !field_model.is_changed() && !loaded.contains(&field_model.0.id()) {continue;}
+            let Some(model) = models.get(&field_model.0) else {continue;};
+            let mut lods = model.lod_info.clone();
+            if model.lod_count() > max_lods {
+                warn!("Corn model has {} lods, but this device only supports {}. Extra lods are ignored", model.lod_count(), max_lods);
+                for variant in lods.iter_mut() {variant.truncate(max_lods as usize);}
+            }
+            let max_variants = CornModelAsset::max_variant_count(render_device.as_ref(), model.lod_count().min(max_lods)) as usize;
+            if lods.len() > max_variants {
+                warn!("Corn model has {} variants, but this device only supports {}. Extra variants are ignored", lods.len(), max_variants);
+                lods.truncate(max_variants);
+            }
+            if let Some(old) = billboard.and_then(|billboard| commands.get_entity(billboard.entity)) {old.despawn_recursive();}
+            let billboard = model.billboard.as_ref().map(|billboard| CornFieldBillboard::spawn(entity, billboard, &mut commands));
+            let bounds = meshes.get(&model.mesh).map_or(Vec2::ZERO, |mesh| CornModelBillboard::fit_size(mesh, &lods));
+            let mut commands = commands.entity(entity);
+            commands.insert((Mesh3d(model.mesh.clone()), CornModelLods(lods), CornModelBounds(bounds)));
+            match billboard{
+                Some(billboard) => {commands.insert(billboard);},
+                None => {commands.remove::<CornFieldBillboard>();}
+            }
+            // Materials set on the field itself are kept
+            match model.material.as_ref(){
+                Some(material) if !has_material || material_applied => {
+                    // The old material is removed first, so the new one is converted to a corn material
+                    commands.remove::<MeshMaterial3d<CornMaterial>>().insert((MeshMaterial3d(material.clone()), ModelMaterialApplied));
+                },
+                None if material_applied => {commands.remove::<(MeshMaterial3d<CornMaterial>, ModelMaterialApplied)>();},
+                _ => {}
+            }
+            // Models without cutoffs are left to `AutoLodCutoffs`
+            if let Some(cutoffs) = model.lod_cutoffs.as_ref() {
+                commands.insert(PerFieldLodCutoffs::Custom(cutoffs.clone())).remove::<AutoLodCutoffsApplied>();
+            }
+        }
     }
 }
-impl FromWorld for CornModel{
-    // Loads the gltf, and creates a handle for the merged mesh
-    fn from_world(world: &mut World) -> Self {
-        let assets = world.resource::<AssetServer>();
-        let gltf_handle = assets.load("models/CornTest.glb");
-        let (tx, rx) = async_channel::bounded(1);
-        world.spawn(CornMeshSender(gltf_handle.clone(), tx));
-        let assets = world.resource::<AssetServer>();
-        let mesh_handle = assets.add_async(async move {
-            let meshes = rx.recv().await.map_err(|_| ConvertCornMeshError)?;
-            Self::convert_gltf(meshes).await
-        });
-        
-        Self{ gltf_handle, mesh_handle, lod_info: vec![] }
+
+/// Marker for corn fields whose material was attached from their corn model, and should be replaced along with the model
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Component)]
+#[component(storage = "SparseSet")]
+pub struct ModelMaterialApplied;
+
+/// Component for corn fields holding the lods of their corn model. List of (# of vtcs, start vtx) for each lod of each variant.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Component, ExtractComponent)]
+#[extract_component_filter(Changed<CornModelLods>)]
+pub struct CornModelLods(pub Vec<Vec<(usize, usize)>>);
+impl CornModelLods{
+    /// Number of variants in the model. At least 1, so shaders can be specialized before the model loads
+    pub fn variant_count(&self) -> u32{
+        (self.0.len() as u32).max(1)
+    }
+    /// Number of lods of each variant. At least 1, so shaders can be specialized before the model loads
+    pub fn lod_count(&self) -> u32{
+        self.0.first().map_or(1, |lods| lods.len() as u32).max(1)
+    }
+    /// Bucket, and indirect draw, of billboarded stalks. It comes after every lod of every variant
+    pub fn billboard_bucket(&self) -> u32{
+        self.lod_count()*self.variant_count()
+    }
+    /// Number of buckets the vote scan sorts stalks into
+    pub fn bucket_count(&self) -> u32{
+        self.billboard_bucket() + 1
     }
 }
 
+/// Component for corn fields holding the width and height of their corn model's first lod, used to cull stalks by their bounds
+#[derive(Default, Debug, Clone, Copy, PartialEq, Component, ExtractComponent)]
+#[extract_component_filter(Changed<CornModelBounds>)]
+pub struct CornModelBounds(pub Vec2);
+
+// Observer which gives corn fields the default corn model
+pub fn attach_default_model(trigger: Trigger<OnAdd, CornField>, mut commands: Commands, model: Res<CornModel>){
+    commands.entity(trigger.entity()).insert_if_new(CornFieldModel(model.0.clone()));
+}
+
+/// Adds the `.cornmodel` asset, and attaches corn models to corn fields
 pub struct CornModelPlugin;
 impl Plugin for CornModelPlugin{
     fn build(&self, app: &mut App) {
-        app.register_type::<CornModel>()
-            .add_plugins(ExtractResourcePlugin::<CornModel>::default())
-            .add_systems(Startup,
-                CornModel::on_load_gltf.run_if(on_event::<AssetEvent<Gltf>>)
-            )
-            .add_systems(Update,
-                CornModel::on_load_gltf.run_if(on_event::<AssetEvent<Gltf>>)
+        app
+            .init_asset::<CornModelAsset>()
+            .register_type::<CornModelAsset>()
+            .register_type::<CornModel>()
+            .register_type::<CornFieldModel>()
+            .init_asset_loader::<CornModelLoader>()
+            .init_asset_loader::<BakedCornModelLoader>()
+            .register_asset_processor::<CornModelProcessor>(
+                CornModelProcessor::new(GenerateCornLods, CornModelSaver)
             )
-            .add_observer_as(attach_mesh, CornFieldObserver);
+            .set_default_asset_processor::<CornModelProcessor>("cornmodel")
+            .add_plugins(ExtractComponentPlugin::<CornModelLods>::default())
+            .add_plugins(ExtractComponentPlugin::<CornModelBounds>::default())
+            .add_systems(Update, (CornFieldModel::attach_model, CornModel::fall_back_to_gltf.run_if(resource_exists::<CornModel>)))
+            .add_observer_as(attach_default_model, CornFieldObserver);
     }
     fn finish(&self, app: &mut App) {
         app.init_resource::<CornModel>();
     }
 }
 
+#[cfg(test)]
+mod tests{
+    use bevy::render::{mesh::{Indices, MeshVertexAttribute, PrimitiveTopology}, render_asset::RenderAssetUsages};
+    use super::*;
+
+    fn triangles(count: u32) -> Mesh{
+        Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0f32; 3]; 3])
+            .with_inserted_indices(Indices::U32((0..count*3).map(|i| i%3).collect()))
+    }
+
+    #[test]
+    fn merged_lods_follow_each_other(){
+        let (merged, lod_info) = CornModelAsset::merge_lods(vec![
+            vec![vec![triangles(4), triangles(2)], vec![triangles(1)]],
+            vec![vec![triangles(3)]]
+        ]).unwrap();
+        assert_eq!(lod_info, [vec![(18, 0), (3, 18)], vec![(9, 21), (9, 21)]]);
+        assert_eq!(merged.indices().map(Indices::len), Some(30));
+        assert!(matches!(CornModelAsset::merge_lods(vec![]), Err(CornModelError::NoMeshes)));
+    }
+
+    #[test]
+    fn mismatched_attributes_are_rejected(){
+        let colored = triangles(1).with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, vec![[1.0f32; 4]; 3]);
+        let result = CornModelAsset::merge_lods(vec![vec![vec![triangles(1)], vec![colored]]]);
+        assert!(matches!(result, Err(CornModelError::MismatchedAttributes(name)) if name == Mesh::ATTRIBUTE_COLOR.name));
+        let [narrow, wide] = [VertexFormat::Float32, VertexFormat::Float32x2].map(|format| MeshVertexAttribute::new("Vertex_Custom", 9000, format));
+        let meshes = vec![
+            triangles(1).with_inserted_attribute(narrow, vec![0.0f32; 3]),
+            triangles(1).with_inserted_attribute(wide, vec![[0.0f32; 2]; 3])
+        ];
+        assert!(matches!(CornModelAsset::merge_lods(vec![vec![meshes]]), Err(CornModelError::MismatchedAttributes(_))));
+    }
+}
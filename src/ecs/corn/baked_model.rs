@@ -0,0 +1,364 @@
+//! Baked corn models. The asset processor loads each `.cornmodel` along with its gltf, fills in missing lods by simplifying
+//! the last authored lod of each variant, and saves the result in a binary format which loads without touching the gltf.
+//!
+//! Layout (little endian):
+//! - `CornModelHeader`: magic `CMDL`, format version, then vertex, index, attribute, variant, lod, and cutoff counts
+//! - for each attribute, its index in `BakedCornModel::ATTRIBUTES` (u32) followed by its vertex data
+//! - `index_count` u32 indices
+//! - (# of vtcs, start vtx) of each lod of each variant, as u32 pairs
+//! - `lod_count` f32 lod errors, then `cutoff_count` f32 lod cutoffs
+//! - the material's asset path, as a u32 length then utf8. Empty if the model has no material
+//! - (version 2) the billboard atlas' asset path, like the material. If it isn't empty, followed by the billboard width, height, and distance as f32s
+use bevy::{
+    asset::{io::{Reader, Writer}, processor::LoadTransformAndSave, saver::{AssetSaver, SavedAsset}, transformer::{AssetTransformer, TransformedAsset}, AssetLoader, AssetPath, LoadContext, RenderAssetUsages},
+    prelude::*,
+    render::{mesh::{Indices, MeshVertexAttribute, PrimitiveTopology, VertexAttributeValues}, render_resource::VertexFormat}
+};
+use bytemuck::{Pod, Zeroable};
+use futures_lite::AsyncWriteExt;
+use serde::{Deserialize, Serialize};
+use crate::util::simplify::simplify;
+use super::{billboard::CornModelBillboard, asset::{CornModelAsset, CornModelError, CornModelLoader}, lod::lod_errors};
+
+/// Header at the start of every baked corn model
+#[derive(Clone, Copy, Debug, Pod, Zeroable, PartialEq, Eq)]
+#[repr(C)]
+pub struct CornModelHeader{
+    magic: [u8; 4],
+    version: u32,
+    vertex_count: u32,
+    index_count: u32,
+    attribute_count: u32,
+    variant_count: u32,
+    lod_count: u32,
+    /// 0 if the model has no lod cutoffs of its own
+    cutoff_count: u32
+}
+impl CornModelHeader{
+    pub const MAGIC: [u8; 4] = *b"CMDL";
+    /// Current version of the format. Bump this whenever the layout changes
+    pub const VERSION: u32 = 2;
+    pub const DATA_SIZE: usize = 32;
+}
+
+/// Reads consecutive values out of a baked corn model
+struct ByteReader<'a>(&'a [u8]);
+impl<'a> ByteReader<'a>{
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CornModelError>{
+        if self.0.len() < len {return Err(CornModelError::Truncated);}
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+    fn read<T: Pod>(&mut self, count: usize) -> Result<Vec<T>, CornModelError>{
+        // Counts come straight from the file, so a corrupt header can ask for more than fits in memory
+        let len = count.checked_mul(size_of::<T>()).ok_or(CornModelError::Truncated)?;
+        Ok(self.take(len)?.chunks_exact(size_of::<T>()).map(bytemuck::pod_read_unaligned).collect())
+    }
+}
+
+/// Contents of a baked corn model
+#[derive(Debug, Clone)]
+pub struct BakedCornModel{
+    pub mesh: Mesh,
+    pub lod_info: Vec<Vec<(usize, usize)>>,
+    pub lod_errors: Vec<f32>,
+    pub lod_cutoffs: Option<Vec<f32>>,
+    /// Asset path of the model's material
+    pub material: Option<String>,
+    pub billboard: Option<BakedBillboard>
+}
+
+/// Billboards of a baked corn model
+#[derive(Debug, Clone, PartialEq)]
+pub struct BakedBillboard{
+    /// Asset path of the billboard atlas
+    pub atlas: String,
+    pub size: Vec2,
+    pub distance: f32
+}
+impl BakedCornModel{
+    /// Vertex attributes kept when baking, anything else is dropped
+    pub const ATTRIBUTES: [MeshVertexAttribute; 6] = [
+        Mesh::ATTRIBUTE_POSITION, Mesh::ATTRIBUTE_NORMAL, Mesh::ATTRIBUTE_UV_0,
+        Mesh::ATTRIBUTE_UV_1, Mesh::ATTRIBUTE_TANGENT, Mesh::ATTRIBUTE_COLOR
+    ];
+
+    /// Serializes the model into the baked format
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let attributes: Vec<(u32, &[u8])> = self.mesh.attributes()
+            .filter_map(|(attribute, values)| match Self::ATTRIBUTES.iter().position(|a| a.id == attribute.id){
+                Some(index) => Some((index as u32, values.get_bytes())),
+                None => {warn!("Corn model attribute {} can't be baked, and is dropped", attribute.name); None}
+            }).collect();
+        let indices: Vec<u32> = self.mesh.indices().map(|i| i.iter().map(|i| i as u32).collect()).unwrap_or_default();
+        let lod_count = self.lod_info.first().map_or(0, Vec::len);
+        let cutoffs = self.lod_cutoffs.clone().unwrap_or_default();
+        let material = self.material.clone().unwrap_or_default();
+        let header = CornModelHeader{
+            magic: CornModelHeader::MAGIC,
+            version: CornModelHeader::VERSION.to_le(),
+            vertex_count: (self.mesh.count_vertices() as u32).to_le(),
+            index_count: (indices.len() as u32).to_le(),
+            attribute_count: (attributes.len() as u32).to_le(),
+            variant_count: (self.lod_info.len() as u32).to_le(),
+            lod_count: (lod_count as u32).to_le(),
+            cutoff_count: (cutoffs.len() as u32).to_le()
+        };
+        let mut bytes = vec![];
+        bytes.extend_from_slice(bytemuck::bytes_of(&header));
+        for (index, data) in attributes{
+            bytes.extend_from_slice(&index.to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes.extend_from_slice(bytemuck::cast_slice(indices.as_slice()));
+        for (count, start) in self.lod_info.iter().flatten(){
+            bytes.extend_from_slice(&(*count as u32).to_le_bytes());
+            bytes.extend_from_slice(&(*start as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(bytemuck::cast_slice(self.lod_errors.as_slice()));
+        bytes.extend_from_slice(bytemuck::cast_slice(cutoffs.as_slice()));
+        bytes.extend_from_slice(&(material.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(material.as_bytes());
+        match self.billboard.as_ref(){
+            Some(billboard) => {
+                bytes.extend_from_slice(&(billboard.atlas.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(billboard.atlas.as_bytes());
+                bytes.extend_from_slice(bytemuck::cast_slice(&[billboard.size.x, billboard.size.y, billboard.distance]));
+            },
+            None => bytes.extend_from_slice(&0u32.to_le_bytes())
+        }
+        bytes
+    }
+
+    /// Parses a model from the baked format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CornModelError>{
+        let mut reader = ByteReader(bytes);
+        let header: CornModelHeader = reader.read(1)?.pop().ok_or(CornModelError::InvalidMagic)?;
+        if header.magic != CornModelHeader::MAGIC {return Err(CornModelError::InvalidMagic);}
+        match u32::from_le(header.version){
+            version @ (1 | 2) => {
+                let vertex_count = u32::from_le(header.vertex_count) as usize;
+                let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
+                for _ in 0..u32::from_le(header.attribute_count){
+                    let index = u32::from_le(reader.read::<u32>(1)?[0]) as usize;
+                    let attribute = *Self::ATTRIBUTES.get(index).ok_or(CornModelError::InvalidMagic)?;
+                    let values = match attribute.format{
+                        VertexFormat::Float32x2 => VertexAttributeValues::Float32x2(reader.read(vertex_count)?),
+                        VertexFormat::Float32x3 => VertexAttributeValues::Float32x3(reader.read(vertex_count)?),
+                        _ => VertexAttributeValues::Float32x4(reader.read(vertex_count)?),
+                    };
+                    mesh.insert_attribute(attribute, values);
+                }
+                let indices: Vec<u32> = reader.read::<u32>(u32::from_le(header.index_count) as usize)?.into_iter().map(u32::from_le).collect();
+                let index_count = indices.len();
+                mesh.insert_indices(Indices::U32(indices));
+                let lod_count = u32::from_le(header.lod_count) as usize;
+                let lod_total = (u32::from_le(header.variant_count) as usize).checked_mul(lod_count).ok_or(CornModelError::Truncated)?;
+                let lods: Vec<[u32; 2]> = reader.read(lod_total)?;
+                // Every lod has to lie inside the index buffer, or its draws would read past the end
+                let lod_info: Vec<Vec<(usize, usize)>> = lods.chunks(lod_count.max(1))
+                    .map(|variant| variant.iter().map(|[count, start]| (u32::from_le(*count) as usize, u32::from_le(*start) as usize)).collect())
+                    .collect();
+                if lod_info.iter().flatten().any(|(count, start)| start.checked_add(*count).is_none_or(|end| end > index_count)){
+                    return Err(CornModelError::Truncated);
+                }
+                let lod_errors = reader.read::<f32>(lod_count)?;
+                let lod_cutoffs = Some(reader.read::<f32>(u32::from_le(header.cutoff_count) as usize)?).filter(|c| !c.is_empty());
+                let length = u32::from_le(reader.read::<u32>(1)?[0]) as usize;
+                let material = String::from_utf8_lossy(reader.take(length)?).into_owned();
+                // Version 1 models have no billboards
+                let length = if version >= 2 {u32::from_le(reader.read::<u32>(1)?[0]) as usize} else {0};
+                let billboard = match length{
+                    0 => None,
+                    length => {
+                        let atlas = String::from_utf8_lossy(reader.take(length)?).into_owned();
+                        let [width, height, distance]: [f32; 3] = reader.read(3)?.try_into().map_err(|_| CornModelError::Truncated)?;
+                        Some(BakedBillboard{atlas, size: Vec2::new(width, height), distance})
+                    }
+                };
+                Ok(Self{mesh, lod_info, lod_errors, lod_cutoffs, material: Some(material).filter(|m| !m.is_empty()), billboard})
+            },
+            version => Err(CornModelError::UnsupportedVersion(version))
+        }
+    }
+}
+
+/// Loads corn models baked by the asset processor
+#[derive(Default, Debug, Clone)]
+pub struct BakedCornModelLoader;
+impl AssetLoader for BakedCornModelLoader{
+    type Asset = CornModelAsset;
+    type Settings = ();
+    type Error = CornModelError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let BakedCornModel{mesh, lod_info, lod_errors, lod_cutoffs, material, billboard} = BakedCornModel::from_bytes(bytes.as_slice())?;
+        let mesh = load_context.add_labeled_asset("Mesh".to_string(), mesh);
+        let material = material.map(|path| load_context.load::<StandardMaterial>(path));
+        let billboard = billboard.map(|BakedBillboard{atlas, size, distance}|
+            CornModelBillboard::load(load_context, AssetPath::parse(atlas.as_str()).into_owned(), size, distance)
+        );
+        Ok(CornModelAsset{mesh, material, lod_info, lod_cutoffs, lod_errors, billboard})
+    }
+}
+
+/// Saves corn models in the baked format
+#[derive(Default, Debug, Clone)]
+pub struct CornModelSaver;
+impl AssetSaver for CornModelSaver{
+    type Asset = CornModelAsset;
+    type Settings = ();
+    type OutputLoader = BakedCornModelLoader;
+    type Error = CornModelError;
+
+    async fn save(
+        &self,
+        writer: &mut Writer,
+        asset: SavedAsset<'_, Self::Asset>,
+        _settings: &Self::Settings,
+    ) -> Result<(), Self::Error> {
+        let mesh = asset.get_labeled::<Mesh, str>("Mesh").ok_or(CornModelError::NoMeshes)?;
+        let baked = BakedCornModel{
+            mesh: mesh.get().clone(),
+            lod_info: asset.lod_info.clone(),
+            lod_errors: asset.lod_errors.clone(),
+            lod_cutoffs: asset.lod_cutoffs.clone(),
+            material: asset.material.as_ref().and_then(|m| m.path()).map(|path| path.to_string()),
+            billboard: asset.billboard.as_ref().and_then(|billboard| Some(BakedBillboard{
+                atlas: billboard.atlas.path()?.to_string(),
+                size: billboard.size,
+                distance: billboard.distance
+            }))
+        };
+        writer.write_all(baked.to_bytes().as_slice()).await?;
+        Ok(())
+    }
+}
+
+/// Settings for generating corn model lods, set per model in its `.cornmodel.meta` file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerateCornLodsSettings{
+    /// Lods each variant is filled up to, by simplifying its last lod. 0 leaves the model as authored
+    pub lod_count: u32,
+    /// Fraction of triangles kept by each generated lod, relative to the lod before it
+    pub ratio: f32
+}
+impl Default for GenerateCornLodsSettings{
+    fn default() -> Self {
+        Self{lod_count: 0, ratio: 0.5}
+    }
+}
+
+/// Generates the missing lods of each corn model variant from its last authored lod, with a quadric error simplifier
+#[derive(Default, Debug, Clone)]
+pub struct GenerateCornLods;
+impl AssetTransformer for GenerateCornLods{
+    type AssetInput = CornModelAsset;
+    type AssetOutput = CornModelAsset;
+    type Settings = GenerateCornLodsSettings;
+    type Error = CornModelError;
+
+    async fn transform<'a>(
+        &'a self,
+        mut asset: TransformedAsset<Self::AssetInput>,
+        settings: &'a Self::Settings
+    ) -> Result<TransformedAsset<Self::AssetOutput>, Self::Error> {
+        // Padded lods repeat the last authored lod
+        let authored: Vec<Vec<(usize, usize)>> = asset.get().lod_info.iter().map(|variant| {
+            let mut variant = variant.clone();
+            variant.dedup();
+            variant
+        }).collect();
+        if authored.iter().all(|variant| variant.len() >= settings.lod_count as usize) {return Ok(asset);}
+
+        let (lod_info, errors) = {
+            let mut mesh = asset.get_labeled::<Mesh, str>("Mesh").ok_or(CornModelError::NoMeshes)?;
+            let mesh = mesh.get_mut();
+            let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {return Err(CornModelError::NoMeshes);};
+            let positions: Vec<Vec3> = positions.iter().map(|p| Vec3::from(*p)).collect();
+            let indices: Vec<u32> = mesh.indices().map(|i| i.iter().map(|i| i as u32).collect()).unwrap_or_default();
+            let mut merged: Vec<u32> = vec![];
+            let mut lod_info: Vec<Vec<(usize, usize)>> = vec![];
+            for variant in authored.iter(){
+                let mut lods: Vec<Vec<u32>> = variant.iter().map(|(count, start)| indices[*start..*start+*count].to_vec()).collect();
+                while lods.len() < settings.lod_count as usize{
+                    let Some(last) = lods.last() else {break;};
+                    let target = ((last.len()/3) as f32*settings.ratio) as usize;
+                    let simplified = simplify(positions.as_slice(), last.as_slice(), target);
+                    // Stop once the simplifier can't remove anything else
+                    if simplified.is_empty() || simplified.len() >= last.len() {break;}
+                    lods.push(simplified);
+                }
+                lod_info.push(lods.into_iter().map(|lod| {
+                    merged.extend_from_slice(lod.as_slice());
+                    (lod.len(), merged.len() - lod.len())
+                }).collect());
+            }
+            // Variants the simplifier gave up on keep drawing their lowest lod
+            let lod_count = lod_info.iter().map(Vec::len).max().unwrap_or(0);
+            for variant in lod_info.iter_mut(){
+                if let Some(last) = variant.last().copied() {variant.resize(lod_count, last);}
+            }
+            mesh.insert_indices(Indices::U32(merged));
+            let errors = lod_errors(mesh, lod_info.as_slice());
+            (lod_info, errors)
+        };
+        let model = asset.get_mut();
+        model.lod_info = lod_info;
+        model.lod_errors = errors;
+        Ok(asset)
+    }
+}
+
+/// Asset processor which generates corn model lods and bakes the model
+pub type CornModelProcessor = LoadTransformAndSave<CornModelLoader, GenerateCornLods, CornModelSaver>;
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn model() -> BakedCornModel{
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vec![[0.0f32, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+        mesh.insert_indices(Indices::U32(vec![0, 1, 2]));
+        BakedCornModel{mesh, lod_info: vec![vec![(3, 0)]], lod_errors: vec![0.0], lod_cutoffs: None, material: None, billboard: None}
+    }
+
+    fn with_header(bytes: &mut [u8], edit: impl FnOnce(&mut CornModelHeader)){
+        let mut header: CornModelHeader = bytemuck::pod_read_unaligned(&bytes[..size_of::<CornModelHeader>()]);
+        edit(&mut header);
+        bytes[..size_of::<CornModelHeader>()].copy_from_slice(bytemuck::bytes_of(&header));
+    }
+
+    #[test]
+    fn round_trip(){
+        let parsed = BakedCornModel::from_bytes(model().to_bytes().as_slice()).unwrap();
+        assert_eq!(parsed.lod_info, vec![vec![(3, 0)]]);
+        assert_eq!(parsed.mesh.count_vertices(), 3);
+    }
+
+    #[test]
+    fn overflowing_counts_are_truncated(){
+        let mut bytes = model().to_bytes();
+        with_header(bytes.as_mut_slice(), |header| {header.variant_count = u32::MAX; header.lod_count = u32::MAX;});
+        assert!(matches!(BakedCornModel::from_bytes(bytes.as_slice()), Err(CornModelError::Truncated)));
+        let mut bytes = model().to_bytes();
+        with_header(bytes.as_mut_slice(), |header| header.vertex_count = u32::MAX);
+        assert!(matches!(BakedCornModel::from_bytes(bytes.as_slice()), Err(CornModelError::Truncated)));
+    }
+
+    #[test]
+    fn lods_past_the_indices_are_rejected(){
+        let mut model = model();
+        model.lod_info = vec![vec![(3, usize::MAX - 1)]];
+        assert!(matches!(BakedCornModel::from_bytes(model.to_bytes().as_slice()), Err(CornModelError::Truncated)));
+    }
+}
@@ -0,0 +1,125 @@
+//! Billboards, the last lod of a corn field. Stalks past the last lod cutoff, but within the model's billboard distance,
+//! are drawn as camera facing quads which sample a pre-baked atlas, with one cell per model variant.
+//!
+//! The vote scan sorts billboarded stalks into an extra bucket after the mesh lods, and the compact pass writes their quad's
+//! transform. Billboards are drawn by a child entity of the field, with its own quad mesh and an alpha clipped `CornMaterial`,
+//! which reuses the field's vertex instance and indirect buffers of the view it's drawn in.
+use bevy::{
+    asset::{AssetPath, LoadContext},
+    pbr::{ExtendedMaterial, NotShadowCaster},
+    prelude::*,
+    render::{
+        batching::NoAutomaticBatching, extract_component::{ExtractComponent, ExtractComponentPlugin},
+        sync_world::RenderEntity, view::NoFrustumCulling, Extract, ExtractSchedule, RenderApp
+    }
+};
+use serde::{Deserialize, Serialize};
+use super::{lod::lod_triangles, render::{CornMaterial, CornMaterialExtension}};
+
+/// Billboard settings of a `.cornmodel` file
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CornBillboardDescriptor{
+    /// Path to the billboard atlas, relative to the `.cornmodel` file. One cell per variant, from left to right
+    pub atlas: String,
+    /// Distance up to which stalks are drawn as billboards, past the last lod cutoff
+    pub distance: f32
+}
+
+/// Billboards of a corn model
+#[derive(Debug, Clone, PartialEq, Reflect)]
+pub struct CornModelBillboard{
+    pub atlas: Handle<Image>,
+    /// Quad the billboards are drawn with, sized to fit the model
+    pub mesh: Handle<Mesh>,
+    /// Alpha clipped corn material sampling the atlas
+    pub material: Handle<CornMaterial>,
+    /// Width and height of the quad
+    pub size: Vec2,
+    pub distance: f32
+}
+impl CornModelBillboard{
+    /// Loads the atlas, and adds the quad and material as labeled assets of the corn model
+    pub fn load(load_context: &mut LoadContext, atlas: AssetPath<'static>, size: Vec2, distance: f32) -> Self{
+        let atlas: Handle<Image> = load_context.load(atlas);
+        let mesh = load_context.add_labeled_asset("Billboard".to_string(), Self::quad(size));
+        let material = load_context.add_labeled_asset("BillboardMaterial".to_string(), ExtendedMaterial{
+            base: StandardMaterial{
+                base_color_texture: Some(atlas.clone()),
+                alpha_mode: AlphaMode::Mask(0.5),
+                perceptual_roughness: 1.0,
+                ..default()
+            },
+            extension: CornMaterialExtension{billboard: true}
+        });
+        Self{atlas, mesh, material, size, distance}
+    }
+
+    /// Quad facing +z, standing on the origin
+    pub fn quad(size: Vec2) -> Mesh{
+        Mesh::from(Rectangle::from_size(size)).translated_by(Vec3::Y*size.y*0.5)
+    }
+
+    /// Size of a billboard which fits around the first lod of every variant of a merged corn model mesh
+    pub fn fit_size(mesh: &Mesh, lod_info: &[Vec<(usize, usize)>]) -> Vec2{
+        lod_info.iter().filter_map(|variant| variant.first())
+            .flat_map(|lod| lod_triangles(mesh, *lod))
+            .flatten()
+            .fold(Vec2::ZERO, |size, p| size.max(Vec2::new(2.0*p.x.abs().max(p.z.abs()), p.y)))
+    }
+}
+
+/// Component for corn fields drawn with billboards, linking to the entity drawing them
+#[derive(Debug, Clone, PartialEq, Component, ExtractComponent)]
+pub struct CornFieldBillboard{
+    pub entity: Entity,
+    /// Quad mesh of the billboards, for the vote scan to find its vertex offset
+    pub mesh: AssetId<Mesh>,
+    pub distance: f32
+}
+impl CornFieldBillboard{
+    /// Spawns the billboard entity for a field
+    pub fn spawn(field: Entity, billboard: &CornModelBillboard, commands: &mut Commands) -> Self{
+        let entity = commands.spawn((
+            Name::from("Corn Billboards"),
+            CornBillboard(field),
+            Mesh3d(billboard.mesh.clone()),
+            MeshMaterial3d(billboard.material.clone())
+        )).set_parent(field).id();
+        Self{entity, mesh: billboard.mesh.id(), distance: billboard.distance}
+    }
+}
+
+/// Entity drawing the billboards of a corn field
+#[derive(Debug, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+#[require(Transform, Visibility, NoFrustumCulling, NotShadowCaster, NoAutomaticBatching(|| NoAutomaticBatching))]
+pub struct CornBillboard(pub Entity);
+impl CornBillboard{
+    /// Links billboard entities in the render world to their field
+    fn extract(
+        billboards: Extract<Query<(RenderEntity, &CornBillboard)>>,
+        fields: Extract<Query<&RenderEntity>>,
+        mut commands: Commands
+    ){
+        for (entity, CornBillboard(field)) in billboards.iter(){
+            let Ok(field) = fields.get(*field) else {continue;};
+            commands.entity(entity).insert(ExtractedCornBillboard(field.id()));
+        }
+    }
+}
+
+/// Render world entity of the field a billboard entity belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+pub struct ExtractedCornBillboard(pub Entity);
+
+/// Adds billboards as the last lod of corn fields
+pub struct CornBillboardPlugin;
+impl Plugin for CornBillboardPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornBillboard>()
+            .add_plugins(ExtractComponentPlugin::<CornFieldBillboard>::default())
+        .sub_app_mut(RenderApp)
+            .add_systems(ExtractSchedule, CornBillboard::extract);
+    }
+}
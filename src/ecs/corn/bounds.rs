@@ -0,0 +1,100 @@
+//! Field level bounds. Each corn field gets an `Aabb` around every stalk it holds, found from its init settings or corn data,
+//! and padded by the size of its corn model, so picking and physics see the whole field.
+//!
+//! Bevy's frustum culling stays disabled for fields, since their mesh is a single stalk. Instead the vote scan tests each field's bounds
+//! against every view, and skips the scan and draws of fields outside it.
+use std::f32::consts::FRAC_1_SQRT_2;
+use bevy::{
+    ecs::system::lifetimeless::Read,
+    math::Affine3A,
+    prelude::*,
+    render::{extract_component::{ExtractComponent, ExtractComponentPlugin}, primitives::{Aabb, Frustum}}
+};
+use super::{asset::CornModelBounds, init::{heightmap::CornHeightmap, shader::AsCornInitShader, InitialCornData}, CornField};
+
+/// Field space bounds of the offsets of every stalk of a corn field, and the largest stalk scale
+#[derive(Debug, Default, Clone, Copy, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CornStalkBounds{
+    pub min: Vec3,
+    pub max: Vec3,
+    pub max_scale: f32
+}
+impl CornStalkBounds{
+    /// Bounds of the stalks an init shader will create, for fields without main world corn data
+    pub fn from_init_settings<S: AsCornInitShader>(
+        query: Query<(Entity, &S::Settings), (With<CornField>, Without<InitialCornData>, Changed<S::Settings>)>,
+        mut commands: Commands
+    ){
+        for (entity, settings) in query.iter(){
+            let Some(bounds) = S::get_stalk_bounds(settings) else {continue;};
+            commands.entity(entity).insert(bounds);
+        }
+    }
+
+    /// Bounds of the main world corn data of a field. Disabled stalks are included, since edits can enable them again
+    fn from_corn_data(query: Query<(Entity, &InitialCornData), Changed<InitialCornData>>, mut commands: Commands){
+        for (entity, InitialCornData(data)) in query.iter(){
+            let Some(first) = data.first() else {continue;};
+            let bounds = data.iter().fold(
+                Self{min: first.offset(), max: first.offset(), max_scale: first.scale()},
+                |bounds, corn| Self{
+                    min: bounds.min.min(corn.offset()),
+                    max: bounds.max.max(corn.offset()),
+                    max_scale: bounds.max_scale.max(corn.scale())
+                }
+            );
+            commands.entity(entity).insert(bounds);
+        }
+    }
+
+    /// Gives fields an `Aabb` once their corn model's size is known.
+    /// Stalks following a heightmap can sit anywhere in its height range, and the model is padded enough for any rotation
+    fn update_aabb(
+        query: Query<
+            (Entity, &Self, &CornModelBounds, Option<&CornHeightmap>),
+            (With<CornField>, Or<(Changed<Self>, Changed<CornModelBounds>, Changed<CornHeightmap>)>)
+        >,
+        mut commands: Commands
+    ){
+        for (entity, stalks, CornModelBounds(model), heightmap) in query.iter(){
+            let (mut min, mut max) = (stalks.min, stalks.max);
+            if let Some(heightmap) = heightmap {
+                min.y = heightmap.height_range.min_element();
+                max.y = heightmap.height_range.max_element();
+            }
+            let radius = model.x*stalks.max_scale*FRAC_1_SQRT_2;
+            let padding = Vec3::new(radius, 0.0, radius);
+            max.y += model.y*stalks.max_scale;
+            commands.entity(entity).insert(Aabb::from_min_max(min - padding, max + padding));
+        }
+    }
+}
+
+/// Field space bounds of a corn field in the render world
+#[derive(Debug, Clone, Copy, Component)]
+pub struct CornFieldAabb(pub Aabb);
+impl ExtractComponent for CornFieldAabb{
+    type Out = Self;
+    type QueryData = Read<Aabb>;
+    type QueryFilter = With<CornField>;
+    fn extract_component(item: bevy::ecs::query::QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {Some(Self(*item))}
+}
+impl CornFieldAabb{
+    /// Whether any part of the field is inside a view. Shadow views keep casters in front of their near plane,
+    /// and camera views have no far plane
+    pub fn is_visible(&self, clip_from_world: &Mat4, field_to_world: &Mat4, shadow: bool) -> bool{
+        Frustum::from_clip_from_world(clip_from_world).intersects_obb(&self.0, &Affine3A::from_mat4(*field_to_world), !shadow, shadow)
+    }
+}
+
+/// Adds field level bounds to corn fields
+pub struct CornBoundsPlugin;
+impl Plugin for CornBoundsPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornStalkBounds>()
+            .add_plugins(ExtractComponentPlugin::<CornFieldAabb>::default())
+            .add_systems(Update, (CornStalkBounds::from_corn_data, CornStalkBounds::update_aabb).chain());
+    }
+}
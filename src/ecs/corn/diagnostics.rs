@@ -0,0 +1,372 @@
+//! Corn diagnostics, read back from the gpu without blocking. Only one frame of each is in flight at a time,
+//! frames rendered while it's being read back are skipped.
+//!
+//! Gpu timings of the corn passes. Each pass writes its start and end timestamps into a query set, which is resolved and
+//! read back after the frame is submitted, then published as `Diagnostics` in the main world and as a tracy zone.
+//! Draws are timed inside the render passes they're part of, which needs `TIMESTAMP_QUERY_INSIDE_PASSES`.
+//!
+//! Stalk counts of the `MainCamera`'s view. The indirect buffers the vote scan writes are copied after the frame is submitted,
+//! then their instance counts are published as `Diagnostics` and `metrics` gauges, for the metrics dashboard.
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticMeasurement, DiagnosticPath, Diagnostics, DiagnosticsStore, RegisterDiagnostic},
+    prelude::*,
+    render::{
+        render_phase::TrackedRenderPass,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet
+    }
+};
+use bevy::utils::Instant;
+use wgpu::{ComputePassTimestampWrites, Features, Maintain, QuerySet, QuerySetDescriptor, QueryType};
+use crate::ecs::cameras::MainCamera;
+use super::{asset::CornModelLods, scan_prepass::vote::VoteScanBuffers, CornLoaded, IndirectBuffer};
+
+/// A corn pass timed on the gpu
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CornPass{
+    /// Corn init and heightmap compute passes
+    Init,
+    /// Vote scan compute passes of every view
+    VoteScan,
+    /// Depth pyramid compute passes of every view
+    HiZ,
+    /// Corn draws in every render phase
+    Draw
+}
+impl CornPass{
+    pub const ALL: [Self; 4] = [Self::Init, Self::VoteScan, Self::HiZ, Self::Draw];
+    pub const INIT: DiagnosticPath = DiagnosticPath::const_new("corn/gpu/init");
+    pub const VOTE_SCAN: DiagnosticPath = DiagnosticPath::const_new("corn/gpu/vote_scan");
+    pub const HI_Z: DiagnosticPath = DiagnosticPath::const_new("corn/gpu/hi_z");
+    pub const DRAW: DiagnosticPath = DiagnosticPath::const_new("corn/gpu/draw");
+
+    /// Diagnostic holding this pass' gpu time in milliseconds
+    pub fn diagnostic(&self) -> DiagnosticPath{
+        match self{
+            Self::Init => Self::INIT,
+            Self::VoteScan => Self::VOTE_SCAN,
+            Self::HiZ => Self::HI_Z,
+            Self::Draw => Self::DRAW
+        }
+    }
+}
+
+/// Gpu time of each corn pass in a frame, in milliseconds. Shared between the render and main world
+#[derive(Default, Debug, Clone, Resource)]
+pub struct CornGpuTimingsChannel(pub Arc<Mutex<Vec<[f64; 4]>>>);
+impl CornGpuTimingsChannel{
+    /// Publishes frames read back since the last update
+    fn publish(channel: Res<Self>, mut diagnostics: Diagnostics){
+        let Ok(mut frames) = channel.0.lock() else {return;};
+        for times in frames.drain(..){
+            let _span = info_span!("corn_gpu_timings", init_ms = times[0], vote_scan_ms = times[1], hi_z_ms = times[2], draw_ms = times[3]).entered();
+            for (pass, time) in CornPass::ALL.iter().zip(times){
+                diagnostics.add_measurement(&pass.diagnostic(), || time);
+            }
+        }
+    }
+}
+
+/// Query set the corn passes write their timestamps into. Only present when the device supports timestamp queries
+#[derive(Debug, Resource)]
+pub struct CornGpuTimings{
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    /// Nanoseconds per timestamp tick
+    period: f32,
+    /// Whether timestamps can be written inside render passes, needed to time draws
+    inside_passes: bool,
+    /// Spans written this frame, with the index of their first query
+    spans: Mutex<Vec<(CornPass, u32)>>,
+    /// Spans in the readback buffer
+    in_flight: Vec<(CornPass, u32)>,
+    /// Set once the readback buffer is mapped
+    mapped: Arc<AtomicBool>
+}
+impl CornGpuTimings{
+    /// Number of queries in the set, each span takes two
+    pub const QUERY_COUNT: u32 = 1024;
+
+    fn new(render_device: &RenderDevice, render_queue: &RenderQueue) -> Option<Self>{
+        if !render_device.features().contains(Features::TIMESTAMP_QUERY) {return None;}
+        let query_set = render_device.wgpu_device().create_query_set(&QuerySetDescriptor{
+            label: Some("Corn Timestamp Query Set"),
+            ty: QueryType::Timestamp,
+            count: Self::QUERY_COUNT
+        });
+        let size = Self::QUERY_COUNT as u64*8;
+        let resolve_buffer = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Timestamp Resolve Buffer"),
+            size,
+            usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false
+        });
+        let readback_buffer = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Timestamp Readback Buffer"),
+            size,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false
+        });
+        Some(Self{
+            query_set, resolve_buffer, readback_buffer,
+            period: render_queue.get_timestamp_period(),
+            inside_passes: render_device.features().contains(Features::TIMESTAMP_QUERY_INSIDE_PASSES),
+            spans: Mutex::default(),
+            in_flight: vec![],
+            mapped: Arc::default()
+        })
+    }
+
+    /// Reserves a span for a pass, returning its first query. None while last frame's timestamps are being read back, or when the set is full
+    fn span(&self, pass: CornPass) -> Option<u32>{
+        if !self.in_flight.is_empty() {return None;}
+        let mut spans = self.spans.lock().ok()?;
+        let index = spans.len() as u32*2;
+        if index + 2 > Self::QUERY_COUNT {return None;}
+        spans.push((pass, index));
+        Some(index)
+    }
+
+    /// Timestamp writes timing a compute pass
+    pub fn compute_pass_writes(&self, pass: CornPass) -> Option<ComputePassTimestampWrites<'_>>{
+        let index = self.span(pass)?;
+        Some(ComputePassTimestampWrites{
+            query_set: &self.query_set, beginning_of_pass_write_index: Some(index), end_of_pass_write_index: Some(index + 1)
+        })
+    }
+
+    /// Writes the start of a draw's span, returning it to pass to `end_draw`
+    pub fn begin_draw(&self, pass: &mut TrackedRenderPass) -> Option<u32>{
+        if !self.inside_passes {return None;}
+        let index = self.span(CornPass::Draw)?;
+        pass.wgpu_pass().write_timestamp(&self.query_set, index);
+        Some(index)
+    }
+
+    /// Writes the end of a draw's span
+    pub fn end_draw(&self, pass: &mut TrackedRenderPass, span: Option<u32>){
+        if let Some(index) = span {pass.wgpu_pass().write_timestamp(&self.query_set, index + 1);}
+    }
+
+    /// Reads back the last timed frame once its buffer is mapped, and sends its timings to the main world
+    fn read_back(mut timings: ResMut<Self>, render_device: Res<RenderDevice>, channel: Res<CornGpuTimingsChannel>){
+        if timings.in_flight.is_empty() {return;}
+        render_device.poll(Maintain::Poll);
+        if !timings.mapped.swap(false, Ordering::Relaxed) {return;}
+        let mut times = [0.0; 4];
+        {
+            let range = timings.readback_buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&range);
+            for (pass, index) in timings.in_flight.iter(){
+                let ticks = timestamps[*index as usize + 1].saturating_sub(timestamps[*index as usize]);
+                times[*pass as usize] += ticks as f64*timings.period as f64/1_000_000.0;
+            }
+        }
+        timings.readback_buffer.unmap();
+        timings.in_flight.clear();
+        if let Ok(mut frames) = channel.0.lock() {frames.push(times);}
+    }
+
+    /// Resolves this frame's timestamps after it's submitted, and starts reading them back
+    fn resolve(mut timings: ResMut<Self>, render_device: Res<RenderDevice>, render_queue: Res<RenderQueue>){
+        let Some(spans) = timings.spans.get_mut().ok().map(std::mem::take) else {return;};
+        if spans.is_empty() {return;}
+        let count = spans.len() as u32*2;
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor{label: Some("Corn Timestamp Resolve")});
+        encoder.resolve_query_set(&timings.query_set, 0..count, &timings.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&timings.resolve_buffer, 0, &timings.readback_buffer, 0, count as u64*8);
+        render_queue.submit([encoder.finish()]);
+        let mapped = timings.mapped.clone();
+        timings.readback_buffer.slice(..).map_async(MapMode::Read, move |result| {
+            if result.is_ok() {mapped.store(true, Ordering::Relaxed);}
+        });
+        timings.in_flight = spans;
+    }
+}
+
+/// Stalk counts of a frame in the `MainCamera`'s view
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CornStalkCounts{
+    /// Visible stalks in each lod, summed over variants
+    pub lods: Vec<u32>,
+    pub billboards: u32,
+    pub triangles: u64,
+    /// Fields with no visible stalks, including fields outside the view entirely
+    pub fields_culled: u32
+}
+impl CornStalkCounts{
+    pub const TOTAL: DiagnosticPath = DiagnosticPath::const_new("corn/stalks/total");
+    pub const BILLBOARDS: DiagnosticPath = DiagnosticPath::const_new("corn/stalks/billboard");
+    pub const TRIANGLES: DiagnosticPath = DiagnosticPath::const_new("corn/triangles");
+    pub const FIELDS_CULLED: DiagnosticPath = DiagnosticPath::const_new("corn/fields_culled");
+
+    /// Diagnostic holding the visible stalks in a lod. Registered once a field with that many lods is drawn
+    pub fn lod(lod: usize) -> DiagnosticPath{
+        DiagnosticPath::new(format!("corn/stalks/lod{lod}"))
+    }
+
+    pub fn total(&self) -> u32{
+        self.lods.iter().sum::<u32>() + self.billboards
+    }
+
+    /// Adds the indirect draws of a field
+    fn add_field(&mut self, lods: &CornModelLods, draws: &[[u32; 5]]){
+        let lod_count = lods.lod_count() as usize;
+        if self.lods.len() < lod_count {self.lods.resize(lod_count, 0);}
+        let mut visible = 0;
+        for (bucket, [index_count, instance_count, ..]) in draws.iter().enumerate().take(lods.bucket_count() as usize){
+            if bucket == lods.billboard_bucket() as usize {self.billboards += instance_count;}
+            else {self.lods[bucket % lod_count] += instance_count;}
+            self.triangles += (*index_count/3) as u64*(*instance_count) as u64;
+            visible += instance_count;
+        }
+        if visible == 0 {self.fields_culled += 1;}
+    }
+
+    /// Diagnostics and their value
+    fn measurements(&self) -> Vec<(DiagnosticPath, f64)>{
+        let mut measurements = vec![
+            (Self::TOTAL, self.total() as f64),
+            (Self::BILLBOARDS, self.billboards as f64),
+            (Self::TRIANGLES, self.triangles as f64),
+            (Self::FIELDS_CULLED, self.fields_culled as f64)
+        ];
+        measurements.extend(self.lods.iter().enumerate().map(|(lod, count)| (Self::lod(lod), *count as f64)));
+        measurements
+    }
+}
+
+/// Stalk counts read back since the last update. Shared between the render and main world
+#[derive(Default, Debug, Clone, Resource)]
+pub struct CornStalkCountsChannel(pub Arc<Mutex<Vec<CornStalkCounts>>>);
+impl CornStalkCountsChannel{
+    /// Publishes stalk counts as diagnostics, registering lods the first time they're seen, and as `metrics` gauges
+    fn publish(channel: Res<Self>, mut store: ResMut<DiagnosticsStore>){
+        let Ok(mut frames) = channel.0.lock() else {return;};
+        for counts in frames.drain(..){
+            for (path, value) in counts.measurements(){
+                metrics::gauge!(path.as_str().replace('/', ".")).set(value);
+                if store.get(&path).is_none() {store.add(Diagnostic::new(path.clone()));}
+                let Some(diagnostic) = store.get_mut(&path) else {continue;};
+                diagnostic.add_measurement(DiagnosticMeasurement{time: Instant::now(), value});
+            }
+        }
+    }
+}
+
+/// Indirect buffers of the `MainCamera`'s view being read back
+#[derive(Default, Debug, Resource)]
+struct CornStalkCountsReadback{
+    /// Staging buffer, and whether it's been mapped
+    staging: Option<(Buffer, Arc<AtomicBool>)>,
+    /// Lods of each field in the staging buffer, in order
+    fields: Vec<CornModelLods>,
+    /// Fields outside the view, which weren't scanned so have nothing to read back
+    outside: u32
+}
+impl CornStalkCountsReadback{
+    /// Sends the stalk counts once they're mapped, then copies the indirect buffers of this frame, once it's submitted
+    fn update(
+        mut readback: ResMut<Self>,
+        fields: Query<(&VoteScanBuffers, &CornModelLods), With<CornLoaded>>,
+        camera: Query<Entity, With<MainCamera>>,
+        render_device: Res<RenderDevice>,
+        render_queue: Res<RenderQueue>,
+        channel: Res<CornStalkCountsChannel>
+    ){
+        if let Some((buffer, mapped)) = &readback.staging {
+            render_device.poll(Maintain::Poll);
+            if !mapped.load(Ordering::Relaxed) {return;}
+            let mut counts = CornStalkCounts{fields_culled: readback.outside, ..default()};
+            {
+                let range = buffer.slice(..).get_mapped_range();
+                let mut draws: &[[u32; 5]] = bytemuck::cast_slice(&range);
+                for lods in readback.fields.iter(){
+                    let (field, rest) = draws.split_at(lods.bucket_count() as usize);
+                    counts.add_field(lods, field);
+                    draws = rest;
+                }
+            }
+            buffer.unmap();
+            readback.staging = None;
+            if let Ok(mut frames) = channel.0.lock() {frames.push(counts);}
+        }
+        let Ok(camera) = camera.get_single() else {return;};
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor{label: Some("Corn Stalk Counts Readback")});
+        let mut sources = vec![];
+        readback.fields.clear();
+        readback.outside = 0;
+        for (buffers, lods) in fields.iter(){
+            let Some(view_buffers) = buffers.0.get(&camera) else {readback.outside += 1; continue;};
+            sources.push((&view_buffers.indirect.0, view_buffers.indirect_offset));
+            readback.fields.push(lods.clone());
+        }
+        let size: u64 = readback.fields.iter().map(|lods| lods.bucket_count() as u64*IndirectBuffer::DRAW_SIZE).sum();
+        if size == 0 {
+            // Every field is outside the view, so there's nothing to copy
+            if readback.outside == 0 {return;}
+            if let Ok(mut frames) = channel.0.lock() {frames.push(CornStalkCounts{fields_culled: readback.outside, ..default()});}
+            return;
+        }
+        let buffer = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Stalk Counts Staging Buffer"),
+            size,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false
+        });
+        let mut offset = 0;
+        for ((source, source_offset), lods) in sources.into_iter().zip(readback.fields.iter()){
+            let field_size = lods.bucket_count() as u64*IndirectBuffer::DRAW_SIZE;
+            encoder.copy_buffer_to_buffer(source, source_offset, &buffer, offset, field_size);
+            offset += field_size;
+        }
+        render_queue.submit([encoder.finish()]);
+        let mapped: Arc<AtomicBool> = Arc::default();
+        let mapped_captured = mapped.clone();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            if result.is_ok() {mapped_captured.store(true, Ordering::Relaxed);}
+        });
+        readback.staging = Some((buffer, mapped));
+    }
+}
+
+/// Times the corn passes on the gpu and counts the visible stalks, and publishes them as diagnostics
+pub struct CornDiagnosticsPlugin;
+impl Plugin for CornDiagnosticsPlugin{
+    fn build(&self, app: &mut App) {
+        let channel = CornGpuTimingsChannel::default();
+        let stalk_channel = CornStalkCountsChannel::default();
+        for pass in CornPass::ALL{
+            app.register_diagnostic(Diagnostic::new(pass.diagnostic()).with_suffix("ms"));
+        }
+        for path in [CornStalkCounts::TOTAL, CornStalkCounts::BILLBOARDS, CornStalkCounts::TRIANGLES, CornStalkCounts::FIELDS_CULLED]{
+            app.register_diagnostic(Diagnostic::new(path));
+        }
+        app
+            .insert_resource(channel.clone())
+            .insert_resource(stalk_channel.clone())
+            .add_systems(PreUpdate, (CornGpuTimingsChannel::publish, CornStalkCountsChannel::publish))
+        .sub_app_mut(RenderApp)
+            .insert_resource(channel)
+            .insert_resource(stalk_channel)
+            .init_resource::<CornStalkCountsReadback>()
+            .add_systems(Render, (
+                (
+                    CornGpuTimings::read_back.in_set(RenderSet::Prepare),
+                    CornGpuTimings::resolve.in_set(RenderSet::Cleanup)
+                ).run_if(resource_exists::<CornGpuTimings>),
+                CornStalkCountsReadback::update.in_set(RenderSet::Cleanup)
+            ));
+    }
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        let world = render_app.world();
+        match CornGpuTimings::new(world.resource::<RenderDevice>(), world.resource::<RenderQueue>()){
+            Some(timings) => {render_app.insert_resource(timings);},
+            None => info!("Timestamp queries aren't supported, corn gpu timings are disabled")
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Downlevel fallback for adapters without push constants, multi draw indirect, or indirect first instances, such as some software rasterizers.
+//! Push constants are replaced by uniform buffers bound with dynamic offsets, and multi draws by an indirect draw per bucket.
+//! The fallback is picked from the `RenderDevice` features once the render app is built.
+//!
+//! Without `INDIRECT_FIRST_INSTANCE`, the first instance of indirect draws is ignored. Each bucket is then given its own part of
+//! the vertex instance buffer, big enough for all of the field's stalks, and drawn with the vertex instance buffer bound at its start.
+use std::{hash::{Hash, Hasher}, sync::atomic::{AtomicU32, Ordering}};
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::RenderDevice,
+        Render, RenderApp, RenderSet
+    }
+};
+use wgpu::{util::BufferInitDescriptor, BindGroupLayoutEntry, BindingType, BufferBindingType, Features, ShaderStages};
+
+/// Layout of the mesh index uniform corn materials bind at group 3. Only present when the render device has no push constants.
+/// Corn materials pick it up as bind group data, so their pipelines are specialized with it
+#[derive(Debug, Clone, Resource)]
+pub struct CornMeshIndexLayout(pub BindGroupLayout);
+impl CornMeshIndexLayout{
+    fn new(render_device: &RenderDevice) -> Self{
+        Self(render_device.create_bind_group_layout(
+            Some("Corn Mesh Index Layout"),
+            &[BindGroupLayoutEntry{
+                binding: 0,
+                visibility: ShaderStages::VERTEX,
+                count: None,
+                ty: BindingType::Buffer{ty: BufferBindingType::Uniform, has_dynamic_offset: true, min_binding_size: BufferSize::new(4)}
+            }]
+        ))
+    }
+}
+impl PartialEq for CornMeshIndexLayout{
+    fn eq(&self, other: &Self) -> bool {self.0.id() == other.0.id()}
+}
+impl Eq for CornMeshIndexLayout{}
+impl Hash for CornMeshIndexLayout{
+    fn hash<H: Hasher>(&self, state: &mut H) {self.0.id().hash(state);}
+}
+
+/// Corn rendering features supported by the render device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Resource)]
+pub struct CornRenderFeatures{
+    /// Constants are passed as push constants, rather than uniforms bound with dynamic offsets
+    pub push_constants: bool,
+    /// Buckets are drawn with a single multi draw indirect, rather than an indirect draw each
+    pub multi_draw_indirect: bool,
+    /// Indirect draws start at their first instance. Otherwise each bucket has its own part of the vertex instance buffer
+    pub indirect_first_instance: bool
+}
+impl CornRenderFeatures{
+    pub fn new(render_device: &RenderDevice) -> Self{
+        let features = render_device.features();
+        Self{
+            push_constants: features.contains(Features::PUSH_CONSTANTS),
+            multi_draw_indirect: features.contains(Features::MULTI_DRAW_INDIRECT),
+            indirect_first_instance: features.contains(Features::INDIRECT_FIRST_INSTANCE)
+        }
+    }
+}
+
+/// Uniform buffer holding every mesh index at the uniform offset alignment, so draws select their mesh index with a dynamic offset.
+/// Only present when the render device has no push constants
+#[derive(Debug, Resource)]
+pub struct CornMeshIndexBuffer{
+    pub bind_group: BindGroup,
+    /// Distance between mesh indices, in bytes
+    pub stride: u32,
+    capacity: u32,
+    /// Mesh index count needed by the draws which didn't fit, the buffer grows to hold them next frame
+    requested: AtomicU32
+}
+impl CornMeshIndexBuffer{
+    pub const INITIAL_CAPACITY: u32 = 1024;
+
+    fn new(capacity: u32, layout: &BindGroupLayout, render_device: &RenderDevice) -> Self{
+        let stride = render_device.limits().min_uniform_buffer_offset_alignment;
+        let contents: Vec<u8> = (0..capacity).flat_map(|index| {
+            let mut entry = vec![0; stride as usize];
+            entry[..4].copy_from_slice(&index.to_ne_bytes());
+            entry
+        }).collect();
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor{
+            label: Some("Corn Mesh Index Buffer"),
+            contents: &contents,
+            usage: BufferUsages::UNIFORM
+        });
+        let bind_group = render_device.create_bind_group(
+            Some("Corn Mesh Index Bind Group"),
+            layout,
+            &BindGroupEntries::single(BufferBinding{buffer: &buffer, offset: 0, size: BufferSize::new(4)})
+        );
+        Self{bind_group, stride, capacity, requested: AtomicU32::new(0)}
+    }
+
+    /// Dynamic offset of a mesh index. None if the buffer doesn't hold it yet
+    pub fn offset(&self, mesh_index: u32) -> Option<u32>{
+        if mesh_index < self.capacity {return Some(mesh_index*self.stride);}
+        self.requested.fetch_max(mesh_index + 1, Ordering::Relaxed);
+        None
+    }
+
+    /// Recreates the buffer when a draw needed a mesh index it didn't hold
+    fn grow(mut buffer: ResMut<Self>, layout: Res<CornMeshIndexLayout>, render_device: Res<RenderDevice>){
+        let requested = *buffer.requested.get_mut();
+        if requested <= buffer.capacity {return;}
+        *buffer = Self::new(requested.next_power_of_two(), &layout.0, &render_device);
+    }
+}
+
+/// Picks the corn rendering paths the render device supports
+pub struct CornDownlevelPlugin;
+impl Plugin for CornDownlevelPlugin{
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .add_systems(Render, CornMeshIndexBuffer::grow.in_set(RenderSet::PrepareBindGroups).run_if(resource_exists::<CornMeshIndexBuffer>));
+    }
+    fn finish(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        let render_device = render_app.world().resource::<RenderDevice>().clone();
+        let features = CornRenderFeatures::new(&render_device);
+        render_app.insert_resource(features);
+        if !features.multi_draw_indirect {
+            info!("Multi draw indirect isn't supported, corn lods are drawn one at a time");
+        }
+        if !features.indirect_first_instance {
+            info!("Indirect first instance isn't supported, each corn lod gets its own vertex instances");
+        }
+        if features.push_constants {return;}
+        info!("Push constants aren't supported, corn constants are passed in uniform buffers");
+        let layout = CornMeshIndexLayout::new(&render_device);
+        render_app.insert_resource(CornMeshIndexBuffer::new(CornMeshIndexBuffer::INITIAL_CAPACITY, &layout.0, &render_device));
+        render_app.insert_resource(layout);
+    }
+}
@@ -0,0 +1,384 @@
+//! Runtime editing of corn stalks. Fields with main world corn data (`InitialCornData`) are edited on the cpu,
+//! and only the changed ranges are written to the field's instance buffer in the render world.
+//!
+//! Fields initialized purely on the gpu have no main world copy, so their edits are sent to the render world and applied by a compute pass.
+use std::{ops::Range, sync::atomic::{AtomicBool, Ordering}};
+use bevy::{prelude::*, render::{
+    extract_component::{ExtractComponent, ExtractComponentPlugin},
+    graph::CameraDriverLabel,
+    render_graph::*, render_resource::*,
+    renderer::{RenderContext, RenderDevice, RenderQueue},
+    Render, RenderApp, RenderSet
+}};
+use bytemuck::{Pod, Zeroable};
+use super::{
+    bounds::CornStalkBounds, diagnostics::{CornGpuTimings, CornPass},
+    init::{heightmap::CornHeightmapStage, InitialCornData}, path::ClearedByPaths, scan_prepass::arena::CornInstanceArena,
+    CornData, CornField, InstanceBuffer
+};
+
+/// Disables every rendered stalk within `radius` of `center` on the xz plane, in every corn field. Used for trampling and cutting paths
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct CutCorn{
+    /// World space center of the cut
+    pub center: Vec3,
+    pub radius: f32
+}
+
+/// Re-enables every stalk within `radius` of `center` on the xz plane that an edit disabled, in every corn field.
+/// Stalks the field's layout disabled, like maze corridors or painted paths, stay disabled
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct RestoreCorn{
+    /// World space center of the restored area
+    pub center: Vec3,
+    pub radius: f32
+}
+
+/// Enables or disables specific stalks of a corn field, by their index in the field. Disabled stalks count as cut, so `RestoreCorn` brings them back
+#[derive(Debug, Clone, PartialEq, Event)]
+pub struct SetCornEnabled{
+    pub field: Entity,
+    pub ids: Vec<u32>,
+    pub enabled: bool
+}
+
+/// Contiguous run of edited stalks, starting at stalk index `start`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CornPatch{
+    pub start: u32,
+    pub data: Vec<CornData>
+}
+
+/// Component for corn fields holding the edits made this frame, required by `InitialCornData`. Extracted to the render world and written to the instance buffer
+#[derive(Default, Debug, Clone, PartialEq, Component, ExtractComponent)]
+#[extract_component_filter(Changed<CornEdits>)]
+pub struct CornEdits(pub Vec<CornPatch>);
+impl CornEdits{
+    /// Stalks this close together are written as one patch
+    const MERGE_GAP: u32 = 16;
+
+    /// Builds patches covering the dirty stalk indices
+    pub fn from_dirty(mut dirty: Vec<u32>, data: &[CornData]) -> Self{
+        dirty.sort_unstable();
+        dirty.dedup();
+        let mut ranges: Vec<Range<u32>> = vec![];
+        for id in dirty{
+            match ranges.last_mut(){
+                Some(range) if id <= range.end + Self::MERGE_GAP => range.end = id+1,
+                _ => ranges.push(id..id+1)
+            }
+        }
+        Self(ranges.into_iter().map(|range| CornPatch{
+            start: range.start,
+            data: data[range.start as usize..range.end as usize].to_vec()
+        }).collect())
+    }
+
+    /// Applies this frame's edit events to the main world corn data and records the dirty ranges.
+    /// Stalks cleared by a path keep their edit until no path clears them. Fields without main world corn data get the edits in `CornGpuEdits` instead
+    pub fn apply_edits(
+        mut fields: Query<(Entity, &mut InitialCornData, &GlobalTransform, Option<&CornStalkBounds>, &mut Self, Option<&mut ClearedByPaths>), With<CornField>>,
+        mut gpu_fields: Query<(Entity, &GlobalTransform, Option<&CornStalkBounds>, &mut CornGpuEdits), (With<CornField>, Without<InitialCornData>)>,
+        mut cuts: EventReader<CutCorn>,
+        mut restores: EventReader<RestoreCorn>,
+        mut sets: EventReader<SetCornEnabled>
+    ){
+        // Clear out last frame's edits, they have already been extracted
+        for (_, _, _, _, mut edits, _) in fields.iter_mut(){
+            if !edits.0.is_empty() {edits.0.clear();}
+        }
+        for (_, _, _, mut edits) in gpu_fields.iter_mut(){
+            if !edits.is_empty() {*edits = CornGpuEdits::default();}
+        }
+        let circles: Vec<(Vec3, f32, bool)> = cuts.read().map(|CutCorn{center, radius}| (*center, *radius, false))
+            .chain(restores.read().map(|RestoreCorn{center, radius}| (*center, *radius, true)))
+            .collect();
+        let sets: Vec<&SetCornEnabled> = sets.read().collect();
+        if circles.is_empty() && sets.is_empty() {return;}
+
+        for (entity, mut data, transform, bounds, mut edits, mut cleared) in fields.iter_mut(){
+            let circles = CornCircleEdit::in_field(circles.as_slice(), transform, bounds);
+            let mut sets = sets.iter().filter(|e| e.field == entity).peekable();
+            if circles.is_empty() && sets.peek().is_none() {continue;}
+            // Don't trigger change detection, the edits are sent on their own instead of re-uploading the whole field
+            let data = &mut data.bypass_change_detection().0;
+            let mut dirty: Vec<u32> = vec![];
+            if !circles.is_empty(){
+                for (id, corn) in data.iter_mut().enumerate(){
+                    // Later circles win, as if the edits were applied one at a time
+                    let Some(circle) = circles.iter().rev().find(|circle| circle.contains(corn.offset().xz())) else {continue;};
+                    let enabled = circle.enabled != 0;
+                    if cleared.as_mut().is_some_and(|cleared| cleared.edit(id as u32, |corn| corn.edit(enabled))) {continue;}
+                    if !corn.edit(enabled) {continue;}
+                    dirty.push(id as u32);
+                }
+            }
+            for event in sets{
+                for id in event.ids.iter(){
+                    let Some(corn) = data.get_mut(*id as usize) else {continue;};
+                    if cleared.as_mut().is_some_and(|cleared| cleared.edit(*id, |corn| corn.set_edited(event.enabled))) {continue;}
+                    if !corn.set_edited(event.enabled) {continue;}
+                    dirty.push(*id);
+                }
+            }
+            if dirty.is_empty() {continue;}
+            edits.0.append(&mut Self::from_dirty(dirty, data.as_slice()).0);
+        }
+        for (entity, transform, bounds, mut edits) in gpu_fields.iter_mut(){
+            let circles = CornCircleEdit::in_field(circles.as_slice(), transform, bounds);
+            let sets: Vec<UVec2> = sets.iter().filter(|e| e.field == entity)
+                .flat_map(|event| event.ids.iter().map(|id| UVec2::new(*id, event.enabled as u32)))
+                .collect();
+            if circles.is_empty() && sets.is_empty() {continue;}
+            *edits = CornGpuEdits{circles, sets};
+        }
+    }
+
+    /// Writes the extracted patches to the instance buffer
+    pub fn write_edits(
+        query: Query<(&InstanceBuffer, &Self), Changed<Self>>,
+        render_queue: Res<RenderQueue>
+    ){
+        for (InstanceBuffer(buffer, count), CornEdits(patches)) in query.iter(){
+            for patch in patches.iter(){
+                if patch.start as u64 + patch.data.len() as u64 > *count {continue;}
+                render_queue.write_buffer(
+                    buffer,
+                    patch.start as u64*CornData::DATA_SIZE,
+                    bytemuck::cast_slice(patch.data.as_slice())
+                );
+            }
+        }
+    }
+}
+
+/// Circle of stalks to enable or disable, in field space. Mirrors `CircleEdit` in `shaders/corn/edit.wgsl`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct CornCircleEdit{
+    pub center: Vec2,
+    pub radius: f32,
+    pub enabled: u32
+}
+impl CornCircleEdit{
+    /// Converts world space circles into a field's space, dropping those which miss every stalk of the field
+    fn in_field(circles: &[(Vec3, f32, bool)], transform: &GlobalTransform, bounds: Option<&CornStalkBounds>) -> Vec<Self>{
+        let inverse = transform.affine().inverse();
+        let scale = transform.scale().xz().min_element();
+        circles.iter()
+            .map(|(center, radius, enabled)| Self{
+                center: inverse.transform_point3(*center).xz(), radius: radius/scale, enabled: *enabled as u32
+            })
+            .filter(|circle| bounds.is_none_or(|bounds|
+                circle.contains(circle.center.clamp(bounds.min.xz(), bounds.max.xz()))
+            ))
+            .collect()
+    }
+    pub fn contains(&self, point: Vec2) -> bool{
+        point.distance_squared(self.center) <= self.radius*self.radius
+    }
+}
+
+/// Component for corn fields without main world corn data, holding the edits made this frame.
+/// Extracted to the render world and applied to the instance buffer by a compute pass
+#[derive(Default, Debug, Clone, PartialEq, Component, ExtractComponent)]
+#[extract_component_filter(Changed<CornGpuEdits>)]
+pub struct CornGpuEdits{
+    pub circles: Vec<CornCircleEdit>,
+    /// Stalk index and enabled state of each stalk set by `SetCornEnabled`
+    pub sets: Vec<UVec2>
+}
+impl CornGpuEdits{
+    pub fn is_empty(&self) -> bool {self.circles.is_empty() && self.sets.is_empty()}
+}
+
+/// Holds the data required to apply a field's edits during the render graph
+#[derive(Debug, Component)]
+pub struct CornEditInvocation{
+    pub bindgroup: BindGroup,
+    /// Workgroups for the circle and set entry points, 0 if there is nothing to dispatch
+    pub dispatch_counts: UVec2,
+    pub finished: AtomicBool
+}
+impl CornEditInvocation{
+    /// Creates invocations for fields that were edited this frame
+    fn create_invocations(
+        query: Query<(Entity, &InstanceBuffer, &CornGpuEdits), Changed<CornGpuEdits>>,
+        pipeline: Res<CornEditPipelineResources>,
+        render_device: Res<RenderDevice>,
+        mut commands: Commands
+    ){
+        for (entity, InstanceBuffer(instance, count), edits) in query.iter(){
+            if edits.is_empty() {continue;}
+            // Bindings can't be empty, entry points without edits aren't dispatched so the padding is never read
+            let (no_circles, no_sets) = ([CornCircleEdit::default()], [UVec2::ZERO]);
+            let circles = render_device.create_buffer_with_data(&BufferInitDescriptor{
+                label: Some("Corn Edit Circles Buffer"),
+                usage: BufferUsages::STORAGE,
+                contents: bytemuck::cast_slice(if edits.circles.is_empty() {&no_circles} else {edits.circles.as_slice()})
+            });
+            let sets = render_device.create_buffer_with_data(&BufferInitDescriptor{
+                label: Some("Corn Edit Sets Buffer"),
+                usage: BufferUsages::STORAGE,
+                contents: bytemuck::cast_slice(if edits.sets.is_empty() {&no_sets} else {edits.sets.as_slice()})
+            });
+            let bindgroup = render_device.create_bind_group(
+                Some("Corn Edit Bind Group"),
+                &pipeline.layout,
+                &BindGroupEntries::sequential((instance.as_entire_binding(), circles.as_entire_binding(), sets.as_entire_binding()))
+            );
+            let dispatch_counts = UVec2::new(
+                if edits.circles.is_empty() {0} else {count.div_ceil(256) as u32},
+                (edits.sets.len() as u32).div_ceil(64)
+            );
+            commands.entity(entity).insert(Self{bindgroup, dispatch_counts, finished: AtomicBool::new(false)});
+        }
+    }
+    /// Removes invocations that have run
+    fn cleanup_invocations(query: Query<(Entity, &Self)>, mut commands: Commands){
+        for (entity, invocation) in query.iter(){
+            if !invocation.finished.load(Ordering::Relaxed) {continue;}
+            commands.entity(entity).remove::<Self>();
+        }
+    }
+}
+
+/// Pipeline resources for the edit shader
+#[derive(Debug, Clone, Resource)]
+pub struct CornEditPipelineResources{
+    pub layout: BindGroupLayout,
+    pub circles: CachedComputePipelineId,
+    pub sets: CachedComputePipelineId,
+    pub shader: Handle<Shader>
+}
+impl FromWorld for CornEditPipelineResources{
+    fn from_world(world: &mut World) -> Self {
+        let shader: Handle<Shader> = world.resource::<AssetServer>().load("shaders/corn/edit.wgsl");
+        let layout = world.resource::<RenderDevice>().create_bind_group_layout(
+            Some("Corn Edit BindGroup Layout"),
+            // instances, circles, sets
+            &[false, true, true].into_iter().enumerate().map(|(binding, read_only)| BindGroupLayoutEntry{
+                binding: binding as u32,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer{
+                    ty: BufferBindingType::Storage{read_only},
+                    has_dynamic_offset: false,
+                    min_binding_size: None
+                },
+                count: None
+            }).collect::<Vec<_>>()
+        );
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let [circles, sets] = ["edit_circles", "edit_sets"].map(|entry_point| pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor{
+            label: Some("Corn Edit Pipeline".into()),
+            layout: vec![layout.clone()],
+            push_constant_ranges: vec![],
+            shader: shader.clone(),
+            shader_defs: vec![],
+            entry_point: entry_point.into(),
+            zero_initialize_workgroup_memory: false
+        }));
+        Self{layout, circles, sets, shader}
+    }
+}
+
+/// Render Graph Label for the edit pass
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, RenderLabel)]
+pub struct CornEditStage;
+/// This is the render graph node which applies edits to fields without main world corn data
+#[derive(Default, Debug, Clone)]
+struct CornEditNode{
+    ready_entities: Vec<Entity>
+}
+impl bevy::render::render_graph::Node for CornEditNode{
+    fn update(&mut self, world: &mut World) {
+        let mut query = world.query_filtered::<Entity, With<CornEditInvocation>>();
+        self.ready_entities = query.iter(world).collect();
+    }
+    fn run(&self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError>{
+        if self.ready_entities.is_empty() {return Ok(());}
+        let resources = world.resource::<CornEditPipelineResources>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let (Some(circles), Some(sets)) = (
+            pipeline_cache.get_compute_pipeline(resources.circles),
+            pipeline_cache.get_compute_pipeline(resources.sets)
+        ) else {return Ok(());};
+        let mut pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor{
+            label: Some("Corn Edit Pass"),
+            timestamp_writes: world.get_resource::<CornGpuTimings>().and_then(|timings| timings.compute_pass_writes(CornPass::Init))
+        });
+        for entity in self.ready_entities.iter(){
+            let Some(invocation) = world.get::<CornEditInvocation>(*entity) else {continue;};
+            pass.set_bind_group(0, &invocation.bindgroup, &[]);
+            for (pipeline, count) in [(circles, invocation.dispatch_counts.x), (sets, invocation.dispatch_counts.y)]{
+                if count == 0 {continue;}
+                pass.set_pipeline(pipeline);
+                pass.dispatch_workgroups(count, 1, 1);
+            }
+            invocation.finished.store(true, Ordering::Relaxed);
+        }
+        drop(pass);
+        // The arena copied these fields before they were edited, so copy them again
+        let arena = world.resource::<CornInstanceArena>();
+        let Some(arena_buffer) = arena.buffer.as_ref() else {return Ok(());};
+        for entity in self.ready_entities.iter(){
+            let (Some(InstanceBuffer(instance, _)), Some(slot)) = (world.get::<InstanceBuffer>(*entity), arena.slots.get(entity)) else {continue;};
+            render_context.command_encoder().copy_buffer_to_buffer(
+                instance, 0, arena_buffer, slot.offset*CornData::DATA_SIZE, slot.count*CornData::DATA_SIZE
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Adds runtime corn editing
+pub struct CornEditPlugin;
+impl Plugin for CornEditPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<CutCorn>()
+            .add_event::<RestoreCorn>()
+            .add_event::<SetCornEnabled>()
+            .add_plugins((ExtractComponentPlugin::<CornEdits>::default(), ExtractComponentPlugin::<CornGpuEdits>::default()))
+            .add_systems(PostUpdate, CornEdits::apply_edits)
+        .sub_app_mut(RenderApp)
+            .add_systems(Render, (
+                CornEdits::write_edits.in_set(RenderSet::PrepareResources).after(InitialCornData::upload_data),
+                CornEditInvocation::create_invocations.in_set(RenderSet::PrepareBindGroups),
+                CornEditInvocation::cleanup_invocations.in_set(RenderSet::Cleanup)
+            ));
+        let mut graph = app.sub_app_mut(RenderApp).world_mut().resource_mut::<RenderGraph>();
+        graph.add_node(CornEditStage, CornEditNode::default());
+        graph.add_node_edge(CornHeightmapStage, CornEditStage);
+        graph.add_node_edge(CornEditStage, CameraDriverLabel);
+    }
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp).init_resource::<CornEditPipelineResources>();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn circles_outside_the_field_are_skipped(){
+        let transform = GlobalTransform::from_translation(Vec3::new(100.0, 0.0, 0.0));
+        let bounds = CornStalkBounds{min: Vec3::ZERO, max: Vec3::new(10.0, 0.0, 10.0), max_scale: 1.0};
+        let circles = [(Vec3::new(105.0, 0.0, 5.0), 1.0, false), (Vec3::new(95.0, 0.0, 5.0), 4.0, true), (Vec3::new(0.0, 0.0, 5.0), 4.0, true)];
+        let edits = CornCircleEdit::in_field(&circles, &transform, Some(&bounds));
+        assert_eq!(edits, [CornCircleEdit{center: Vec2::new(5.0, 5.0), radius: 1.0, enabled: 0}]);
+        assert_eq!(CornCircleEdit::in_field(&circles, &transform, None).len(), 3);
+    }
+
+    #[test]
+    fn restores_only_bring_back_cut_stalks(){
+        let mut corn = [CornData{enabled: 1, ..default()}, CornData::default()];
+        assert!(corn[0].edit(false) && corn[0].is_cut());
+        // Disabled by the layout, like a maze corridor
+        assert!(!corn[1].edit(false) && !corn[1].edit(true));
+        assert!(corn[0].edit(true) && corn[0].is_enabled());
+        assert!(!corn[1].is_enabled());
+        assert!(corn[1].set_edited(true) && corn[1].set_edited(false) && corn[1].edit(true));
+    }
+}
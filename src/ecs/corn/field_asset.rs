@@ -0,0 +1,216 @@
+//! Baked corn fields. A `.cornfield` file holds the per stalk data of a field so it can be uploaded directly,
+//! instead of running an init shader every launch.
+//!
+//! Layout (little endian):
+//! - `CornFieldHeader`: magic `CORN`, format version, stalk count
+//! - `count` packed `CornData` records, `CornData::DATA_SIZE` bytes each
+use bevy::{
+    asset::{io::{Reader, Writer}, processor::LoadTransformAndSave, saver::{AssetSaver, SavedAsset}, transformer::IdentityAssetTransformer, AssetLoader, LoadContext},
+    prelude::*
+};
+use bytemuck::{Pod, Zeroable};
+use futures_lite::AsyncWriteExt;
+use super::{init::InitialCornData, CornData, CornField};
+
+#[derive(Debug)]
+pub enum CornFieldAssetError{
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    Truncated{expected: u64, found: u64}
+}
+impl std::fmt::Display for CornFieldAssetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Self::Io(err) => write!(f, "Could not read corn field: {err}"),
+            Self::InvalidMagic => write!(f, "File is not a corn field"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported corn field version {version}, expected <= {}", CornFieldHeader::VERSION),
+            Self::Truncated{expected, found} => write!(f, "Corn field is truncated, expected {expected} bytes, found {found}")
+        }
+    }
+}
+impl core::error::Error for CornFieldAssetError{}
+impl From<std::io::Error> for CornFieldAssetError{
+    fn from(value: std::io::Error) -> Self {Self::Io(value)}
+}
+
+/// Header at the start of every `.cornfield` file
+#[derive(Clone, Copy, Debug, Pod, Zeroable, PartialEq, Eq)]
+#[repr(C)]
+pub struct CornFieldHeader{
+    magic: [u8; 4],
+    version: u32,
+    count: u64
+}
+impl CornFieldHeader{
+    pub const MAGIC: [u8; 4] = *b"CORN";
+    /// Current version of the format. Bump this whenever `CornData` or the header changes
+    pub const VERSION: u32 = 1;
+    pub const DATA_SIZE: usize = 16;
+
+    pub fn new(count: u64) -> Self{
+        Self{magic: Self::MAGIC, version: Self::VERSION.to_le(), count: count.to_le()}
+    }
+}
+
+/// Asset holding the stalks of a baked corn field
+#[derive(Default, Debug, Clone, PartialEq, Asset, Reflect)]
+pub struct CornFieldAsset{
+    pub data: Vec<CornData>
+}
+impl CornFieldAsset{
+    /// Serializes the field into the `.cornfield` format
+    pub fn to_bytes(&self) -> Vec<u8>{
+        let header = CornFieldHeader::new(self.data.len() as u64);
+        let mut bytes = Vec::with_capacity(CornFieldHeader::DATA_SIZE + self.data.len()*CornData::DATA_SIZE as usize);
+        bytes.extend_from_slice(bytemuck::bytes_of(&header));
+        bytes.extend_from_slice(bytemuck::cast_slice(self.data.as_slice()));
+        bytes
+    }
+    /// Parses a field from the `.cornfield` format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CornFieldAssetError>{
+        if bytes.len() < CornFieldHeader::DATA_SIZE {
+            return Err(CornFieldAssetError::Truncated{expected: CornFieldHeader::DATA_SIZE as u64, found: bytes.len() as u64});
+        }
+        let header: CornFieldHeader = bytemuck::pod_read_unaligned(&bytes[..CornFieldHeader::DATA_SIZE]);
+        if header.magic != CornFieldHeader::MAGIC {return Err(CornFieldAssetError::InvalidMagic);}
+        match u32::from_le(header.version){
+            1 => {
+                let body = &bytes[CornFieldHeader::DATA_SIZE..];
+                // The count comes straight from the file, so a corrupt header can ask for more than fits in memory
+                let expected = u64::from_le(header.count).saturating_mul(CornData::DATA_SIZE);
+                if (body.len() as u64) < expected {
+                    return Err(CornFieldAssetError::Truncated{expected, found: body.len() as u64});
+                }
+                let data = body[..expected as usize].chunks_exact(CornData::DATA_SIZE as usize)
+                    .map(|chunk| bytemuck::pod_read_unaligned::<CornData>(chunk))
+                    .collect();
+                Ok(Self{data})
+            },
+            version => Err(CornFieldAssetError::UnsupportedVersion(version))
+        }
+    }
+}
+
+/// Loads `.cornfield` files
+#[derive(Default, Debug, Clone)]
+pub struct CornFieldLoader;
+impl AssetLoader for CornFieldLoader{
+    type Asset = CornFieldAsset;
+    type Settings = ();
+    type Error = CornFieldAssetError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        CornFieldAsset::from_bytes(bytes.as_slice())
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cornfield"]
+    }
+}
+
+/// Saves corn fields in the current `.cornfield` version. Used by the asset processor to upgrade old files
+#[derive(Default, Debug, Clone)]
+pub struct CornFieldSaver;
+impl AssetSaver for CornFieldSaver{
+    type Asset = CornFieldAsset;
+    type Settings = ();
+    type OutputLoader = CornFieldLoader;
+    type Error = CornFieldAssetError;
+
+    async fn save(
+        &self,
+        writer: &mut Writer,
+        asset: SavedAsset<'_, Self::Asset>,
+        _settings: &Self::Settings,
+    ) -> Result<(), Self::Error> {
+        writer.write_all(asset.to_bytes().as_slice()).await?;
+        Ok(())
+    }
+}
+
+/// Asset processor which re-saves corn fields in the current format version
+pub type CornFieldProcessor = LoadTransformAndSave<CornFieldLoader, IdentityAssetTransformer<CornFieldAsset>, CornFieldSaver>;
+
+/// Component for corn fields which are initialized from a baked `.cornfield` asset rather than an init shader
+#[derive(Default, Debug, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+#[require(CornField)]
+pub struct BakedCornField(pub Handle<CornFieldAsset>);
+impl BakedCornField{
+    /// Once the asset loads, hands its data to `InitialCornData` to be uploaded in the render world
+    fn insert_initial_data(
+        query: Query<(Entity, &Self), Without<InitialCornData>>,
+        assets: Res<Assets<CornFieldAsset>>,
+        mut commands: Commands
+    ){
+        for (entity, BakedCornField(handle)) in query.iter(){
+            let Some(asset) = assets.get(handle) else {continue;};
+            commands.entity(entity).insert(InitialCornData(asset.data.clone()));
+        }
+    }
+}
+
+/// Adds the `.cornfield` asset and baked corn fields
+pub struct CornFieldAssetPlugin;
+impl Plugin for CornFieldAssetPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .init_asset::<CornFieldAsset>()
+            .register_type::<CornFieldAsset>()
+            .register_type::<BakedCornField>()
+            .init_asset_loader::<CornFieldLoader>()
+            .register_asset_processor::<CornFieldProcessor>(
+                CornFieldProcessor::new(IdentityAssetTransformer::new(), CornFieldSaver)
+            )
+            .set_default_asset_processor::<CornFieldProcessor>("cornfield")
+            .add_systems(Update, BakedCornField::insert_initial_data);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn round_trip(){
+        let asset = CornFieldAsset{data: vec![CornData::default(); 3]};
+        assert_eq!(CornFieldAsset::from_bytes(asset.to_bytes().as_slice()).unwrap(), asset);
+    }
+
+    #[test]
+    fn short_header_is_truncated(){
+        let bytes = CornFieldAsset::default().to_bytes();
+        assert!(matches!(
+            CornFieldAsset::from_bytes(&bytes[..CornFieldHeader::DATA_SIZE-1]),
+            Err(CornFieldAssetError::Truncated{expected: 16, found: 15})
+        ));
+        assert!(matches!(CornFieldAsset::from_bytes(&[]), Err(CornFieldAssetError::Truncated{..})));
+    }
+
+    #[test]
+    fn missing_stalks_are_truncated(){
+        let bytes = CornFieldAsset{data: vec![CornData::default(); 2]}.to_bytes();
+        assert!(matches!(
+            CornFieldAsset::from_bytes(&bytes[..bytes.len()-1]),
+            Err(CornFieldAssetError::Truncated{expected: 64, found: 63})
+        ));
+    }
+
+    #[test]
+    fn overflowing_count_is_truncated(){
+        let mut bytes = bytemuck::bytes_of(&CornFieldHeader::new(u64::MAX)).to_vec();
+        bytes.extend_from_slice(bytemuck::bytes_of(&CornData::default()));
+        assert!(matches!(
+            CornFieldAsset::from_bytes(bytes.as_slice()),
+            Err(CornFieldAssetError::Truncated{expected: u64::MAX, ..})
+        ));
+    }
+}
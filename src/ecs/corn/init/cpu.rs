@@ -0,0 +1,147 @@
+//! Cpu reference implementations of the init shaders.
+//! These produce the same corn data as the gpu kernels (up to float rounding in sin/cos),
+//! so field layouts can be inspected or uploaded through `InitialCornData` without running a compute pass.
+use bevy::prelude::*;
+use crate::ecs::corn::CornData;
+use super::{shader::AsCornInitShader, simple::{SimpleHexagonalInitShader, SimpleInitShader, SimpleInitShaderSettings}, InitialCornData};
+
+/// Mirror of the random number generator in `shaders/noise.wgsl`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CornRng(pub u32);
+impl CornRng{
+    /// Seeds the generator using Thomas Wang's hash. Same as `wang_hash`
+    pub fn new(seed: u32) -> Self{
+        let mut state = (seed ^ 61) ^ (seed >> 16);
+        state = state.wrapping_mul(9);
+        state = state ^ (state >> 4);
+        state = state.wrapping_mul(0x27d4eb2d);
+        state = state ^ (state >> 15);
+        Self(state)
+    }
+    /// Seeds the generator for element `index` of the sequence picked by `seed`. Same as `initRand`
+    pub fn seeded(seed: u32, index: u32) -> Self{
+        Self::new(Self::new(seed).0 ^ index)
+    }
+    /// Xorshift step. Same as `rand_xorshift`
+    pub fn next_u32(&mut self) -> u32{
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+    /// Returns a float in [0, 1). Same as `randNext`
+    pub fn next_f32(&mut self) -> f32{
+        self.next_u32() as f32 * (1.0 / 4294967296.0)
+    }
+}
+
+/// Cpu version of `simple_rect_init` in `shaders/corn/init/simple_init.wgsl`
+pub fn simple_rect_init(settings: &SimpleInitShaderSettings, instance_index: u32) -> CornData{
+    let res_width = settings.resolution_width;
+    let pos = Vec2::new((instance_index%res_width) as f32, (instance_index/res_width) as f32);
+    place_stalk(settings, instance_index, pos)
+}
+
+/// Cpu version of `simple_init` in `shaders/corn/init/simple_init.wgsl`
+pub fn simple_init(settings: &SimpleInitShaderSettings, instance_index: u32) -> CornData{
+    let res_width = settings.resolution_width;
+    let expanded_index = UVec2::new(instance_index*2%res_width, instance_index*2/res_width);
+    place_stalk(settings, instance_index, expanded_index.as_vec2())
+}
+
+/// Shared body of the simple init kernels, given the stalks grid position
+fn place_stalk(settings: &SimpleInitShaderSettings, instance_index: u32, pos: Vec2) -> CornData{
+    let xz_offset = pos*settings.step_size;
+    // Add the field's origin position to the corn stalk position
+    let mut offset = settings.origin + Vec3::new(xz_offset.x, 0.0, xz_offset.y);
+    // Add random offsets to the x and z position of the corn stalk
+    let mut rng = CornRng::seeded(settings.seed, instance_index);
+    let rand_x = rng.next_f32();
+    let rand_z = rng.next_f32();
+    offset += Vec3::new(rand_x, 0.5, rand_z)*settings.random_settings.x*2.0 - 1.0;
+    // set the random scale of the corn stalk
+    let scale = rng.next_f32() * settings.height_range + settings.minimum_height;
+    // set the random rotation of the corn stalk
+    // Same constant as the shader rather than TAU, so rotations match exactly
+    #[allow(clippy::approx_constant)]
+    let theta = rng.next_f32()*6.2832;
+    CornData{
+        offset,
+        scale,
+        rotation: Vec2::new(theta.sin(), theta.cos()),
+        uuid: rng.next_u32(),
+        enabled: 1
+    }
+}
+
+/// Init shaders which can generate their corn data on the cpu
+pub trait CpuCornInit{
+    /// Returns the same corn data the gpu init shader would write into the instance buffer
+    fn generate_corn_data(&self) -> Vec<CornData>;
+    /// Returns the corn data wrapped for upload, skipping the init shader entirely
+    fn get_initial_data(&self) -> InitialCornData{
+        InitialCornData(self.generate_corn_data())
+    }
+}
+impl CpuCornInit for SimpleInitShader{
+    fn generate_corn_data(&self) -> Vec<CornData> {
+        let settings = SimpleInitShaderSettings::from(self);
+        let count = Self::get_instance_count(self) as u32;
+        (0..count).map(|i| simple_rect_init(&settings, i)).collect()
+    }
+}
+impl CpuCornInit for SimpleHexagonalInitShader{
+    fn generate_corn_data(&self) -> Vec<CornData> {
+        let settings = SimpleInitShaderSettings::from(self);
+        let count = Self::get_instance_count(self) as u32;
+        (0..count).map(|i| simple_init(&settings, i)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use bevy::prelude::*;
+    use crate::ecs::corn::init::{shader::AsCornInitShader, simple::{SimpleHexagonalInitShader, SimpleInitShader, SimpleInitShaderSettings}};
+    use super::CpuCornInit;
+
+    /// Every simple kernel shifts stalks by -1 when they have no random offset
+    const SHIFT: Vec3 = Vec3::NEG_ONE;
+
+    #[test]
+    fn rect_layout_follows_grid(){
+        let shader = SimpleInitShader::new(Vec3::new(1.0, 2.0, 3.0), Vec2::new(2.0, 1.0), UVec2::new(3, 2), Vec2::new(1.0, 2.0), 0.0, 7);
+        let data = shader.generate_corn_data();
+        assert_eq!(data.len() as u64, SimpleInitShader::get_instance_count(&shader));
+        for (index, corn) in data.iter().enumerate(){
+            let cell = Vec2::new((index%3) as f32, (index/3) as f32)*shader.get_step();
+            let expected = shader.get_origin() + Vec3::new(cell.x, 0.0, cell.y) + SHIFT;
+            assert!(corn.offset.abs_diff_eq(expected, 1e-5), "stalk {index} at {} instead of {expected}", corn.offset);
+            assert!((1.0..2.0).contains(&corn.scale));
+            assert!((corn.rotation.length() - 1.0).abs() < 1e-5);
+            assert_eq!(corn.enabled, 1);
+        }
+    }
+
+    #[test]
+    fn hex_layout_alternates_rows(){
+        let shader = SimpleHexagonalInitShader::new(Vec3::ZERO, Vec2::new(4.0, 4.0), 1.0, Vec2::ONE, 0.0, 3);
+        let settings = SimpleInitShaderSettings::from(&shader);
+        let data = shader.generate_corn_data();
+        assert_eq!(data.len() as u64, SimpleHexagonalInitShader::get_instance_count(&shader));
+        for (index, corn) in data.iter().enumerate(){
+            let expanded = index as u32*2;
+            let cell = UVec2::new(expanded%settings.resolution_width, expanded/settings.resolution_width);
+            let expected = settings.origin + Vec3::new(cell.x as f32*settings.step_size.x, 0.0, cell.y as f32*settings.step_size.y) + SHIFT;
+            assert!(corn.offset.abs_diff_eq(expected, 1e-5), "stalk {index} at {} instead of {expected}", corn.offset);
+            // Neighbouring rows are offset by half a cell
+            assert_eq!((cell.x + cell.y)%2, 0);
+        }
+    }
+
+    #[test]
+    fn seed_picks_the_layout(){
+        let field = |seed| SimpleInitShader::new(Vec3::ZERO, Vec2::splat(10.0), UVec2::splat(8), Vec2::new(0.8, 1.2), 0.3, seed);
+        assert_eq!(field(1).generate_corn_data(), field(1).generate_corn_data());
+        assert_ne!(field(1).generate_corn_data(), field(2).generate_corn_data());
+    }
+}
@@ -0,0 +1,397 @@
+//! Terrain following corn. After a field is initialized, each stalk's `offset.y` is moved onto the ground described by a heightmap.
+//! This works with any init shader. Fields with main world corn data (`InitialCornData`) are adjusted on the cpu so the data stays in sync,
+//! fields initialized on the gpu are adjusted by a compute pass.
+//!
+//! A terrain mesh can be used instead of an image with `CornGroundMesh`, which bakes the mesh into a heightmap.
+use std::sync::atomic::{AtomicBool, Ordering};
+use bevy::{
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        graph::CameraDriverLabel,
+        mesh::{Indices, VertexAttributeValues},
+        render_asset::{RenderAssetUsages, RenderAssets},
+        render_graph::*, render_resource::*,
+        renderer::{RenderContext, RenderDevice}, texture::GpuImage,
+        Render, RenderApp, RenderSet
+    },
+    utils::HashSet
+};
+use bytemuck::{Pod, Zeroable};
+use crate::{ecs::corn::{diagnostics::{CornGpuTimings, CornPass}, edit::CornEdits, CornFieldObserver, CornLoaded, InstanceBuffer}, util::observer_ext::ObserveAsAppExt};
+use super::{shader::CornInitStage, InitialCornData};
+
+/// Component for corn fields whose stalks should follow the ground.
+/// The image covers the rectangle `origin..origin+size` of the field's xz plane, u along x and v along z.
+/// The red channel is mapped to a field space height of `height_range.x + red*(height_range.y-height_range.x)`
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Component, ExtractComponent)]
+#[reflect(Component)]
+#[extract_component_filter(Changed<CornHeightmap>)]
+pub struct CornHeightmap{
+    pub image: Handle<Image>,
+    pub origin: Vec2,
+    pub size: Vec2,
+    /// Min and Max height
+    pub height_range: Vec2
+}
+impl CornHeightmap{
+    /// Size of the covered rectangle, kept above zero so an empty heightmap doesn't divide by zero and write NaN heights
+    pub fn covered_size(&self) -> Vec2{
+        self.size.max(Vec2::splat(f32::EPSILON))
+    }
+    /// Height at a point in field space. Mirrors `apply_heightmap` in `shaders/corn/init/heightmap.wgsl`
+    pub fn sample(&self, image: &Image, point: Vec2) -> f32{
+        let dimensions = image.size().as_ivec2();
+        let load = |texel: IVec2| {
+            let texel = texel.clamp(IVec2::ZERO, dimensions - 1).as_uvec2();
+            image.get_color_at(texel.x, texel.y).map(|c| c.to_linear().red).unwrap_or_default()
+        };
+        let uv = ((point - self.origin)/self.covered_size()).clamp(Vec2::ZERO, Vec2::ONE);
+        let position = uv*dimensions.as_vec2() - 0.5;
+        let base = position.floor();
+        let fraction = position - base;
+        let texel = base.as_ivec2();
+        let height = load(texel).lerp(load(texel + IVec2::X), fraction.x)
+            .lerp(load(texel + IVec2::Y).lerp(load(texel + IVec2::ONE), fraction.x), fraction.y);
+        self.height_range.x + height*(self.height_range.y - self.height_range.x)
+    }
+
+    /// Moves the main world corn data onto the ground, and sends the moved stalks as edits so uploaded buffers match
+    fn apply_cpu_heightmap(
+        mut query: Query<(Entity, &Self, &mut InitialCornData, &mut CornEdits), Without<HeightmapApplied>>,
+        images: Res<Assets<Image>>,
+        mut commands: Commands
+    ){
+        for (entity, heightmap, mut data, mut edits) in query.iter_mut(){
+            let Some(image) = images.get(heightmap.image.id()) else {continue;};
+            // Don't trigger change detection, the field is sent as an edit instead of re-uploading
+            let data = &mut data.bypass_change_detection().0;
+            let mut dirty: Vec<u32> = vec![];
+            for (id, corn) in data.iter_mut().enumerate(){
+                let height = heightmap.sample(image, corn.offset.xz());
+                if corn.offset.y == height {continue;}
+                corn.offset.y = height;
+                dirty.push(id as u32);
+            }
+            edits.0.append(&mut CornEdits::from_dirty(dirty, data.as_slice()).0);
+            commands.entity(entity).insert(HeightmapApplied);
+        }
+    }
+
+    /// Observer which makes the heightmap reapply whenever it or the corn data is replaced
+    fn reset_applied(trigger: Trigger<OnInsert, (Self, InitialCornData)>, mut commands: Commands){
+        commands.entity(trigger.entity()).remove::<HeightmapApplied>();
+    }
+}
+
+/// Struct mirroring the heightmap settings in `shaders/corn/init/heightmap.wgsl`
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct HeightmapSettings{
+    origin: Vec2,
+    inv_size: Vec2,
+    height_min_range: Vec2,
+    padding: Vec2
+}
+impl From<&CornHeightmap> for HeightmapSettings{
+    fn from(value: &CornHeightmap) -> Self {
+        Self{
+            origin: value.origin,
+            inv_size: Vec2::ONE/value.covered_size(),
+            height_min_range: Vec2::new(value.height_range.x, value.height_range.y - value.height_range.x),
+            padding: Vec2::ZERO
+        }
+    }
+}
+
+/// Component for corn fields which should follow a terrain mesh. The mesh is baked into a `CornHeightmap` once it loads,
+/// and baked again whenever it changes or moves relative to the field
+#[derive(Debug, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CornGroundMesh{
+    /// Entity with the terrain's `Mesh3d`
+    pub mesh: Entity,
+    /// Resolution of the baked heightmap
+    pub resolution: UVec2
+}
+impl CornGroundMesh{
+    /// Rasterizes the top surface of a mesh into a heightmap covering the mesh's xz bounds in field space
+    pub fn bake(mesh: &Mesh, mesh_to_field: Mat4, resolution: UVec2, images: &mut Assets<Image>) -> Option<CornHeightmap>{
+        let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {return None;};
+        let positions: Vec<Vec3> = positions.iter().map(|p| mesh_to_field.transform_point3(Vec3::from_array(*p))).collect();
+        let indices: Vec<usize> = match mesh.indices(){
+            Some(Indices::U16(i)) => i.iter().map(|i| *i as usize).collect(),
+            Some(Indices::U32(i)) => i.iter().map(|i| *i as usize).collect(),
+            None => (0..positions.len()).collect()
+        };
+        let min = positions.iter().fold(Vec3::INFINITY, |a, b| a.min(*b));
+        let max = positions.iter().fold(Vec3::NEG_INFINITY, |a, b| a.max(*b));
+        if !min.is_finite() || !max.is_finite() {return None;}
+        let origin = min.xz();
+        let size = (max.xz() - min.xz()).max(Vec2::splat(f32::EPSILON));
+        let texel_size = size/resolution.as_vec2();
+
+        let mut heights = vec![f32::NEG_INFINITY; (resolution.x*resolution.y) as usize];
+        for triangle in indices.chunks_exact(3){
+            let [a, b, c] = [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]];
+            let area = (b.xz() - a.xz()).perp_dot(c.xz() - a.xz());
+            if area.abs() <= f32::EPSILON {continue;}
+            let low = ((a.xz().min(b.xz()).min(c.xz()) - origin)/texel_size - 0.5).floor().max(Vec2::ZERO).as_uvec2();
+            let high = ((a.xz().max(b.xz()).max(c.xz()) - origin)/texel_size - 0.5).ceil().as_uvec2().min(resolution - 1);
+            for y in low.y..=high.y{
+                for x in low.x..=high.x{
+                    let point = origin + (UVec2::new(x, y).as_vec2() + 0.5)*texel_size;
+                    // barycentric coordinates of the texel center
+                    let u = (c.xz() - b.xz()).perp_dot(point - b.xz())/area;
+                    let v = (a.xz() - c.xz()).perp_dot(point - c.xz())/area;
+                    let w = 1.0 - u - v;
+                    if u < 0.0 || v < 0.0 || w < 0.0 {continue;}
+                    let index = (y*resolution.x + x) as usize;
+                    heights[index] = heights[index].max(u*a.y + v*b.y + w*c.y);
+                }
+            }
+        }
+        // Texels the mesh doesn't cover sit at the lowest point of the mesh
+        for height in heights.iter_mut(){
+            if !height.is_finite() {*height = min.y;}
+        }
+        let image = Image::new(
+            Extent3d{width: resolution.x, height: resolution.y, depth_or_array_layers: 1},
+            TextureDimension::D2,
+            bytemuck::cast_slice(heights.as_slice()).to_vec(),
+            TextureFormat::R32Float,
+            RenderAssetUsages::default()
+        );
+        Some(CornHeightmap{image: images.add(image), origin, size, height_range: Vec2::new(0.0, 1.0)})
+    }
+
+    /// Bakes the ground mesh once it has loaded, and again whenever it changes or moves relative to the field
+    fn bake_ground_meshes(
+        query: Query<(Entity, Ref<Self>, &GlobalTransform, Option<&BakedGroundMesh>)>,
+        meshes: Query<(&Mesh3d, &GlobalTransform)>,
+        mesh_assets: Res<Assets<Mesh>>,
+        mut mesh_events: EventReader<AssetEvent<Mesh>>,
+        mut images: ResMut<Assets<Image>>,
+        mut commands: Commands
+    ){
+        let modified: HashSet<AssetId<Mesh>> = mesh_events.read().filter_map(|event| match event{
+            AssetEvent::Modified{id} => Some(*id),
+            _ => None
+        }).collect();
+        for (entity, ground, field_transform, baked) in query.iter(){
+            let Ok((Mesh3d(handle), mesh_transform)) = meshes.get(ground.mesh) else {continue;};
+            // Only the mesh's transform relative to the field matters, so a field and its ground can move together
+            let mesh_to_field = field_transform.compute_matrix().inverse()*mesh_transform.compute_matrix();
+            let up_to_date = baked.is_some_and(|baked|
+                baked.mesh == handle.id() && baked.mesh_to_field.abs_diff_eq(mesh_to_field, BakedGroundMesh::EPSILON)
+            );
+            if up_to_date && !ground.is_changed() && !modified.contains(&handle.id()) {continue;}
+            let Some(mesh) = mesh_assets.get(handle) else {continue;};
+            let Some(heightmap) = Self::bake(mesh, mesh_to_field, ground.resolution, images.as_mut()) else {
+                warn!("Ground mesh of corn field {} has no positions to bake", entity);
+                continue;
+            };
+            commands.entity(entity).insert((heightmap, BakedGroundMesh{mesh: handle.id(), mesh_to_field}));
+        }
+    }
+}
+
+/// The ground mesh a field's heightmap was baked from, and the mesh's transform in field space at the time
+#[derive(Debug, Clone, PartialEq, Component)]
+pub struct BakedGroundMesh{
+    pub mesh: AssetId<Mesh>,
+    pub mesh_to_field: Mat4
+}
+impl BakedGroundMesh{
+    /// Largest change in the ground's transform which doesn't bake it again
+    const EPSILON: f32 = 1e-4;
+}
+
+/// Tag component for corn fields which have had their heightmap applied
+#[derive(Default, Debug, Clone, PartialEq, Eq, Component)]
+#[component(storage="SparseSet")]
+pub struct HeightmapApplied;
+
+/// Holds the data required to apply a heightmap during the render pass
+#[derive(Debug, Component)]
+pub struct HeightmapInvocation{
+    pub bindgroup: BindGroup,
+    pub dispatch_count: u32,
+    pub finished: AtomicBool
+}
+impl HeightmapInvocation{
+    /// Creates invocations for gpu initialized fields once their corn and heightmap image are ready
+    fn create_invocations(
+        query: Query<
+            (Entity, &InstanceBuffer, Ref<CornHeightmap>, Has<HeightmapApplied>), 
+            (With<CornLoaded>, Without<InitialCornData>, Without<Self>)
+        >,
+        pipeline: Res<HeightmapPipelineResources>,
+        images: Res<RenderAssets<GpuImage>>,
+        render_device: Res<RenderDevice>,
+        mut commands: Commands
+    ){
+        for (entity, InstanceBuffer(instance, count), heightmap, applied) in query.iter(){
+            if applied && !heightmap.is_changed() {continue;}
+            let Some(image) = images.get(heightmap.image.id()) else {continue;};
+            let settings = render_device.create_buffer_with_data(&BufferInitDescriptor{
+                label: Some("Corn Heightmap Settings Buffer"),
+                usage: BufferUsages::UNIFORM,
+                contents: bytemuck::cast_slice(&[HeightmapSettings::from(heightmap.as_ref())])
+            });
+            let bindgroup = render_device.create_bind_group(
+                Some("Corn Heightmap Bind Group"),
+                &pipeline.layout,
+                &[
+                    BindGroupEntry{binding: 0, resource: instance.as_entire_binding()},
+                    BindGroupEntry{binding: 1, resource: settings.as_entire_binding()},
+                    BindGroupEntry{binding: 2, resource: BindingResource::TextureView(&image.texture_view)},
+                ]
+            );
+            commands.entity(entity).remove::<HeightmapApplied>().insert(Self{
+                bindgroup, dispatch_count: count.div_ceil(256) as u32, finished: AtomicBool::new(false)
+            });
+        }
+    }
+    /// Marks fields whose invocation has run as applied
+    fn cleanup_invocations(query: Query<(Entity, &Self)>, mut commands: Commands){
+        for (entity, invocation) in query.iter(){
+            if !invocation.finished.load(Ordering::Relaxed) {continue;}
+            commands.entity(entity).remove::<Self>().insert(HeightmapApplied);
+        }
+    }
+}
+
+/// Pipeline resources for the heightmap shader
+#[derive(Debug, Clone, Resource)]
+pub struct HeightmapPipelineResources{
+    pub layout: BindGroupLayout,
+    pub pipeline: CachedComputePipelineId,
+    pub shader: Handle<Shader>
+}
+impl FromWorld for HeightmapPipelineResources{
+    fn from_world(world: &mut World) -> Self {
+        let shader: Handle<Shader> = world.resource::<AssetServer>().load("shaders/corn/init/heightmap.wgsl");
+        let layout = world.resource::<RenderDevice>().create_bind_group_layout(
+            Some("Corn Heightmap BindGroup Layout"),
+            &[
+                BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer{
+                        ty: BufferBindingType::Storage{read_only: false},
+                        has_dynamic_offset: false,
+                        min_binding_size: None
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry{
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer{
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None
+                    },
+                    count: None
+                },
+                BindGroupLayoutEntry{
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture{
+                        sample_type: TextureSampleType::Float{filterable: false},
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false
+                    },
+                    count: None
+                }
+            ]
+        );
+        let pipeline = world.resource::<PipelineCache>().queue_compute_pipeline(ComputePipelineDescriptor{
+            label: Some("Corn Heightmap Pipeline".into()),
+            layout: vec![layout.clone()],
+            push_constant_ranges: vec![],
+            shader: shader.clone(),
+            shader_defs: vec![],
+            entry_point: "apply_heightmap".into(),
+            zero_initialize_workgroup_memory: false
+        });
+        Self{layout, pipeline, shader}
+    }
+}
+
+/// Render Graph Label for the heightmap pass
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, RenderLabel)]
+pub struct CornHeightmapStage;
+/// This is the render graph node which applies heightmaps to gpu initialized fields
+#[derive(Default, Debug, Clone)]
+struct CornHeightmapNode{
+    ready_entities: Vec<Entity>
+}
+impl bevy::render::render_graph::Node for CornHeightmapNode{
+    fn update(&mut self, world: &mut World) {
+        let mut query = world.query_filtered::<Entity, With<HeightmapInvocation>>();
+        self.ready_entities = query.iter(world).collect();
+    }
+    fn run(&self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext, world: &World) -> Result<(), NodeRunError>{
+        if self.ready_entities.is_empty() {return Ok(());}
+        let resources = world.resource::<HeightmapPipelineResources>();
+        let Some(pipeline) = world.resource::<PipelineCache>().get_compute_pipeline(resources.pipeline) else {return Ok(());};
+        let mut pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor{
+            label: Some("Corn Heightmap Pass"),
+            timestamp_writes: world.get_resource::<CornGpuTimings>().and_then(|timings| timings.compute_pass_writes(CornPass::Init))
+        });
+        pass.set_pipeline(pipeline);
+        for entity in self.ready_entities.iter(){
+            let Some(invocation) = world.get::<HeightmapInvocation>(*entity) else {continue;};
+            pass.set_bind_group(0, &invocation.bindgroup, &[]);
+            pass.dispatch_workgroups(invocation.dispatch_count, 1, 1);
+            invocation.finished.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Adds terrain following corn fields
+pub struct CornHeightmapPlugin;
+impl Plugin for CornHeightmapPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornHeightmap>()
+            .register_type::<CornGroundMesh>()
+            .add_plugins(ExtractComponentPlugin::<CornHeightmap>::default())
+            .add_systems(Update, CornGroundMesh::bake_ground_meshes)
+            .add_systems(PostUpdate, CornHeightmap::apply_cpu_heightmap.after(CornEdits::apply_edits))
+            .add_observer_as(CornHeightmap::reset_applied, CornFieldObserver)
+        .sub_app_mut(RenderApp)
+            .add_systems(Render, (
+                HeightmapInvocation::create_invocations.in_set(RenderSet::PrepareBindGroups),
+                HeightmapInvocation::cleanup_invocations.in_set(RenderSet::Cleanup)
+            ));
+        let mut graph = app.sub_app_mut(RenderApp).world_mut().resource_mut::<RenderGraph>();
+        graph.add_node(CornHeightmapStage, CornHeightmapNode::default());
+        // Heightmaps move the stalks the init shaders wrote
+        graph.add_node_edge(CornInitStage, CornHeightmapStage);
+        graph.add_node_edge(CornHeightmapStage, CameraDriverLabel);
+    }
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp).init_resource::<HeightmapPipelineResources>();
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use bevy::render::{render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension, TextureFormat}};
+    use super::*;
+
+    #[test]
+    fn empty_heightmaps_sample_finite_heights(){
+        let image = Image::new_fill(
+            Extent3d{width: 2, height: 2, depth_or_array_layers: 1}, TextureDimension::D2,
+            &[255; 4], TextureFormat::Rgba8Unorm, RenderAssetUsages::all()
+        );
+        let heightmap = CornHeightmap{height_range: Vec2::new(1.0, 3.0), ..default()};
+        assert_eq!(heightmap.sample(&image, Vec2::ZERO), 3.0);
+        assert!(HeightmapSettings::from(&heightmap).inv_size.is_finite());
+    }
+}
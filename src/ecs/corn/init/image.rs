@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+
+use bevy::{prelude::*, render::{
+    extract_component::ExtractComponent, render_asset::RenderAssets,
+    render_resource::*, renderer::RenderDevice, texture::GpuImage
+}};
+
+use crate::ecs::corn::{bounds::CornStalkBounds, shader::AsCornShader};
+use super::{shader::{AsCornInitShader, CornInitShaderAppExt}, simple::{SimpleHexagonalInitShader, SimpleInitShaderSettings}};
+
+/// Hexagonal corn field which is cut out using a black and white image.
+/// The image is stretched over the field with u along x and v along z.
+/// Corn is only enabled where the red channel of the image is at or above the threshold
+#[derive(Debug, Default, Clone, PartialEq, Reflect, Component, ExtractComponent)]
+#[reflect(Component)]
+pub struct ImageInitShader{
+    /// World Space center of the Corn Field
+    center: Vec3,
+    /// How far left and right the corn field extends.
+    half_extents: Vec2,
+    /// The minimum distance between adjacent pieces of corn
+    dist_between: f32,
+    /// The minimum and maximum height scalar
+    height_range: Vec2,
+    /// percentage of dist between of which corn can shift randomly
+    rand_offset_factor: f32,
+    /// Path image. Black is path, white is corn
+    image: Handle<Image>,
+    /// Red channel value below which corn is disabled
+    threshold: f32,
+    /// Seed for the random offsets, scales and rotations of the stalks
+    seed: u32
+}
+impl ImageInitShader{
+    /// Creates new Corn Field
+    pub fn new(
+        center: Vec3,
+        half_extents: Vec2,
+        seperation_distance: f32,
+        height_range: Vec2,
+        rand_offset: f32,
+        image: Handle<Image>,
+        threshold: f32,
+        seed: u32
+    ) -> Self{
+        Self{
+            center,
+            half_extents,
+            dist_between: seperation_distance,
+            height_range,
+            rand_offset_factor: rand_offset,
+            image,
+            threshold,
+            seed
+        }
+    }
+    /// Returns the hexagonal layout the image is applied to
+    pub fn get_layout(&self) -> SimpleHexagonalInitShader{
+        SimpleHexagonalInitShader::new(
+            self.center,
+            self.half_extents,
+            self.dist_between,
+            self.height_range,
+            self.rand_offset_factor,
+            self.seed
+        )
+    }
+    /// Returns the size of the area covered by corn stalks, which the image is stretched over
+    pub fn get_field_size(&self) -> Vec2{
+        let (width_res, height_res) = self.get_layout().get_resolution();
+        Vec2::new(
+            (width_res-1) as f32*self.dist_between,
+            (height_res-1) as f32*self.dist_between*3f32.sqrt()*0.5
+        )
+    }
+}
+impl AsCornShader for ImageInitShader{
+    fn load_shader(assets: &AssetServer) -> Handle<Shader> {
+        assets.load("shaders/corn/init/image_init.wgsl")
+    }
+
+    fn get_bindgroup_layout() -> Vec<BindGroupLayoutEntry> {
+        vec![
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D2,
+                    multisampled: false },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            }
+        ]
+    }
+
+    fn get_entry_point() -> impl Into<Cow<'static, str>> {
+        "simple_image_hex_init"
+    }
+
+    fn get_label() -> impl Into<Cow<'static, str>> {
+        "Corn Image Init Shader"
+    }
+}
+impl AsCornInitShader for ImageInitShader{
+    type Settings = Self;
+
+    fn get_instance_count(settings: &Self::Settings) -> u64 {
+        SimpleHexagonalInitShader::get_instance_count(&settings.get_layout())
+    }
+
+    fn get_settings_buffer(settings: &Self::Settings, render_device: &RenderDevice) -> Vec<Buffer> {
+        let settings_struct = SimpleInitShaderSettings::from(settings);
+        vec![render_device.create_buffer_with_data(&BufferInitDescriptor{
+            label: Some("Image Corn Init Settings Buffer"),
+            usage: BufferUsages::UNIFORM,
+            contents: bytemuck::cast_slice(&[settings_struct])
+        })]
+    }
+
+    fn get_settings_resources(settings: &Self::Settings, images: &RenderAssets<GpuImage>) -> Option<Vec<OwnedBindingResource>> {
+        let image = images.get(settings.image.id())?;
+        Some(vec![
+            OwnedBindingResource::TextureView(image.texture_view.clone()),
+            OwnedBindingResource::Sampler(image.sampler.clone())
+        ])
+    }
+
+    fn get_invocation_count(settings: &Self::Settings) -> UVec3 {
+        let count = Self::get_instance_count(settings);
+        UVec3::new(count.div_ceil(256) as u32, 1, 1)
+    }
+
+    fn get_stalk_bounds(settings: &Self::Settings) -> Option<CornStalkBounds> {
+        Some(SimpleInitShaderSettings::from(settings).stalk_bounds(settings.get_layout().get_last_cell()))
+    }
+}
+impl From<&ImageInitShader> for SimpleInitShaderSettings{
+    fn from(value: &ImageInitShader) -> Self {
+        let mut output = Self::from(&value.get_layout());
+        let size = value.get_field_size();
+        let uv_scale = Vec2::new(
+            if size.x > 0.0 {1.0/size.x} else {0.0},
+            if size.y > 0.0 {1.0/size.y} else {0.0}
+        );
+        output.random_settings = Vec4::new(output.random_settings.x, uv_scale.x, uv_scale.y, value.threshold);
+        output
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct ImageInitPlugin;
+impl Plugin for ImageInitPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<ImageInitShader>()
+            .register_init_shader::<ImageInitShader>();
+    }
+}
@@ -1,11 +1,12 @@
 use std::sync::atomic::{AtomicBool, Ordering};
 use bevy::{prelude::*, render::{
     extract_component::{ExtractComponent, ExtractComponentPlugin}, 
-    render_graph::*, render_resource::*, 
-    renderer::{RenderContext, RenderDevice}, 
+    render_asset::RenderAssets, render_graph::*, render_resource::*, 
+    renderer::{RenderContext, RenderDevice}, texture::GpuImage, 
     Render, RenderApp, RenderSet
 }};
-use crate::ecs::corn::{shader::*, CornField, CornLoaded, InstanceBuffer};
+use crate::ecs::corn::{bounds::CornStalkBounds, diagnostics::{CornGpuTimings, CornPass}, shader::*, CornField, CornLoaded, InstanceBuffer};
+use super::InitialCornData;
 
 /// Component for corn fields which holds the invocation entity which will create their instance buffer
 #[derive(Debug, Clone, PartialEq, Eq, Reflect, Component)]
@@ -22,6 +23,11 @@ pub struct InitShaderInvocation(pub Entity);
 #[component(storage="SparseSet")]
 pub struct InitSettingsBuffers(pub Vec<Buffer>);
 
+/// Component for Init Invocations containing any non buffer resources, bound after the settings buffers
+#[derive(Default, Debug, Component)]
+#[component(storage="SparseSet")]
+pub struct InitSettingsResources(pub Vec<OwnedBindingResource>);
+
 /// Final component added to invocations, holds the data required to invoke during the render pass
 #[derive(Debug, Component)]
 pub struct InitInvocationSettings{
@@ -37,7 +43,7 @@ pub struct CornInitShader;
 
 /// Render Graph Label for Init Operations
 #[derive(Debug, Clone, Default, Hash, PartialEq, Eq, RenderLabel)]
-struct CornInitStage;
+pub struct CornInitStage;
 /// This is the render graph node which executes init shaders
 #[derive(Default, Debug, Clone)]
 struct CornInitNode{
@@ -63,7 +69,8 @@ impl bevy::render::render_graph::Node for CornInitNode{
         if self.ready_shaders.is_empty() {return Ok(());}
         // Start compute pass
         let mut pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor{
-            label: Some("Corn Init Pass".into()), timestamp_writes: None
+            label: Some("Corn Init Pass".into()),
+            timestamp_writes: world.get_resource::<CornGpuTimings>().and_then(|timings| timings.compute_pass_writes(CornPass::Init))
         });
 
         for (pipeline, children) in self.ready_shaders.iter(){
@@ -94,10 +101,14 @@ pub trait AsCornInitShader: Component+Sized+AsCornShader{
     fn get_push_constants(_settings: &Self::Settings) -> Vec<u8> {vec![]}
     /// Function which converts a settings component into a collection of settings buffers
     fn get_settings_buffer(settings: &Self::Settings, render_device: &RenderDevice) -> Vec<Buffer>;
+    /// Returns the textures and samplers bound after the settings buffers. Returns None if they aren't ready yet
+    fn get_settings_resources(_settings: &Self::Settings, _images: &RenderAssets<GpuImage>) -> Option<Vec<OwnedBindingResource>> {Some(vec![])}
+    /// Returns the field space bounds of the stalks an invocation will make. Fields without bounds are never culled as a whole
+    fn get_stalk_bounds(_settings: &Self::Settings) -> Option<CornStalkBounds> {None}
 }
 
 pub fn create_invocation_entities<S: AsCornInitShader>(
-    query: Query<(Entity, &S::Settings), (Without<CornLoaded>, Without<WaitingOnInvocation>, With<CornField>)>,
+    query: Query<(Entity, &S::Settings), (Without<CornLoaded>, Without<WaitingOnInvocation>, Without<InitialCornData>, With<CornField>)>,
     shader: Query<Entity, (With<CornInitShader>, With<S>)>,
     mut commands: Commands
 ){
@@ -138,18 +149,32 @@ pub fn create_settings_buffers<S: AsCornInitShader>(
     }
 } 
 
+pub fn create_settings_resources<S: AsCornInitShader>(
+    query: Query<(Entity, &S::Settings), (With<InitShaderInvocation>, Without<InitSettingsResources>)>,
+    images: Res<RenderAssets<GpuImage>>,
+    mut commands: Commands
+){
+    for (entity, settings) in query.iter(){
+        let Some(resources) = S::get_settings_resources(settings, images.as_ref()) else {continue;};
+        commands.entity(entity).insert(InitSettingsResources(resources));
+    }
+}
+
 pub fn create_invocation_settings<S: AsCornInitShader>(
-    query: Query<(Entity, &S::Settings, &InstanceBuffer, &InitSettingsBuffers), (With<InitShaderInvocation>, Without<InitInvocationSettings>)>,
+    query: Query<(Entity, &S::Settings, &InstanceBuffer, &InitSettingsBuffers, &InitSettingsResources), (With<InitShaderInvocation>, Without<InitInvocationSettings>)>,
     shader: Query<&ShaderPipelineResources, (With<CornInitShader>, With<S>)>,
     render_device: Res<RenderDevice>,
     mut commands: Commands
 ){
     let layout = &shader.single().layout;
-    for (entity, settings, instance, buffers) in query.iter(){
+    for (entity, settings, instance, buffers, resources) in query.iter(){
         let mut entries = vec![BindGroupEntry{binding: 0, resource: instance.0.as_entire_binding()}];
         for (i, buffer) in buffers.0.iter().enumerate(){
             entries.push(BindGroupEntry { binding: (i+1) as u32, resource: buffer.as_entire_binding() });
         }
+        for (i, resource) in resources.0.iter().enumerate(){
+            entries.push(BindGroupEntry { binding: (i+1+buffers.0.len()) as u32, resource: resource.get_binding() });
+        }
         let bindgroup = render_device.create_bind_group(
             Some((S::get_label().into().to_string() + " Bind Group").as_str()), 
             layout,
@@ -164,18 +189,23 @@ pub fn create_invocation_settings<S: AsCornInitShader>(
 }
 
 pub fn cleanup_invocations(
-    invocations: Query<(Entity, &InitInvocationSettings), With<InitShaderInvocation>>,
+    invocations: Query<(Entity, &InitShaderInvocation, Option<&InitInvocationSettings>)>,
+    waiting: Query<(), With<WaitingOnInvocation>>,
     mut commands: Commands
 ){
-    for (entity, invocation) in invocations.iter(){
-        if !invocation.finished.load(Ordering::Relaxed) {continue;}
+    for (entity, InitShaderInvocation(field), invocation) in invocations.iter(){
+        // Fields can be despawned while their invocation is pending, like streamed tiles leaving the residency radius
+        if !waiting.contains(*field) {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        if !invocation.is_some_and(|invocation| invocation.finished.load(Ordering::Relaxed)) {continue;}
         // Move instance buffer over to the corn field, and set corn field as loaded. Finally despawn invocation
         commands.entity(entity).queue(|mut entity: EntityWorldMut|{
             let Some(InitShaderInvocation(field)) = entity.take::<InitShaderInvocation>() else {return;};
             let Some(buffer) = entity.take::<InstanceBuffer>() else {return;};
-            entity.into_world_mut().entity_mut(field)
-                .remove::<WaitingOnInvocation>()
-                .insert((buffer, CornLoaded));
+            let Ok(mut field) = entity.into_world_mut().get_entity_mut(field) else {return;};
+            field.remove::<WaitingOnInvocation>().insert((buffer, CornLoaded));
         });
         commands.entity(entity).despawn_recursive();
     }
@@ -197,11 +227,12 @@ impl CornInitShaderAppExt for App{
         // Schedule Systems
         self.sub_app_mut(RenderApp).add_systems(Render, (
             create_invocation_entities::<S>.before(RenderSet::PrepareResources).after(RenderSet::ExtractCommands), 
-            (create_instance_buffers::<S>, create_settings_buffers::<S>).in_set(RenderSet::PrepareResources), 
+            (create_instance_buffers::<S>, create_settings_buffers::<S>, create_settings_resources::<S>).in_set(RenderSet::PrepareResources), 
             create_invocation_settings::<S>.in_set(RenderSet::PrepareBindGroups)
         ));
         // Add extract plugins
         self.add_plugins(ExtractComponentPlugin::<S::Settings>::default());
+        self.add_systems(Update, CornStalkBounds::from_init_settings::<S>);
         self
     }
 }
@@ -3,18 +3,33 @@ use std::borrow::Cow;
 use bevy::{prelude::*, render::{extract_component::ExtractComponent, render_resource::*, renderer::RenderDevice}};
 use bytemuck::{Pod, Zeroable};
 
-use crate::ecs::corn::shader::AsCornShader;
-use super::shader::{AsCornInitShader, CornInitShaderAppExt};
+use crate::ecs::corn::{bounds::CornStalkBounds, shader::AsCornShader, stream::TiledCornInit};
+use super::{cpu::CornRng, shader::{AsCornInitShader, CornInitShaderAppExt}};
 
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C)]
 pub struct SimpleInitShaderSettings{
-    origin: Vec3,
-    resolution_width: u32,
-    height_range: f32,
-    minimum_height: f32,
-    step_size: Vec2,
-    random_settings: Vec4
+    pub origin: Vec3,
+    pub resolution_width: u32,
+    pub height_range: f32,
+    pub minimum_height: f32,
+    pub step_size: Vec2,
+    pub random_settings: Vec4,
+    pub seed: u32,
+    pub padding: [u32; 3]
+}
+impl SimpleInitShaderSettings{
+    /// Bounds of the stalks on a grid whose farthest cell is `last_cell`.
+    /// Covers the random offsets of every simple kernel, which can also shift stalks by up to one unit
+    pub fn stalk_bounds(&self, last_cell: Vec2) -> CornStalkBounds{
+        let jitter = Vec3::splat(self.random_settings.x.abs()*2.0 + 1.0);
+        let far = self.origin + Vec3::new(last_cell.x*self.step_size.x, 0.0, last_cell.y*self.step_size.y);
+        CornStalkBounds{
+            min: self.origin.min(far) - jitter,
+            max: self.origin.max(far) + jitter,
+            max_scale: self.minimum_height + self.height_range.max(0.0)
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Reflect, Component, ExtractComponent)]
@@ -29,18 +44,21 @@ pub struct SimpleInitShader{
     /// Min and Max height scalars
     height_range: Vec2,
     /// How much the corn can shift as a percentage of the distance between the corn normally
-    rand_offset_factor: f32
+    rand_offset_factor: f32,
+    /// Seed for the random offsets, scales and rotations of the stalks
+    seed: u32
 }
 impl SimpleInitShader{
     /// Returns new Corn Field
-    pub fn new(center: Vec3, half_extents: Vec2, resolution: UVec2, height_range: Vec2, rand_offset: f32) -> Self{
+    pub fn new(center: Vec3, half_extents: Vec2, resolution: UVec2, height_range: Vec2, rand_offset: f32, seed: u32) -> Self{
         assert!(resolution != UVec2::ZERO, "Tried to create empty corn field!");
         Self{
             center, 
             half_extents, 
             resolution,
             height_range,
-            rand_offset_factor: rand_offset
+            rand_offset_factor: rand_offset,
+            seed
         }
     }
     /// Returns the origin of the corn field, bottom left corner
@@ -120,6 +138,51 @@ impl AsCornInitShader for SimpleInitShader{
         let count = Self::get_instance_count(settings);
         UVec3::new(count.div_ceil(256) as u32, 1, 1)
     }
+
+    fn get_stalk_bounds(settings: &Self::Settings) -> Option<CornStalkBounds> {
+        Some(SimpleInitShaderSettings::from(settings).stalk_bounds(settings.resolution.saturating_sub(UVec2::ONE).as_vec2()))
+    }
+}
+impl TiledCornInit for SimpleInitShader{
+    fn tile_grid(&self, tile_size: f32) -> UVec2 {
+        let step = self.get_step();
+        let cells = UVec2::new(
+            if step.x > 0.0 {(tile_size/step.x).round().max(1.0) as u32} else {self.resolution.x},
+            if step.y > 0.0 {(tile_size/step.y).round().max(1.0) as u32} else {self.resolution.y}
+        );
+        (self.resolution + cells - 1)/cells
+    }
+
+    fn tile(&self, tile: UVec2, grid: UVec2) -> (Self, Rect) {
+        let cells = (self.resolution + grid - 1)/grid;
+        let start = tile*cells;
+        let resolution = (start + cells).min(self.resolution) - start;
+        let step = self.get_step();
+        let origin = self.get_origin().xz() + start.as_vec2()*step;
+        let half_extents = (resolution - 1).as_vec2()*step*0.5;
+        let center = origin + half_extents;
+        (
+            Self{
+                center: Vec3::new(center.x, self.center.y, center.y),
+                half_extents,
+                resolution,
+                // Tiles index their stalks from zero, so each needs its own sequence
+                seed: CornRng::seeded(self.seed, tile.y*grid.x + tile.x).next_u32(),
+                ..*self
+            },
+            Rect::from_corners(origin - step*0.5, origin + half_extents*2.0 + step*0.5)
+        )
+    }
+
+    fn tile_range(&self, grid: UVec2, area: Rect) -> (UVec2, UVec2) {
+        let cells = (self.resolution + grid - 1)/grid;
+        let step = self.get_step();
+        let start = self.get_origin().xz() - step*0.5;
+        let size = cells.as_vec2()*step;
+        // A single row or column has no step, and a grid one tile across, so its tile is always picked
+        let tile = |point: Vec2| ((point - start)/size).floor().max(Vec2::ZERO).min((grid - 1).as_vec2()).as_uvec2();
+        (tile(area.min), tile(area.max))
+    }
 }
 impl From<&SimpleInitShader> for SimpleInitShaderSettings{
     fn from(value: &SimpleInitShader) -> Self {
@@ -129,7 +192,9 @@ impl From<&SimpleInitShader> for SimpleInitShaderSettings{
             minimum_height: value.height_range.x,
             step_size: value.get_step(),
             resolution_width: value.resolution.x,
-            random_settings: value.get_random_offset_range().extend(0.0).extend(0.0)
+            random_settings: value.get_random_offset_range().extend(0.0).extend(0.0),
+            seed: value.seed,
+            padding: [0; 3]
          }
     }
 }
@@ -146,17 +211,20 @@ pub struct SimpleHexagonalInitShader{
     /// The minimum and maximum height scalar
     height_range: Vec2,
     /// percentage of dist between of which corn can shift randomly
-    rand_offset_factor: f32
+    rand_offset_factor: f32,
+    /// Seed for the random offsets, scales and rotations of the stalks
+    seed: u32
 }
 impl SimpleHexagonalInitShader{
     /// Creates new Corn Field
-    pub fn new(center: Vec3, half_extents: Vec2, seperation_distance: f32, height_range: Vec2, rand_offset: f32) -> Self{
+    pub fn new(center: Vec3, half_extents: Vec2, seperation_distance: f32, height_range: Vec2, rand_offset: f32, seed: u32) -> Self{
         Self{
             center, 
             half_extents, 
             dist_between: seperation_distance,
             height_range,
-            rand_offset_factor: rand_offset
+            rand_offset_factor: rand_offset,
+            seed
         }
     }
     /// Returns the resolution of the corn field in (# corn across) x (# corn down)
@@ -187,6 +255,11 @@ impl SimpleHexagonalInitShader{
     pub fn get_random_offset_range(&self) -> f32{
         return self.dist_between*self.rand_offset_factor;
     }
+    /// Returns the farthest spot on the corn field grid
+    pub fn get_last_cell(&self) -> Vec2{
+        let (width_res, height_res) = self.get_resolution();
+        Vec2::new(((width_res - 1)*2) as f32, (height_res - 1) as f32)
+    }
 }
 impl AsCornShader for SimpleHexagonalInitShader{
     fn load_shader(assets: &AssetServer) -> Handle<Shader> {
@@ -242,9 +315,12 @@ impl AsCornInitShader for SimpleHexagonalInitShader{
     }
     
     fn get_invocation_count(settings: &Self::Settings) -> UVec3 {
-        todo!();
-        let (width, height) = settings.get_resolution();
-        UVec3::new(width.div_ceil(16) as u32, height.div_ceil(16) as u32, 1)
+        let count = Self::get_instance_count(settings);
+        UVec3::new(count.div_ceil(256) as u32, 1, 1)
+    }
+
+    fn get_stalk_bounds(settings: &Self::Settings) -> Option<CornStalkBounds> {
+        Some(SimpleInitShaderSettings::from(settings).stalk_bounds(settings.get_last_cell()))
     }
 }
 impl From<&SimpleHexagonalInitShader> for SimpleInitShaderSettings{
@@ -255,7 +331,9 @@ impl From<&SimpleHexagonalInitShader> for SimpleInitShaderSettings{
             minimum_height: value.height_range.x,
             step_size: value.get_step(),
             resolution_width: (value.get_resolution().0*2-1) as u32,
-            random_settings: Vec4::new(value.get_random_offset_range(), 0.0, 0.0, 0.0)
+            random_settings: Vec4::new(value.get_random_offset_range(), 0.0, 0.0, 0.0),
+            seed: value.seed,
+            padding: [0; 3]
          };
          if !output.step_size.x.is_finite() || output.step_size.x.is_nan(){
             output.origin.x = value.center.x;
@@ -280,3 +358,27 @@ impl Plugin for SimpleInitPlugin{
             .register_init_shader::<SimpleHexagonalInitShader>();
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use crate::ecs::corn::stream::TiledCornInit;
+    use super::*;
+
+    #[test]
+    fn tile_range_covers_overlapping_tiles(){
+        for resolution in [UVec2::new(100, 37), UVec2::new(1, 20)]{
+            let shader = SimpleInitShader::new(Vec3::new(3.0, 0.0, -2.0), Vec2::new(50.0, 20.0), resolution, Vec2::ONE, 0.0, 0);
+            let grid = shader.tile_grid(9.0);
+            for area in [Rect::from_center_half_size(Vec2::ZERO, Vec2::splat(12.0)), Rect::new(-100.0, -100.0, -60.0, 100.0), Rect::new(40.0, 10.0, 200.0, 200.0)]{
+                let (first, last) = shader.tile_range(grid, area);
+                for y in 0..grid.y{
+                    for x in 0..grid.x{
+                        let (_, bounds) = shader.tile(UVec2::new(x, y), grid);
+                        if bounds.intersect(area).is_empty() {continue;}
+                        assert!(x >= first.x && x <= last.x && y >= first.y && y <= last.y, "tile {x}x{y} of {grid} missing from {first}..{last}");
+                    }
+                }
+            }
+        }
+    }
+}
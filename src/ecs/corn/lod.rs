@@ -0,0 +1,285 @@
+//! Automatic lod cutoffs. The geometric error between consecutive lods of a corn model is measured when it loads,
+//! and each cutoff is placed at the distance where that error projects to less than `AutoLodCutoffs::pixel_threshold` pixels on screen.
+//!
+//! The error is measured on the corn model, so it is scaled up by the largest stalk scale of each field, and by the field's transform.
+//! The default model's `GlobalLodCutoffs` use the largest scale of every field drawn with it.
+//!
+//! Models with cutoffs in their `.cornmodel` file, and fields with their own `PerFieldLodCutoffs`, keep them.
+use bevy::{prelude::*, render::mesh::{Indices, VertexAttributeValues}, utils::HashSet};
+use super::{asset::{CornFieldModel, CornModel, CornModelAsset}, bounds::CornStalkBounds, scan_prepass::vote::PerFieldLodCutoffs, CornField, GlobalLodCutoffs};
+
+/// Triangles of a single lod of a merged corn model mesh, given its (# of vtcs, start vtx)
+pub fn lod_triangles(mesh: &Mesh, (count, start): (usize, usize)) -> Vec<[Vec3; 3]>{
+    let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {return vec![];};
+    let indices: Vec<usize> = match mesh.indices(){
+        Some(Indices::U16(indices)) => indices.iter().skip(start).take(count).map(|i| *i as usize).collect(),
+        Some(Indices::U32(indices)) => indices.iter().skip(start).take(count).map(|i| *i as usize).collect(),
+        None => return vec![]
+    };
+    indices.chunks_exact(3).filter_map(|triangle| Some([
+        Vec3::from(*positions.get(triangle[0])?),
+        Vec3::from(*positions.get(triangle[1])?),
+        Vec3::from(*positions.get(triangle[2])?)
+    ])).collect()
+}
+
+/// Closest point to `p` on the triangle `abc`
+fn closest_point(p: Vec3, [a, b, c]: [Vec3; 3]) -> Vec3{
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0.0 && d2 <= 0.0 {return a;}
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0.0 && d4 <= d3 {return b;}
+    let vc = d1*d4 - d3*d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {return a + ab*(d1/(d1 - d3));}
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0.0 && d5 <= d6 {return c;}
+    let vb = d5*d2 - d1*d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {return a + ac*(d2/(d2 - d6));}
+    let va = d3*d6 - d5*d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {return b + (c - b)*((d4 - d3)/((d4 - d3) + (d5 - d6)));}
+    let denom = 1.0/(va + vb + vc).max(f32::EPSILON);
+    a + ab*(vb*denom) + ac*(vc*denom)
+}
+
+/// Uniform grid of triangles, for finding the closest triangle to a point without checking every triangle
+struct TriangleGrid<'a>{
+    triangles: &'a [[Vec3; 3]],
+    min: Vec3,
+    cell_size: f32,
+    size: IVec3,
+    cells: Vec<Vec<u32>>
+}
+impl<'a> TriangleGrid<'a>{
+    /// Cells along the longest side of the grid
+    const RESOLUTION: f32 = 32.0;
+
+    fn new(triangles: &'a [[Vec3; 3]]) -> Self{
+        let min = triangles.iter().flatten().fold(Vec3::INFINITY, |a, b| a.min(*b));
+        let max = triangles.iter().flatten().fold(Vec3::NEG_INFINITY, |a, b| a.max(*b));
+        let cell_size = ((max - min).max_element()/Self::RESOLUTION).max(f32::EPSILON);
+        let size = ((max - min)/cell_size).floor().as_ivec3() + 1;
+        let mut cells = vec![vec![]; (size.x*size.y*size.z) as usize];
+        for (i, triangle) in triangles.iter().enumerate(){
+            let low = ((triangle[0].min(triangle[1]).min(triangle[2]) - min)/cell_size).floor().as_ivec3().clamp(IVec3::ZERO, size - 1);
+            let high = ((triangle[0].max(triangle[1]).max(triangle[2]) - min)/cell_size).floor().as_ivec3().clamp(IVec3::ZERO, size - 1);
+            for z in low.z..=high.z { for y in low.y..=high.y { for x in low.x..=high.x {
+                cells[(x + size.x*(y + size.y*z)) as usize].push(i as u32);
+            }}}
+        }
+        Self{triangles, min, cell_size, size, cells}
+    }
+
+    /// Distance from `p` to the closest triangle. Searches shells of cells outward until no closer triangle is possible
+    fn distance(&self, p: Vec3) -> f32{
+        let center = ((p - self.min)/self.cell_size).floor().as_ivec3();
+        // Distance from the point to the grid, no cell can be closer than this
+        let outside = (self.min - p).max(p - (self.min + self.size.as_vec3()*self.cell_size)).max(Vec3::ZERO).length();
+        let mut best = f32::INFINITY;
+        for ring in 0..=self.size.max_element() + center.abs().max_element(){
+            // Every cell in this ring is at least this far away
+            if best < outside.max((ring - 1).max(0) as f32*self.cell_size) {break;}
+            let low = (center - ring).max(IVec3::ZERO);
+            let high = (center + ring).min(self.size - 1);
+            for z in low.z..=high.z { for y in low.y..=high.y { for x in low.x..=high.x {
+                // Only the shell of the ring, inner cells were checked already
+                if (IVec3::new(x, y, z) - center).abs().max_element() != ring {continue;}
+                for i in self.cells[(x + self.size.x*(y + self.size.y*z)) as usize].iter(){
+                    best = best.min(p.distance(closest_point(p, self.triangles[*i as usize])));
+                }
+            }}}
+        }
+        best
+    }
+
+    /// Largest distance from the vertices and triangle centers of `triangles` to this grid's surface
+    fn max_distance(&self, triangles: &[[Vec3; 3]]) -> f32{
+        triangles.iter()
+            .flat_map(|[a, b, c]| [*a, *b, *c, (*a + *b + *c)/3.0])
+            .map(|p| self.distance(p))
+            .fold(0.0, f32::max)
+    }
+}
+
+/// Geometric error between two lods. The largest distance from a point on either surface to the other, measured at vertices and triangle centers
+pub fn geometric_error(a: &[[Vec3; 3]], b: &[[Vec3; 3]]) -> f32{
+    if a.is_empty() || b.is_empty() {return lod_extent(a).max(lod_extent(b));}
+    TriangleGrid::new(b).max_distance(a).max(TriangleGrid::new(a).max_distance(b))
+}
+
+/// Size of a lod, the longest side of its bounding box. Used as the error of culling a stalk past its last lod
+pub fn lod_extent(triangles: &[[Vec3; 3]]) -> f32{
+    if triangles.is_empty() {return 0.0;}
+    let min = triangles.iter().flatten().fold(Vec3::INFINITY, |a, b| a.min(*b));
+    let max = triangles.iter().flatten().fold(Vec3::NEG_INFINITY, |a, b| a.max(*b));
+    (max - min).max_element()
+}
+
+/// Error of switching from each lod of a merged corn model to the next, the worst of all variants. The last lod's error is the error of culling the stalk
+pub fn lod_errors(mesh: &Mesh, lod_info: &[Vec<(usize, usize)>]) -> Vec<f32>{
+    let lod_count = lod_info.first().map_or(0, Vec::len);
+    let mut errors = vec![0.0; lod_count];
+    for variant in lod_info.iter(){
+        let lods: Vec<Vec<[Vec3; 3]>> = variant.iter().map(|lod| lod_triangles(mesh, *lod)).collect();
+        for (i, lod) in lods.iter().enumerate(){
+            let error = match lods.get(i+1){
+                // Padded lods are the same lod, and switching between them costs nothing
+                Some(_) if variant[i] == variant[i+1] => 0.0,
+                Some(next) => geometric_error(lod, next),
+                None => lod_extent(lod)
+            };
+            errors[i] = f32::max(errors[i], error);
+        }
+    }
+    errors
+}
+
+/// Settings for computing lod cutoffs from the geometric error of corn model lods
+#[derive(Debug, Clone, PartialEq, Reflect, Resource)]
+#[reflect(Resource)]
+pub struct AutoLodCutoffs{
+    /// Largest error, in pixels, allowed when switching to a lower lod
+    pub pixel_threshold: f32,
+    /// Vertical field of view the cutoffs are computed for, in radians
+    pub fov: f32,
+    /// Vertical resolution the cutoffs are computed for, in pixels
+    pub resolution: u32
+}
+impl Default for AutoLodCutoffs{
+    fn default() -> Self {
+        Self{pixel_threshold: 1.0, fov: std::f32::consts::FRAC_PI_4, resolution: 1080}
+    }
+}
+impl AutoLodCutoffs{
+    /// Distance at which a geometric error of `error` projects to `pixel_threshold` pixels
+    pub fn distance(&self, error: f32) -> f32{
+        error*self.resolution as f32/(2.0*self.pixel_threshold.max(f32::EPSILON)*(self.fov*0.5).tan())
+    }
+
+    /// Cutoffs for a model with the given lod errors, drawn `scale` times larger than the model. See `AutoLodCutoffs::field_scale`.
+    /// Cutoffs never decrease, a lod with less error than the one before it is skipped
+    pub fn cutoffs(&self, errors: &[f32], scale: f32) -> Vec<f32>{
+        errors.iter().scan(0.0, |cutoff: &mut f32, error| {
+            *cutoff = cutoff.max(self.distance(*error*scale));
+            Some(*cutoff)
+        }).collect()
+    }
+
+    /// How much larger a field's stalks are drawn than its corn model, relative to the field space distances cutoffs are compared against.
+    /// The largest stalk scale, times how far the field's transform stretches the model beyond its xz plane
+    pub fn field_scale(bounds: Option<&CornStalkBounds>, transform: &GlobalTransform) -> f32{
+        let scale = transform.scale().abs();
+        // Stalks without bounds yet are assumed to be unscaled
+        bounds.map_or(1.0, |bounds| bounds.max_scale.max(0.0))*scale.max_element()/scale.xz().min_element().max(f32::EPSILON)
+    }
+
+    /// Writes automatic cutoffs for the default corn model to `GlobalLodCutoffs`, and for every other model to its fields' `PerFieldLodCutoffs`
+    #[allow(clippy::too_many_arguments)]
+    fn apply_cutoffs(
+        settings: Res<Self>,
+        default_model: Res<CornModel>,
+        models: Res<Assets<CornModelAsset>>,
+        mut events: EventReader<AssetEvent<CornModelAsset>>,
+        mut global: ResMut<GlobalLodCutoffs>,
+        fields: Query<(
+            Entity, Ref<CornFieldModel>, Has<PerFieldLodCutoffs>, Has<AutoLodCutoffsApplied>, Option<Ref<CornStalkBounds>>, Ref<GlobalTransform>
+        ), With<CornField>>,
+        mut default_scale: Local<f32>,
+        mut commands: Commands
+    ){
+        let loaded: HashSet<AssetId<CornModelAsset>> = events.read().filter_map(|event| match event{
+            AssetEvent::LoadedWithDependencies{id} | AssetEvent::Modified{id} => Some(*id),
+            _ => None
+        }).collect();
+        let all = settings.is_changed() || default_model.is_changed();
+        let scale = fields.iter()
+            .filter(|(_, field_model, ..)| field_model.0 == default_model.0)
+            .map(|(.., bounds, transform)| Self::field_scale(bounds.as_deref(), &transform))
+            .reduce(f32::max)
+            .unwrap_or(1.0);
+        if all || loaded.contains(&default_model.0.id()) || scale != *default_scale{
+            if let Some(model) = models.get(&default_model.0).filter(|model| model.lod_cutoffs.is_none()){
+                global.0 = settings.cutoffs(&model.lod_errors, scale);
+                *default_scale = scale;
+            }
+        }
+        for (entity, field_model, has_cutoffs, applied, bounds, transform) in fields.iter(){
+            if has_cutoffs && !applied {continue;}
+            // Fields using the default model follow the global cutoffs
+            if field_model.0 == default_model.0 {
+                if applied {commands.entity(entity).remove::<(PerFieldLodCutoffs, AutoLodCutoffsApplied)>();}
+                continue;
+            }
+            let rescaled = bounds.as_ref().is_some_and(Ref::is_changed) || transform.is_changed();
+            if !all && !rescaled && !field_model.is_changed() && !loaded.contains(&field_model.0.id()) {continue;}
+            // Cutoffs from the model's file are attached along with the model
+            let Some(model) = models.get(&field_model.0).filter(|model| model.lod_cutoffs.is_none()) else {continue;};
+            let cutoffs = settings.cutoffs(&model.lod_errors, Self::field_scale(bounds.as_deref(), &transform));
+            commands.entity(entity).insert((PerFieldLodCutoffs::Custom(cutoffs), AutoLodCutoffsApplied));
+        }
+    }
+}
+
+/// Marker for corn fields whose `PerFieldLodCutoffs` were computed by `AutoLodCutoffs`, and should be recomputed when it changes
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Component)]
+#[component(storage = "SparseSet")]
+pub struct AutoLodCutoffsApplied;
+
+/// Adds automatic lod cutoffs
+pub struct CornLodPlugin;
+impl Plugin for CornLodPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<AutoLodCutoffs>()
+            .init_resource::<AutoLodCutoffs>()
+            .add_systems(Update, AutoLodCutoffs::apply_cutoffs.run_if(resource_exists::<CornModel>));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const TRIANGLE: [Vec3; 3] = [Vec3::ZERO, Vec3::X, Vec3::Z];
+
+    fn shifted(offset: Vec3) -> [Vec3; 3]{
+        TRIANGLE.map(|p| p + offset)
+    }
+
+    #[test]
+    fn geometric_error_is_the_largest_distance_between_lods(){
+        assert_eq!(geometric_error(&[TRIANGLE], &[TRIANGLE]), 0.0);
+        assert!((geometric_error(&[TRIANGLE], &[shifted(Vec3::Y*0.5)]) - 0.5).abs() < 1e-5);
+        // Measured both ways, a lod covering more than the other still has error
+        assert!((geometric_error(&[TRIANGLE, shifted(Vec3::X*3.0)], &[TRIANGLE]) - 3.0).abs() < 1e-5);
+        // Nothing to compare against, the error is the size of the lod
+        assert_eq!(geometric_error(&[shifted(Vec3::ZERO)], &[]), 1.0);
+    }
+
+    #[test]
+    fn distance_projects_the_error_to_the_pixel_threshold(){
+        let settings = AutoLodCutoffs{pixel_threshold: 2.0, fov: std::f32::consts::FRAC_PI_2, resolution: 1000};
+        // 1 unit covers 500 pixels at a distance of 1
+        assert!((settings.distance(0.1) - 25.0).abs() < 1e-3);
+        assert_eq!(settings.distance(0.0), 0.0);
+    }
+
+    #[test]
+    fn cutoffs_scale_with_the_stalks_and_never_decrease(){
+        let settings = AutoLodCutoffs{pixel_threshold: 1.0, fov: std::f32::consts::FRAC_PI_2, resolution: 2};
+        assert_eq!(settings.cutoffs(&[1.0, 0.5, 3.0], 1.0), [1.0, 1.0, 3.0]);
+        assert_eq!(settings.cutoffs(&[1.0, 0.5, 3.0], 2.0), [2.0, 2.0, 6.0]);
+    }
+
+    #[test]
+    fn field_scale_covers_stalk_and_transform_scale(){
+        let bounds = CornStalkBounds{max_scale: 2.0, ..default()};
+        assert_eq!(AutoLodCutoffs::field_scale(None, &GlobalTransform::IDENTITY), 1.0);
+        assert_eq!(AutoLodCutoffs::field_scale(Some(&bounds), &GlobalTransform::IDENTITY), 2.0);
+        // Distances are in field space, so only stretching the stalks past the xz plane adds error
+        assert_eq!(AutoLodCutoffs::field_scale(Some(&bounds), &GlobalTransform::from_scale(Vec3::splat(3.0))), 2.0);
+        assert_eq!(AutoLodCutoffs::field_scale(Some(&bounds), &GlobalTransform::from_scale(Vec3::new(1.0, 4.0, 2.0))), 8.0);
+    }
+}
@@ -0,0 +1,399 @@
+//! Procedural mazes carved into corn fields.
+//! Algorithms follow "Mazes for Programmers" (Jamis Buck): recursive backtracker, Wilson's, and braiding to remove dead ends.
+//!
+//! The maze is a grid of cells laid out on the xz plane of the corn field.
+//! Each cell has a corridor in its center, extended toward every neighbor it is linked to. Corn inside a corridor is disabled.
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::util::observer_ext::ObserveAsAppExt;
+use super::{
+    init::{cpu::CpuCornInit, image::ImageInitShader, simple::{SimpleHexagonalInitShader, SimpleInitShader}, InitialCornData},
+    path::ClearedByPaths, CornData, CornFieldObserver
+};
+
+/// Direction from a maze cell to its neighbor. North is +z, East is +x
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub enum MazeDirection{North, East, South, West}
+impl MazeDirection{
+    pub const ALL: [Self; 4] = [Self::North, Self::East, Self::South, Self::West];
+    /// Bit used for this direction in a cell's link mask
+    pub fn bit(&self) -> u8{
+        match self{
+            Self::North => 1,
+            Self::East => 2,
+            Self::South => 4,
+            Self::West => 8
+        }
+    }
+    pub fn opposite(&self) -> Self{
+        match self{
+            Self::North => Self::South,
+            Self::East => Self::West,
+            Self::South => Self::North,
+            Self::West => Self::East
+        }
+    }
+    pub fn offset(&self) -> IVec2{
+        match self{
+            Self::North => IVec2::Y,
+            Self::East => IVec2::X,
+            Self::South => IVec2::NEG_Y,
+            Self::West => IVec2::NEG_X
+        }
+    }
+}
+
+/// Algorithm used to generate the maze
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub enum MazeAlgorithm{
+    /// Long winding corridors, few dead ends
+    #[default] RecursiveBacktracker,
+    /// Uniform spanning tree, unbiased but slower to generate
+    Wilsons
+}
+
+/// Grid of maze cells. Each cell stores a bitmask of which neighbors it is linked to
+#[derive(Default, Debug, Clone, PartialEq, Eq, Reflect)]
+pub struct Maze{
+    size: UVec2,
+    cells: Vec<u8>
+}
+impl Maze{
+    /// Creates a maze with no passages
+    pub fn new(size: UVec2) -> Self{
+        assert!(size.x > 0 && size.y > 0, "Tried to create empty maze!");
+        Self{size, cells: vec![0; (size.x*size.y) as usize]}
+    }
+    /// Generates a maze with the given algorithm, then removes each dead end with probability `braid`
+    pub fn generate(size: UVec2, algorithm: MazeAlgorithm, braid: f32, seed: u64) -> Self{
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut maze = Self::new(size);
+        match algorithm{
+            MazeAlgorithm::RecursiveBacktracker => maze.recursive_backtracker(&mut rng),
+            MazeAlgorithm::Wilsons => maze.wilsons(&mut rng)
+        }
+        if braid > 0.0 {maze.braid(braid, &mut rng);}
+        maze
+    }
+    pub fn size(&self) -> UVec2 {self.size}
+    fn index(&self, cell: UVec2) -> usize {(cell.y*self.size.x + cell.x) as usize}
+    fn cell(&self, index: usize) -> UVec2 {UVec2::new(index as u32 % self.size.x, index as u32 / self.size.x)}
+    /// Returns the neighbor of a cell in a direction, if it is inside the grid
+    pub fn neighbor(&self, cell: UVec2, direction: MazeDirection) -> Option<UVec2>{
+        let next = cell.as_ivec2() + direction.offset();
+        if next.x < 0 || next.y < 0 || next.x >= self.size.x as i32 || next.y >= self.size.y as i32 {return None;}
+        Some(next.as_uvec2())
+    }
+    /// Whether a cell has a passage in a direction. Passages may lead out of the grid for entrances
+    pub fn is_linked(&self, cell: UVec2, direction: MazeDirection) -> bool{
+        self.cells[self.index(cell)] & direction.bit() != 0
+    }
+    /// Opens a passage between a cell and its neighbor. If there is no neighbor, the passage leads out of the maze
+    pub fn link(&mut self, cell: UVec2, direction: MazeDirection){
+        let index = self.index(cell);
+        self.cells[index] |= direction.bit();
+        if let Some(neighbor) = self.neighbor(cell, direction){
+            let index = self.index(neighbor);
+            self.cells[index] |= direction.opposite().bit();
+        }
+    }
+    /// Number of passages leaving a cell
+    pub fn link_count(&self, cell: UVec2) -> u32{
+        self.cells[self.index(cell)].count_ones()
+    }
+    /// Cells with exactly one passage
+    pub fn dead_ends(&self) -> Vec<UVec2>{
+        (0..self.cells.len()).map(|i| self.cell(i)).filter(|cell| self.link_count(*cell) == 1).collect()
+    }
+    /// Cells with three or more passages
+    pub fn junctions(&self) -> Vec<UVec2>{
+        (0..self.cells.len()).map(|i| self.cell(i)).filter(|cell| self.link_count(*cell) >= 3).collect()
+    }
+
+    fn recursive_backtracker(&mut self, rng: &mut StdRng){
+        let mut visited = vec![false; self.cells.len()];
+        let start = rng.random_range(0..self.cells.len());
+        visited[start] = true;
+        let mut stack = vec![self.cell(start)];
+        while let Some(&cell) = stack.last(){
+            let options: Vec<(MazeDirection, UVec2)> = MazeDirection::ALL.iter()
+                .filter_map(|dir| self.neighbor(cell, *dir).map(|n| (*dir, n)))
+                .filter(|(_, n)| !visited[self.index(*n)])
+                .collect();
+            if options.is_empty() {stack.pop(); continue;}
+            let (direction, next) = options[rng.random_range(0..options.len())];
+            self.link(cell, direction);
+            visited[self.index(next)] = true;
+            stack.push(next);
+        }
+    }
+
+    fn wilsons(&mut self, rng: &mut StdRng){
+        let mut in_maze = vec![false; self.cells.len()];
+        in_maze[rng.random_range(0..self.cells.len())] = true;
+        let mut unvisited: Vec<usize> = (0..self.cells.len()).filter(|i| !in_maze[*i]).collect();
+        // Direction the random walk last left each cell in. Following these from the start gives the loop erased walk
+        let mut exits: Vec<Option<MazeDirection>> = vec![None; self.cells.len()];
+        while !unvisited.is_empty(){
+            let start = unvisited[rng.random_range(0..unvisited.len())];
+            let mut cell = self.cell(start);
+            while !in_maze[self.index(cell)]{
+                let options: Vec<(MazeDirection, UVec2)> = MazeDirection::ALL.iter()
+                    .filter_map(|dir| self.neighbor(cell, *dir).map(|n| (*dir, n)))
+                    .collect();
+                let (direction, next) = options[rng.random_range(0..options.len())];
+                let index = self.index(cell);
+                exits[index] = Some(direction);
+                cell = next;
+            }
+            let mut cell = self.cell(start);
+            while !in_maze[self.index(cell)]{
+                let index = self.index(cell);
+                in_maze[index] = true;
+                let Some(direction) = exits[index] else {break;};
+                self.link(cell, direction);
+                let Some(next) = self.neighbor(cell, direction) else {break;};
+                cell = next;
+            }
+            unvisited.retain(|i| !in_maze[*i]);
+        }
+    }
+
+    /// Links each dead end to a neighbor with probability `p`, preferring neighbors that are dead ends too
+    fn braid(&mut self, p: f32, rng: &mut StdRng){
+        let mut dead_ends = self.dead_ends();
+        for i in (1..dead_ends.len()).rev(){
+            dead_ends.swap(i, rng.random_range(0..=i));
+        }
+        for cell in dead_ends{
+            // an earlier link may have already fixed this one
+            if self.link_count(cell) != 1 || rng.random::<f32>() >= p {continue;}
+            let options: Vec<(MazeDirection, UVec2)> = MazeDirection::ALL.iter()
+                .filter(|dir| !self.is_linked(cell, **dir))
+                .filter_map(|dir| self.neighbor(cell, *dir).map(|n| (*dir, n)))
+                .collect();
+            if options.is_empty() {continue;}
+            let best: Vec<(MazeDirection, UVec2)> = options.iter()
+                .filter(|(_, n)| self.link_count(*n) == 1).cloned().collect();
+            let options = if best.is_empty() {options} else {best};
+            let (direction, _) = options[rng.random_range(0..options.len())];
+            self.link(cell, direction);
+        }
+    }
+}
+
+/// Component for corn fields which carves a procedural maze out of the field.
+/// The maze is carved out of the field's `InitialCornData`, or the data of an init shader with a cpu implementation,
+/// whenever either is inserted.
+/// Coordinates are in corn field space.
+#[derive(Debug, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CornMaze{
+    /// Corner of the maze with the lowest x and z
+    pub origin: Vec2,
+    /// Number of cells across and down
+    pub size: UVec2,
+    /// Width and depth of a single cell
+    pub cell_size: f32,
+    /// Width of the corridors carved through the corn
+    pub path_width: f32,
+    pub algorithm: MazeAlgorithm,
+    /// Probability in 0..1 of removing each dead end. 0 is a perfect maze, 1 removes every dead end
+    pub braid: f32,
+    /// Opens an entrance on the west side of the first cell and an exit on the east side of the last cell
+    pub entrances: bool,
+    pub seed: u64
+}
+impl Default for CornMaze{
+    fn default() -> Self {
+        Self{
+            origin: Vec2::ZERO,
+            size: UVec2::new(10, 10),
+            cell_size: 5.0,
+            path_width: 2.0,
+            algorithm: MazeAlgorithm::default(),
+            braid: 0.0,
+            entrances: true,
+            seed: 0
+        }
+    }
+}
+impl CornMaze{
+    /// Creates a maze centered on the field covering `half_extents`, with cells as close to `cell_size` as possible
+    pub fn fit(center: Vec2, half_extents: Vec2, cell_size: f32, path_width: f32, algorithm: MazeAlgorithm, seed: u64) -> Self{
+        let size = (half_extents*2.0/cell_size).floor().as_uvec2().max(UVec2::ONE);
+        Self{
+            origin: center - size.as_vec2()*cell_size*0.5,
+            size,
+            cell_size,
+            path_width,
+            algorithm,
+            seed,
+            ..default()
+        }
+    }
+    /// Generates the maze grid for these settings
+    pub fn generate(&self) -> Maze{
+        let mut maze = Maze::generate(self.size, self.algorithm, self.braid, self.seed);
+        if self.entrances{
+            maze.link(UVec2::ZERO, MazeDirection::West);
+            maze.link(self.size - 1, MazeDirection::East);
+        }
+        maze
+    }
+    /// Center of a cell in field space
+    pub fn cell_center(&self, cell: UVec2) -> Vec2{
+        self.origin + (cell.as_vec2() + 0.5)*self.cell_size
+    }
+    /// Whether a point in field space lies in a corridor of the maze
+    pub fn is_path(&self, maze: &Maze, point: Vec2) -> bool{
+        let local = (point - self.origin)/self.cell_size;
+        if local.x < 0.0 || local.y < 0.0 || local.x >= self.size.x as f32 || local.y >= self.size.y as f32 {return false;}
+        let cell = local.floor().as_uvec2();
+        let inner = local - local.floor() - 0.5;
+        let half_width = self.path_width*0.5/self.cell_size;
+        let in_x = inner.x.abs() <= half_width;
+        let in_y = inner.y.abs() <= half_width;
+        match (in_x, in_y){
+            (true, true) => true,
+            (true, false) => maze.is_linked(cell, if inner.y > 0.0 {MazeDirection::North} else {MazeDirection::South}),
+            (false, true) => maze.is_linked(cell, if inner.x > 0.0 {MazeDirection::East} else {MazeDirection::West}),
+            (false, false) => false
+        }
+    }
+    /// Disables every stalk that lies in a corridor
+    pub fn carve(&self, maze: &Maze, data: &mut [CornData]){
+        for corn in data.iter_mut(){
+            if self.is_path(maze, corn.offset().xz()) {corn.set_enabled(false);}
+        }
+    }
+    /// Observer which carves the maze again whenever the settings or a cpu init shader are inserted
+    fn on_insert(
+        trigger: Trigger<OnInsert, (Self, SimpleInitShader, SimpleHexagonalInitShader)>,
+        mut commands: Commands
+    ){
+        commands.entity(trigger.entity()).queue(Self::carve_field);
+    }
+    /// Observer which carves the maze into newly inserted corn data, dropping the un-carved copy of the old data
+    fn on_insert_data(
+        trigger: Trigger<OnInsert, InitialCornData>,
+        query: Query<(), With<Self>>,
+        mut commands: Commands
+    ){
+        if !query.contains(trigger.entity()) {return;}
+        commands.entity(trigger.entity()).remove::<UncarvedCornData>().queue(Self::carve_field);
+    }
+    /// Generates the maze and carves it into fresh corn data, so a changed maze or init shader doesn't keep the old corridors.
+    /// Fresh data comes from the cpu init shader, then the un-carved copy, then data which was never carved.
+    fn carve_field(mut entity: EntityWorldMut){
+        let Some(settings) = entity.get::<Self>().cloned() else {return;};
+        let data = entity.get::<SimpleInitShader>().map(|shader| shader.generate_corn_data())
+            .or_else(|| entity.get::<SimpleHexagonalInitShader>().map(|shader| shader.generate_corn_data()))
+            .or_else(|| entity.get::<UncarvedCornData>().map(|data| data.0.clone()))
+            .or_else(|| entity.get::<InitialCornData>().map(|data| data.0.clone()));
+        let Some(mut data) = data else {
+            // Baked fields carve once their data arrives, but image fields only ever exist on the gpu
+            if entity.contains::<ImageInitShader>() {
+                warn!("CornMaze on {} has no effect: image init shaders have no cpu corn data to carve", entity.id());
+            }
+            return;
+        };
+        let uncarved = UncarvedCornData(data.clone());
+        let maze = settings.generate();
+        settings.carve(&maze, data.as_mut_slice());
+        let features = MazeFeatures{
+            dead_ends: maze.dead_ends().into_iter().map(|c| settings.cell_center(c).extend(0.0).xzy()).collect(),
+            junctions: maze.junctions().into_iter().map(|c| settings.cell_center(c).extend(0.0).xzy()).collect()
+        };
+        match entity.get_mut::<InitialCornData>(){
+            Some(mut initial) => {
+                initial.0 = data;
+                // Replaced without an insert, so paths have to be told their stalks are gone
+                entity.remove::<ClearedByPaths>();
+            },
+            None => {entity.insert(InitialCornData(data));}
+        }
+        entity.insert((uncarved, GeneratedMaze(maze), features));
+    }
+}
+
+/// Corn data of a maze's field from before it was carved, so a changed maze can be carved into fresh data
+#[derive(Default, Debug, Clone, PartialEq, Component)]
+pub struct UncarvedCornData(pub Vec<CornData>);
+
+/// The maze generated for a `CornMaze`
+#[derive(Default, Debug, Clone, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+pub struct GeneratedMaze(pub Maze);
+
+/// Interesting cells of a generated maze for placing objectives. Positions are cell centers in field space
+#[derive(Default, Debug, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+pub struct MazeFeatures{
+    pub dead_ends: Vec<Vec3>,
+    pub junctions: Vec<Vec3>
+}
+
+/// Adds procedural corn mazes
+pub struct CornMazePlugin;
+impl Plugin for CornMazePlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornMaze>()
+            .register_type::<GeneratedMaze>()
+            .register_type::<MazeFeatures>()
+            .add_observer_as(CornMaze::on_insert, CornFieldObserver)
+            .add_observer_as(CornMaze::on_insert_data, CornFieldObserver);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn stalks() -> InitialCornData{
+        let stalk = |x: f32, z: f32| CornData{offset: Vec3::new(x, 0.0, z), enabled: 1, ..default()};
+        InitialCornData(vec![stalk(2.5, 2.5), stalk(0.1, 0.1)])
+    }
+
+    fn carved(world: &World, entity: Entity) -> Vec<bool>{
+        assert!(world.get::<GeneratedMaze>(entity).is_some());
+        world.get::<InitialCornData>(entity).unwrap().0.iter().map(|corn| !corn.is_enabled()).collect()
+    }
+
+    #[test]
+    fn carves_data_inserted_after_the_maze(){
+        let mut world = World::new();
+        world.add_observer(CornMaze::on_insert);
+        world.add_observer(CornMaze::on_insert_data);
+        let entity = world.spawn(CornMaze{size: UVec2::ONE, ..default()}).id();
+        assert!(world.get::<GeneratedMaze>(entity).is_none());
+        world.entity_mut(entity).insert(stalks());
+        world.flush();
+        assert_eq!(carved(&world, entity), [true, false]);
+    }
+
+    #[test]
+    fn carves_data_inserted_before_the_maze(){
+        let mut world = World::new();
+        world.add_observer(CornMaze::on_insert);
+        world.add_observer(CornMaze::on_insert_data);
+        let entity = world.spawn(stalks()).id();
+        world.entity_mut(entity).insert(CornMaze{size: UVec2::ONE, ..default()});
+        world.flush();
+        assert_eq!(carved(&world, entity), [true, false]);
+    }
+
+    #[test]
+    fn reinserting_the_maze_carves_fresh_data(){
+        let mut world = World::new();
+        world.add_observer(CornMaze::on_insert);
+        world.add_observer(CornMaze::on_insert_data);
+        let entity = world.spawn((stalks(), CornMaze{size: UVec2::ONE, ..default()})).id();
+        world.flush();
+        assert_eq!(carved(&world, entity), [true, false]);
+        world.entity_mut(entity).insert(CornMaze{origin: Vec2::splat(-2.4), size: UVec2::ONE, ..default()});
+        world.flush();
+        assert_eq!(carved(&world, entity), [false, true]);
+    }
+}
@@ -3,20 +3,40 @@ pub mod init;
 pub mod scan_prepass;
 pub mod asset;
 pub mod render;
+pub mod field_asset;
+pub mod maze;
+pub mod edit;
+pub mod path;
+pub mod stream;
+pub mod lod;
+pub mod baked_model;
+pub mod billboard;
+pub mod diagnostics;
+pub mod downlevel;
+pub mod readback;
+pub mod bounds;
 
 use bevy::{prelude::*, render::{
     batching::NoAutomaticBatching, extract_component::{ExtractComponent, ExtractComponentPlugin}, 
     extract_resource::{ExtractResource, ExtractResourcePlugin}, 
-    render_resource::*, renderer::RenderDevice, view::NoFrustumCulling, Render, RenderApp, RenderSet
+    render_resource::*, renderer::RenderDevice, view::NoFrustumCulling
 }};
 use bytemuck::{Pod, Zeroable};
 use init::{simple::SimpleInitShader, CornInitializationPlugin};
-use asset::{CornModel, CornModelPlugin};
+use asset::{CornModel, CornModelLods, CornModelPlugin};
+use field_asset::CornFieldAssetPlugin;
+use maze::CornMazePlugin;
+use edit::{CornEditPlugin, CornGpuEdits};
+use path::CornPathPlugin;
+use stream::{CornStreamingPlugin, StreamedCornField};
+use lod::CornLodPlugin;
+use billboard::CornBillboardPlugin;
 use render::CornRenderPlugin;
 use scan_prepass::ScanPrepassPlugin;
 use crate::{scenes::lobby::LobbyScene, systems::{scenes::OnSpawnScene, util::default_resources::SimpleMaterials}, util::observer_ext::ObserverParent};
 
-pub const LOD_COUNT: u32 = 6;
+/// Number of lods the default global lod cutoffs are made for. Corn models can have any number of lods
+pub const DEFAULT_LOD_COUNT: u32 = 6;
 
 /// Struct representing the Per Corn Stalk data on  the GPU
 #[derive(Default, Clone, Copy, Pod, Zeroable, Debug, ShaderType, PartialEq, Reflect)]
@@ -28,37 +48,101 @@ pub struct CornData{
     scale: f32,
     /// Rotation of this corn stalk in the form <sin(theta), cos(theta)>
     rotation: Vec2,
-    /// an id, not used by most corn fields, but can be used to signify special traits
+    /// an id, used to pick the model variant of this stalk (`uuid % variant_count`). Can also be used to signify special traits
     uuid: u32,
-    /// whether or not the corn piece should be rendered
+    /// whether or not the corn piece should be rendered. 1 if it is, `CornData::CUT` if an edit disabled it, and 0 otherwise
     enabled: u32
 }
 impl CornData{
     pub const DATA_SIZE: u64 = 32;
     pub const VERTEX_DATA_SIZE: u64 = 64;
+    /// Value of `enabled` for stalks disabled by an edit, so restoring them leaves stalks the layout disabled alone. Mirrors `CORN_CUT` in `corn_common.wgsl`
+    pub const CUT: u32 = 2;
+
+    /// Offset of this stalk in field space
+    pub fn offset(&self) -> Vec3 {self.offset}
+    /// Height scale of this stalk
+    pub fn scale(&self) -> f32 {self.scale}
+    /// Rotation of this stalk in the form <sin(theta), cos(theta)>
+    pub fn rotation(&self) -> Vec2 {self.rotation}
+    /// Id of this stalk
+    pub fn uuid(&self) -> u32 {self.uuid}
+    /// Whether this stalk is rendered
+    pub fn is_enabled(&self) -> bool {self.enabled == 1}
+    /// Sets whether this stalk is rendered
+    pub fn set_enabled(&mut self, enabled: bool) {self.enabled = enabled as u32;}
+    /// Whether an edit disabled this stalk
+    pub fn is_cut(&self) -> bool {self.enabled == Self::CUT}
+    /// Applies a cut (`enabled` false) or restore (`enabled` true) edit. Cuts only disable rendered stalks, and restores only enable cut ones.
+    /// Returns whether the stalk changed
+    pub fn edit(&mut self, enabled: bool) -> bool{
+        let edited = match (enabled, self.enabled){
+            (false, 1) => Self::CUT,
+            (true, Self::CUT) => 1,
+            _ => return false
+        };
+        self.enabled = edited;
+        true
+    }
+    /// Enables this stalk, or cuts it as in `CornData::edit`. Used by edits which target specific stalks. Returns whether the stalk changed
+    pub fn set_edited(&mut self, enabled: bool) -> bool{
+        if !enabled {return self.edit(false);}
+        let changed = !self.is_enabled();
+        self.set_enabled(true);
+        changed
+    }
 }
 
 /// Top level Tag Component for Corn Fields. 
 /// Each entity with a CornField and CornPositionInitializer Component has a corresponding Buffer of corn stalk instances in the render app.
+/// Fields are culled as a whole against their `Aabb` by the vote scan, not by bevy, whose culling only sees a single stalk
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Reflect, Component, ExtractComponent)]
 #[reflect(Component)]
-#[require(Transform, Visibility, NoFrustumCulling, NoAutomaticBatching(|| NoAutomaticBatching))]
+#[require(Transform, Visibility, NoFrustumCulling, NoAutomaticBatching(|| NoAutomaticBatching), CornGpuEdits)]
 pub struct CornField;
 
 /// Global resource for lod cutoffs
 #[derive(Debug, Clone, Reflect, Resource, ExtractResource)]
 #[reflect(Resource)]
-pub struct GlobalLodCutoffs(pub [f32; LOD_COUNT as usize]);
+pub struct GlobalLodCutoffs(pub Vec<f32>);
 impl Default for GlobalLodCutoffs{
     fn default() -> Self {
-        let mut cutoffs = [0.0; LOD_COUNT as usize];
-        for i in 0..LOD_COUNT {
-            cutoffs[i as usize] = 2_i32.pow(i) as f32 * 20.0;
-        }
-        Self(cutoffs)
+        Self((0..DEFAULT_LOD_COUNT).map(|i| 2_i32.pow(i) as f32 * 20.0).collect())
+    }
+}
+
+/// Global resource for the lod cutoffs and stalk density of shadow views, so shadows switch to coarse lods sooner than the main pass
+#[derive(Debug, Clone, Reflect, Resource, ExtractResource)]
+#[reflect(Resource)]
+pub struct ShadowLodCutoffs{
+    /// Cutoff distance of each lod in shadow views. A field's own cutoffs are used where they're nearer
+    pub cutoffs: Vec<f32>,
+    /// Distance past which stalks are thinned out of shadow views
+    pub thinning_distance: f32,
+    /// Fraction of stalks past `thinning_distance` which still cast shadows
+    pub density: f32
+}
+impl Default for ShadowLodCutoffs{
+    fn default() -> Self {
+        Self{cutoffs: (0..DEFAULT_LOD_COUNT).map(|i| 2_i32.pow(i) as f32 * 8.0).collect(), thinning_distance: 60.0, density: 0.5}
+    }
+}
+impl ShadowLodCutoffs{
+    /// Fits the shadow cutoffs to a model with `lod_count` lods, never picking a finer lod than `view_cutoffs`
+    pub fn fit(&self, view_cutoffs: &[f32], lod_count: u32) -> Vec<f32>{
+        fit_lod_cutoffs(&self.cutoffs, lod_count).into_iter().zip(view_cutoffs).map(|(shadow, view)| shadow.min(*view)).collect()
     }
 }
 
+/// Fits lod cutoffs to a model with `lod_count` lods. Extra cutoffs are dropped, and missing ones keep doubling the last cutoff
+pub fn fit_lod_cutoffs(cutoffs: &[f32], lod_count: u32) -> Vec<f32>{
+    let mut fitted: Vec<f32> = cutoffs.iter().copied().take(lod_count as usize).collect();
+    while fitted.len() < lod_count as usize{
+        fitted.push(fitted.last().map_or(20.0, |last| last*2.0));
+    }
+    fitted
+}
+
 /// Common shader files used in all shaders
 #[derive(Debug, Clone, PartialEq, Eq, Reflect, Resource)]
 #[reflect(Resource)]
@@ -86,7 +170,7 @@ impl InstanceBuffer{
     pub fn create_buffer(label: String, count: u64, render_device: &RenderDevice) -> Self{
         Self(render_device.create_buffer(&BufferDescriptor{
             label: Some(label.as_str()),
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
             size: count*CornData::DATA_SIZE,
             mapped_at_creation: false
         }), count)
@@ -95,53 +179,49 @@ impl InstanceBuffer{
         Self(render_device.create_buffer_with_data(&BufferInitDescriptor { 
             label: Some(label.as_str()), 
             contents: data, 
-            usage:  BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
         }), data.len() as u64/CornData::DATA_SIZE)
     }
 }
 
-/// Component for Corn Fields containing the indirect buffer
-#[derive(Debug, Clone, Component)]
+/// Indirect buffer of the corn fields sharing a model layout in a single view.
+/// Holds one indirect draw per lod of each model variant, then one for billboards, for each field
+#[derive(Debug, Clone)]
 pub struct IndirectBuffer(pub Buffer);
 impl IndirectBuffer{
-    // System which creates indirect buffers for loaded corn field
-    fn spawn_indirect(
-        query: Query<Entity, (With<CornLoaded>, Without<Self>)>,
-        corn_model: Res<CornModel>,
-        render_device: Res<RenderDevice>,
-        mut commands: Commands
-    ){
-        for entity in query.iter(){
-            let data: Vec<u32> = corn_model.lod_info.iter().map(|(total, start)| 
-                [*total as u32, 0, *start as u32, 0, 0]
-            ).flatten().collect();
-            let indirect_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor { 
-                label: Some("Corn Field Indirect Buffer"), 
-                contents: bytemuck::cast_slice(data.as_slice()), 
-                usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_SRC
-            });
-            commands.entity(entity).insert(IndirectBuffer(indirect_buffer));
-        }
+    /// Size of a single indexed indirect draw
+    pub const DRAW_SIZE: u64 = 20;
+    /// Indices of a billboard quad
+    pub const BILLBOARD_INDEX_COUNT: u32 = 6;
+
+    /// Indirect draws for the lods of a corn model, with instance counts filled in by the vote scan
+    pub fn draws(CornModelLods(lods): &CornModelLods) -> Vec<[u32; 5]>{
+        lods.iter().flatten().map(|(total, start)| 
+            [*total as u32, 0, *start as u32, 0, 0]
+        ).chain([[Self::BILLBOARD_INDEX_COUNT, 0, 0, 0, 0]]).collect()
+    }
+
+    /// Creates an indirect buffer holding the draws of each field, one after another
+    pub fn create_buffer(draws: &[[u32; 5]], render_device: &RenderDevice) -> Self{
+        Self(render_device.create_buffer_with_data(&BufferInitDescriptor { 
+            label: Some("Corn Field Indirect Buffer"), 
+            contents: bytemuck::cast_slice(draws), 
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_SRC
+        }))
     }
 }
 
-/// Component for Corn Fields containing the vertex instance buffer
-#[derive(Debug, Clone, Component)]
+/// Vertex instance buffer of the corn fields sharing a model layout in a single view, holding the transforms of the stalks drawn in it
+#[derive(Debug, Clone)]
 pub struct VertexInstanceBuffer(pub Buffer);
 impl VertexInstanceBuffer{
-    fn spawn_vertex_buffer(
-        query: Query<(Entity, &InstanceBuffer), (With<CornLoaded>, Without<Self>)>,
-        render_device: Res<RenderDevice>,
-        mut commands: Commands
-    ){
-        for (entity, InstanceBuffer(_, count)) in query.iter(){
-            commands.entity(entity).insert(VertexInstanceBuffer(render_device.create_buffer(&BufferDescriptor{
-                label: Some("Corn Field Vertex Instance Buffer"),
-                size: count*64,
-                usage: BufferUsages::STORAGE | BufferUsages::VERTEX | BufferUsages::COPY_SRC,
-                mapped_at_creation: false
-            })));
-        }
+    pub fn create_buffer(count: u64, render_device: &RenderDevice) -> Self{
+        Self(render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Field Vertex Instance Buffer"),
+            size: count.max(1)*CornData::VERTEX_DATA_SIZE,
+            usage: BufferUsages::STORAGE | BufferUsages::VERTEX | BufferUsages::COPY_SRC,
+            mapped_at_creation: false
+        }))
     }
 }
 
@@ -168,14 +248,13 @@ impl Plugin for CornFieldComponentPlugin{
             .register_type::<GlobalLodCutoffs>()
             .init_resource::<GlobalLodCutoffs>()
             .add_plugins(ExtractResourcePlugin::<GlobalLodCutoffs>::default())
+            .register_type::<ShadowLodCutoffs>()
+            .init_resource::<ShadowLodCutoffs>()
+            .add_plugins(ExtractResourcePlugin::<ShadowLodCutoffs>::default())
 
             .register_type::<CornCommonShader>()
-            .init_resource::<CornCommonShader>()
-
-            .sub_app_mut(RenderApp).add_systems(Render, (
-                IndirectBuffer::spawn_indirect, VertexInstanceBuffer::spawn_vertex_buffer
-            ).in_set(RenderSet::PrepareResources));
-        app.add_plugins((CornInitializationPlugin, ScanPrepassPlugin, CornModelPlugin, CornRenderPlugin));
+            .init_resource::<CornCommonShader>();
+        app.add_plugins((CornInitializationPlugin, ScanPrepassPlugin, CornModelPlugin, CornRenderPlugin, CornFieldAssetPlugin, CornMazePlugin, CornEditPlugin, CornPathPlugin, CornStreamingPlugin, CornLodPlugin, CornBillboardPlugin, diagnostics::CornDiagnosticsPlugin, downlevel::CornDownlevelPlugin, readback::CornReadbackPlugin, bounds::CornBoundsPlugin));
 
         app.add_systems(OnSpawnScene(LobbyScene), test_init);
     }
@@ -192,16 +271,21 @@ pub fn test_init(
     model: Res<CornModel>
 ){
     commands.spawn((
-        CornField,
+        StreamedCornField{
+            tile_size: 100.0,
+            residency_radius: 400.0,
+            memory_budget: 64*1024*1024,
+            model: model.0.clone(),
+            material: default_resources.red.clone()
+        },
         SimpleInitShader::new(
             Vec3::ZERO, 
             Vec2::ONE*500.0, 
             UVec2::new(1000, 1000), 
             Vec2::new(0.9, 1.1), 
-            0.0
+            0.0,
+            0
         ),
-        Transform::from_xyz(0.0, 2.0, 0.0),
-        Mesh3d(model.mesh_handle.clone()),
-        MeshMaterial3d(default_resources.red.clone())
+        Transform::from_xyz(0.0, 2.0, 0.0)
     ));
 }
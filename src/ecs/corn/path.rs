@@ -0,0 +1,267 @@
+//! Spline paths which clear corn. A `CornPath` disables every stalk inside its corridor, in every corn field it overlaps,
+//! and thins out the stalks along its soft edges. Paths are re-applied whenever they, their transform, or a field's corn data change.
+//! A stalk is restored once no path clears it, to whatever state edits left it in underneath the paths.
+//!
+//! Paths only affect fields with main world corn data (`InitialCornData`). A warning is logged for each path overlapping a field without it.
+use bevy::{math::{cubic_splines::{CubicBezier, CubicCardinalSpline, CubicGenerator}, Affine3A}, prelude::*, utils::{HashMap, HashSet}};
+use super::{bounds::CornStalkBounds, edit::CornEdits, init::{cpu::CornRng, InitialCornData}, CornData, CornField, CornFieldObserver};
+use crate::util::observer_ext::ObserveAsAppExt;
+
+/// Curve used to interpolate the control points of a `CornPath`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub enum CornPathCurve{
+    /// Passes through every control point
+    #[default] CatmullRom,
+    /// Cubic bezier segments. Points are laid out as `start, handle, handle, end, handle, handle, end, ...`
+    Bezier
+}
+
+/// Component for a path cleared through corn fields. Control points are in the path's local space
+#[derive(Debug, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+#[require(Transform)]
+pub struct CornPath{
+    pub points: Vec<Vec3>,
+    pub curve: CornPathCurve,
+    /// Width of the fully cleared corridor
+    pub width: f32,
+    /// Distance past each edge of the corridor over which corn is thinned out, from fully cleared to untouched
+    pub edge_softness: f32,
+    /// Seed deciding which stalks are removed along the soft edges
+    pub seed: u32
+}
+impl Default for CornPath{
+    fn default() -> Self {
+        Self{points: vec![], curve: CornPathCurve::CatmullRom, width: 2.0, edge_softness: 0.5, seed: 0}
+    }
+}
+impl CornPath{
+    /// Number of line segments each curve segment is split into
+    const SUBDIVISIONS: usize = 16;
+
+    /// Samples the curve into a polyline in the path's local space
+    pub fn polyline(&self) -> Vec<Vec3>{
+        let curve = match self.curve{
+            CornPathCurve::CatmullRom => CubicCardinalSpline::new_catmull_rom(self.points.clone()).to_curve().ok(),
+            CornPathCurve::Bezier => CubicBezier::new(
+                (0..self.points.len().saturating_sub(1)/3).map(|i| [
+                    self.points[i*3], self.points[i*3+1], self.points[i*3+2], self.points[i*3+3]
+                ]).collect::<Vec<_>>()
+            ).to_curve().ok()
+        };
+        match curve{
+            Some(curve) => curve.iter_positions(curve.segments().len()*Self::SUBDIVISIONS).collect(),
+            // Not enough points for a curve, fall back to straight lines
+            None => self.points.clone()
+        }
+    }
+
+    /// Whether a stalk `distance` away from the center of the path is cleared. `id` picks which stalks survive along the edges
+    pub fn clears(&self, distance: f32, id: u32) -> bool{
+        let edge = distance - self.width*0.5;
+        if edge <= 0.0 {return true;}
+        if edge >= self.edge_softness {return false;}
+        CornRng::seeded(self.seed, id).next_f32() > edge/self.edge_softness
+    }
+
+    /// Polyline on a field's xz plane, and the rectangle of the field this path can clear
+    pub fn field_polyline(&self, path_to_field: Affine3A, scale: f32) -> Option<(Vec<Vec2>, Rect)>{
+        let points: Vec<Vec2> = self.polyline().into_iter().map(|p| path_to_field.transform_point3(p).xz()).collect();
+        if points.is_empty() {return None;}
+        let reach = (self.width*0.5 + self.edge_softness)/scale;
+        let min = points.iter().fold(Vec2::INFINITY, |a, b| a.min(*b)) - reach;
+        let max = points.iter().fold(Vec2::NEG_INFINITY, |a, b| a.max(*b)) + reach;
+        Some((points, Rect::from_corners(min, max)))
+    }
+
+    /// Ids of the stalks this path clears
+    pub fn cleared(&self, path_to_field: Affine3A, scale: f32, data: &[CornData]) -> Vec<u32>{
+        let Some((points, area)) = self.field_polyline(path_to_field, scale) else {return vec![];};
+        let mut cleared = vec![];
+        for (id, corn) in data.iter().enumerate(){
+            let point = corn.offset().xz();
+            if !area.contains(point) {continue;}
+            let distance = match points.len(){
+                1 => point.distance(points[0]),
+                _ => points.windows(2).map(|segment| {
+                    let line = segment[1] - segment[0];
+                    let t = ((point - segment[0]).dot(line)/line.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                    point.distance(segment[0] + line*t)
+                }).fold(f32::INFINITY, f32::min)
+            };
+            if self.clears(distance*scale, id as u32) {cleared.push(id as u32);}
+        }
+        cleared
+    }
+
+    /// Re-applies changed paths to every corn field, restoring the stalks no path clears anymore
+    fn apply_paths(
+        paths: Query<(Entity, Ref<Self>, Ref<GlobalTransform>)>,
+        mut fields: Query<(Entity, &mut InitialCornData, Ref<GlobalTransform>, &mut CornEdits, Option<&mut ClearedByPaths>), With<CornField>>,
+        gpu_fields: Query<(Entity, &GlobalTransform, &CornStalkBounds), (With<CornField>, Without<InitialCornData>)>,
+        mut removed: RemovedComponents<Self>,
+        mut warned: Local<HashSet<(Entity, Entity)>>,
+        mut commands: Commands
+    ){
+        let changed: HashSet<Entity> = paths.iter()
+            .filter(|(_, path, transform)| path.is_changed() || transform.is_changed())
+            .map(|(entity, _, _)| entity)
+            .chain(removed.read())
+            .collect();
+        for (entity, mut data, transform, mut edits, cleared) in fields.iter_mut(){
+            // New corn data has nothing cleared yet, a moved field needs every path cleared again
+            let new_data = cleared.is_none();
+            let all = new_data || transform.is_changed();
+            if !all && changed.is_empty() {continue;}
+            let mut cleared = cleared.map(|mut cleared| std::mem::take(cleared.as_mut())).unwrap_or_default();
+            // Don't trigger change detection, the edits are sent on their own instead of re-uploading the whole field
+            let data = &mut data.bypass_change_detection().0;
+            let mut dirty: Vec<u32> = vec![];
+            let stale: Vec<Entity> = cleared.paths.keys().filter(|path| all || changed.contains(*path)).copied().collect();
+            for path in stale{
+                cleared.remove_path(path, data.as_mut_slice(), &mut dirty);
+            }
+            let field_from_world = transform.affine().inverse();
+            let scale = transform.scale().xz().min_element();
+            for (path_entity, path, path_transform) in paths.iter(){
+                if !all && !changed.contains(&path_entity) {continue;}
+                let ids = path.cleared(field_from_world*path_transform.affine(), scale, data.as_slice());
+                cleared.add_path(path_entity, ids, data.as_mut_slice(), &mut dirty);
+            }
+            commands.entity(entity).insert(cleared);
+            if dirty.is_empty() {continue;}
+            edits.0.append(&mut CornEdits::from_dirty(dirty, data.as_slice()).0);
+        }
+        // Fields initialized on the gpu have no stalks to clear
+        for (path_entity, path, path_transform) in paths.iter(){
+            if !changed.contains(&path_entity) {continue;}
+            for (field, transform, bounds) in gpu_fields.iter(){
+                if warned.contains(&(path_entity, field)) {continue;}
+                let path_to_field = transform.affine().inverse()*path_transform.affine();
+                let Some((_, area)) = path.field_polyline(path_to_field, transform.scale().xz().min_element()) else {continue;};
+                if area.intersect(Rect::from_corners(bounds.min.xz(), bounds.max.xz())).is_empty() {continue;}
+                warn!("CornPath {} overlaps corn field {}, which has no cpu corn data for the path to clear", path_entity, field);
+                warned.insert((path_entity, field));
+            }
+        }
+    }
+
+    /// Observer which forgets the stalks paths cleared whenever the corn data is replaced, since the new data has none cleared
+    fn reset_cleared(trigger: Trigger<OnInsert, InitialCornData>, mut commands: Commands){
+        commands.entity(trigger.entity()).remove::<ClearedByPaths>();
+    }
+}
+
+/// Component for corn fields, recording which stalks each `CornPath` clears.
+/// Stalks stay disabled while any path clears them, and go back to their state underneath the paths once none do
+#[derive(Default, Debug, Clone, PartialEq, Eq, Component)]
+pub struct ClearedByPaths{
+    /// Stalks cleared by each path
+    pub paths: HashMap<Entity, Vec<u32>>,
+    /// Number of paths clearing each stalk, and the stalk's `enabled` value underneath them
+    pub stalks: HashMap<u32, (u32, u32)>
+}
+impl ClearedByPaths{
+    /// Clears the stalks of a path, recording the ones which changed in `dirty`
+    pub fn add_path(&mut self, path: Entity, ids: Vec<u32>, data: &mut [CornData], dirty: &mut Vec<u32>){
+        for id in ids.iter(){
+            let Some(corn) = data.get_mut(*id as usize) else {continue;};
+            self.stalks.entry(*id).or_insert((0, corn.enabled)).0 += 1;
+            if !corn.is_enabled() {continue;}
+            corn.set_enabled(false);
+            dirty.push(*id);
+        }
+        if !ids.is_empty() {self.paths.insert(path, ids);}
+    }
+    /// Releases the stalks of a path, restoring the ones no other path clears and recording the ones which changed in `dirty`
+    pub fn remove_path(&mut self, path: Entity, data: &mut [CornData], dirty: &mut Vec<u32>){
+        let Some(ids) = self.paths.remove(&path) else {return;};
+        for id in ids{
+            let Some((count, enabled)) = self.stalks.get_mut(&id) else {continue;};
+            *count -= 1;
+            if *count > 0 {continue;}
+            let enabled = *enabled;
+            self.stalks.remove(&id);
+            let Some(corn) = data.get_mut(id as usize) else {continue;};
+            if corn.enabled == enabled {continue;}
+            corn.enabled = enabled;
+            dirty.push(id);
+        }
+    }
+    /// Edits a stalk underneath the paths, so the edit takes effect once no path clears it.
+    /// Returns false if no path clears the stalk, in which case it should be edited directly
+    pub fn edit(&mut self, id: u32, edit: impl FnOnce(&mut CornData) -> bool) -> bool{
+        let Some((_, underneath)) = self.stalks.get_mut(&id) else {return false;};
+        let mut corn = CornData{enabled: *underneath, ..default()};
+        edit(&mut corn);
+        *underneath = corn.enabled;
+        true
+    }
+}
+
+/// Adds spline paths which clear corn
+pub struct CornPathPlugin;
+impl Plugin for CornPathPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornPath>()
+            .add_observer_as(CornPath::reset_cleared, CornFieldObserver)
+            .add_systems(PostUpdate, CornPath::apply_paths.after(CornEdits::apply_edits).after(TransformSystem::TransformPropagate));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn field(count: usize) -> Vec<CornData>{
+        vec![CornData{enabled: 1, ..default()}; count]
+    }
+
+    #[test]
+    fn overlapping_paths_keep_stalks_cleared(){
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut data = field(3);
+        let mut cleared = ClearedByPaths::default();
+        let mut dirty = vec![];
+        cleared.add_path(a, vec![0, 1], data.as_mut_slice(), &mut dirty);
+        cleared.add_path(b, vec![1, 2], data.as_mut_slice(), &mut dirty);
+        assert_eq!(dirty, [0, 1, 2]);
+        dirty.clear();
+        cleared.remove_path(a, data.as_mut_slice(), &mut dirty);
+        assert_eq!(dirty, [0]);
+        assert_eq!(data.iter().map(CornData::is_enabled).collect::<Vec<_>>(), [true, false, false]);
+        cleared.remove_path(b, data.as_mut_slice(), &mut dirty);
+        assert!(data.iter().all(CornData::is_enabled));
+    }
+
+    #[test]
+    fn cut_stalks_stay_cut_when_paths_are_removed(){
+        let path = Entity::from_raw(1);
+        let mut data = field(2);
+        data[1].set_enabled(false);
+        let mut cleared = ClearedByPaths::default();
+        let mut dirty = vec![];
+        cleared.add_path(path, vec![0, 1], data.as_mut_slice(), &mut dirty);
+        // Cut after the path was applied
+        assert!(cleared.edit(0, |corn| corn.edit(false)));
+        assert!(!cleared.edit(5, |corn| corn.edit(false)));
+        cleared.remove_path(path, data.as_mut_slice(), &mut dirty);
+        assert!(data.iter().all(|corn| !corn.is_enabled()));
+    }
+
+    #[test]
+    fn replaced_data_forgets_cleared_stalks(){
+        let mut world = World::new();
+        world.add_observer(CornPath::reset_cleared);
+        let entity = world.spawn(InitialCornData(field(2))).id();
+        world.flush();
+        world.entity_mut(entity).insert(ClearedByPaths::default());
+        world.get_mut::<InitialCornData>(entity).unwrap().0[0].set_enabled(false);
+        world.flush();
+        assert!(world.get::<ClearedByPaths>(entity).is_some());
+        world.entity_mut(entity).insert(InitialCornData(field(2)));
+        world.flush();
+        assert!(world.get::<ClearedByPaths>(entity).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! Non blocking readback of corn field buffers. Send a `CornReadbackRequest` for a field, and a `CornReadbackComplete`
+//! holding a snapshot of the buffer is sent a few frames later, once the gpu has finished with it.
+//!
+//! The snapshot is copied after the frame is submitted, so per view buffers hold what was culled and drawn that frame.
+//! Fields outside a view aren't scanned for it, so their readbacks for that view wait until the field is back in it.
+//! Requests for a view which doesn't exist, or isn't rendered, are dropped with a warning.
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        sync_world::RenderEntity,
+        Extract, ExtractSchedule, Render, RenderApp, RenderSet
+    }
+};
+use wgpu::Maintain;
+use crate::ecs::cameras::MainCamera;
+use super::{asset::CornModelLods, scan_prepass::vote::VoteScanBuffers, CornData, IndirectBuffer, InstanceBuffer};
+
+/// A buffer of a corn field which can be read back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect)]
+pub enum CornReadbackBuffer{
+    /// Every stalk of the field
+    Instances,
+    /// Indirect draw arguments of each bucket, for a camera. The `MainCamera` when None
+    Indirect(Option<Entity>),
+    /// Bucket of each stalk and its index within it, for a camera. The `MainCamera` when None.
+    /// Each bucket's stalk count is the instance count of its indirect draw
+    Votes(Option<Entity>)
+}
+
+/// Snapshot of a corn field buffer
+#[derive(Debug, Clone, PartialEq)]
+pub enum CornReadbackData{
+    Instances(Vec<CornData>),
+    /// Indexed indirect draw arguments, index count, instance count, first index, base vertex, then first instance
+    Indirect(Vec<[u32; 5]>),
+    /// Bucket and index within the bucket of each stalk. Stalks which weren't drawn are in bucket `CornModelLods::bucket_count`
+    Votes(Vec<UVec2>)
+}
+
+/// Requests a snapshot of a corn field's buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Event)]
+pub struct CornReadbackRequest{
+    pub field: Entity,
+    pub buffer: CornReadbackBuffer
+}
+
+/// Snapshot of a corn field's buffer, sent in response to a `CornReadbackRequest`
+#[derive(Debug, Clone, Event)]
+pub struct CornReadbackComplete{
+    pub field: Entity,
+    pub buffer: CornReadbackBuffer,
+    pub data: CornReadbackData
+}
+
+/// Readbacks finished in the render world, waiting to be sent in the main world
+#[derive(Default, Debug, Clone, Resource)]
+pub struct CornReadbackChannel(pub Arc<Mutex<Vec<CornReadbackComplete>>>);
+impl CornReadbackChannel{
+    fn send_events(channel: Res<Self>, mut events: EventWriter<CornReadbackComplete>){
+        let Ok(mut readbacks) = channel.0.lock() else {return;};
+        events.send_batch(readbacks.drain(..));
+    }
+}
+
+/// A readback in the render world
+#[derive(Debug)]
+struct CornReadback{
+    request: CornReadbackRequest,
+    /// Render world entities of the field and the view
+    field: Entity,
+    view: Option<Entity>,
+    /// Staging buffer, and whether it's been mapped. None until the source buffer exists and is copied
+    staging: Option<(Buffer, Arc<AtomicBool>)>
+}
+impl CornReadback{
+    /// Source buffer of the readback, with the offset and size of the field's part of it, if the field has been scanned into it
+    fn source<'w>(&self, world: &'w World) -> Option<(&'w Buffer, u64, u64)>{
+        let InstanceBuffer(instances, count) = world.get::<InstanceBuffer>(self.field)?;
+        let view = || world.get::<VoteScanBuffers>(self.field)?.0.get(&self.view?);
+        match self.request.buffer{
+            CornReadbackBuffer::Instances => Some((instances, 0, instances.size())),
+            CornReadbackBuffer::Indirect(_) => view().zip(world.get::<CornModelLods>(self.field)).map(|(buffers, lods)|
+                (&buffers.indirect.0, buffers.indirect_offset, lods.bucket_count() as u64*IndirectBuffer::DRAW_SIZE)
+            ),
+            CornReadbackBuffer::Votes(_) => view().map(|buffers| (&buffers.vote, buffers.vote_offset, count*8))
+        }
+    }
+
+    fn data(&self, bytes: &[u8]) -> CornReadbackData{
+        match self.request.buffer{
+            CornReadbackBuffer::Instances => CornReadbackData::Instances(bytemuck::cast_slice::<u8, CornData>(bytes).to_vec()),
+            CornReadbackBuffer::Indirect(_) => CornReadbackData::Indirect(
+                bytemuck::cast_slice::<u8, u32>(bytes).chunks_exact((IndirectBuffer::DRAW_SIZE/4) as usize)
+                    .map(|draw| [draw[0], draw[1], draw[2], draw[3], draw[4]]).collect()
+            ),
+            CornReadbackBuffer::Votes(_) => CornReadbackData::Votes(
+                bytemuck::cast_slice::<u8, [u32; 2]>(bytes).iter().map(|vote| UVec2::from_array(*vote)).collect()
+            )
+        }
+    }
+}
+
+/// Readbacks which have been requested but not yet sent back
+#[derive(Default, Debug, Resource)]
+struct CornReadbacks(Vec<CornReadback>);
+impl CornReadbacks{
+    fn extract(
+        mut requests: Extract<EventReader<CornReadbackRequest>>,
+        entities: Extract<Query<&RenderEntity>>,
+        main_camera: Extract<Query<&RenderEntity, With<MainCamera>>>,
+        mut readbacks: ResMut<Self>
+    ){
+        for request in requests.read(){
+            let Ok(field) = entities.get(request.field) else {continue;};
+            let view = match request.buffer{
+                CornReadbackBuffer::Instances => None,
+                CornReadbackBuffer::Indirect(camera) | CornReadbackBuffer::Votes(camera) => {
+                    let view = match camera{
+                        Some(camera) => entities.get(camera).ok(),
+                        None => main_camera.get_single().ok()
+                    };
+                    // Without a view the buffer is never scanned, and the readback would wait forever
+                    let Some(view) = view else {
+                        warn!("Dropped corn readback of {:?} for field {}: its camera doesn't exist or isn't rendered", request.buffer, request.field);
+                        continue;
+                    };
+                    Some(view.id())
+                }
+            };
+            readbacks.0.push(CornReadback{request: *request, field: field.id(), view, staging: None});
+        }
+    }
+
+    /// Sends the readbacks which have been mapped, then copies the buffers of new readbacks, once the frame is submitted
+    fn update(world: &mut World){
+        world.resource_scope(|world, mut readbacks: Mut<Self>| {
+            let render_device = world.resource::<RenderDevice>();
+            let render_queue = world.resource::<RenderQueue>();
+            let channel = world.resource::<CornReadbackChannel>();
+            render_device.poll(Maintain::Poll);
+            // Requests whose field or view is gone will never be read back
+            readbacks.0.retain(|readback| world.get_entity(readback.field).is_ok() && readback.view.is_none_or(|view| world.get_entity(view).is_ok()));
+            readbacks.0.retain(|readback| {
+                let Some((buffer, mapped)) = &readback.staging else {return true;};
+                if !mapped.load(Ordering::Relaxed) {return true;}
+                let data = readback.data(&buffer.slice(..).get_mapped_range());
+                buffer.unmap();
+                if let Ok(mut complete) = channel.0.lock() {
+                    complete.push(CornReadbackComplete{field: readback.request.field, buffer: readback.request.buffer, data});
+                }
+                false
+            });
+            let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor{label: Some("Corn Readback")});
+            let mut copied = vec![];
+            for (index, readback) in readbacks.0.iter_mut().enumerate(){
+                if readback.staging.is_some() {continue;}
+                let Some((source, offset, size)) = readback.source(world).filter(|(_, _, size)| *size > 0) else {continue;};
+                let buffer = render_device.create_buffer(&BufferDescriptor{
+                    label: Some("Corn Readback Staging Buffer"),
+                    size,
+                    usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                    mapped_at_creation: false
+                });
+                encoder.copy_buffer_to_buffer(source, offset, &buffer, 0, size);
+                readback.staging = Some((buffer, Arc::default()));
+                copied.push(index);
+            }
+            if copied.is_empty() {return;}
+            render_queue.submit([encoder.finish()]);
+            for index in copied{
+                let Some((buffer, mapped)) = &readbacks.0[index].staging else {continue;};
+                let mapped = mapped.clone();
+                buffer.slice(..).map_async(MapMode::Read, move |result| {
+                    if result.is_ok() {mapped.store(true, Ordering::Relaxed);}
+                });
+            }
+        });
+    }
+}
+
+/// Adds non blocking readback of corn field buffers
+pub struct CornReadbackPlugin;
+impl Plugin for CornReadbackPlugin{
+    fn build(&self, app: &mut App) {
+        let channel = CornReadbackChannel::default();
+        app
+            .add_event::<CornReadbackRequest>()
+            .add_event::<CornReadbackComplete>()
+            .insert_resource(channel.clone())
+            .add_systems(PreUpdate, CornReadbackChannel::send_events)
+        .sub_app_mut(RenderApp)
+            .insert_resource(channel)
+            .init_resource::<CornReadbacks>()
+            .add_systems(ExtractSchedule, CornReadbacks::extract)
+            .add_systems(Render, CornReadbacks::update.in_set(RenderSet::Cleanup));
+    }
+}
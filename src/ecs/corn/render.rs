@@ -1,8 +1,11 @@
 use crate::util::{observer_ext::ObserveAsAppExt, specialized_material::{SpecializedDrawMaterial, SpecializedDrawPrepass, SpecializedMaterialPlugin}};
-use super::{CornData, CornField, CornFieldObserver, CornLoaded, IndirectBuffer, VertexInstanceBuffer, LOD_COUNT};
+use super::{
+    asset::CornModelLods, billboard::ExtractedCornBillboard, diagnostics::CornGpuTimings, downlevel::{CornMeshIndexBuffer, CornMeshIndexLayout, CornRenderFeatures}, scan_prepass::vote::{VoteScanBuffers, VoteScanViewBuffers},
+    CornData, CornField, CornFieldObserver, CornLoaded, IndirectBuffer, VertexInstanceBuffer
+};
 use bevy::{
-    asset::Asset, ecs::{query::ROQueryItem, system::{lifetimeless::{Read, SRes}, SystemParamItem}}, log::Level, pbr::{ExtendedMaterial, MaterialExtension, RenderMeshInstances, StandardMaterial}, prelude::*, reflect::Reflect, render::{
-        mesh::{allocator::MeshAllocator, RenderMesh, RenderMeshBufferInfo}, render_asset::RenderAssets, render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass}, render_resource::{AsBindGroup, ShaderDefVal, VertexBufferLayout}
+    asset::Asset, ecs::{query::ROQueryItem, system::{lifetimeless::{Read, SQuery, SRes}, SystemParamItem}}, log::Level, pbr::{ExtendedMaterial, MaterialExtension, RenderMeshInstances, StandardMaterial}, prelude::*, reflect::Reflect, render::{
+        mesh::{allocator::MeshAllocator, RenderMesh, RenderMeshBufferInfo}, render_asset::RenderAssets, render_phase::{PhaseItem, RenderCommand, RenderCommandResult, TrackedRenderPass}, render_resource::{AsBindGroup, AsBindGroupError, BindGroupLayout, BindGroupLayoutEntry, ShaderDefVal, UnpreparedBindGroup, VertexBufferLayout}, renderer::RenderDevice
     }, utils::tracing::event
 };
 use wgpu::{vertex_attr_array, IndexFormat, PushConstantRange, ShaderStages};
@@ -34,7 +37,7 @@ pub fn replace_standard_materials(
 ){
     let Ok((entity, material)) = query.get(trigger.entity()) else {return;};
     let Some(material) = std_mats.get(material.id()) else {error!("Std Material on Corn Field is not Loaded, wierd"); return;};
-    let extd_mat = ExtendedMaterial{base: material.clone(), extension: CornMaterialExtension{}};
+    let extd_mat = ExtendedMaterial{base: material.clone(), extension: CornMaterialExtension::default()};
     let handle = assets.add(extd_mat);
     commands.entity(entity).remove::<MeshMaterial3d<StandardMaterial>>().insert(MeshMaterial3d(handle));
 }
@@ -42,14 +45,56 @@ pub fn replace_standard_materials(
 pub trait ExtendWithCornMaterial: Material{fn extend_with_corn(self) -> ExtendedMaterial<Self, CornMaterialExtension>;}
 impl<M: Material> ExtendWithCornMaterial for M {
     fn extend_with_corn(self) -> ExtendedMaterial<Self, CornMaterialExtension> {
-        ExtendedMaterial { base: self, extension: CornMaterialExtension {} }
+        ExtendedMaterial { base: self, extension: CornMaterialExtension::default() }
     }
 }
 
 /// A material extension for the corn. Adds our instance buffer as a vertex buffer,
 /// adds a shaderdef enabling our instanced code
-#[derive(Default, Clone, AsBindGroup, Asset, Reflect)]
-pub struct CornMaterialExtension{}
+#[derive(Default, Clone, Asset, Reflect)]
+pub struct CornMaterialExtension{
+    /// Draws camera facing billboards, sampling their variant's cell of the base color texture. Used with `AlphaMode::Mask`
+    pub billboard: bool
+}
+impl CornMaterialExtension{
+    /// Layout of the vertex instance buffer, one field to world matrix per stalk in locations 8 to 11
+    pub fn instance_buffer_layout() -> VertexBufferLayout{
+        VertexBufferLayout {
+            array_stride: CornData::VERTEX_DATA_SIZE,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: vertex_attr_array![8 => Float32x4, 9 => Float32x4, 10 => Float32x4, 11 => Float32x4].to_vec(),
+        }
+    }
+}
+
+/// Pipeline key of corn materials
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CornMaterialKey{
+    billboard: bool,
+    /// Layout of the mesh index uniform, when the render device has no push constants
+    mesh_index_layout: Option<CornMeshIndexLayout>
+}
+/// The extension has no bindings of its own. Its bind group data carries the mesh index layout from the render world to specialization
+impl AsBindGroup for CornMaterialExtension{
+    type Data = CornMaterialKey;
+    type Param = Option<SRes<CornMeshIndexLayout>>;
+
+    fn unprepared_bind_group(
+        &self,
+        _layout: &BindGroupLayout,
+        _render_device: &RenderDevice,
+        mesh_index_layout: &mut SystemParamItem<'_, '_, Self::Param>,
+    ) -> Result<UnpreparedBindGroup<Self::Data>, AsBindGroupError> {
+        Ok(UnpreparedBindGroup{
+            bindings: vec![],
+            data: CornMaterialKey{billboard: self.billboard, mesh_index_layout: mesh_index_layout.as_deref().cloned()}
+        })
+    }
+
+    fn bind_group_layout_entries(_render_device: &RenderDevice) -> Vec<BindGroupLayoutEntry> {
+        vec![]
+    }
+}
 impl MaterialExtension for CornMaterialExtension {
     fn vertex_shader() -> bevy::render::render_resource::ShaderRef {
         shaders::INSTANCED_VERTEX.into()
@@ -65,18 +110,24 @@ impl MaterialExtension for CornMaterialExtension {
         _pipeline: &bevy::pbr::MaterialExtensionPipeline,
         descriptor: &mut bevy::render::render_resource::RenderPipelineDescriptor,
         _layout: &bevy::render::mesh::MeshVertexBufferLayoutRef,
-        _key: bevy::pbr::MaterialExtensionKey<Self>,
+        key: bevy::pbr::MaterialExtensionKey<Self>,
     ) -> Result<(), bevy::render::render_resource::SpecializedMeshPipelineError> {
         descriptor
             .vertex
             .shader_defs
             .push(ShaderDefVal::Bool("CORN_INSTANCED".to_string(), true));
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: CornData::VERTEX_DATA_SIZE,
-            step_mode: wgpu::VertexStepMode::Instance,
-            attributes: vertex_attr_array![8 => Float32x4, 9 => Float32x4, 10 => Float32x4, 11 => Float32x4].to_vec(),
-        });
-        descriptor.push_constant_ranges.push(PushConstantRange{stages: ShaderStages::VERTEX, range: 0..4});
+        if key.bind_group_data.billboard {
+            descriptor.vertex.shader_defs.push(ShaderDefVal::Bool("CORN_BILLBOARD".to_string(), true));
+        }
+        descriptor.vertex.buffers.push(Self::instance_buffer_layout());
+        // Without push constants, the mesh index is a uniform bound at its offset by `DrawCorn`
+        match key.bind_group_data.mesh_index_layout{
+            Some(CornMeshIndexLayout(layout)) => {
+                descriptor.vertex.shader_defs.push(ShaderDefVal::Bool("CORN_NO_PUSH_CONSTANTS".to_string(), true));
+                descriptor.layout.push(layout);
+            },
+            None => descriptor.push_constant_ranges.push(PushConstantRange{stages: ShaderStages::VERTEX, range: 0..4})
+        }
         Ok(())
     }
 }
@@ -87,19 +138,37 @@ impl<P: PhaseItem> RenderCommand<P> for DrawCorn {
         SRes<RenderAssets<RenderMesh>>,
         SRes<RenderMeshInstances>,
         SRes<MeshAllocator>,
+        SQuery<(Read<VoteScanBuffers>, Read<CornModelLods>), With<CornLoaded>>,
+        Option<SRes<CornGpuTimings>>,
+        SRes<CornRenderFeatures>,
+        Option<SRes<CornMeshIndexBuffer>>
     );
-    type ViewQuery = ();
-    type ItemQuery = (Read<VertexInstanceBuffer>, Read<IndirectBuffer>, Has<CornLoaded>);
+    type ViewQuery = Entity;
+    type ItemQuery = Option<Read<ExtractedCornBillboard>>;
     #[inline]
     fn render<'w>(
         item: &P,
-        _: ROQueryItem<Self::ViewQuery>,
+        view: ROQueryItem<Self::ViewQuery>,
         entity_query: Option<ROQueryItem<'w, Self::ItemQuery>>,
-        (meshes, mesh_instances, mesh_allocator): SystemParamItem<'w, '_, Self::Param>,
+        (meshes, mesh_instances, mesh_allocator, fields, timings, features, mesh_index): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        let Some((VertexInstanceBuffer(instance_buffer), IndirectBuffer(indirect_buffer), true)) = entity_query.as_ref() 
-        else {return RenderCommandResult::Skip;};
+        // Fields draw every lod of every variant, and their billboard entity draws the billboard bucket after them.
+        // Both draw from the field's part of the buffers it was culled into for this view, unless the whole field is outside it
+        let billboard = entity_query.flatten();
+        let field = billboard.map_or(item.entity(), |ExtractedCornBillboard(field)| *field);
+        let Ok((VoteScanBuffers(buffers), lods)) = fields.get_inner(field) else {return RenderCommandResult::Skip;};
+        let Some(VoteScanViewBuffers{
+            vertex: VertexInstanceBuffer(instance_buffer), vertex_offset, indirect: IndirectBuffer(indirect_buffer), indirect_offset, bucket_stride, ..
+        }) = buffers.get(&view).filter(|buffers| buffers.vertex_offset < buffers.vertex.0.size()) else {return RenderCommandResult::Skip;};
+        let (first_bucket, count) = match billboard{
+            Some(_) => (lods.billboard_bucket(), 1),
+            None => (0, lods.billboard_bucket())
+        };
+        let offset = indirect_offset + first_bucket as u64*IndirectBuffer::DRAW_SIZE;
+        // Without indirect first instances, each bucket is drawn with the vertex instances bound at the start of its own part of them
+        let multi_draw = features.multi_draw_indirect && *bucket_stride == 0;
+        let bucket_instances = |draw: u64| instance_buffer.slice(vertex_offset + (first_bucket as u64 + draw)*bucket_stride..);
 
         let meshes = meshes.into_inner();
         let mesh_instances = mesh_instances.into_inner();
@@ -118,8 +187,14 @@ impl<P: PhaseItem> RenderCommand<P> for DrawCorn {
         };
 
         pass.set_vertex_buffer(0, vertex_buffer_slice.buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.slice(..));
-        pass.set_push_constants(ShaderStages::VERTEX, 0, bytemuck::cast_slice(&[item.batch_range().start]));
+        pass.set_vertex_buffer(1, instance_buffer.slice(*vertex_offset..));
+        match mesh_index{
+            Some(mesh_index) => {
+                let Some(offset) = mesh_index.offset(item.batch_range().start) else {return RenderCommandResult::Skip;};
+                pass.set_bind_group(3, &mesh_index.into_inner().bind_group, &[offset]);
+            },
+            None => pass.set_push_constants(ShaderStages::VERTEX, 0, bytemuck::cast_slice(&[item.batch_range().start]))
+        }
 
         // Draw either directly or indirectly, as appropriate.
         match &gpu_mesh.buffer_info {
@@ -138,11 +213,29 @@ impl<P: PhaseItem> RenderCommand<P> for DrawCorn {
                 };
                 pass.set_index_buffer(index_buffer_slice.buffer.slice(start..end), 0, *index_format);
                 event!(Level::TRACE, "Rendering Corn, indexed: {}", true);
-                pass.multi_draw_indexed_indirect(indirect_buffer, 0, LOD_COUNT);
+                let span = timings.as_ref().and_then(|timings| timings.begin_draw(pass));
+                if multi_draw {
+                    pass.multi_draw_indexed_indirect(indirect_buffer, offset, count);
+                } else {
+                    for draw in 0..count as u64 {
+                        if *bucket_stride != 0 {pass.set_vertex_buffer(1, bucket_instances(draw));}
+                        pass.draw_indexed_indirect(indirect_buffer, offset + draw*IndirectBuffer::DRAW_SIZE);
+                    }
+                }
+                if let Some(timings) = timings {timings.end_draw(pass, span);}
             }
             RenderMeshBufferInfo::NonIndexed => {
                 event!(Level::TRACE, "Rendering Corn, indexed: {}", false);
-                pass.multi_draw_indirect(indirect_buffer, 0, LOD_COUNT);
+                let span = timings.as_ref().and_then(|timings| timings.begin_draw(pass));
+                if multi_draw {
+                    pass.multi_draw_indirect(indirect_buffer, offset, count);
+                } else {
+                    for draw in 0..count as u64 {
+                        if *bucket_stride != 0 {pass.set_vertex_buffer(1, bucket_instances(draw));}
+                        pass.draw_indirect(indirect_buffer, offset + draw*IndirectBuffer::DRAW_SIZE);
+                    }
+                }
+                if let Some(timings) = timings {timings.end_draw(pass, span);}
             }
         }
         RenderCommandResult::Success
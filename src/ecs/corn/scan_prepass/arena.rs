@@ -0,0 +1,217 @@
+//! Instance arena. The stalks of every loaded corn field are copied into one large buffer, so the vote scan can cull
+//! every field in the same dispatches. Fields keep their own instance buffer, which init shaders, heightmaps and edits write to,
+//! and the arena copies a field again whenever it's written to.
+//!
+//! Each field is given a slot by a first fit allocator, so loading or unloading a field only copies that field.
+//! The arena doubles when a field doesn't fit, and is packed into a smaller buffer once it's mostly empty.
+use std::ops::Range;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet
+    },
+    utils::HashMap
+};
+use super::super::{edit::CornEdits, init::heightmap::HeightmapApplied, CornData, CornLoaded, InstanceBuffer};
+
+/// Where a corn field's stalks are in the instance arena
+#[derive(Debug, Clone)]
+pub struct ArenaSlot{
+    /// First stalk of the field
+    pub offset: u64,
+    pub count: u64,
+    /// Instance buffer the stalks are copied from
+    source: Buffer
+}
+
+/// First fit allocator of the stalks in the instance arena
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ArenaAllocator{
+    /// Free stalks, sorted and never touching each other
+    free: Vec<Range<u64>>,
+    capacity: u64
+}
+impl ArenaAllocator{
+    pub fn new(capacity: u64) -> Self{
+        Self{free: (capacity > 0).then_some(0..capacity).into_iter().collect(), capacity}
+    }
+
+    pub fn capacity(&self) -> u64 {self.capacity}
+
+    /// Stalks in use
+    pub fn used(&self) -> u64{
+        self.capacity - self.free.iter().map(|range| range.end - range.start).sum::<u64>()
+    }
+
+    /// First stalk of the first free range which fits `count` stalks
+    pub fn allocate(&mut self, count: u64) -> Option<u64>{
+        if count == 0 {return Some(0);}
+        let index = self.free.iter().position(|range| range.end - range.start >= count)?;
+        let offset = self.free[index].start;
+        self.free[index].start += count;
+        if self.free[index].is_empty() {self.free.remove(index);}
+        Some(offset)
+    }
+
+    /// Allocates `count` stalks, doubling the capacity until they fit
+    pub fn allocate_or_grow(&mut self, count: u64) -> u64{
+        loop{
+            if let Some(offset) = self.allocate(count) {return offset;}
+            self.grow((self.capacity*2).max(count.next_power_of_two()));
+        }
+    }
+
+    /// Returns stalks to the allocator, merging them with the free ranges around them
+    pub fn free(&mut self, offset: u64, count: u64){
+        if count == 0 {return;}
+        let index = self.free.partition_point(|range| range.start < offset);
+        self.free.insert(index, offset..offset + count);
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start{
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start{
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+
+    /// Adds free stalks at the end, up to `capacity`
+    pub fn grow(&mut self, capacity: u64){
+        if capacity <= self.capacity {return;}
+        let old = std::mem::replace(&mut self.capacity, capacity);
+        self.free(old, capacity - old);
+    }
+}
+
+/// Instance buffer holding the stalks of every loaded corn field
+#[derive(Default, Debug, Resource)]
+pub struct CornInstanceArena{
+    pub buffer: Option<Buffer>,
+    pub slots: HashMap<Entity, ArenaSlot>,
+    pub allocator: ArenaAllocator,
+    /// Incremented whenever a slot is added, removed or moved, so everything laid out from the slots is rebuilt
+    pub generation: u32
+}
+impl CornInstanceArena{
+    /// Smallest capacity the arena shrinks to, in stalks
+    pub const MIN_CAPACITY: u64 = 1 << 16;
+
+    /// Gives new fields a slot and frees the slots of unloaded ones, then copies the stalks of new and updated fields into the arena
+    pub fn prepare(
+        arena: ResMut<Self>,
+        fields: Query<(Entity, &InstanceBuffer, Option<Ref<CornEdits>>, Option<Ref<HeightmapApplied>>), With<CornLoaded>>,
+        render_device: Res<RenderDevice>,
+        render_queue: Res<RenderQueue>
+    ){
+        let Self{buffer, slots, allocator, generation} = arena.into_inner();
+        let mut changed = false;
+        // Free the slots of fields which were unloaded, or whose instance buffer was replaced
+        slots.retain(|entity, slot| {
+            let keep = fields.get(*entity).is_ok_and(|(_, InstanceBuffer(source, count), _, _)| slot.source.id() == source.id() && slot.count == *count);
+            if !keep {allocator.free(slot.offset, slot.count); changed = true;}
+            keep
+        });
+        let added: u64 = fields.iter()
+            .filter(|(entity, ..)| !slots.contains_key(entity))
+            .map(|(_, InstanceBuffer(_, count), _, _)| count).sum();
+        // Pack the fields into a smaller buffer once most of the arena is free
+        let needed = allocator.used() + added;
+        let mut rebuilt = false;
+        if allocator.capacity() > Self::MIN_CAPACITY && needed*4 < allocator.capacity(){
+            slots.clear();
+            *allocator = ArenaAllocator::new((needed*2).next_power_of_two().max(Self::MIN_CAPACITY));
+            rebuilt = true;
+        }
+        let mut new_slots = vec![];
+        for (entity, InstanceBuffer(source, count), _, _) in fields.iter(){
+            if slots.contains_key(&entity) {continue;}
+            let offset = allocator.allocate_or_grow(*count);
+            slots.insert(entity, ArenaSlot{offset, count: *count, source: source.clone()});
+            new_slots.push(entity);
+            changed = true;
+        }
+        if buffer.as_ref().is_none_or(|buffer| buffer.size() != allocator.capacity().max(1)*CornData::DATA_SIZE){
+            *buffer = Some(render_device.create_buffer(&BufferDescriptor{
+                label: Some("Corn Instance Arena"),
+                size: allocator.capacity().max(1)*CornData::DATA_SIZE,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+                mapped_at_creation: false
+            }));
+            rebuilt = true;
+        }
+        if changed || rebuilt {*generation = generation.wrapping_add(1);}
+        let Some(buffer) = buffer.as_ref() else {return;};
+        let mut encoder = render_device.create_command_encoder(&CommandEncoderDescriptor{label: Some("Corn Instance Arena Copy")});
+        let mut copied = false;
+        for (entity, _, edits, heightmap) in fields.iter(){
+            let Some(slot) = slots.get(&entity) else {continue;};
+            // A new buffer has none of the stalks. Heightmaps are applied to the whole field, edits only patch a few stalks
+            if rebuilt || new_slots.contains(&entity) || heightmap.is_some_and(|applied| applied.is_added()) {
+                if slot.count == 0 {continue;}
+                encoder.copy_buffer_to_buffer(&slot.source, 0, buffer, slot.offset*CornData::DATA_SIZE, slot.count*CornData::DATA_SIZE);
+                copied = true;
+                continue;
+            }
+            let Some(edits) = edits.filter(|edits| edits.is_changed()) else {continue;};
+            for patch in edits.0.iter(){
+                if patch.start as u64 + patch.data.len() as u64 > slot.count {continue;}
+                render_queue.write_buffer(
+                    buffer,
+                    (slot.offset + patch.start as u64)*CornData::DATA_SIZE,
+                    bytemuck::cast_slice(patch.data.as_slice())
+                );
+            }
+        }
+        if copied {render_queue.submit([encoder.finish()]);}
+    }
+}
+
+/// Packs every loaded corn field into the instance arena
+pub struct CornInstanceArenaPlugin;
+impl Plugin for CornInstanceArenaPlugin{
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .init_resource::<CornInstanceArena>()
+            .add_systems(Render, CornInstanceArena::prepare.in_set(RenderSet::PrepareResources).after(CornEdits::write_edits));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn freed_slots_are_reused_first_fit(){
+        let mut allocator = ArenaAllocator::new(100);
+        let a = allocator.allocate(30).unwrap();
+        let b = allocator.allocate(30).unwrap();
+        let c = allocator.allocate(30).unwrap();
+        assert_eq!((a, b, c), (0, 30, 60));
+        assert_eq!(allocator.allocate(20), None);
+        allocator.free(b, 30);
+        assert_eq!(allocator.allocate(20), Some(30));
+        assert_eq!(allocator.allocate(10), Some(50));
+        assert_eq!(allocator.used(), 90);
+    }
+
+    #[test]
+    fn freed_neighbours_merge(){
+        let mut allocator = ArenaAllocator::new(90);
+        let offsets: Vec<u64> = (0..3).map(|_| allocator.allocate(30).unwrap()).collect();
+        allocator.free(offsets[0], 30);
+        allocator.free(offsets[2], 30);
+        allocator.free(offsets[1], 30);
+        assert_eq!(allocator, ArenaAllocator::new(90));
+    }
+
+    #[test]
+    fn allocations_grow_the_arena(){
+        let mut allocator = ArenaAllocator::new(64);
+        allocator.allocate(60).unwrap();
+        assert_eq!(allocator.allocate_or_grow(10), 60);
+        assert_eq!(allocator.capacity(), 128);
+        assert_eq!(allocator.allocate_or_grow(1000), 70);
+        assert_eq!(allocator.capacity(), 2048);
+    }
+}
@@ -0,0 +1,243 @@
+//! Hierarchical-Z occlusion culling. After the depth prepass of a camera with `CornOcclusionCulling`, its depth is reduced into
+//! a pyramid where each texel holds the farthest depth below it. The next frame's vote scan projects each stalk's bounds with
+//! the matrix the pyramid was rendered with, and drops stalks which are behind the farthest depth they cover.
+//!
+//! Mip 0 is half the size of the depth texture, rounded up to a power of two, so every mip exactly halves the one before it.
+use super::super::diagnostics::{CornGpuTimings, CornPass};
+use bevy::{
+    core_pipeline::{core_3d::graph::{Core3d, Node3d}, prepass::{DepthPrepass, ViewPrepassTextures}},
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_graph::{NodeRunError, RenderGraphApp, RenderGraphContext, RenderLabel, ViewNode, ViewNodeRunner},
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice},
+        view::ExtractedView, Render, RenderApp, RenderSet
+    }
+};
+
+/// Culls corn hidden behind nearer geometry in a camera's view, using the depth prepass of its previous frame
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect, Component, ExtractComponent)]
+#[reflect(Component)]
+#[require(DepthPrepass)]
+pub struct CornOcclusionCulling;
+
+/// Depth pyramid of a view
+#[derive(Debug, Clone, Component)]
+pub struct HiZPyramid{
+    pub texture: Texture,
+    /// View of every mip, read by the vote scan
+    pub view: TextureView,
+    /// View of each mip, written while building the pyramid
+    pub mips: Vec<TextureView>,
+    /// Size of the depth texture the pyramid is built from
+    pub depth_size: UVec2,
+    /// Clip from world matrix of the depth currently in the pyramid, a frame behind the view
+    pub clip_from_world: Mat4,
+    /// Whether the pyramid has been built at least once
+    pub ready: bool,
+    next_clip_from_world: Mat4
+}
+impl HiZPyramid{
+    pub const FORMAT: TextureFormat = TextureFormat::R32Float;
+
+    /// Size of mip 0 for a depth texture
+    pub fn mip_size(depth_size: UVec2) -> UVec2{
+        UVec2::new(depth_size.x.div_ceil(2).next_power_of_two(), depth_size.y.div_ceil(2).next_power_of_two())
+    }
+
+    pub fn mip_count(&self) -> u32{
+        self.mips.len() as u32
+    }
+
+    /// Creates pyramids for views with occlusion culling, recreating them when the depth texture is resized
+    fn prepare(
+        mut views: Query<(Entity, &ExtractedView, &ViewPrepassTextures, Option<&mut Self>), With<CornOcclusionCulling>>,
+        render_device: Res<RenderDevice>,
+        mut commands: Commands
+    ){
+        for (entity, view, prepass, pyramid) in views.iter_mut(){
+            let depth_size = UVec2::new(prepass.size.width, prepass.size.height);
+            let clip_from_world = view.clip_from_view*view.world_from_view.compute_matrix().inverse();
+            if let Some(mut pyramid) = pyramid.filter(|pyramid| pyramid.depth_size == depth_size){
+                pyramid.clip_from_world = std::mem::replace(&mut pyramid.next_clip_from_world, clip_from_world);
+                pyramid.ready = true;
+                continue;
+            }
+            let size = Self::mip_size(depth_size);
+            let mip_count = size.max_element().ilog2() + 1;
+            let texture = render_device.create_texture(&TextureDescriptor{
+                label: Some("Corn Hi-Z Pyramid"),
+                size: Extent3d{width: size.x, height: size.y, depth_or_array_layers: 1},
+                mip_level_count: mip_count,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: Self::FORMAT,
+                usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+                view_formats: &[]
+            });
+            let view = texture.create_view(&TextureViewDescriptor::default());
+            let mips = (0..mip_count).map(|mip| texture.create_view(&TextureViewDescriptor{
+                label: Some("Corn Hi-Z Pyramid Mip"),
+                base_mip_level: mip,
+                mip_level_count: Some(1),
+                ..default()
+            })).collect();
+            commands.entity(entity).insert(Self{
+                texture, view, mips, depth_size, clip_from_world, ready: false, next_clip_from_world: clip_from_world
+            });
+        }
+    }
+}
+
+/// Pipelines building the depth pyramid. The first reduces the depth texture into mip 0, the second downsamples each mip
+#[derive(Debug, Clone, Resource)]
+pub struct HiZPipelines{
+    /// Depth layout, for single and multisampled depth
+    pub depth_layouts: [BindGroupLayout; 2],
+    pub downsample_layout: BindGroupLayout,
+    /// Depth pipelines for single and multisampled depth, then the downsample pipeline
+    pub pipelines: [CachedComputePipelineId; 3]
+}
+impl FromWorld for HiZPipelines{
+    fn from_world(world: &mut World) -> Self {
+        let shader: Handle<Shader> = world.resource::<AssetServer>().load("shaders/corn/hi_z.wgsl");
+        let render_device = world.resource::<RenderDevice>();
+        let output = BindGroupLayoutEntry{
+            binding: 1,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::StorageTexture{
+                access: StorageTextureAccess::WriteOnly,
+                format: HiZPyramid::FORMAT,
+                view_dimension: TextureViewDimension::D2
+            },
+            count: None
+        };
+        let depth_layouts = [false, true].map(|multisampled| render_device.create_bind_group_layout(
+            Some("Corn Hi-Z Depth Layout"),
+            &[
+                BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture{sample_type: TextureSampleType::Depth, view_dimension: TextureViewDimension::D2, multisampled},
+                    count: None
+                },
+                output
+            ]
+        ));
+        let downsample_layout = render_device.create_bind_group_layout(
+            Some("Corn Hi-Z Downsample Layout"),
+            &[
+                BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Texture{
+                        sample_type: TextureSampleType::Float{filterable: false}, view_dimension: TextureViewDimension::D2, multisampled: false
+                    },
+                    count: None
+                },
+                output
+            ]
+        );
+        let cache = world.resource::<PipelineCache>();
+        let queue = |layout: &BindGroupLayout, shader_defs: Vec<ShaderDefVal>, entry_point: &'static str| {
+            cache.queue_compute_pipeline(ComputePipelineDescriptor{
+                label: Some("Corn Hi-Z Pipeline".into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: vec![],
+                shader: shader.clone(),
+                shader_defs,
+                entry_point: entry_point.into(),
+                zero_initialize_workgroup_memory: false
+            })
+        };
+        let pipelines = [
+            queue(&depth_layouts[0], vec![], "reduce_depth"),
+            queue(&depth_layouts[1], vec!["MULTISAMPLED".into()], "reduce_depth"),
+            queue(&downsample_layout, vec!["DOWNSAMPLE".into()], "downsample")
+        ];
+        Self{depth_layouts, downsample_layout, pipelines}
+    }
+}
+
+/// Bind groups building a view's depth pyramid, one per mip
+#[derive(Debug, Clone, Component)]
+pub struct HiZBindGroups{
+    pub bind_groups: Vec<BindGroup>,
+    pub multisampled: bool
+}
+impl HiZBindGroups{
+    fn prepare(
+        views: Query<(Entity, &ViewPrepassTextures, &HiZPyramid)>,
+        pipelines: Res<HiZPipelines>,
+        render_device: Res<RenderDevice>,
+        mut commands: Commands
+    ){
+        for (entity, prepass, pyramid) in views.iter(){
+            let Some(depth) = prepass.depth.as_ref() else {continue;};
+            let multisampled = depth.texture.texture.sample_count() > 1;
+            let mut bind_groups = vec![render_device.create_bind_group(
+                Some("Corn Hi-Z Depth Bind Group"),
+                &pipelines.depth_layouts[multisampled as usize],
+                &BindGroupEntries::sequential((&depth.texture.default_view, &pyramid.mips[0]))
+            )];
+            bind_groups.extend(pyramid.mips.windows(2).map(|mips| render_device.create_bind_group(
+                Some("Corn Hi-Z Downsample Bind Group"),
+                &pipelines.downsample_layout,
+                &BindGroupEntries::sequential((&mips[0], &mips[1]))
+            )));
+            commands.entity(entity).insert(Self{bind_groups, multisampled});
+        }
+    }
+}
+
+/// Render graph label of the node building depth pyramids
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq, RenderLabel)]
+pub struct HiZStage;
+/// Builds the depth pyramid of a view, once its prepasses are done
+#[derive(Debug, Default, Clone)]
+pub struct HiZNode;
+impl ViewNode for HiZNode{
+    type ViewQuery = (&'static HiZPyramid, &'static HiZBindGroups);
+    fn run<'w>(
+        &self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext<'w>, (pyramid, bind_groups): QueryItem<'w, Self::ViewQuery>, world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let pipelines = world.resource::<HiZPipelines>();
+        let cache = world.resource::<PipelineCache>();
+        let Some(depth_pipeline) = cache.get_compute_pipeline(pipelines.pipelines[bind_groups.multisampled as usize]) else {return Ok(());};
+        let Some(downsample_pipeline) = cache.get_compute_pipeline(pipelines.pipelines[2]) else {return Ok(());};
+        let mut compute_pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor{
+            label: Some("Corn Hi-Z Compute Pass"),
+            timestamp_writes: world.get_resource::<CornGpuTimings>().and_then(|timings| timings.compute_pass_writes(CornPass::HiZ))
+        });
+        let size = HiZPyramid::mip_size(pyramid.depth_size);
+        for (mip, bind_group) in bind_groups.bind_groups.iter().enumerate(){
+            compute_pass.set_pipeline(if mip == 0 {depth_pipeline} else {downsample_pipeline});
+            compute_pass.set_bind_group(0, bind_group, &[]);
+            let mip_size = (size >> mip as u32).max(UVec2::ONE);
+            compute_pass.dispatch_workgroups(mip_size.x.div_ceil(8), mip_size.y.div_ceil(8), 1);
+        }
+        Ok(())
+    }
+}
+
+/// Builds depth pyramids for views with `CornOcclusionCulling`
+pub struct HiZPlugin;
+impl Plugin for HiZPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<CornOcclusionCulling>()
+            .add_plugins(ExtractComponentPlugin::<CornOcclusionCulling>::default())
+        .sub_app_mut(RenderApp)
+            .add_systems(Render, (
+                HiZPyramid::prepare.in_set(RenderSet::PrepareResources),
+                HiZBindGroups::prepare.in_set(RenderSet::PrepareBindGroups)
+            ))
+            .add_render_graph_node::<ViewNodeRunner<HiZNode>>(Core3d, HiZStage)
+            .add_render_graph_edges(Core3d, (Node3d::EndPrepasses, HiZStage, Node3d::StartMainPass));
+    }
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp).init_resource::<HiZPipelines>();
+    }
+}
@@ -1,4 +1,6 @@
+pub mod arena;
 pub mod vote;
+pub mod hi_z;
 
 use bevy::prelude::*;
 
@@ -6,6 +8,6 @@ use bevy::prelude::*;
 pub struct ScanPrepassPlugin;
 impl Plugin for ScanPrepassPlugin{
     fn build(&self, app: &mut App) {
-        app.add_plugins(vote::VoteScanPlugin);
+        app.add_plugins((arena::CornInstanceArenaPlugin, vote::VoteScanPlugin, hi_z::HiZPlugin));
     }
 }
\ No newline at end of file
@@ -1,20 +1,28 @@
-//! Traditional Scan Prepass Algorithm. Data is stored in an instance buffer, voted on, and then copied to a vertex buffer
-use std::num::NonZero;
+//! Traditional Scan Prepass Algorithm. Data is stored in an instance buffer, voted on, and then copied to a vertex buffer.
+//!
+//! Stalks are read from the instance arena, and every field sharing a model layout is scanned into the same buffers for each view,
+//! described by its entry in a table of scans. Each stage is a single dispatch per view and model layout, regardless of field count
 use bevy::{
-    core_pipeline::core_3d::graph::Core3d, 
-    ecs::system::lifetimeless::Read, 
-    pbr::{graph::NodePbr, RenderMeshInstances}, 
+    core_pipeline::core_3d::{graph::{Core3d, Node3d}, Camera3d}, 
+    ecs::{query::QueryItem, system::lifetimeless::Read}, 
+    pbr::{graph::NodePbr, RenderMeshInstances, ViewLightEntities}, 
     prelude::*, 
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin}, mesh::allocator::MeshAllocator, render_graph::*, render_resource::*, 
-        renderer::{RenderContext, RenderDevice}, sync_world::MainEntity, view::ExtractedView, Render, RenderApp, RenderSet
-    }
+        renderer::{RenderContext, RenderDevice, RenderQueue}, sync_world::MainEntity, view::ExtractedView, Render, RenderApp, RenderSet
+    },
+    utils::HashMap
 };
 use bytemuck::{Pod, Zeroable};
 use wgpu::{BindGroupLayoutEntry, BindingType, BufferBindingType, ShaderStages};
 use wgpu_types::BufferDescriptor;
-use crate::ecs::{cameras::MainCamera, corn::CornField};
-use super::super::{CornLoaded, GlobalLodCutoffs, IndirectBuffer, InstanceBuffer, VertexInstanceBuffer, LOD_COUNT};
+use crate::ecs::corn::CornField;
+use super::{arena::CornInstanceArena, hi_z::HiZPyramid};
+use super::super::diagnostics::{CornGpuTimings, CornPass};
+use super::super::{
+    bounds::CornFieldAabb, asset::{CornModelBounds, CornModelLods}, billboard::CornFieldBillboard, downlevel::CornRenderFeatures, fit_lod_cutoffs,
+    CornData, CornLoaded, GlobalLodCutoffs, IndirectBuffer, ShadowLodCutoffs, VertexInstanceBuffer
+};
 
 #[derive(Default, Debug, Clone, PartialEq, Reflect, Component)]
 pub struct CornFieldTransform(pub Transform);
@@ -25,53 +33,86 @@ impl ExtractComponent for CornFieldTransform{
     fn extract_component(item: bevy::ecs::query::QueryItem<'_, Self::QueryData>) -> Option<Self::Out> {Some(Self(item.compute_transform()))}
 }
 
-/// Struct mirroring the config data needed for the vote-scan-compact shaders. Passed in as a buffer
+/// Struct mirroring the config data needed for the vote-scan-compact shaders, at the start of each scan
 #[derive(Clone, Copy, Default, Debug, Zeroable, Pod, ShaderType)]
 #[repr(C)]
 pub struct ConfigData{
     field_to_world: Mat4,
     field_to_clip: Mat4,
-    cam_pos_field: Vec4
+    cam_pos_field: Vec4,
+    /// Field object space to the clip space of the depth in the Hi-Z pyramid, which lags a frame behind
+    field_to_occlusion_clip: Mat4,
+    /// Depth texture size, pyramid mip count, and whether occlusion culling is enabled
+    occlusion: Vec4,
+    /// Width and height of the corn model, then whether the view is a shadow view
+    stalk_bounds: Vec4
 }
-impl ConfigData{
-    const DATA_SIZE: NonZero<u64> = NonZero::new(144).unwrap();
+
+/// Struct mirroring a `Scan` of the vote-scan-compact shaders, up to its lod cutoffs. One per field scanned in a view
+#[derive(Clone, Copy, Default, Debug, Zeroable, Pod)]
+#[repr(C)]
+pub struct ScanDescriptor{
+    config: ConfigData,
+    /// First stalk of the field in the instance arena, and its stalk count
+    instance_offset: u32,
+    instance_count: u32,
+    /// First vote, first level count and second level count of the scan
+    vote_offset: u32,
+    count_offset: u32,
+    count_offset_2: u32,
+    /// First indirect draw and vertex instance of the field
+    draw_offset: u32,
+    vertex_instance_offset: u32,
+    /// Vertex offsets of the field's mesh and of the billboard quad mesh
+    vertex_offset: u32,
+    billboard_vertex_offset: u32,
+    billboard_cutoff: f32,
+    thinning_distance: f32,
+    density: f32
+}
+impl ScanDescriptor{
+    /// Unused entry of a table of scans. Its votes and counts start past every workgroup, so no workgroup belongs to it
+    fn unused() -> Self{
+        Self{vote_offset: u32::MAX, count_offset: u32::MAX, ..default()}
+    }
+
+    /// Appends the scan and its lod cutoffs to a table of scans. Storage structs are padded to 16 bytes
+    fn write(&self, lod_cutoffs: &[f32], table: &mut Vec<u8>){
+        table.extend_from_slice(bytemuck::bytes_of(self));
+        table.extend_from_slice(bytemuck::cast_slice(lod_cutoffs));
+        table.resize(table.len().next_multiple_of(16), 0);
+    }
 }
 
 /// Pipeline resources for the 4 vote-scan-compact shaders
 #[derive(Debug, Clone, Resource)]
 pub struct VoteScanPipelineResources{
     pub layout: BindGroupLayout,
-    pub pipelines: Vec<CachedComputePipelineId>,
+    /// Layout of the depth pyramid used for occlusion culling, shared by every field
+    pub occlusion_layout: BindGroupLayout,
+    /// Pyramid bound when occlusion culling is disabled
+    pub fallback_pyramid: TextureView,
+    /// Pipelines specialized for each (lod count, variant count) of the corn models in use
+    pub pipelines: HashMap<(u32, u32), Vec<CachedComputePipelineId>>,
     pub shader: Handle<Shader>
 }
-impl FromWorld for VoteScanPipelineResources{
-    fn from_world(world: &mut World) -> Self {
-        let shader: Handle<Shader> = world.resource::<AssetServer>().load("shaders/corn/scan_prepass.wgsl");
-        let layout = world.resource::<RenderDevice>().create_bind_group_layout(
-            Some("Scan Prepass BindGroup Layout"), 
-            [false, false, false, false, false, false, true].into_iter().enumerate()
-                .map(|(binding, uniform)| BindGroupLayoutEntry{
-                    binding: binding as u32, 
-                    visibility: ShaderStages::COMPUTE,
-                    count: None,
-                    ty: BindingType::Buffer { 
-                        ty: if uniform {BufferBindingType::Uniform} else {BufferBindingType::Storage { read_only: binding==0 }}, 
-                        has_dynamic_offset: false, 
-                        min_binding_size: None 
-                    }
-                }).collect::<Vec<BindGroupLayoutEntry>>().as_slice()
-        );
-        let cache = world.resource::<PipelineCache>();
+impl VoteScanPipelineResources{
+    fn queue_pipelines(&self, cache: &PipelineCache, lod_count: u32, variant_count: u32, features: &CornRenderFeatures) -> Vec<CachedComputePipelineId>{
         let mut pipelines = vec![];
+        let mut shader_defs = vec![
+            ShaderDefVal::UInt("OVERRIDE_LOD_COUNT".to_string(), lod_count),
+            ShaderDefVal::UInt("OVERRIDE_VARIANT_COUNT".to_string(), variant_count)
+        ];
+        if !features.indirect_first_instance {
+            shader_defs.push(ShaderDefVal::Bool("CORN_NO_INDIRECT_FIRST_INSTANCE".to_string(), true));
+        }
         for i in 0..4{
             pipelines.push(cache.queue_compute_pipeline(ComputePipelineDescriptor{
                 label: Some("Scan Prepass Vote Stage".into()),
-                layout: vec![layout.clone()],
-                push_constant_ranges: vec![PushConstantRange{stages: ShaderStages::COMPUTE, range: 0..(4*LOD_COUNT+4)}],
-                shader: shader.clone(),
-                shader_defs: vec![
-                    ShaderDefVal::UInt("OVERRIDE_LOD_COUNT".to_string(), LOD_COUNT)
-                ],
+                layout: vec![self.layout.clone(), self.occlusion_layout.clone()],
+                push_constant_ranges: vec![],
+                shader: self.shader.clone(),
+                shader_defs: shader_defs.clone(),
                 entry_point: match i{
                     0 => "vote_scan",
                     1 => "group_scan",
@@ -81,7 +122,65 @@ impl FromWorld for VoteScanPipelineResources{
                 zero_initialize_workgroup_memory: true
             }));
         }
-        Self{layout, pipelines, shader}
+        pipelines
+    }
+    /// Layout of the buffers of a batch. The instance arena and the table of scans are only read
+    pub fn layout_entries() -> Vec<BindGroupLayoutEntry>{
+        [true, false, false, false, false, false, true].into_iter().enumerate()
+            .map(|(binding, read_only)| BindGroupLayoutEntry{
+                binding: binding as u32, 
+                visibility: ShaderStages::COMPUTE,
+                count: None,
+                ty: BindingType::Buffer { 
+                    ty: BufferBindingType::Storage { read_only }, 
+                    has_dynamic_offset: false, 
+                    min_binding_size: None 
+                }
+            }).collect()
+    }
+    /// Layout of a view's depth pyramid
+    pub fn occlusion_layout_entries() -> Vec<BindGroupLayoutEntry>{
+        vec![BindGroupLayoutEntry{
+            binding: 0,
+            visibility: ShaderStages::COMPUTE,
+            count: None,
+            ty: BindingType::Texture{
+                sample_type: TextureSampleType::Float{filterable: false}, view_dimension: TextureViewDimension::D2, multisampled: false
+            }
+        }]
+    }
+    /// Specializes pipelines for the number of lods and variants in each field's corn model
+    fn specialize(mut resources: ResMut<Self>, query: Query<&CornModelLods, Changed<CornModelLods>>, cache: Res<PipelineCache>, features: Res<CornRenderFeatures>){
+        for lods in query.iter(){
+            let key = (lods.lod_count(), lods.variant_count());
+            if resources.pipelines.contains_key(&key) {continue;}
+            let pipelines = resources.queue_pipelines(cache.as_ref(), key.0, key.1, features.as_ref());
+            resources.pipelines.insert(key, pipelines);
+        }
+    }
+}
+impl FromWorld for VoteScanPipelineResources{
+    fn from_world(world: &mut World) -> Self {
+        let shader: Handle<Shader> = world.resource::<AssetServer>().load("shaders/corn/scan_prepass.wgsl");
+        let layout = world.resource::<RenderDevice>().create_bind_group_layout(
+            Some("Scan Prepass BindGroup Layout"), 
+            &Self::layout_entries()
+        );
+        let occlusion_layout = world.resource::<RenderDevice>().create_bind_group_layout(
+            Some("Scan Prepass Occlusion BindGroup Layout"),
+            &Self::occlusion_layout_entries()
+        );
+        let fallback_pyramid = world.resource::<RenderDevice>().create_texture(&TextureDescriptor{
+            label: Some("Scan Prepass Fallback Hi-Z Pyramid"),
+            size: Extent3d{width: 1, height: 1, depth_or_array_layers: 1},
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: HiZPyramid::FORMAT,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[]
+        }).create_view(&TextureViewDescriptor::default());
+        Self{layout, occlusion_layout, fallback_pyramid, pipelines: HashMap::default(), shader}
     }
 }
 
@@ -90,7 +189,8 @@ impl FromWorld for VoteScanPipelineResources{
 #[reflect(Component)]
 pub enum PerFieldLodCutoffs{
     #[default] Global,
-    Custom([f32; LOD_COUNT as usize])
+    /// Cutoff distance of each lod. Fit to the field's corn model with `fit_lod_cutoffs`
+    Custom(Vec<f32>)
 }
 impl PerFieldLodCutoffs{
     fn insert_default(query: Query<Entity, (With<CornField>, Without<Self>)>, mut commands: Commands){
@@ -100,108 +200,383 @@ impl PerFieldLodCutoffs{
     }
 }
 
-/// Component which holds the vote scan buffers
-#[derive(Component)]
-pub struct VoteScanBuffers{
+/// A view corn is culled and drawn for. Every 3d camera, and the shadow views of its lights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CornView{
+    pub entity: Entity,
+    /// Position lods are picked from. Shadow views use their camera's, with the coarser `ShadowLodCutoffs`
+    pub lod_origin: Vec3,
+    pub shadow: bool
+}
+
+/// Views corn is culled and drawn for this frame
+#[derive(Default, Debug, Clone, Resource)]
+pub struct CornViews(pub Vec<CornView>);
+impl CornViews{
+    fn prepare(mut views: ResMut<Self>, cameras: Query<(Entity, &ExtractedView, Option<&ViewLightEntities>), With<Camera3d>>){
+        views.0.clear();
+        for (entity, view, lights) in cameras.iter(){
+            let lod_origin = view.world_from_view.translation();
+            views.0.push(CornView{entity, lod_origin, shadow: false});
+            views.0.extend(lights.into_iter().flat_map(|lights| lights.lights.iter())
+                .map(|light| CornView{entity: *light, lod_origin, shadow: true}));
+        }
+    }
+}
+
+/// Where the fields sharing a model layout are drawn from, in the buffers each view scans them into
+#[derive(Default, Debug, Clone)]
+pub struct BatchLayout{
+    /// First indirect draw and vertex instance of each field
+    pub fields: HashMap<Entity, (u64, u64)>,
+    /// Indirect draws of every field, before the vote scan fills in their instances
+    pub draws: Vec<[u32; 5]>,
+    /// Stalks of every field
+    pub count: u64,
+    /// Votes, first level counts and second level counts needed to scan every field at once
+    pub scratch: [u64; 3]
+}
+impl BatchLayout{
+    /// Votes, first level counts and second level counts needed to scan a field. Both are padded to whole workgroups
+    fn scratch(count: u64) -> [u64; 3]{
+        let group1_size = count.div_ceil(256);
+        let group2_size = group1_size.div_ceil(256);
+        if group2_size > 256 {panic!("Too much corn in a single entity. total.div_ceil(256).div_ceil(256) > 256")}
+        [group1_size*256, group2_size*256, group2_size]
+    }
+}
+
+/// Layout of the fields scanned together, for each (lod count, variant count) of the corn models in use
+#[derive(Default, Debug, Resource)]
+pub struct VoteScanLayout{
+    pub batches: HashMap<(u32, u32), BatchLayout>,
+    /// Incremented whenever the layout changes, so each view's batches are rebuilt
+    pub generation: u32,
+    arena_generation: u32
+}
+impl VoteScanLayout{
+    /// Lays the fields out again when a field gets a new slot in the instance arena, or a field's corn model changes.
+    /// Without indirect first instances, each bucket of a field gets room for all of its stalks
+    fn prepare(
+        mut layout: ResMut<Self>,
+        fields: Query<(Entity, Ref<CornModelLods>), With<CornLoaded>>,
+        arena: Res<CornInstanceArena>,
+        features: Res<CornRenderFeatures>
+    ){
+        if layout.arena_generation == arena.generation && !fields.iter().any(|(_, lods)| lods.is_changed()) {return;}
+        let mut fields: Vec<_> = fields.iter().filter_map(|(entity, lods)| Some((arena.slots.get(&entity)?, entity, lods))).collect();
+        fields.sort_by_key(|(slot, _, _)| slot.offset);
+        layout.batches.clear();
+        for (slot, entity, lods) in fields{
+            let batch = layout.batches.entry((lods.lod_count(), lods.variant_count())).or_default();
+            batch.fields.insert(entity, (batch.draws.len() as u64, batch.count));
+            batch.draws.extend(IndirectBuffer::draws(&lods));
+            batch.count += slot.count*Self::bucket_regions(&lods, &features);
+            for (total, needed) in batch.scratch.iter_mut().zip(BatchLayout::scratch(slot.count)) {*total += needed;}
+        }
+        layout.arena_generation = arena.generation;
+        layout.generation = layout.generation.wrapping_add(1);
+    }
+
+    /// Parts of the vertex instance buffer a field's stalks are spread over
+    fn bucket_regions(lods: &CornModelLods, features: &CornRenderFeatures) -> u64{
+        if features.indirect_first_instance {1} else {lods.bucket_count() as u64}
+    }
+}
+
+/// Buffers a view scans the fields of one model layout into, shared by each of them
+pub struct VoteScanBatch{
     pub vote: Buffer,
     pub groups: (Buffer, Buffer),
-    pub config: Buffer,
-    pub data_upload: Buffer
+    pub indirect: IndirectBuffer,
+    pub vertex: VertexInstanceBuffer,
+    /// Bind group of the scan table and the instance arena, rebuilt when either is replaced. None until the batch first has a scan
+    pub bind_group: Option<BindGroup>,
+    /// Table of the fields scanned this frame
+    scans: Vec<u8>,
+    /// Buffer the table is written to, and the instance arena it's bound with
+    scan_table: Option<Buffer>,
+    bound_instances: Option<BufferId>,
+    scan_count: u32,
+    /// Votes, first level counts and second level counts taken up by this frame's scans
+    scratch: [u64; 3],
+    generation: u32
+}
+impl VoteScanBatch{
+    fn new((lod_count, variant_count): (u32, u32), layout: &BatchLayout, generation: u32, render_device: &RenderDevice) -> Self{
+        // One scan bucket per lod of each variant, and one for billboards
+        let bucket_count = (lod_count*variant_count + 1) as u64;
+        let [votes, counts, counts_2] = layout.scratch.map(|size| size.max(1));
+        let vote = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Field Vote Buffer"),
+            size: votes*8,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false
+        });
+        let group1 = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Field Group 1 Buffer"),
+            size: counts*4*bucket_count,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false
+        });
+        let group2 = render_device.create_buffer(&BufferDescriptor{
+            label: Some("Corn Field Group 2 Buffer"),
+            size: counts_2*4*bucket_count,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false
+        });
+        let indirect = IndirectBuffer::create_buffer(&layout.draws, render_device);
+        let vertex = VertexInstanceBuffer::create_buffer(layout.count, render_device);
+        Self{
+            vote, groups: (group1, group2), indirect, vertex, bind_group: None,
+            scans: vec![], scan_table: None, bound_instances: None, scan_count: 0, scratch: [0; 3], generation
+        }
+    }
+
+    /// Writes this frame's table of scans, growing the table's buffer and binding it with the instance arena again when needed.
+    /// The shader finds a workgroup's scan by searching the whole table, so unused entries are filled with scans it never picks
+    fn finish(&mut self, instances: &Buffer, layout: &BindGroupLayout, render_device: &RenderDevice, render_queue: &RenderQueue){
+        if self.scan_count == 0 {return;}
+        let entry = self.scans.len()/self.scan_count as usize;
+        let written = self.scan_table.as_ref().map_or(0, |table| table.size() as usize/entry);
+        let capacity = (self.scan_count as usize).next_power_of_two().max(written);
+        while self.scans.len() < capacity*entry{
+            let start = self.scans.len();
+            self.scans.extend_from_slice(bytemuck::bytes_of(&ScanDescriptor::unused()));
+            self.scans.resize(start + entry, 0);
+        }
+        if written < capacity {
+            self.scan_table = Some(render_device.create_buffer(&BufferDescriptor{
+                label: Some("Corn Field Scan Prepass Scan Table"),
+                size: self.scans.len() as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false
+            }));
+            self.bind_group = None;
+        }
+        let Some(scans) = self.scan_table.as_ref() else {return;};
+        render_queue.write_buffer(scans, 0, &self.scans);
+        if self.bound_instances == Some(instances.id()) && self.bind_group.is_some() {return;}
+        self.bound_instances = Some(instances.id());
+        self.bind_group = Some(render_device.create_bind_group(
+                Some("Corn Field Scan Prepass Bind Group"), 
+                layout, 
+                &[
+                    BindGroupEntry{binding: 0, resource: instances.as_entire_binding()},
+                    BindGroupEntry{binding: 1, resource: self.vote.as_entire_binding()},
+                    BindGroupEntry{binding: 2, resource: self.groups.0.as_entire_binding()},
+                    BindGroupEntry{binding: 3, resource: self.groups.1.as_entire_binding()},
+                    BindGroupEntry{binding: 4, resource: self.indirect.0.as_entire_binding()},
+                    BindGroupEntry{binding: 5, resource: self.vertex.0.as_entire_binding()},
+                    BindGroupEntry{binding: 6, resource: scans.as_entire_binding()},
+                ]
+            ));
+    }
+
+    /// Workgroups of the vote, group scan, group scan 2 and compact stages. Group scan 2 has one per scan
+    pub fn dispatch(&self) -> [u32; 4]{
+        let votes = (self.scratch[0]/256) as u32;
+        [votes, (self.scratch[1]/256) as u32, self.scan_count, votes]
+    }
 }
-impl VoteScanBuffers{
-    fn spawn_scan_buffers(
-        query: Query<(Entity, &InstanceBuffer), (With<CornLoaded>, Without<Self>)>,
+
+/// Vote scan batches of each view, by model layout
+#[derive(Default, Resource)]
+pub struct VoteScanBatches(pub HashMap<Entity, HashMap<(u32, u32), VoteScanBatch>>);
+
+/// Everything about a field's scan that's the same in every view
+struct FieldScan<'a>{
+    entity: Entity,
+    key: (u32, u32),
+    descriptor: ScanDescriptor,
+    lod_cutoffs: Vec<f32>,
+    shadow_lod_cutoffs: Vec<f32>,
+    field_to_world: Mat4,
+    stalk_bounds: Vec2,
+    aabb: Option<&'a CornFieldAabb>,
+    bucket_stride: u64
+}
+
+impl VoteScanBatches{
+    /// Culls every field against each view, and adds the fields in it to the view's batch for their model layout.
+    /// Batches are rebuilt when the layout changes, and dropped with their view
+    #[allow(clippy::too_many_arguments)]
+    fn prepare(
+        mut batches: ResMut<Self>,
+        fields: Query<(
+            Entity, &MainEntity, &CornModelLods, &CornFieldTransform, &PerFieldLodCutoffs,
+            Option<&CornModelBounds>, Option<&CornFieldAabb>, Option<&CornFieldBillboard>
+        ), With<CornLoaded>>,
+        views: Query<(&ExtractedView, Option<&HiZPyramid>)>,
+        corn_views: Res<CornViews>,
+        arena: Res<CornInstanceArena>,
+        layout: Res<VoteScanLayout>,
+        global_cutoffs: Res<GlobalLodCutoffs>,
+        shadow_cutoffs: Res<ShadowLodCutoffs>,
+        mesh_instances: Res<RenderMeshInstances>,
+        allocator: Res<MeshAllocator>,
+        pipeline: Res<VoteScanPipelineResources>,
+        features: Res<CornRenderFeatures>,
         render_device: Res<RenderDevice>,
+        render_queue: Res<RenderQueue>,
         mut commands: Commands
     ){
-        for (entity, InstanceBuffer(_, count)) in query.iter(){
-            let vote_buffer = render_device.create_buffer(&BufferDescriptor{
-                label: Some("Corn Field Vote Buffer"),
-                size: count*8,
-                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
-                mapped_at_creation: false
-            });
-            let group1_size = count.div_ceil(256);
-            let group2_size = group1_size.div_ceil(256);
-            if group2_size > 256 {panic!("Too much corn in a single entity. total.div_ceil(256).div_ceil(256) > 256")}
-            let group1_buffer = render_device.create_buffer(&BufferDescriptor{
-                label: Some("Corn Field Group 1 Buffer"),
-                size: group1_size*4*LOD_COUNT as u64,
-                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
-                mapped_at_creation: false
-            });
-            let group2_buffer = render_device.create_buffer(&BufferDescriptor{
-                label: Some("Corn Field Group 2 Buffer"),
-                size: group2_size*4*LOD_COUNT as u64,
-                usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
-                mapped_at_creation: false
-            });
-            let config = render_device.create_buffer(&BufferDescriptor { 
-                label: Some("Corn Field Scan Prepass Config Buffer"), 
-                size: ConfigData::DATA_SIZE.into(), 
-                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST | BufferUsages::COPY_SRC, 
-                mapped_at_creation: false 
-            });
-            let data_upload = render_device.create_buffer(&BufferDescriptor { 
-                label: Some("Corn Field Scan Prepass Config Buffer Data Upload Buffer"), 
-                size: ConfigData::DATA_SIZE.into(), 
-                usage: BufferUsages::COPY_SRC, 
-                mapped_at_creation: false 
-            });
-            commands.entity(entity).insert(VoteScanBuffers{
-                vote: vote_buffer, groups: (group1_buffer, group2_buffer), config, data_upload
-            });
+        batches.0.retain(|view, _| corn_views.0.iter().any(|corn_view| corn_view.entity == *view));
+        let mut field_buffers: HashMap<Entity, VoteScanBuffers> = HashMap::default();
+        // Fields whose mesh isn't allocated yet aren't scanned
+        let scans: Vec<FieldScan> = fields.iter().filter_map(|(entity, main_entity, lods, transform, cutoffs, bounds, aabb, billboard)| {
+            let slot = arena.slots.get(&entity)?;
+            let key = (lods.lod_count(), lods.variant_count());
+            let (draw_offset, vertex_instance_offset) = *layout.batches.get(&key)?.fields.get(&entity)?;
+            let lod_cutoffs = match cutoffs{
+                PerFieldLodCutoffs::Custom(l) => fit_lod_cutoffs(l, lods.lod_count()),
+                PerFieldLodCutoffs::Global => fit_lod_cutoffs(&global_cutoffs.0, lods.lod_count())
+            };
+            let instance = mesh_instances.render_mesh_queue_data(*main_entity)?;
+            let vertex_offset = allocator.mesh_vertex_slice(&instance.mesh_asset_id)?.range.start;
+            // Billboards are drawn with their own quad mesh. Without one, no stalks are billboarded
+            let (billboard_vertex_offset, billboard_cutoff) = billboard
+                .and_then(|billboard| Some((allocator.mesh_vertex_slice(&billboard.mesh)?.range.start, billboard.distance)))
+                .unwrap_or((0, 0.0));
+            Some(FieldScan{
+                entity,
+                key,
+                descriptor: ScanDescriptor{
+                    instance_offset: slot.offset as u32,
+                    instance_count: slot.count as u32,
+                    draw_offset: draw_offset as u32,
+                    vertex_instance_offset: vertex_instance_offset as u32,
+                    vertex_offset,
+                    billboard_vertex_offset,
+                    billboard_cutoff,
+                    ..default()
+                },
+                shadow_lod_cutoffs: shadow_cutoffs.fit(&lod_cutoffs, lods.lod_count()),
+                lod_cutoffs,
+                field_to_world: transform.0.compute_matrix(),
+                stalk_bounds: bounds.map_or(Vec2::ZERO, |bounds| bounds.0),
+                aabb,
+                bucket_stride: match features.indirect_first_instance{
+                    true => 0,
+                    false => slot.count*CornData::VERTEX_DATA_SIZE
+                }
+            })
+        }).collect();
+        let Some(instances) = arena.buffer.as_ref() else {return;};
+        let max_workgroups = render_device.limits().max_compute_workgroups_per_dimension as u64;
+        for corn_view in corn_views.0.iter(){
+            let Ok((view, pyramid)) = views.get(corn_view.entity) else {continue;};
+            let clip_from_world = view.clip_from_view*view.world_from_view.compute_matrix().inverse();
+            let view_batches = batches.0.entry(corn_view.entity).or_default();
+            view_batches.retain(|_, batch| batch.generation == layout.generation);
+            for batch in view_batches.values_mut(){
+                batch.scans.clear();
+                batch.scan_count = 0;
+                batch.scratch = [0; 3];
+            }
+            for scan in scans.iter(){
+                if !scan.aabb.is_none_or(|aabb| aabb.is_visible(&clip_from_world, &scan.field_to_world, corn_view.shadow)) {continue;}
+                let Some(batch_layout) = layout.batches.get(&scan.key) else {continue;};
+                let batch = view_batches.entry(scan.key)
+                    .or_insert_with(|| VoteScanBatch::new(scan.key, batch_layout, layout.generation, render_device.as_ref()));
+                let scratch = BatchLayout::scratch(scan.descriptor.instance_count as u64);
+                if (batch.scratch[0] + scratch[0])/256 > max_workgroups {
+                    warn_once!("Too many stalks in view for a single vote scan dispatch, some corn fields aren't drawn");
+                    continue;
+                }
+                // Shadow views use coarser lods, and thin out distant stalks
+                let (lod_cutoffs, thinning_distance, density) = match corn_view.shadow{
+                    true => (&scan.shadow_lod_cutoffs, shadow_cutoffs.thinning_distance, shadow_cutoffs.density),
+                    false => (&scan.lod_cutoffs, f32::MAX, 1.0)
+                };
+                ScanDescriptor{
+                    config: ConfigData::new(view, pyramid, corn_view, scan.field_to_world, scan.stalk_bounds),
+                    vote_offset: batch.scratch[0] as u32,
+                    count_offset: batch.scratch[1] as u32,
+                    count_offset_2: batch.scratch[2] as u32,
+                    thinning_distance,
+                    density,
+                    ..scan.descriptor
+                }.write(lod_cutoffs, &mut batch.scans);
+                field_buffers.entry(scan.entity).or_default().0.insert(corn_view.entity, VoteScanViewBuffers{
+                    vote: batch.vote.clone(),
+                    vote_offset: batch.scratch[0]*8,
+                    indirect: batch.indirect.clone(),
+                    indirect_offset: scan.descriptor.draw_offset as u64*IndirectBuffer::DRAW_SIZE,
+                    vertex: batch.vertex.clone(),
+                    vertex_offset: scan.descriptor.vertex_instance_offset as u64*CornData::VERTEX_DATA_SIZE,
+                    bucket_stride: scan.bucket_stride
+                });
+                batch.scan_count += 1;
+                for (total, needed) in batch.scratch.iter_mut().zip(scratch) {*total += needed;}
+            }
+            for batch in view_batches.values_mut(){
+                batch.finish(instances, &pipeline.layout, render_device.as_ref(), render_queue.as_ref());
+            }
+        }
+        for (entity, ..) in fields.iter(){
+            commands.entity(entity).insert(field_buffers.remove(&entity).unwrap_or_default());
         }
     }
-    fn update_config(
-        mut query: Query<(&mut Self, &CornFieldTransform)>,
-        camera: Query<&ExtractedView, With<MainCamera>>,
-        render_device: Res<RenderDevice>
-    ){
-        let Ok(view) = camera.get_single() else {return;};
-        let cam_pos = view.world_from_view.translation().extend(1.0);
-        let w2c = view.clip_from_view*view.world_from_view.compute_matrix().inverse();
+}
 
-        for (mut buffers, transform) in query.iter_mut(){
-            let field_to_world = transform.0.compute_matrix();
-            let field_to_clip = w2c*field_to_world;
-            let cam_pos_field = field_to_world.inverse().mul_vec4(cam_pos);
-            buffers.data_upload = render_device.create_buffer_with_data(&BufferInitDescriptor { 
-                label: Some("Vote Scan Compact Config Buffer Data Upload"), 
-                contents: bytemuck::cast_slice::<ConfigData, u8>(&[ConfigData{field_to_clip, field_to_world, cam_pos_field}]), 
-                usage: BufferUsages::COPY_SRC
-            });
-        }
+/// Where a corn field was scanned into for a single view. The view draws the field from its part of the batch's
+/// vertex instance and indirect buffers
+#[derive(Debug, Clone)]
+pub struct VoteScanViewBuffers{
+    /// Vote buffer of the batch, and the byte offset of the field's votes, one per stalk
+    pub vote: Buffer,
+    pub vote_offset: u64,
+    /// Indirect buffer of the batch, and the byte offset of the field's draws, one per bucket
+    pub indirect: IndirectBuffer,
+    pub indirect_offset: u64,
+    /// Vertex instance buffer of the batch, and the byte offset of the field's stalks
+    pub vertex: VertexInstanceBuffer,
+    pub vertex_offset: u64,
+    /// Bytes between the start of each bucket's stalks, when each bucket has its own part of the field's vertex instances.
+    /// 0 when the buckets are packed together, and drawn from their first instance
+    pub bucket_stride: u64
+}
+
+/// Component which holds where a corn field was scanned into, for each view it's in this frame.
+/// Fields outside a view have no buffers for it, and aren't drawn in it
+#[derive(Default, Component)]
+pub struct VoteScanBuffers(pub HashMap<Entity, VoteScanViewBuffers>);
+
+impl ConfigData{
+    fn new(view: &ExtractedView, pyramid: Option<&HiZPyramid>, corn_view: &CornView, field_to_world: Mat4, stalk_bounds: Vec2) -> Self{
+        let w2c = view.clip_from_view*view.world_from_view.compute_matrix().inverse();
+        let field_to_clip = w2c*field_to_world;
+        let cam_pos_field = field_to_world.inverse().mul_vec4(corn_view.lod_origin.extend(1.0));
+        let pyramid = pyramid.filter(|pyramid| pyramid.ready);
+        let occlusion = pyramid.map_or(Vec4::ZERO, |pyramid| pyramid.depth_size.as_vec2().extend(pyramid.mip_count() as f32).extend(1.0));
+        let field_to_occlusion_clip = pyramid.map_or(field_to_clip, |pyramid| pyramid.clip_from_world*field_to_world);
+        let stalk_bounds = stalk_bounds.extend(corn_view.shadow as u32 as f32).extend(0.0);
+        Self{field_to_world, field_to_clip, cam_pos_field, field_to_occlusion_clip, occlusion, stalk_bounds}
     }
 }
 
-/// Component which holds the bind group as well as the dispatch count
+/// Bind group of a view's depth pyramid, or the fallback pyramid for views without one
 #[derive(Debug, Clone, Component)]
-pub struct VoteScanBindGroup(pub BindGroup, pub [u32; 4]);
-impl VoteScanBindGroup{
-    fn spawn_scan_bindgroup(
-        query: Query<(Entity, &VoteScanBuffers, &InstanceBuffer, &IndirectBuffer, &VertexInstanceBuffer), Without<Self>>,
+pub struct VoteScanOcclusionBindGroup(pub BindGroup, TextureViewId);
+impl VoteScanOcclusionBindGroup{
+    /// Binds each view's pyramid, once it's ready and whenever it's recreated
+    fn prepare(
+        corn_views: Res<CornViews>,
+        views: Query<(Option<&HiZPyramid>, Option<&Self>)>,
         pipeline: Res<VoteScanPipelineResources>,
         render_device: Res<RenderDevice>,
         mut commands: Commands
     ){
-        for(entity, scan, instance, indirect, vertex) in query.iter(){
-            let bindgroup = render_device.create_bind_group(
-                Some("Corn Field Scan Prepass Bind Group"), 
-                &pipeline.layout, 
-                &[
-                    BindGroupEntry{binding: 0, resource: instance.0.as_entire_binding()},
-                    BindGroupEntry{binding: 1, resource: scan.vote.as_entire_binding()},
-                    BindGroupEntry{binding: 2, resource: scan.groups.0.as_entire_binding()},
-                    BindGroupEntry{binding: 3, resource: scan.groups.1.as_entire_binding()},
-                    BindGroupEntry{binding: 4, resource: indirect.0.as_entire_binding()},
-                    BindGroupEntry{binding: 5, resource: vertex.0.as_entire_binding()},
-                    BindGroupEntry{binding: 6, resource: scan.config.as_entire_binding()},
-                ]
-            );
-            let a = instance.1.div_ceil(256); let b = a.div_ceil(256); let c = b.div_ceil(256);
-            let dispatch = [a as u32, b as u32,c as u32, a as u32];
-            commands.entity(entity).insert(VoteScanBindGroup(bindgroup, dispatch));
+        for corn_view in corn_views.0.iter(){
+            let Ok((pyramid, bind_group)) = views.get(corn_view.entity) else {continue;};
+            let pyramid = pyramid.filter(|pyramid| pyramid.ready).map_or(&pipeline.fallback_pyramid, |pyramid| &pyramid.view);
+            if bind_group.is_some_and(|bind_group| bind_group.1 == pyramid.id()) {continue;}
+            commands.entity(corn_view.entity).insert(Self(render_device.create_bind_group(
+                Some("Corn Field Scan Prepass Occlusion Bind Group"),
+                &pipeline.occlusion_layout,
+                &BindGroupEntries::single(pyramid)
+            ), pyramid.id()));
         }
     }
 }
@@ -209,91 +584,43 @@ impl VoteScanBindGroup{
 /// Render Graph Label for Init Operations
 #[derive(Debug, Clone, Default, Hash, PartialEq, Eq, RenderLabel)]
 struct VoteScanStage;
-/// This is the render graph node which executes the Scan Prepass
+/// This is the render graph node which executes the Scan Prepass, for a camera and each of its shadow views
 #[derive(Debug, Default, Clone)]
-pub struct VoteScanNode{
-    ready_entities: Vec<Entity>
-}
-impl bevy::render::render_graph::Node for VoteScanNode{
-    fn update(&mut self, world: &mut World) {
-        let mut query: _ = world.query_filtered::<Entity, (With<CornLoaded>, With<VoteScanBindGroup>)>();
-        self.ready_entities = query.iter(world).collect();
-    }
+pub struct VoteScanNode;
+impl ViewNode for VoteScanNode{
+    type ViewQuery = (Entity, Option<&'static ViewLightEntities>);
     fn run<'w>(
-        &self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext<'w>, world: &'w World,
+        &self, _graph: &mut RenderGraphContext, render_context: &mut RenderContext<'w>, (view, lights): QueryItem<'w, Self::ViewQuery>, world: &'w World,
     ) -> Result<(), NodeRunError> {
-        let global_cutoffs = world.resource::<GlobalLodCutoffs>();
-        let mesh_instances = world.resource::<RenderMeshInstances>();
-        let allocator = world.resource::<MeshAllocator>();
-        // Get corn field data. Bind Group, dispatch count, vertex offset, Lod Push Constants, Config Buffer src/dst
-        let field_data: Vec<(BindGroup, [u32; 4], u32, Vec<u8>, (Buffer, Buffer))> = self.ready_entities.iter().filter_map(|entity| {
-            let Some(VoteScanBindGroup(bindgroup, dispatch)) = world.get::<VoteScanBindGroup>(*entity) else {return None;};
-            let Some(buffers) = world.get::<VoteScanBuffers>(*entity) else {return None;};
-            let lods = match world.get::<PerFieldLodCutoffs>(*entity) {
-                None => {return None;},
-                Some(PerFieldLodCutoffs::Custom(l)) => l.clone(),
-                Some(PerFieldLodCutoffs::Global) => global_cutoffs.0.clone()
-            };
-            let bytes = bytemuck::cast_slice::<f32, u8>(&lods).to_owned();
-            // Get vertex offset
-            let Some(main_entity) = world.get::<MainEntity>(*entity) else {return None;};
-            let Some(instance) = mesh_instances.render_mesh_queue_data(*main_entity) else {return None;};
-            let Some(vertex_buffer) = allocator.mesh_vertex_slice(&instance.mesh_asset_id) else {return None;};
-            let vertex_offset = vertex_buffer.range.start;
-            // return
-            Some((bindgroup.clone(), dispatch.to_owned(), vertex_offset, bytes, (buffers.data_upload.clone(), buffers.config.clone())))
-        }).collect();
-        if field_data.is_empty() {return Ok(());}
-        // Get resources
         let resources = world.resource::<VoteScanPipelineResources>();
         let cache = world.resource::<PipelineCache>();
-        // Get pipelines
-        let mut pipelines = vec![];
-        for pipeline in resources.pipelines.iter(){
-            let Some(pipeline) = cache.get_compute_pipeline(*pipeline) else {return Ok(());};
-            pipelines.push(pipeline);
-        }
-        // Copy Buffers
-        for (_, _, _, _, (src, dst)) in field_data.iter(){
-            render_context.command_encoder().copy_buffer_to_buffer(
-                src, 0, dst, 0, src.size()
-            );
-        }
+        let batches = world.resource::<VoteScanBatches>();
+        // Batches of the camera and each of its shadow views. Bind group, dispatch count, pipelines for the batch's model layout, occlusion bind group
+        let scans: Vec<(&BindGroup, [u32; 4], Vec<&ComputePipeline>, &BindGroup)> = std::iter::once(view)
+            .chain(lights.into_iter().flat_map(|lights| lights.lights.iter().copied()))
+            .filter_map(|view| Some((batches.0.get(&view)?, &world.get::<VoteScanOcclusionBindGroup>(view)?.0)))
+            .flat_map(|(view_batches, occlusion)| view_batches.iter().filter_map(move |(key, batch)| {
+                let pipelines = resources.pipelines.get(key)?.iter()
+                    .map(|pipeline| cache.get_compute_pipeline(*pipeline))
+                    .collect::<Option<Vec<&ComputePipeline>>>()?;
+                let bind_group = batch.bind_group.as_ref().filter(|_| batch.scan_count > 0)?;
+                Some((bind_group, batch.dispatch(), pipelines, occlusion))
+            }))
+            .collect();
+        if scans.is_empty() {return Ok(());}
         // Start Compute Pass
         let mut compute_pass = render_context.command_encoder().begin_compute_pass(&ComputePassDescriptor { 
-            label: Some("Scan Prepass Compute Pass"), timestamp_writes: None 
+            label: Some("Scan Prepass Compute Pass"),
+            timestamp_writes: world.get_resource::<CornGpuTimings>().and_then(|timings| timings.compute_pass_writes(CornPass::VoteScan))
         });
-        // Vote
-        compute_pass.set_pipeline(pipelines[0]);
-        for (bindgroup, dispatch, offset, bytes, _) in field_data.iter(){
-            compute_pass.set_bind_group(0, bindgroup, &[]);
-            compute_pass.set_push_constants(0, bytemuck::cast_slice(&[*offset]));
-            compute_pass.set_push_constants(4, bytes.as_slice());
-            compute_pass.dispatch_workgroups(dispatch[0], 1, 1);
-        }
-        // Group 1
-        compute_pass.set_pipeline(pipelines[1]);
-        for (bindgroup, dispatch, offset, bytes, _) in field_data.iter(){
-            compute_pass.set_bind_group(0, bindgroup, &[]);
-            compute_pass.set_push_constants(0, bytemuck::cast_slice(&[*offset]));
-            compute_pass.set_push_constants(4, bytes.as_slice());
-            compute_pass.dispatch_workgroups(dispatch[1], 1, 1);
-        }
-        // Group 2
-        compute_pass.set_pipeline(pipelines[2]);
-        for (bindgroup, dispatch, offset, bytes, _) in field_data.iter(){
-            compute_pass.set_bind_group(0, bindgroup, &[]);
-            compute_pass.set_push_constants(0, bytemuck::cast_slice(&[*offset]));
-            compute_pass.set_push_constants(4, bytes.as_slice());
-            compute_pass.dispatch_workgroups(dispatch[2], 1, 1);
-        }
-        // Compact
-        compute_pass.set_pipeline(pipelines[3]);
-        for (bindgroup, dispatch, offset, bytes, _) in field_data.iter(){
-            compute_pass.set_bind_group(0, bindgroup, &[]);
-            compute_pass.set_push_constants(0, bytemuck::cast_slice(&[*offset]));
-            compute_pass.set_push_constants(4, bytes.as_slice());
-            compute_pass.dispatch_workgroups(dispatch[3], 1, 1);
+        // Vote, Group 1, Group 2, then Compact. Every batch finishes a stage before the next stage starts
+        for stage in 0..4{
+            for (bind_group, dispatch, pipelines, occlusion) in scans.iter(){
+                compute_pass.set_pipeline(pipelines[stage]);
+                compute_pass.set_bind_group(0, *bind_group, &[]);
+                compute_pass.set_bind_group(1, *occlusion, &[]);
+                compute_pass.dispatch_workgroups(dispatch[stage], 1, 1);
+            }
         }
         Ok(())
     }
@@ -310,245 +637,25 @@ impl Plugin for VoteScanPlugin{
         .sub_app_mut(RenderApp)
             .add_systems(Render, (
                 PerFieldLodCutoffs::insert_default.in_set(RenderSet::Prepare),
+                VoteScanPipelineResources::specialize.in_set(RenderSet::Queue),
                 (
-                    VoteScanBuffers::spawn_scan_buffers,
-                    VoteScanBuffers::update_config
+                    CornViews::prepare,
+                    VoteScanLayout::prepare.after(CornInstanceArena::prepare),
+                    VoteScanBatches::prepare
                 ).chain().in_set(RenderSet::PrepareResources),
-                VoteScanBindGroup::spawn_scan_bindgroup.in_set(RenderSet::PrepareBindGroups)
+                VoteScanOcclusionBindGroup::prepare.in_set(RenderSet::PrepareBindGroups)
             ));
         // Add Scan Node to RenderGraph
-        let mut render_graph = app.sub_app_mut(RenderApp)
-            .world_mut().resource_mut::<RenderGraph>();
-        let graph = render_graph.sub_graph_mut(Core3d);
-        graph.add_node(VoteScanStage, VoteScanNode::default());
-        graph.add_node_edge(VoteScanStage, NodePbr::ShadowPass);
-
-        #[cfg(debug_assertions)]
-        app.add_plugins(readback::ReadbackPlugin);
+        app.sub_app_mut(RenderApp)
+            .init_resource::<CornViews>()
+            .init_resource::<VoteScanLayout>()
+            .init_resource::<VoteScanBatches>()
+            .add_render_graph_node::<ViewNodeRunner<VoteScanNode>>(Core3d, VoteScanStage)
+            .add_render_graph_edge(Core3d, VoteScanStage, NodePbr::ShadowPass)
+            // Prepasses draw the corn too. Scanning before them also means the Hi-Z pyramid, built after them, still holds last frame's depth
+            .add_render_graph_edge(Core3d, VoteScanStage, Node3d::Prepass);
     }
     fn finish(&self, app: &mut App) {
         app.sub_app_mut(RenderApp).init_resource::<VoteScanPipelineResources>();
     }
 }
-
-#[cfg(debug_assertions)]
-pub mod readback{
-    use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
-    use bevy::{core_pipeline::core_3d::graph::Core3d, prelude::*, render::{
-        extract_component::{ExtractComponent, ExtractComponentPlugin}, 
-        render_graph::{RenderGraph, RenderLabel}, 
-        render_resource::Buffer, renderer::RenderDevice, 
-        Render, RenderApp, RenderSet
-    }};
-    use bytemuck::Pod;
-    use wgpu::{BufferUsages, Maintain, MapMode};
-    use wgpu_types::BufferDescriptor;
-    use crate::ecs::corn::{CornData, CornLoaded, IndirectBuffer, InstanceBuffer, VertexInstanceBuffer, LOD_COUNT};
-    use super::{ConfigData, VoteScanBuffers, VoteScanStage};
-    
-    pub fn readback_buffer<T: std::fmt::Debug + Pod>(message: String, buffer: &Buffer, render_device: &RenderDevice){
-        let slice = buffer.slice(..);
-        let flag: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-        let flag_captured = flag.clone();
-        slice.map_async(MapMode::Read, move |v|{
-            if v.is_ok() {flag_captured.store(true, Ordering::Relaxed);}
-        });
-        render_device.poll(Maintain::Wait);
-        if flag.load(Ordering::Relaxed) {
-            let raw = buffer
-                .slice(..).get_mapped_range()
-                .iter().map(|v| *v).collect::<Vec<u8>>();
-            let data = bytemuck::cast_slice::<u8, T>(raw.as_slice()).to_vec();
-            println!("{}", message);
-            for corn in data{
-                println!("{:?}", corn);
-            }
-            println!("");
-        }
-        buffer.unmap(); 
-    }
-
-    #[derive(Default, Debug, Clone, PartialEq, Eq, Reflect, Component, ExtractComponent)]
-    #[reflect(Component)]
-    pub struct ReadbackVoteScan;
-
-    #[derive(Debug, Clone, Component)]
-    pub struct ReadbackVoteScanBuffers{
-        instance: Buffer,
-        vote: Buffer,
-        groups: (Buffer, Buffer),
-        indirect: Buffer,
-        vertex: Buffer,
-        config: Buffer
-    }
-    impl ReadbackVoteScanBuffers{
-        fn create_buffers(
-            query: Query<(
-                Entity, &InstanceBuffer, &VertexInstanceBuffer, &IndirectBuffer, &VoteScanBuffers
-            ), (With<CornLoaded>, With<ReadbackVoteScan>, Without<Self>)>,
-            render_device: Res<RenderDevice>,
-            mut commands: Commands
-        ){
-            for (
-                entity, 
-                InstanceBuffer(instance, _), 
-                VertexInstanceBuffer(vertex), 
-                IndirectBuffer(indirect),
-                VoteScanBuffers { vote, groups, config, ..}
-            ) in query.iter(){
-                commands.entity(entity).insert(Self { 
-                    instance: render_device.create_buffer(&BufferDescriptor { 
-                        label: Some("Vote Scan Instance Buffer Readback"), 
-                        size: instance.size(), 
-                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                        mapped_at_creation: false 
-                    }), 
-                    vote: render_device.create_buffer(&BufferDescriptor { 
-                        label: Some("Vote Scan Vote Buffer Readback"), 
-                        size: vote.size(), 
-                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                        mapped_at_creation: false 
-                    }),
-                    groups: (
-                        render_device.create_buffer(&BufferDescriptor { 
-                            label: Some("Vote Scan Group 1 Buffer Readback"), 
-                            size: groups.0.size(), 
-                            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                            mapped_at_creation: false 
-                        }), 
-                        render_device.create_buffer(&BufferDescriptor { 
-                            label: Some("Vote Scan Group 2 Buffer Readback"), 
-                            size: groups.1.size(), 
-                            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                            mapped_at_creation: false 
-                        }),
-                    ),
-                    indirect: render_device.create_buffer(&BufferDescriptor { 
-                        label: Some("Vote Scan Indirect Buffer Readback"), 
-                        size: indirect.size(), 
-                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                        mapped_at_creation: false 
-                    }),
-                    vertex: render_device.create_buffer(&BufferDescriptor { 
-                        label: Some("Vote Scan Vertex Buffer Readback"), 
-                        size: vertex.size(), 
-                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                        mapped_at_creation: false 
-                    }),
-                    config: render_device.create_buffer(&BufferDescriptor { 
-                        label: Some("Vote Scan Config Buffer Readback"), 
-                        size: config.size(), 
-                        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ, 
-                        mapped_at_creation: false 
-                    }),
-                });
-            }
-        }
-        fn print_readback(
-            query: Query<&ReadbackVoteScanBuffers>,
-            render_device: Res<RenderDevice>
-        ){
-            for ReadbackVoteScanBuffers { 
-                instance, vote, groups, indirect, vertex, config 
-            } in query.iter(){
-                readback_buffer::<CornData>("Instance Buffer: ".to_string(), instance, render_device.as_ref());
-                readback_buffer::<[u32; 2]>("vote Buffer: ".to_string(), vote, render_device.as_ref());
-                readback_buffer::<[u32; LOD_COUNT as usize]>("Group 1 Buffer: ".to_string(), &groups.0, render_device.as_ref());
-                readback_buffer::<[u32; LOD_COUNT as usize]>("Group 2 Buffer: ".to_string(), &groups.1, render_device.as_ref());
-                readback_buffer::<[u32; 5]>("Indirect Buffer: ".to_string(), indirect, render_device.as_ref());
-                readback_buffer::<Mat4>("Vertex Buffer: ".to_string(), vertex, render_device.as_ref());
-                readback_buffer::<ConfigData>("Config Buffer: ".to_string(), config, render_device.as_ref());
-            }
-        }
-    }
-
-    #[derive(Debug, Default, Clone, PartialEq, Eq, Hash, RenderLabel)]
-    pub struct ReadbackVoteScanStage;
-    #[derive(Default, Debug, Clone)]
-    pub struct ReadbackVoteScanNode{
-        pub ready_entities: Vec<Entity>
-    }
-    impl bevy::render::render_graph::Node for ReadbackVoteScanNode{
-        fn update(&mut self, world: &mut World) {
-            let mut query = world.query_filtered::<Entity, (
-                With<ReadbackVoteScanBuffers>, With<InstanceBuffer>, With<IndirectBuffer>, With<VertexInstanceBuffer>, With<VoteScanBuffers>
-            )>();
-            self.ready_entities = query.iter(world).collect();
-        }
-        fn run<'w>(
-            &self,
-            _graph: &mut bevy::render::render_graph::RenderGraphContext,
-            render_context: &mut bevy::render::renderer::RenderContext<'w>,
-            world: &'w World,
-        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
-            for entity in self.ready_entities.iter(){
-                let Some(InstanceBuffer(instance_src, _)) = world.get::<InstanceBuffer>(*entity) else {continue;};
-                let Some(IndirectBuffer(indirect_src)) = world.get::<IndirectBuffer>(*entity) else {continue;};
-                let Some(VertexInstanceBuffer(vertex_src)) = world.get::<VertexInstanceBuffer>(*entity) else {continue;};
-                let Some(VoteScanBuffers{
-                    vote: vote_src, groups: (group1_src, group2_src), config: config_src, ..
-                }) = world.get::<VoteScanBuffers>(*entity) else {continue;};
-
-                let Some(ReadbackVoteScanBuffers { 
-                    instance, vote, groups: (group1, group2), indirect, vertex, config 
-                }) = world.get::<ReadbackVoteScanBuffers>(*entity) else {continue;};
-
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    instance_src, 0, 
-                    instance, 0, 
-                    instance.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    indirect_src, 0, 
-                    indirect, 0, 
-                    indirect.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    vertex_src, 0, 
-                    vertex, 0, 
-                    vertex.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    group1_src, 0, 
-                    group1, 0, 
-                    group1.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    group2_src, 0, 
-                    group2, 0, 
-                    group2.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    vote_src, 0, 
-                    vote, 0, 
-                    vote.size()
-                );
-                render_context.command_encoder().copy_buffer_to_buffer(
-                    config_src, 0, 
-                    config, 0, 
-                    config.size()
-                );
-            }
-            Ok(())
-        }
-    }
-
-    pub struct ReadbackPlugin;
-    impl Plugin for ReadbackPlugin{
-        fn build(&self, app: &mut App) {
-            app
-                .register_type::<ReadbackVoteScan>()
-                .add_plugins(ExtractComponentPlugin::<ReadbackVoteScan>::default())
-            .sub_app_mut(RenderApp)
-                .add_systems(Render, (
-                    ReadbackVoteScanBuffers::create_buffers.in_set(RenderSet::PrepareResources),
-                    ReadbackVoteScanBuffers::print_readback.in_set(RenderSet::Cleanup)
-                ));
-            let mut render_graph = app.sub_app_mut(RenderApp)
-                .world_mut().resource_mut::<RenderGraph>();
-            let graph = render_graph.sub_graph_mut(Core3d);
-            graph.add_node(ReadbackVoteScanStage, ReadbackVoteScanNode::default());
-            graph.add_node_edge(VoteScanStage, ReadbackVoteScanStage);
-        }
-    }
-}
@@ -82,3 +82,86 @@ impl<S: AsCornShader> Plugin for CornShaderPlugin<S>{
         S::on_startup_systems(app.sub_app_mut(RenderApp).world_mut());
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use std::path::Path;
+    use bevy::render::render_resource::{BindingType, BufferBindingType, VertexFormat};
+    use wgpu::BindGroupLayoutEntry;
+    use crate::ecs::corn::{
+        init::{image::ImageInitShader, simple::{SimpleHexagonalInitShader, SimpleInitShader}},
+        render::CornMaterialExtension, scan_prepass::vote::VoteScanPipelineResources
+    };
+    use super::AsCornShader;
+
+    fn read_shader(path: &str) -> String{
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap()
+    }
+
+    /// Kind of resource a layout entry binds
+    fn entry_kind(entry: &BindGroupLayoutEntry) -> &'static str{
+        match entry.ty{
+            BindingType::Buffer{ty: BufferBindingType::Storage{read_only: true}, ..} => "storage_read",
+            BindingType::Buffer{ty: BufferBindingType::Storage{read_only: false}, ..} => "storage",
+            BindingType::Buffer{ty: BufferBindingType::Uniform, ..} => "uniform",
+            BindingType::Texture{..} => "texture",
+            BindingType::Sampler(_) => "sampler",
+            _ => "other"
+        }
+    }
+
+    /// Kind of resource a wgsl `var` declaration binds
+    fn declaration_kind(declaration: &str) -> &'static str{
+        let declaration: String = declaration.split_whitespace().collect();
+        if declaration.starts_with("var<storage,read_write>") {"storage"}
+        else if declaration.starts_with("var<storage>") || declaration.starts_with("var<storage,read>") {"storage_read"}
+        else if declaration.starts_with("var<uniform>") {"uniform"}
+        else if declaration.contains(":texture_") {"texture"}
+        else if declaration.contains(":sampler") {"sampler"}
+        else {"other"}
+    }
+
+    /// Checks a layout binds the same kind of resource at every binding a shader declares in a group, and nothing else
+    fn assert_layout_matches(entries: &[BindGroupLayoutEntry], path: &str, group: u32){
+        let source = read_shader(path);
+        let marker = format!("@group({group}) @binding(");
+        let mut declared: Vec<(u32, &str)> = source.match_indices(&marker).map(|(index, _)| {
+            let rest = &source[index + marker.len()..];
+            let binding = rest[..rest.find(')').unwrap()].parse().unwrap();
+            let var = &rest[rest.find("var").unwrap()..];
+            (binding, declaration_kind(&var[..var.find(';').unwrap()]))
+        }).collect();
+        let mut layout: Vec<(u32, &str)> = entries.iter().map(|entry| (entry.binding, entry_kind(entry))).collect();
+        declared.sort();
+        layout.sort();
+        assert_eq!(layout, declared, "layout doesn't match group {group} of {path}");
+    }
+
+    #[test]
+    fn init_layouts_match_shaders(){
+        assert_layout_matches(&SimpleInitShader::get_bindgroup_layout(), "shaders/corn/init/simple_init.wgsl", 0);
+        assert_layout_matches(&SimpleHexagonalInitShader::get_bindgroup_layout(), "shaders/corn/init/simple_init.wgsl", 0);
+        assert_layout_matches(&ImageInitShader::get_bindgroup_layout(), "shaders/corn/init/image_init.wgsl", 0);
+    }
+
+    #[test]
+    fn vote_layouts_match_shader(){
+        assert_layout_matches(&VoteScanPipelineResources::layout_entries(), "shaders/corn/scan_prepass.wgsl", 0);
+        assert_layout_matches(&VoteScanPipelineResources::occlusion_layout_entries(), "shaders/corn/scan_prepass.wgsl", 1);
+    }
+
+    #[test]
+    fn render_instance_layout_matches_shaders(){
+        let layout = CornMaterialExtension::instance_buffer_layout();
+        assert_eq!(layout.array_stride, layout.attributes.iter().map(|attribute| attribute.format.size()).sum::<u64>());
+        for path in ["shaders/corn/render/vertex.wgsl", "shaders/corn/render/prepass.wgsl"]{
+            let source: String = read_shader(path).split_whitespace().collect();
+            for attribute in layout.attributes.iter(){
+                assert_eq!(attribute.format, VertexFormat::Float32x4);
+                let location = format!("@location({})", attribute.shader_location);
+                let declaration = &source[source.find(&location).unwrap_or_else(|| panic!("{path} has no {location}"))..];
+                assert!(declaration[..declaration.find(',').unwrap()].ends_with(":vec4<f32>"), "{location} of {path} isn't a vec4<f32>");
+            }
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Streaming for very large corn fields. A `StreamedCornField` is never initialized as a whole,
+//! instead its init shader is split into a grid of tiles, and each tile near the `MainCamera` is spawned as its own `CornField` child.
+//! Tiles which fall out of range are despawned, dropping their instance, vertex instance, and indirect buffers in the render world.
+//!
+//! Only `SimpleInitShader` fields can be streamed. Hexagonal and image layouts derive their rows from the whole field's extents,
+//! and image fields stretch their image over the whole field, so neither splits into tiles which line up. A warning is logged for them instead.
+use bevy::{prelude::*, utils::HashMap};
+use crate::ecs::cameras::MainCamera;
+use super::{asset::{CornFieldModel, CornModelAsset}, init::{shader::AsCornInitShader, simple::SimpleInitShader}, CornData, CornField, IndirectBuffer};
+
+/// Init shaders whose fields can be split into tiles for streaming
+pub trait TiledCornInit: AsCornInitShader<Settings = Self>+Clone{
+    /// Number of tiles the field is split into, for tiles roughly `tile_size` across
+    fn tile_grid(&self, tile_size: f32) -> UVec2;
+    /// Init shader for a single tile of the grid, along with the tile's bounds in field space
+    fn tile(&self, tile: UVec2, grid: UVec2) -> (Self, Rect);
+    /// First and last tile of the grid whose bounds can overlap `area`, in field space
+    fn tile_range(&self, grid: UVec2, area: Rect) -> (UVec2, UVec2);
+}
+
+/// Component for corn fields which are streamed in tiles around the main camera.
+/// Must be on an entity with a `TiledCornInit` init shader (only `SimpleInitShader`), and without a `CornField`
+#[derive(Debug, Clone, PartialEq, Reflect, Component)]
+#[reflect(Component)]
+#[require(Transform, Visibility, StreamedTiles)]
+pub struct StreamedCornField{
+    /// Approximate size of a tile in field space
+    pub tile_size: f32,
+    /// Tiles closer than this to the camera are loaded
+    pub residency_radius: f32,
+    /// Maximum number of bytes of gpu buffers used by loaded tiles. Closer tiles are loaded first
+    pub memory_budget: u64,
+    /// Corn model the tiles are drawn with
+    pub model: Handle<CornModelAsset>,
+    pub material: Handle<StandardMaterial>
+}
+impl StreamedCornField{
+    /// Loaded tiles are only unloaded once they are this many tiles past the residency radius, to avoid thrashing at the edge
+    const HYSTERESIS: f32 = 0.5;
+
+    /// Gpu memory used by a tile with `count` stalks, drawn with `draw_count` indirect draws, in a single view.
+    /// Stalks are held in the tile's instance buffer and again in the instance arena.
+    /// Every other view it's drawn in adds its own vertex instances and indirect draws
+    pub fn tile_memory(count: u64, draw_count: u64) -> u64{
+        count*(2*CornData::DATA_SIZE + CornData::VERTEX_DATA_SIZE) + draw_count*IndirectBuffer::DRAW_SIZE
+    }
+
+    /// Spawns and despawns tiles of streamed fields as the main camera moves
+    pub fn stream_tiles<S: TiledCornInit>(
+        mut fields: Query<(Entity, &Self, &S, &GlobalTransform, &mut StreamedTiles), Without<CornField>>,
+        camera: Query<&GlobalTransform, With<MainCamera>>,
+        models: Res<Assets<CornModelAsset>>,
+        mut commands: Commands
+    ){
+        let Ok(camera) = camera.get_single() else {return;};
+        for (entity, settings, shader, transform, mut tiles) in fields.iter_mut(){
+            let camera = transform.affine().inverse().transform_point3(camera.translation()).xz();
+            let grid = shader.tile_grid(settings.tile_size);
+            let unload_radius = settings.residency_radius + settings.tile_size*Self::HYSTERESIS;
+            // One draw per lod of each variant, and one for billboards
+            let draw_count = models.get(&settings.model).map_or(0, |model| model.lod_info.iter().map(Vec::len).sum::<usize>() + 1) as u64;
+            let mut candidates: Vec<(f32, UVec2, S)> = vec![];
+            // Only tiles near the camera can be in range
+            let (first, last) = shader.tile_range(grid, Rect::from_center_half_size(camera, Vec2::splat(unload_radius)));
+            for y in first.y..=last.y{
+                for x in first.x..=last.x{
+                    let tile = UVec2::new(x, y);
+                    let (tile_shader, bounds) = shader.tile(tile, grid);
+                    let distance = camera.distance(camera.clamp(bounds.min, bounds.max));
+                    let radius = if tiles.0.contains_key(&tile) {unload_radius} else {settings.residency_radius};
+                    if distance <= radius {candidates.push((distance, tile, tile_shader));}
+                }
+            }
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let mut memory = 0;
+            let mut resident: HashMap<UVec2, Entity> = HashMap::default();
+            for (_, tile, tile_shader) in candidates{
+                memory += Self::tile_memory(S::get_instance_count(&tile_shader), draw_count);
+                if memory > settings.memory_budget {break;}
+                let tile_entity = tiles.0.remove(&tile).unwrap_or_else(|| commands.spawn((
+                    CornField,
+                    tile_shader,
+                    CornTile(tile),
+                    CornFieldModel(settings.model.clone()),
+                    MeshMaterial3d(settings.material.clone()),
+                    Name::new(format!("Corn Tile {}x{}", tile.x, tile.y))
+                )).set_parent(entity).id());
+                resident.insert(tile, tile_entity);
+            }
+            for tile_entity in tiles.0.values(){
+                commands.entity(*tile_entity).despawn_recursive();
+            }
+            tiles.0 = resident;
+        }
+    }
+
+    /// Warns about streamed fields whose init shader can't be split into tiles, since they never spawn any corn
+    fn warn_untiled(query: Query<Entity, (Added<Self>, Without<SimpleInitShader>)>){
+        for entity in query.iter(){
+            warn!("StreamedCornField on {} has no effect: only fields with a SimpleInitShader can be streamed", entity);
+        }
+    }
+}
+
+/// Component for streamed corn fields holding the currently loaded tiles
+#[derive(Default, Debug, Clone, PartialEq, Eq, Component)]
+pub struct StreamedTiles(pub HashMap<UVec2, Entity>);
+
+/// Component for the tiles of a streamed corn field, holding the tile's position in the grid
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Reflect, Component)]
+#[reflect(Component)]
+pub struct CornTile(pub UVec2);
+
+/// Adds streamed corn fields
+pub struct CornStreamingPlugin;
+impl Plugin for CornStreamingPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .register_type::<StreamedCornField>()
+            .register_type::<CornTile>()
+            .add_systems(Update, (StreamedCornField::stream_tiles::<SimpleInitShader>, StreamedCornField::warn_untiled));
+    }
+}
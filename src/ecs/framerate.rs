@@ -3,7 +3,7 @@ use bevy::{
     color::palettes::css,
     diagnostic::{Diagnostic, DiagnosticPath, DiagnosticsStore, FrameTimeDiagnosticsPlugin}
 };
-use super::cameras::MainCamera;
+use super::{cameras::MainCamera, corn::diagnostics::{CornPass, CornStalkCounts}};
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Reflect, Component)]
 pub struct DiagPos;
@@ -19,6 +19,22 @@ pub fn update_position(
     }
 }
 
+/// Text span listing the visible stalks in each lod
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash, Reflect, Component)]
+pub struct DiagLodCounts;
+
+pub fn update_lod_counts(
+    mut query: Query<&mut TextSpan, With<DiagLodCounts>>,
+    diagnostics: Res<DiagnosticsStore>
+){
+    let counts: Vec<String> = (0..).map_while(|lod| diagnostics.get(&CornStalkCounts::lod(lod))
+        .map(|d| format!(" lod{} {:.0}", lod, d.value().unwrap_or_default()))
+    ).collect();
+    for mut text in query.iter_mut(){
+        text.0 = format!("\nLods:{}", counts.concat());
+    }
+}
+
 pub struct FrameRatePlugin;
 impl Plugin for FrameRatePlugin{
     fn build(&self, app: &mut bevy::prelude::App) {
@@ -27,6 +43,7 @@ impl Plugin for FrameRatePlugin{
             .add_systems(Update, (
                 update_diagnostics,
                 update_position,
+                update_lod_counts,
             ));
     }
 }
@@ -73,6 +90,26 @@ pub fn spawn_fps_text(mut commands: Commands){
         builder.spawn(fps);
         builder.spawn(fps_range);
 
+        builder.spawn(TextSpan::new("\nCorn GPU ms:"));
+        for (label, pass) in [(" init ", CornPass::INIT), (" vote scan ", CornPass::VOTE_SCAN), (" hi-z ", CornPass::HI_Z), (" draw ", CornPass::DRAW)]{
+            builder.spawn(TextSpan::new(label)).with_children(|builder|{
+                builder.spawn((TextSpan::default(), TextFromDiagnostic(pass)));
+            });
+        }
+
+        builder.spawn(TextSpan::new("\nStalks:"));
+        for (label, path) in [
+            (" visible ", CornStalkCounts::TOTAL), (" billboards ", CornStalkCounts::BILLBOARDS),
+            (" tris ", CornStalkCounts::TRIANGLES), (" fields culled ", CornStalkCounts::FIELDS_CULLED)
+        ]{
+            builder.spawn(TextSpan::new(label)).with_children(|builder|{
+                builder.spawn((TextSpan::default(), TextFromDiagnostic(path), DiagnosticMode::Function(|d|{
+                    format!("{:.0}", d.value().unwrap_or_default())
+                })));
+            });
+        }
+        builder.spawn((TextSpan::default(), DiagLodCounts));
+
         builder.spawn(TextSpan::new("\nPos: ")).with_children(|builder|{
             builder.spawn((TextSpan::default(), DiagPos));    
         }); 
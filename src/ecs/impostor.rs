@@ -0,0 +1,604 @@
+//! Impostors, pre-rendered images of a model from a set of view directions, for billboard lods and distant props.
+//! The baker renders every object of a model into a grid of tiles, one tile per view direction, and saves an albedo atlas,
+//! a normal and depth atlas, and a `.impostor` file describing them. Objects are laid out from left to right.
+//!
+//! Bake with `corn_game bake-impostor <input> <output>`, where the input is a `.cornmodel`, baked with one object per variant,
+//! or a gltf, baked as a single object. Pass `--software` to render with a software adapter, on machines without a gpu, and `--assets` to load from another assets folder.
+//!
+//! Layouts:
+//! - `Billboard`: a single side view looking down -z, cropped to the model like `CornModelBillboard`. Its albedo atlas
+//!   can be used directly as the atlas of a corn model billboard
+//! - `Hemisphere`: a hemi-octahedral grid of views from above the horizon
+//! - `Octahedral`: an octahedral grid of views from every direction
+//!
+//! The normal atlas holds world space normals remapped to 0..1 in rgb, and depth toward the camera in a, where 0 and 1 are the
+//! far and near side of the object's bounding sphere. Empty texels have an albedo alpha of 0.
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+use bevy::{
+    asset::{io::Reader, AssetLoader, AssetPath, LoadContext, RenderAssetUsages},
+    core_pipeline::tonemapping::{DebandDither, Tonemapping},
+    gltf::{Gltf, GltfMesh, GltfNode},
+    pbr::{MaterialPipeline, MaterialPipelineKey},
+    prelude::*,
+    render::{
+        camera::{RenderTarget, ScalingMode, Viewport},
+        mesh::MeshVertexBufferLayoutRef,
+        render_resource::{
+            AsBindGroup, Extent3d, RenderPipelineDescriptor, ShaderRef, ShaderType, SpecializedMeshPipelineError, TextureDimension,
+            TextureFormat, TextureUsages
+        },
+        renderer::{initialize_renderer, RenderInstance, WgpuWrapper},
+        settings::{RenderCreation, WgpuSettings},
+        view::{screenshot::{Screenshot, ScreenshotCaptured}, RenderLayers}
+    }
+};
+use serde::{Deserialize, Serialize};
+use super::corn::asset::CornModelDescriptor;
+
+/// Set of view directions an impostor is rendered from
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash, Reflect, Serialize, Deserialize)]
+pub enum ImpostorLayout{
+    #[default]
+    Billboard,
+    Hemisphere,
+    Octahedral
+}
+impl ImpostorLayout{
+    /// Direction from an object to the camera of a tile, given the tile's center in -1..1. Rows go from top to bottom
+    pub fn direction(&self, uv: Vec2) -> Vec3{
+        match self{
+            Self::Billboard => Vec3::Z,
+            Self::Hemisphere => {
+                let xz = Vec2::new(uv.x + uv.y, uv.x - uv.y)*0.5;
+                Vec3::new(xz.x, 1.0 - xz.x.abs() - xz.y.abs(), xz.y).normalize()
+            },
+            Self::Octahedral => {
+                let y = 1.0 - uv.x.abs() - uv.y.abs();
+                let xz = if y < 0.0 {(Vec2::ONE - uv.yx().abs())*uv.signum()} else {uv};
+                Vec3::new(xz.x, y, xz.y).normalize()
+            }
+        }
+    }
+}
+impl FromStr for ImpostorLayout{
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str(){
+            "billboard" => Ok(Self::Billboard),
+            "hemisphere" => Ok(Self::Hemisphere),
+            "octahedral" => Ok(Self::Octahedral),
+            _ => Err(format!("unknown impostor layout {s}, expected billboard, hemisphere, or octahedral"))
+        }
+    }
+}
+
+/// A `.impostor` file, written by the baker
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImpostorDescriptor{
+    pub layout: ImpostorLayout,
+    /// Number of tiles along each side of an object's grid. Always 1 for billboards
+    pub grid: u32,
+    /// Size of a tile in pixels
+    pub tile_size: u32,
+    /// Bounding sphere (center, radius) of each object, in the order they are laid out
+    pub bounds: Vec<(Vec3, f32)>,
+    /// Width and height of the quad billboards are drawn with. Unused by other layouts, which fit their bounding sphere
+    pub size: Vec2,
+    /// Path to the albedo atlas, relative to the `.impostor` file
+    pub albedo: String,
+    /// Path to the normal and depth atlas, relative to the `.impostor` file
+    pub normal_depth: String
+}
+
+/// A baked impostor
+#[derive(Debug, Clone, PartialEq, Asset, Reflect)]
+pub struct ImpostorAsset{
+    #[dependency]
+    pub albedo: Handle<Image>,
+    #[dependency]
+    pub normal_depth: Handle<Image>,
+    pub layout: ImpostorLayout,
+    pub grid: u32,
+    pub tile_size: u32,
+    pub bounds: Vec<(Vec3, f32)>,
+    pub size: Vec2
+}
+
+#[derive(Debug)]
+pub enum ImpostorError{
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    Path(bevy::asset::ParseAssetPathError)
+}
+impl std::fmt::Display for ImpostorError{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Self::Io(err) => write!(f, "Could not read impostor: {err}"),
+            Self::Ron(err) => write!(f, "Could not parse impostor: {err}"),
+            Self::Path(err) => write!(f, "Invalid impostor atlas path: {err}")
+        }
+    }
+}
+impl std::error::Error for ImpostorError{}
+impl From<std::io::Error> for ImpostorError{
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<ron::error::SpannedError> for ImpostorError{
+    fn from(value: ron::error::SpannedError) -> Self {
+        Self::Ron(value)
+    }
+}
+impl From<bevy::asset::ParseAssetPathError> for ImpostorError{
+    fn from(value: bevy::asset::ParseAssetPathError) -> Self {
+        Self::Path(value)
+    }
+}
+
+/// Loads `.impostor` files, along with their atlases
+#[derive(Default)]
+pub struct ImpostorLoader;
+impl AssetLoader for ImpostorLoader{
+    type Asset = ImpostorAsset;
+    type Settings = ();
+    type Error = ImpostorError;
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).await?;
+        let descriptor: ImpostorDescriptor = ron::de::from_bytes(bytes.as_slice())?;
+        let albedo = load_context.asset_path().resolve_embed(descriptor.albedo.as_str())?;
+        let normal_depth = load_context.asset_path().resolve_embed(descriptor.normal_depth.as_str())?;
+        Ok(ImpostorAsset{
+            albedo: load_context.load(albedo),
+            normal_depth: load_context.load(normal_depth),
+            layout: descriptor.layout,
+            grid: descriptor.grid,
+            tile_size: descriptor.tile_size,
+            bounds: descriptor.bounds,
+            size: descriptor.size
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["impostor"]
+    }
+}
+
+/// Adds the impostor asset
+pub struct ImpostorPlugin;
+impl Plugin for ImpostorPlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .init_asset::<ImpostorAsset>()
+            .register_type::<ImpostorAsset>()
+            .init_asset_loader::<ImpostorLoader>();
+    }
+}
+
+/// Settings of a bake, set from the command line
+#[derive(Debug, Clone, PartialEq, Resource)]
+pub struct ImpostorBakeSettings{
+    /// `.cornmodel` or gltf to bake, relative to the assets folder
+    pub input: String,
+    /// The assets folder, the `AssetPlugin` file path of the bake
+    pub assets: PathBuf,
+    /// Path the atlases and `.impostor` file are written to, without an extension
+    pub output: PathBuf,
+    pub layout: ImpostorLayout,
+    pub grid: u32,
+    pub tile_size: u32
+}
+impl ImpostorBakeSettings{
+    fn output_path(&self, extension: &str) -> PathBuf{
+        let mut path = self.output.clone().into_os_string();
+        path.push(".");
+        path.push(extension);
+        path.into()
+    }
+
+    fn grid(&self) -> u32{
+        if self.layout == ImpostorLayout::Billboard {1} else {self.grid.max(1)}
+    }
+}
+
+/// Unlit material the baker renders with, writing either albedo or normal and depth
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
+pub struct ImpostorBakeMaterial{
+    #[uniform(0)]
+    pub settings: ImpostorBakeUniform,
+    #[texture(1)]
+    #[sampler(2)]
+    pub base_color_texture: Option<Handle<Image>>
+}
+impl ImpostorBakeMaterial{
+    fn from_standard(material: Option<&StandardMaterial>, output: ImpostorBakeOutput, (center, radius): (Vec3, f32)) -> Self{
+        let default = StandardMaterial::default();
+        let material = material.unwrap_or(&default);
+        let alpha_cutoff = match material.alpha_mode{
+            AlphaMode::Opaque => 0.0,
+            AlphaMode::Mask(cutoff) => cutoff,
+            _ => 0.5
+        };
+        Self{
+            settings: ImpostorBakeUniform{
+                base_color: material.base_color.to_linear().to_vec4(),
+                center,
+                radius,
+                alpha_cutoff,
+                output: output as u32
+            },
+            base_color_texture: material.base_color_texture.clone()
+        }
+    }
+}
+impl Material for ImpostorBakeMaterial{
+    fn fragment_shader() -> ShaderRef {
+        "shaders/impostor/bake.wgsl".into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        _layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialPipelineKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // Corn leaves are single sided planes
+        descriptor.primitive.cull_mode = None;
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, ShaderType, Reflect)]
+pub struct ImpostorBakeUniform{
+    pub base_color: Vec4,
+    /// Bounding sphere of the object, for depth
+    pub center: Vec3,
+    pub radius: f32,
+    pub alpha_cutoff: f32,
+    /// `ImpostorBakeOutput` as an int
+    pub output: u32
+}
+
+/// Atlas a bake pass renders to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImpostorBakeOutput{
+    Albedo = 0,
+    NormalDepth = 1
+}
+impl ImpostorBakeOutput{
+    const ALL: [Self; 2] = [Self::Albedo, Self::NormalDepth];
+
+    fn format(&self) -> TextureFormat{
+        match self{
+            Self::Albedo => TextureFormat::Rgba8UnormSrgb,
+            Self::NormalDepth => TextureFormat::Rgba8Unorm
+        }
+    }
+
+    fn extension(&self) -> &'static str{
+        match self{
+            Self::Albedo => "albedo.png",
+            Self::NormalDepth => "normal_depth.png"
+        }
+    }
+}
+
+/// A mesh of an object being baked
+#[derive(Debug, Clone)]
+struct BakePrimitive{
+    mesh: Handle<Mesh>,
+    material: Option<Handle<StandardMaterial>>,
+    transform: Mat4
+}
+
+/// Progress of a bake
+#[derive(Default, Debug, Clone, Resource)]
+enum ImpostorBake{
+    #[default]
+    Start,
+    /// Waiting for the gltf to load. Holds the names of the root node of each object, or None to bake every root node as one object
+    Loading{gltf: Handle<Gltf>, objects: Option<Vec<String>>},
+    /// Cameras are spawned, waiting a few frames for pipelines and textures to be ready
+    Rendering{frames: u32, descriptor: ImpostorDescriptor, targets: [Handle<Image>; 2]},
+    /// Screenshots of the atlases have been requested, counting the ones written
+    Capturing{saved: u32, failed: bool, descriptor: ImpostorDescriptor}
+}
+impl ImpostorBake{
+    /// Frames rendered before the atlases are captured
+    const WARMUP_FRAMES: u32 = 8;
+
+    fn start(
+        mut bake: ResMut<Self>,
+        settings: Res<ImpostorBakeSettings>,
+        asset_server: Res<AssetServer>,
+        mut exit: EventWriter<AppExit>
+    ){
+        let Self::Start = *bake else {return;};
+        if !settings.input.ends_with(".cornmodel"){
+            *bake = Self::Loading{gltf: asset_server.load(settings.input.clone()), objects: None};
+            return;
+        }
+        // The corn model asset merges its variants, so read the descriptor for the nodes of each variant instead
+        let loading = std::fs::read(settings.assets.join(&settings.input)).map_err(ImpostorError::from)
+            .and_then(|bytes| Ok(ron::de::from_bytes::<CornModelDescriptor>(bytes.as_slice())?))
+            .and_then(|descriptor| Ok(Self::Loading{
+                gltf: asset_server.load(AssetPath::parse(&settings.input).resolve_embed(&descriptor.gltf)?),
+                objects: Some(descriptor.variants.iter().filter_map(|variant| variant.lods.first().cloned()).collect())
+            }));
+        match loading{
+            Ok(loading) => *bake = loading,
+            Err(err) => {
+                error!("Could not read {}: {err}", settings.input);
+                exit.send(AppExit::error());
+            }
+        }
+    }
+
+    /// Once the gltf has loaded, spawns every object on its own render layers, and a camera for each of its tiles
+    #[allow(clippy::too_many_arguments)]
+    fn spawn_views(
+        mut bake: ResMut<Self>,
+        settings: Res<ImpostorBakeSettings>,
+        asset_server: Res<AssetServer>,
+        gltfs: Res<Assets<Gltf>>,
+        nodes: Res<Assets<GltfNode>>,
+        gltf_meshes: Res<Assets<GltfMesh>>,
+        meshes: Res<Assets<Mesh>>,
+        standard_materials: Res<Assets<StandardMaterial>>,
+        mut materials: ResMut<Assets<ImpostorBakeMaterial>>,
+        mut images: ResMut<Assets<Image>>,
+        mut commands: Commands,
+        mut exit: EventWriter<AppExit>
+    ){
+        let Self::Loading{gltf, objects} = &*bake else {return;};
+        if !asset_server.is_loaded_with_dependencies(gltf) {
+            if asset_server.load_state(gltf).is_failed() {
+                error!("Could not load {}", settings.input);
+                exit.send(AppExit::error());
+            }
+            return;
+        }
+        let Some(gltf) = gltfs.get(gltf) else {return;};
+
+        // Corn model nodes are merged without their transforms, so they are ignored here to match
+        let objects: Vec<Vec<BakePrimitive>> = match objects{
+            Some(names) => names.iter().map(|name| {
+                let mut primitives = vec![];
+                if let Some(node) = gltf.named_nodes.get(name.as_str()) {
+                    Self::node_primitives(node, Mat4::IDENTITY, false, &nodes, &gltf_meshes, &mut primitives);
+                }else{
+                    warn!("Missing node {name} in {}", settings.input);
+                }
+                primitives
+            }).collect(),
+            None => {
+                let children: Vec<AssetId<GltfNode>> = gltf.nodes.iter().filter_map(|node| nodes.get(node))
+                    .flat_map(|node| node.children.iter().map(|child| child.id())).collect();
+                let mut primitives = vec![];
+                for root in gltf.nodes.iter().filter(|node| !children.contains(&node.id())){
+                    Self::node_primitives(root, Mat4::IDENTITY, true, &nodes, &gltf_meshes, &mut primitives);
+                }
+                vec![primitives]
+            }
+        };
+        if objects.iter().all(|primitives| primitives.is_empty()) {
+            error!("Nothing to bake in {}", settings.input);
+            exit.send(AppExit::error());
+            return;
+        }
+
+        let positions = |primitives: &[BakePrimitive]| -> Vec<Vec3> {
+            primitives.iter().filter_map(|primitive| {
+                let positions = meshes.get(&primitive.mesh)?.attribute(Mesh::ATTRIBUTE_POSITION)?.as_float3()?;
+                Some(positions.iter().map(|p| primitive.transform.transform_point3(Vec3::from_array(*p))).collect::<Vec<_>>())
+            }).flatten().collect()
+        };
+        let bounds: Vec<(Vec3, f32)> = objects.iter().map(|primitives| {
+            let positions = positions(primitives);
+            let (min, max) = positions.iter().fold((Vec3::MAX, Vec3::MIN), |(min, max), p| (min.min(*p), max.max(*p)));
+            let center = if positions.is_empty() {Vec3::ZERO} else {(min + max)*0.5};
+            (center, positions.iter().fold(0.0f32, |radius, p| radius.max(p.distance(center))).max(0.001))
+        }).collect();
+        // Matches `CornModelBillboard::fit_size`, so a billboard atlas lines up with the corn model's billboard quad
+        let size = objects.iter().flat_map(|primitives| positions(primitives))
+            .fold(Vec2::ZERO, |size, p| size.max(Vec2::new(2.0*p.x.abs().max(p.z.abs()), p.y)));
+
+        let grid = settings.grid();
+        let extent = Extent3d{width: settings.tile_size*grid*objects.len() as u32, height: settings.tile_size*grid, depth_or_array_layers: 1};
+        let targets = ImpostorBakeOutput::ALL.map(|output| {
+            let mut image = Image::new_fill(extent, TextureDimension::D2, &[0; 4], output.format(), RenderAssetUsages::default());
+            image.texture_descriptor.usage = TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST | TextureUsages::RENDER_ATTACHMENT;
+            images.add(image)
+        });
+
+        let mut order = 0;
+        for (object, primitives) in objects.iter().enumerate(){
+            let (center, radius) = bounds[object];
+            for (output, target) in ImpostorBakeOutput::ALL.into_iter().zip(targets.iter()){
+                let layers = RenderLayers::layer(1 + 2*object + output as usize);
+                for primitive in primitives.iter(){
+                    let material = ImpostorBakeMaterial::from_standard(
+                        primitive.material.as_ref().and_then(|material| standard_materials.get(material)), output, (center, radius)
+                    );
+                    commands.spawn((
+                        Mesh3d(primitive.mesh.clone()),
+                        MeshMaterial3d(materials.add(material)),
+                        Transform::from_matrix(primitive.transform),
+                        layers.clone()
+                    ));
+                }
+                for tile in 0..grid*grid{
+                    let (x, y) = (tile%grid, tile/grid);
+                    let direction = settings.layout.direction((Vec2::new(x as f32, y as f32) + 0.5)/grid as f32*2.0 - 1.0);
+                    let (target_center, scaling_mode) = match settings.layout{
+                        ImpostorLayout::Billboard => (Vec3::Y*size.y*0.5, ScalingMode::Fixed{width: size.x, height: size.y}),
+                        _ => (center, ScalingMode::Fixed{width: 2.0*radius, height: 2.0*radius})
+                    };
+                    let eye = target_center + direction*2.0*radius;
+                    let up = if direction.y.abs() > 0.999 {-direction.y*Vec3::Z} else {Vec3::Y};
+                    commands.spawn((
+                        Name::from(format!("Impostor Camera {object} {x} {y}")),
+                        Camera3d::default(),
+                        Camera{
+                            target: RenderTarget::Image(target.clone()),
+                            viewport: Some(Viewport{
+                                physical_position: UVec2::new(object as u32*grid + x, y)*settings.tile_size,
+                                physical_size: UVec2::splat(settings.tile_size),
+                                ..default()
+                            }),
+                            order,
+                            ..default()
+                        },
+                        Projection::Orthographic(OrthographicProjection{
+                            scaling_mode,
+                            near: 0.0,
+                            far: 4.0*radius,
+                            ..OrthographicProjection::default_3d()
+                        }),
+                        Transform::from_translation(eye).looking_at(target_center, up),
+                        Tonemapping::None,
+                        DebandDither::Disabled,
+                        Msaa::Off,
+                        layers.clone()
+                    ));
+                    order += 1;
+                }
+            }
+        }
+
+        let name = settings.output.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        *bake = Self::Rendering{
+            frames: 0,
+            descriptor: ImpostorDescriptor{
+                layout: settings.layout,
+                grid,
+                tile_size: settings.tile_size,
+                bounds,
+                size,
+                albedo: format!("{name}.{}", ImpostorBakeOutput::Albedo.extension()),
+                normal_depth: format!("{name}.{}", ImpostorBakeOutput::NormalDepth.extension())
+            },
+            targets
+        };
+    }
+
+    /// Collects the meshes below a gltf node
+    fn node_primitives(
+        node: &Handle<GltfNode>,
+        parent: Mat4,
+        use_transforms: bool,
+        nodes: &Assets<GltfNode>,
+        gltf_meshes: &Assets<GltfMesh>,
+        primitives: &mut Vec<BakePrimitive>
+    ){
+        let Some(node) = nodes.get(node) else {return;};
+        let transform = if use_transforms {parent*node.transform.compute_matrix()} else {parent};
+        if let Some(mesh) = node.mesh.as_ref().and_then(|mesh| gltf_meshes.get(mesh)){
+            primitives.extend(mesh.primitives.iter().map(|primitive| BakePrimitive{
+                mesh: primitive.mesh.clone(),
+                material: primitive.material.clone(),
+                transform
+            }));
+        }
+        for child in node.children.iter(){
+            Self::node_primitives(child, transform, use_transforms, nodes, gltf_meshes, primitives);
+        }
+    }
+
+    /// Captures both atlases once the cameras have rendered a few frames
+    fn capture(
+        mut bake: ResMut<Self>,
+        settings: Res<ImpostorBakeSettings>,
+        mut commands: Commands
+    ){
+        let Self::Rendering{frames, descriptor, targets} = &mut *bake else {return;};
+        *frames += 1;
+        if *frames < Self::WARMUP_FRAMES {return;}
+        for (output, target) in ImpostorBakeOutput::ALL.into_iter().zip(targets.iter()){
+            let path = settings.output_path(output.extension());
+            // `save_to_disk` drops the alpha channel, which holds coverage and depth
+            commands.spawn(Screenshot::image(target.clone())).observe(
+                move |trigger: Trigger<ScreenshotCaptured>, mut bake: ResMut<Self>| {
+                    let result = trigger.event().0.clone().try_into_dynamic().map_err(|err| err.to_string())
+                        .and_then(|image| image.to_rgba8().save(&path).map_err(|err| err.to_string()));
+                    if let Err(err) = &result {error!("Could not save {}: {err}", path.display());}
+                    if let Self::Capturing{saved, failed, ..} = &mut *bake{
+                        *saved += 1;
+                        *failed |= result.is_err();
+                    }
+                }
+            );
+        }
+        *bake = Self::Capturing{saved: 0, failed: false, descriptor: std::mem::take(descriptor)};
+    }
+
+    /// Writes the `.impostor` file and exits, once both atlases are saved
+    fn finish(
+        bake: Res<Self>,
+        settings: Res<ImpostorBakeSettings>,
+        mut exit: EventWriter<AppExit>
+    ){
+        let Self::Capturing{saved, failed, descriptor} = &*bake else {return;};
+        if (*saved as usize) < ImpostorBakeOutput::ALL.len() {return;}
+        if *failed {
+            exit.send(AppExit::error());
+            return;
+        }
+        let path = settings.output_path("impostor");
+        let result = ron::ser::to_string_pretty(descriptor, ron::ser::PrettyConfig::default()).map_err(|err| err.to_string())
+            .and_then(|ron| std::fs::write(&path, ron).map_err(|err| err.to_string()));
+        match result{
+            Ok(()) => {
+                info!("Baked impostor {}", path.display());
+                exit.send(AppExit::Success);
+            },
+            Err(err) => {
+                error!("Could not save {}: {err}", path.display());
+                exit.send(AppExit::error());
+            }
+        }
+    }
+}
+
+/// Renders with a software adapter, so bakes run on machines without a gpu. None if there is no software adapter
+pub fn software_render_creation() -> Option<RenderCreation>{
+    let settings = WgpuSettings::default();
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor{
+        backends: settings.backends.unwrap_or(wgpu::Backends::all()),
+        ..default()
+    });
+    let options = wgpu::RequestAdapterOptions{
+        power_preference: settings.power_preference,
+        force_fallback_adapter: true,
+        compatible_surface: None
+    };
+    // initialize_renderer panics without an adapter, so check for one first
+    futures_lite::future::block_on(instance.request_adapter(&options))?;
+    let (device, queue, adapter_info, adapter) = futures_lite::future::block_on(initialize_renderer(&instance, &settings, &options));
+    Some(RenderCreation::Manual(device, queue, adapter_info, adapter, RenderInstance(Arc::new(WgpuWrapper::new(instance)))))
+}
+
+/// Bakes a single impostor, then exits. Expects a headless app, without the game's plugins
+pub struct ImpostorBakePlugin(pub ImpostorBakeSettings);
+impl Plugin for ImpostorBakePlugin{
+    fn build(&self, app: &mut App) {
+        app
+            .add_plugins(MaterialPlugin::<ImpostorBakeMaterial>::default())
+            .insert_resource(self.0.clone())
+            .insert_resource(ClearColor(Color::NONE))
+            .init_resource::<ImpostorBake>()
+            .add_systems(Update, (
+                ImpostorBake::start,
+                ImpostorBake::spawn_views,
+                ImpostorBake::capture,
+                ImpostorBake::finish
+            ).chain());
+    }
+}
@@ -3,7 +3,11 @@ pub mod cameras;
 pub mod flycam;
 pub mod framerate;
 pub mod test_cube;
+pub mod auto_lod;
+pub mod impostor;
 
+use auto_lod::AutoLodPlugin;
+use impostor::ImpostorPlugin;
 use bevy::prelude::*;
 use corn::CornFieldComponentPlugin;
 use test_cube::TestCube;
@@ -17,7 +21,9 @@ impl Plugin for CornECSPlugin{
             FrameRatePlugin, 
             FlyCamPlugin, 
             CornFieldComponentPlugin,
-            TestCube
+            TestCube,
+            AutoLodPlugin,
+            ImpostorPlugin
         ));
     }
 }
\ No newline at end of file
@@ -4,18 +4,95 @@ pub mod ecs;
 pub mod util;
 
 use std::path::PathBuf;
-use bevy::{log::LogPlugin, prelude::*, render::{sync_world::RenderEntity, RenderApp}};
+use bevy::{
+    log::LogPlugin, prelude::*, render::{settings::RenderCreation, sync_world::RenderEntity, RenderApp, RenderPlugin},
+    window::ExitCondition, winit::WinitPlugin
+};
 use clap::Parser;
+use ecs::impostor::{software_render_creation, ImpostorBakePlugin, ImpostorBakeSettings, ImpostorLayout};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, clap::Parser, Default, Reflect, Serialize, Deserialize, Resource)]
 #[reflect(Resource)]
-struct Cli {
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
     scenes: Vec<PathBuf>,
     #[arg(short, long)]
     client: bool,
     #[arg(short, long)]
     server: bool,
+    #[command(subcommand)]
+    pub command: Option<CornCommand>,
+}
+
+/// Tools run instead of the game
+#[derive(Debug, Clone, clap::Subcommand, Reflect, Serialize, Deserialize)]
+pub enum CornCommand {
+    /// Bakes impostor atlases of a `.cornmodel` or gltf, see `ecs::impostor`
+    BakeImpostor(BakeImpostorArgs),
+}
+
+#[derive(Debug, Clone, clap::Args, Reflect, Serialize, Deserialize)]
+pub struct BakeImpostorArgs {
+    /// `.cornmodel` or gltf to bake, relative to the assets folder
+    input: String,
+    /// Output path, without an extension
+    output: PathBuf,
+    /// Assets folder the input is loaded from
+    #[arg(long, default_value = "assets")]
+    assets: PathBuf,
+    /// billboard, hemisphere, or octahedral
+    #[arg(short, long, default_value = "billboard")]
+    layout: ImpostorLayout,
+    /// Tiles along each side of an object's grid
+    #[arg(short, long, default_value_t = 8)]
+    grid: u32,
+    /// Size of a tile in pixels
+    #[arg(short, long, default_value_t = 256)]
+    tile_size: u32,
+    /// Render with a software adapter, for machines without a gpu
+    #[arg(long)]
+    software: bool,
+}
+
+/// Runs a headless app which bakes an impostor, then exits
+pub fn bake_impostor(args: BakeImpostorArgs) -> AppExit {
+    let software = args.software.then(software_render_creation);
+    let missing_software = matches!(software, Some(None));
+    let render_creation = software.flatten().unwrap_or_else(|| RenderCreation::Automatic(default()));
+    let mut app = App::new();
+    app
+        .add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .set(RenderPlugin {
+                    render_creation,
+                    synchronous_pipeline_compilation: true,
+                    ..default()
+                })
+                .set(AssetPlugin {
+                    file_path: args.assets.to_string_lossy().into_owned(),
+                    ..default()
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugins(ImpostorBakePlugin(ImpostorBakeSettings {
+            input: args.input,
+            assets: args.assets,
+            output: args.output,
+            layout: args.layout,
+            grid: args.grid,
+            tile_size: args.tile_size,
+        }));
+    // Logging only starts with the app, so report the missing adapter from inside it
+    if missing_software {
+        app.add_systems(Startup, || error!("No software adapter found, baking with the default adapter"));
+    }
+    app.run()
 }
 
 pub struct CornGame;
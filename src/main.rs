@@ -1,11 +1,15 @@
 use bevy::prelude::*;
-use corn_game::{util::debug_app::DebugApp, CornGame};
+use clap::Parser;
+use corn_game::{bake_impostor, util::debug_app::DebugApp, Cli, CornCommand, CornGame};
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 /*
 Use grave key to lock mouse and enable free cam movement
 space/shift to go up and down
 */
-fn main() {
+fn main() -> AppExit {
+    if let Some(CornCommand::BakeImpostor(args)) = Cli::parse().command {
+        return bake_impostor(args);
+    }
     let mut app = App::new();
     app.add_plugins(CornGame);
     app.add_debug_plugins((
@@ -14,5 +18,5 @@ fn main() {
         bevy::remote::http::RemoteHttpPlugin::default(),
         bevy_remote_inspector::RemoteInspectorPlugins
     ));
-    app.run();
+    app.run()
 }
\ No newline at end of file
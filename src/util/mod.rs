@@ -5,3 +5,4 @@ pub mod scene_set;
 pub mod observer_ext;
 pub mod clone_entity;
 
+pub mod simplify;
@@ -0,0 +1,252 @@
+//! Quadric error metric mesh simplification (Garland & Heckbert, see `doc/LOD.md`).
+//! Edges are collapsed onto one of their endpoints, so the simplified mesh only needs a new index buffer and keeps every vertex attribute as is.
+use std::{cmp::Ordering, collections::BinaryHeap};
+use bevy::{math::DVec3, prelude::*, render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues}, utils::HashMap};
+
+/// Error quadric, the sum of squared distances to a set of planes. Stored as the upper triangle of a symmetric 4x4 matrix
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Quadric([f64; 10]);
+impl Quadric{
+    fn from_plane(normal: DVec3, d: f64, weight: f64) -> Self{
+        let (a, b, c) = (normal.x, normal.y, normal.z);
+        Self([a*a, a*b, a*c, a*d, b*b, b*c, b*d, c*c, c*d, d*d].map(|v| v*weight))
+    }
+    fn add(&mut self, other: &Self){
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {*a += b;}
+    }
+    fn error(&self, p: DVec3) -> f64{
+        let q = &self.0;
+        q[0]*p.x*p.x + 2.0*q[1]*p.x*p.y + 2.0*q[2]*p.x*p.z + 2.0*q[3]*p.x
+            + q[4]*p.y*p.y + 2.0*q[5]*p.y*p.z + 2.0*q[6]*p.y
+            + q[7]*p.z*p.z + 2.0*q[8]*p.z
+            + q[9]
+    }
+}
+
+/// Candidate edge collapse, moving vertex `from` onto vertex `to`. Ordered so the cheapest collapse is popped first
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Collapse{
+    cost: f64,
+    from: u32,
+    to: u32,
+    /// Versions of both vertices when this collapse was computed. Outdated collapses are skipped
+    versions: (u32, u32)
+}
+impl Eq for Collapse{}
+impl PartialOrd for Collapse{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {Some(self.cmp(other))}
+}
+impl Ord for Collapse{
+    fn cmp(&self, other: &Self) -> Ordering {other.cost.total_cmp(&self.cost)}
+}
+
+/// Simplifies a triangle list down to about `target` triangles, returning the new indices.
+/// Vertices sharing a position are collapsed together, and open borders are kept in place as much as possible
+pub fn simplify(positions: &[Vec3], indices: &[u32], target: usize) -> Vec<u32>{
+    /// Weight of the planes holding open borders in place, relative to the surface
+    const BORDER_WEIGHT: f64 = 10.0;
+    /// Collapses which turn a triangle further than this from its old normal are rejected
+    const MIN_NORMAL_DOT: f64 = 0.2;
+
+    // Weld vertices by position, so seams in normals or uvs don't split the surface
+    let mut welded: HashMap<[u32; 3], u32> = HashMap::default();
+    let mut points: Vec<DVec3> = vec![];
+    let mut representative: Vec<u32> = vec![];
+    let vertex_point: Vec<u32> = positions.iter().enumerate().map(|(i, p)| {
+        *welded.entry(p.to_array().map(f32::to_bits)).or_insert_with(|| {
+            points.push(p.as_dvec3());
+            representative.push(i as u32);
+            points.len() as u32 - 1
+        })
+    }).collect();
+    let mut triangles: Vec<[u32; 3]> = indices.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+    let mut corners: Vec<[u32; 3]> = triangles.iter().map(|t| t.map(|v| vertex_point[v as usize])).collect();
+    let mut alive: Vec<bool> = corners.iter().map(|[a, b, c]| a != b && b != c && a != c).collect();
+    let mut count = alive.iter().filter(|a| **a).count();
+    if count <= target {return indices.to_vec();}
+
+    // Quadrics of every point, from the planes of its triangles, and planes along open borders
+    let mut quadrics = vec![Quadric::default(); points.len()];
+    let mut point_triangles: Vec<Vec<u32>> = vec![vec![]; points.len()];
+    let mut edges: HashMap<(u32, u32), u32> = HashMap::default();
+    let normal = |[a, b, c]: [u32; 3], points: &[DVec3]| (points[b as usize] - points[a as usize]).cross(points[c as usize] - points[a as usize]);
+    for (i, corner) in corners.iter().enumerate(){
+        if !alive[i] {continue;}
+        let n = normal(*corner, &points);
+        let area = n.length();
+        if area <= 0.0 {continue;}
+        let n = n/area;
+        let plane = Quadric::from_plane(n, -n.dot(points[corner[0] as usize]), area);
+        for (j, point) in corner.iter().enumerate(){
+            quadrics[*point as usize].add(&plane);
+            point_triangles[*point as usize].push(i as u32);
+            let next = corner[(j+1)%3];
+            *edges.entry((*point.min(&next), *point.max(&next))).or_default() += 1;
+        }
+    }
+    for (i, corner) in corners.iter().enumerate(){
+        if !alive[i] {continue;}
+        let n = normal(*corner, &points).normalize_or_zero();
+        for j in 0..3{
+            let (a, b) = (corner[j], corner[(j+1)%3]);
+            if edges.get(&(a.min(b), a.max(b))) != Some(&1) {continue;}
+            let edge = points[b as usize] - points[a as usize];
+            let border = edge.cross(n).normalize_or_zero();
+            let plane = Quadric::from_plane(border, -border.dot(points[a as usize]), edge.length_squared()*BORDER_WEIGHT);
+            quadrics[a as usize].add(&plane);
+            quadrics[b as usize].add(&plane);
+        }
+    }
+
+    let mut versions = vec![0u32; points.len()];
+    let mut heap: BinaryHeap<Collapse> = BinaryHeap::new();
+    let push = |heap: &mut BinaryHeap<Collapse>, quadrics: &[Quadric], versions: &[u32], a: u32, b: u32| {
+        let mut q = quadrics[a as usize];
+        q.add(&quadrics[b as usize]);
+        let (cost_a, cost_b) = (q.error(points[a as usize]), q.error(points[b as usize]));
+        let (from, to, cost) = if cost_a <= cost_b {(b, a, cost_a)} else {(a, b, cost_b)};
+        heap.push(Collapse{cost, from, to, versions: (versions[from as usize], versions[to as usize])});
+    };
+    for (a, b) in edges.keys(){
+        push(&mut heap, &quadrics, &versions, *a, *b);
+    }
+
+    while count > target{
+        let Some(collapse) = heap.pop() else {break;};
+        let (from, to) = (collapse.from as usize, collapse.to as usize);
+        if collapse.versions != (versions[from], versions[to]) {continue;}
+        // Reject collapses which flip a triangle
+        let flips = point_triangles[from].iter().any(|t| {
+            let corner = corners[*t as usize];
+            if !alive[*t as usize] || corner.contains(&(to as u32)) {return false;}
+            let moved = corner.map(|p| if p as usize == from {to as u32} else {p});
+            let (before, after) = (normal(corner, &points).normalize_or_zero(), normal(moved, &points).normalize_or_zero());
+            before.dot(after) < MIN_NORMAL_DOT
+        });
+        if flips {continue;}
+        // Move every triangle of `from` onto `to`
+        let moved = std::mem::take(&mut point_triangles[from]);
+        for t in moved.iter(){
+            let t = *t as usize;
+            if !alive[t] {continue;}
+            for (point, vertex) in corners[t].iter_mut().zip(triangles[t].iter_mut()){
+                if *point as usize != from {continue;}
+                *point = to as u32;
+                *vertex = representative[to];
+            }
+            if corners[t][0] == corners[t][1] || corners[t][1] == corners[t][2] || corners[t][0] == corners[t][2]{
+                alive[t] = false;
+                count -= 1;
+            }
+        }
+        point_triangles[to].extend(moved);
+        point_triangles[to].retain(|t| alive[*t as usize]);
+        let q = quadrics[from];
+        quadrics[to].add(&q);
+        versions[from] += 1;
+        versions[to] += 1;
+        // Recompute the collapses around `to`
+        let mut neighbors: Vec<u32> = point_triangles[to].iter().flat_map(|t| corners[*t as usize]).filter(|p| *p as usize != to).collect();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        for neighbor in neighbors{
+            push(&mut heap, &quadrics, &versions, to as u32, neighbor);
+        }
+    }
+    triangles.into_iter().zip(alive).filter(|(_, alive)| *alive).flat_map(|(t, _)| t).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimplifyError{
+    /// Only triangle lists can be simplified
+    UnsupportedTopology(PrimitiveTopology),
+    MissingPositions,
+    NoTriangles
+}
+impl std::fmt::Display for SimplifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Self::UnsupportedTopology(topology) => write!(f, "Can't simplify a mesh with {topology:?} topology, expected a triangle list"),
+            Self::MissingPositions => write!(f, "Can't simplify a mesh without Float32x3 positions"),
+            Self::NoTriangles => write!(f, "Can't simplify a mesh without triangles")
+        }
+    }
+}
+impl core::error::Error for SimplifyError{}
+
+/// Simplifies a triangle list mesh down to about `ratio` of its triangles, keeping its vertices
+pub fn simplify_mesh(mesh: &Mesh, ratio: f32) -> Result<Mesh, SimplifyError>{
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {return Err(SimplifyError::UnsupportedTopology(mesh.primitive_topology()));}
+    let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {return Err(SimplifyError::MissingPositions);};
+    let positions: Vec<Vec3> = positions.iter().map(|p| Vec3::from(*p)).collect();
+    let indices: Vec<u32> = match mesh.indices(){
+        Some(indices) => indices.iter().map(|i| i as u32).collect(),
+        None => (0..positions.len() as u32).collect()
+    };
+    if indices.len() < 3 {return Err(SimplifyError::NoTriangles);}
+    let target = ((indices.len()/3) as f32*ratio) as usize;
+    let mut simplified = mesh.clone();
+    simplified.insert_indices(Indices::U32(simplify(positions.as_slice(), indices.as_slice(), target)));
+    Ok(simplified)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Flat grid of `size`x`size` cells on the xz plane, facing +y
+    fn grid(size: u32) -> (Vec<Vec3>, Vec<u32>){
+        let positions = (0..=size).flat_map(|z| (0..=size).map(move |x| Vec3::new(x as f32, 0.0, z as f32))).collect();
+        let indices = (0..size).flat_map(|z| (0..size).flat_map(move |x| {
+            let i = z*(size + 1) + x;
+            [i, i + size + 1, i + 1, i + 1, i + size + 1, i + size + 2]
+        })).collect();
+        (positions, indices)
+    }
+
+    fn triangles(positions: &[Vec3], indices: &[u32]) -> Vec<[Vec3; 3]>{
+        indices.chunks_exact(3).map(|t| [positions[t[0] as usize], positions[t[1] as usize], positions[t[2] as usize]]).collect()
+    }
+
+    fn normal([a, b, c]: [Vec3; 3]) -> Vec3{
+        (b - a).cross(c - a)
+    }
+
+    #[test]
+    fn meets_the_target(){
+        let (positions, indices) = grid(10);
+        let simplified = simplify(&positions, &indices, 50);
+        assert!(simplified.len()/3 <= 50);
+        assert!(!simplified.is_empty());
+    }
+
+    #[test]
+    fn keeps_borders_in_place(){
+        let (positions, indices) = grid(10);
+        let simplified = simplify(&positions, &indices, 20);
+        // Every corner survives, and the grid still covers its whole area
+        for corner in [Vec3::ZERO, Vec3::X*10.0, Vec3::Z*10.0, Vec3::new(10.0, 0.0, 10.0)]{
+            assert!(simplified.iter().any(|i| positions[*i as usize] == corner), "lost corner {corner}");
+        }
+        let area: f32 = triangles(&positions, &simplified).into_iter().map(|t| normal(t).length()*0.5).sum();
+        assert!((area - 100.0).abs() < 1e-3, "area {area}");
+    }
+
+    #[test]
+    fn never_flips_normals(){
+        let (mut positions, indices) = grid(12);
+        // A bumpy surface, so collapses have somewhere to fold over
+        for p in positions.iter_mut() {p.y = (p.x*0.9).sin()*(p.z*0.7).cos();}
+        let simplified = simplify(&positions, &indices, 30);
+        for triangle in triangles(&positions, &simplified){
+            assert!(normal(triangle).y > 0.0, "flipped triangle {triangle:?}");
+        }
+    }
+
+    #[test]
+    fn small_meshes_are_returned_as_is(){
+        let (positions, indices) = grid(2);
+        assert_eq!(simplify(&positions, &indices, 8), indices);
+        assert_eq!(simplify(&positions, &indices, 100), indices);
+    }
+}